@@ -1,7 +1,10 @@
 use x86_64::{instructions::port::Port, structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB}, PhysAddr, VirtAddr};
-use crate::{println, serial_println};
+use crate::{memory, println, serial_println};
 use core::isize;
 
+pub mod hpet;
+pub mod madt;
+
 #[repr(C, packed)]
 pub struct RSDP {
     pub signature: [u8; 8],
@@ -409,3 +412,82 @@ pub fn parse_fadt(fadt_addr: u32) {
     serial_println!("Extended Firmware Control: {:#X}", fadt.x_firmware_ctrl);
     serial_println!("Extended DSDT: {:#X}", fadt.x_dsdt);
 }
+
+/// The BIOS data area word holding the EBDA's segment.
+const EBDA_SEGMENT_POINTER: u64 = 0x40E;
+
+/// Returns a reference to `T` at physical address `addr`, through the
+/// physical memory mapping.
+unsafe fn phys_ref<T>(addr: u64) -> Option<&'static T> {
+    let virt = memory::phys_to_virt(PhysAddr::new(addr))?;
+    memory::check_range(virt, core::mem::size_of::<T>() as u64, false).ok()?;
+    Some(&*virt.as_ptr::<T>())
+}
+
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |acc, &x| acc.wrapping_add(x)) == 0
+}
+
+/// Looks for the RSDP in the EBDA and the BIOS area like `find_rsdp`, but
+/// through the physical memory mapping, so nothing has to be identity
+/// mapped first. Returns its physical address.
+pub fn locate_rsdp() -> Option<PhysAddr> {
+    let ebda = u64::from(*unsafe { phys_ref::<u16>(EBDA_SEGMENT_POINTER)? }) << 4;
+    let areas = [(ebda, ebda + 1024), (0xE0000, 0x100000)];
+    for &(start, end) in areas.iter() {
+        for addr in (start..end).step_by(16) {
+            let rsdp = match unsafe { phys_ref::<RSDP>(addr) } {
+                Some(rsdp) => rsdp,
+                None => break,
+            };
+            if rsdp.signature == *b"RSD PTR " && rsdp.validate() {
+                return Some(PhysAddr::new(addr));
+            }
+        }
+    }
+    None
+}
+
+/// The whole of the ACPI table at `addr`, if its checksum is right.
+pub fn table_bytes(addr: PhysAddr) -> Option<&'static [u8]> {
+    let header = unsafe { phys_ref::<SDTHeader>(addr.as_u64())? };
+    let length = header.length as usize;
+    if length < core::mem::size_of::<SDTHeader>() {
+        return None;
+    }
+    let virt = memory::phys_to_virt(addr)?;
+    memory::check_range(virt, length as u64, false).ok()?;
+    let bytes = unsafe { core::slice::from_raw_parts(virt.as_ptr::<u8>(), length) };
+    if checksum_ok(bytes) {
+        Some(bytes)
+    } else {
+        None
+    }
+}
+
+/// Finds the table with `signature` (like `b"APIC"`) through the XSDT, or the
+/// RSDT on ACPI 1.0. Returns its physical address.
+pub fn find_table(signature: &[u8; 4]) -> Option<PhysAddr> {
+    let rsdp_addr = locate_rsdp()?;
+    let rsdp = unsafe { phys_ref::<RSDP>(rsdp_addr.as_u64())? };
+    let (root, entry_size) = if rsdp.revision >= 2 {
+        let xsdp = unsafe { phys_ref::<XSDP>(rsdp_addr.as_u64())? };
+        (xsdp.xsdt_address, 8)
+    } else {
+        (u64::from(rsdp.rsdt_address), 4)
+    };
+
+    let root = table_bytes(PhysAddr::new(root))?;
+    let entries = &root[core::mem::size_of::<SDTHeader>()..];
+    for entry in entries.chunks_exact(entry_size) {
+        let mut address = [0u8; 8];
+        address[..entry_size].copy_from_slice(entry);
+        let address = PhysAddr::new(u64::from_le_bytes(address));
+        if let Some(table) = table_bytes(address) {
+            if table[..4] == signature[..] {
+                return Some(address);
+            }
+        }
+    }
+    None
+}
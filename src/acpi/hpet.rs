@@ -0,0 +1,63 @@
+//! The `HPET` table, which says where the High Precision Event Timer is.
+
+use super::{find_table, table_bytes, SDTHeader};
+use core::mem::size_of;
+use x86_64::PhysAddr;
+
+/// `GenericAddress::addr_space` for system memory, the only kind of HPET
+/// we can use.
+const SYSTEM_MEMORY: u8 = 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HpetTable {
+    /// Where its registers are.
+    pub address: PhysAddr,
+    /// Which HPET this is, when there's more than one.
+    pub number: u8,
+    /// The shortest period it can do periodic interrupts at without losing
+    /// any, in counter ticks.
+    pub minimum_tick: u16,
+}
+
+impl HpetTable {
+    /// Reads the HPET table out of the ACPI tables, if there is one.
+    pub fn find() -> Option<HpetTable> {
+        HpetTable::parse(table_bytes(find_table(b"HPET")?)?)
+    }
+
+    /// Parses a whole HPET table, header included.
+    pub fn parse(table: &[u8]) -> Option<HpetTable> {
+        // the event timer block ID, then the base address as a GenericAddress
+        let base = size_of::<SDTHeader>() + 4;
+        let fields = table.get(base..base + 16)?;
+        if fields[0] != SYSTEM_MEMORY {
+            return None;
+        }
+        let mut address = [0u8; 8];
+        address.copy_from_slice(&fields[4..12]);
+        Some(HpetTable {
+            address: PhysAddr::new(u64::from_le_bytes(address)),
+            number: fields[12],
+            minimum_tick: u16::from_le_bytes([fields[13], fields[14]]),
+        })
+    }
+}
+
+#[test_case]
+fn test_parse_hpet_table() {
+    let mut table = alloc::vec![0u8; size_of::<SDTHeader>()];
+    table[..4].copy_from_slice(b"HPET");
+    table.extend_from_slice(&0x8086_A201u32.to_le_bytes());
+    table.extend_from_slice(&[SYSTEM_MEMORY, 64, 0, 0]);
+    table.extend_from_slice(&0xFED0_0000u64.to_le_bytes());
+    table.extend_from_slice(&[0, 0x80, 0, 0]);
+
+    let hpet = HpetTable::parse(&table).unwrap();
+    assert_eq!(hpet.address, PhysAddr::new(0xFED0_0000));
+    assert_eq!((hpet.number, hpet.minimum_tick), (0, 0x80));
+
+    // in I/O space
+    table[size_of::<SDTHeader>() + 4] = 1;
+    assert_eq!(HpetTable::parse(&table), None);
+    assert_eq!(HpetTable::parse(&table[..40]), None);
+}
@@ -0,0 +1,190 @@
+//! The MADT (signature `APIC`), which lists the interrupt controllers.
+
+use super::{find_table, table_bytes, SDTHeader};
+use alloc::vec::Vec;
+use core::mem::size_of;
+use x86_64::PhysAddr;
+
+/// Entry types.
+const ENTRY_LOCAL_APIC: u8 = 0;
+const ENTRY_IO_APIC: u8 = 1;
+const ENTRY_INTERRUPT_OVERRIDE: u8 = 2;
+const ENTRY_LOCAL_APIC_NMI: u8 = 4;
+const ENTRY_LOCAL_APIC_ADDRESS: u8 = 5;
+
+/// `flags` bit saying there are 8259 PICs too.
+const PCAT_COMPAT: u32 = 0x1;
+/// Local APIC entry flag bit for a CPU that can be used.
+const LOCAL_APIC_ENABLED: u32 = 0x1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocalApic {
+    pub processor_id: u8,
+    pub apic_id: u8,
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoApic {
+    pub id: u8,
+    pub address: PhysAddr,
+    /// The first global system interrupt it handles.
+    pub gsi_base: u32,
+}
+
+/// An ISA IRQ that isn't wired to the GSI with the same number, or isn't
+/// edge triggered and active high like ISA IRQs normally are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    pub active_low: bool,
+    pub level_triggered: bool,
+}
+
+/// Which LINT pin of which CPU's local APIC the NMI comes in on. A
+/// `processor_id` of 0xFF means every CPU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocalApicNmi {
+    pub processor_id: u8,
+    pub lint: u8,
+    pub active_low: bool,
+    pub level_triggered: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Madt {
+    pub local_apic_address: PhysAddr,
+    /// Whether there are 8259 PICs, which have to be masked.
+    pub has_pics: bool,
+    pub local_apics: Vec<LocalApic>,
+    pub io_apics: Vec<IoApic>,
+    pub overrides: Vec<InterruptOverride>,
+    pub nmis: Vec<LocalApicNmi>,
+}
+
+impl Madt {
+    /// Reads the MADT out of the ACPI tables, if there is one.
+    pub fn find() -> Option<Madt> {
+        Madt::parse(table_bytes(find_table(b"APIC")?)?)
+    }
+
+    /// Parses a whole MADT, header included. Returns `None` if it, or any
+    /// entry in it, is cut short.
+    pub fn parse(table: &[u8]) -> Option<Madt> {
+        // polarity and trigger mode are 2 bits each, 0b11 meaning low/level
+        let active_low = |flags: u16| flags & 0x3 == 0x3;
+        let level_triggered = |flags: u16| (flags >> 2) & 0x3 == 0x3;
+
+        let header = size_of::<SDTHeader>();
+        let mut madt = Madt {
+            local_apic_address: PhysAddr::new(u64::from(u32_at(table, header)?)),
+            has_pics: u32_at(table, header + 4)? & PCAT_COMPAT != 0,
+            local_apics: Vec::new(),
+            io_apics: Vec::new(),
+            overrides: Vec::new(),
+            nmis: Vec::new(),
+        };
+
+        let mut offset = header + 8;
+        while offset + 2 <= table.len() {
+            let (kind, length) = (table[offset], usize::from(table[offset + 1]));
+            if length < 2 {
+                return None;
+            }
+            // everything after the type and length, which is all an entry
+            // can be read from
+            let body = table.get(offset + 2..offset + length)?;
+            match kind {
+                ENTRY_LOCAL_APIC => madt.local_apics.push(LocalApic {
+                    processor_id: *body.get(0)?,
+                    apic_id: *body.get(1)?,
+                    enabled: u32_at(body, 2)? & LOCAL_APIC_ENABLED != 0,
+                }),
+                ENTRY_IO_APIC => madt.io_apics.push(IoApic {
+                    id: *body.get(0)?,
+                    address: PhysAddr::new(u64::from(u32_at(body, 2)?)),
+                    gsi_base: u32_at(body, 6)?,
+                }),
+                ENTRY_INTERRUPT_OVERRIDE => {
+                    let flags = u16_at(body, 6)?;
+                    madt.overrides.push(InterruptOverride {
+                        irq: *body.get(1)?,
+                        gsi: u32_at(body, 2)?,
+                        active_low: active_low(flags),
+                        level_triggered: level_triggered(flags),
+                    });
+                }
+                ENTRY_LOCAL_APIC_NMI => {
+                    let flags = u16_at(body, 1)?;
+                    madt.nmis.push(LocalApicNmi {
+                        processor_id: *body.get(0)?,
+                        lint: *body.get(3)?,
+                        active_low: active_low(flags),
+                        level_triggered: level_triggered(flags),
+                    });
+                }
+                ENTRY_LOCAL_APIC_ADDRESS => {
+                    let low = u64::from(u32_at(body, 2)?);
+                    let high = u64::from(u32_at(body, 6)?);
+                    madt.local_apic_address = PhysAddr::new(high << 32 | low);
+                }
+                // x2APIC entries and the like, which we don't use
+                _ => {}
+            }
+            offset += length;
+        }
+        Some(madt)
+    }
+
+    /// The GSI an ISA IRQ comes in on, and whether it's active low and level
+    /// triggered.
+    pub fn isa_irq(&self, irq: u8) -> (u32, bool, bool) {
+        match self.overrides.iter().find(|o| o.irq == irq) {
+            Some(o) => (o.gsi, o.active_low, o.level_triggered),
+            None => (u32::from(irq), false, false),
+        }
+    }
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes([
+        *bytes.get(offset)?,
+        *bytes.get(offset + 1)?,
+    ]))
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from(u16_at(bytes, offset)?) | u32::from(u16_at(bytes, offset + 2)?) << 16)
+}
+
+#[test_case]
+fn test_parse_madt() {
+    let mut table = alloc::vec![0u8; size_of::<SDTHeader>()];
+    table[..4].copy_from_slice(b"APIC");
+    table.extend_from_slice(&0xFEE0_0000u32.to_le_bytes());
+    table.extend_from_slice(&PCAT_COMPAT.to_le_bytes());
+    // CPU 0 with APIC ID 0, enabled
+    table.extend_from_slice(&[ENTRY_LOCAL_APIC, 8, 0, 0, 1, 0, 0, 0]);
+    // I/O APIC 1 at 0xFEC00000, from GSI 0
+    table.extend_from_slice(&[ENTRY_IO_APIC, 12, 1, 0, 0x00, 0x00, 0xC0, 0xFE, 0, 0, 0, 0]);
+    // IRQ 0 on GSI 2, and IRQ 9 on GSI 9 level triggered and active low
+    table.extend_from_slice(&[ENTRY_INTERRUPT_OVERRIDE, 10, 0, 0, 2, 0, 0, 0, 0, 0]);
+    table.extend_from_slice(&[ENTRY_INTERRUPT_OVERRIDE, 10, 0, 9, 9, 0, 0, 0, 0x0F, 0]);
+
+    let madt = Madt::parse(&table).unwrap();
+    assert_eq!(madt.local_apic_address, PhysAddr::new(0xFEE0_0000));
+    assert!(madt.has_pics && madt.local_apics[0].enabled);
+    assert_eq!(madt.io_apics[0].address, PhysAddr::new(0xFEC0_0000));
+    assert_eq!(madt.isa_irq(0), (2, false, false));
+    assert_eq!(madt.isa_irq(9), (9, true, true));
+    assert_eq!(madt.isa_irq(1), (1, false, false));
+
+    // an entry too short for what it's meant to hold
+    let mut short = table.clone();
+    short.extend_from_slice(&[ENTRY_LOCAL_APIC, 2]);
+    assert_eq!(Madt::parse(&short), None);
+    // an entry running off the end
+    table.extend_from_slice(&[ENTRY_LOCAL_APIC, 8, 0]);
+    assert_eq!(Madt::parse(&table), None);
+}
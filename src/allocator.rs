@@ -1,5 +1,9 @@
+use crate::memory::{self, BootInfoFrameAllocator};
 use alloc::alloc::{GlobalAlloc, Layout};
-use core::ptr::null_mut;
+use core::{
+    ptr::null_mut,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 use fixed_size_block::FixedSizeBlockAllocator;
 use x86_64::{
     structures::paging::{
@@ -13,30 +17,68 @@ pub mod fixed_size_block;
 pub mod linked_list;
 
 pub const HEAP_START: usize = 0x_4444_4444_0000;
+/// How much `init_heap` maps to start with. It grows from there as needed.
 pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB
+/// How big the heap can grow, unless `set_heap_limit` says otherwise.
+pub const HEAP_MAX_SIZE: usize = 64 * 1024 * 1024; // 64 MiB
+/// The least the heap grows by at once, so it isn't a page at a time.
+const GROW_STEP: usize = 64 * 1024;
+const PAGE_SIZE: usize = 4096;
 
 #[global_allocator]
 static ALLOCATOR: Locked<FixedSizeBlockAllocator> = Locked::new(FixedSizeBlockAllocator::new());
 
-pub fn init_heap(
+/// How much of the heap is mapped, from `HEAP_START`.
+static HEAP_MAPPED: AtomicUsize = AtomicUsize::new(0);
+static HEAP_LIMIT: AtomicUsize = AtomicUsize::new(HEAP_MAX_SIZE);
+
+/// Maps every page in `start..end` to a new frame, stopping at the first that
+/// fails. Returns how many bytes it mapped, along with the error if one did.
+fn map_heap(
+    start: usize,
+    end: usize,
     mapper: &mut impl Mapper<Size4KiB>,
     frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-) -> Result<(), MapToError<Size4KiB>> {
+) -> (usize, Result<(), MapToError<Size4KiB>>) {
     let page_range = {
-        let heap_start = VirtAddr::new(HEAP_START as u64);
-        let heap_end = heap_start + HEAP_SIZE - 1u64;
-        let heap_start_page = Page::containing_address(heap_start);
-        let heap_end_page = Page::containing_address(heap_end);
+        let heap_start_page = Page::containing_address(VirtAddr::new(start as u64));
+        let heap_end_page = Page::containing_address(VirtAddr::new(end as u64 - 1));
         Page::range_inclusive(heap_start_page, heap_end_page)
     };
 
+    let mut mapped = 0;
     for page in page_range {
-        let frame = frame_allocator
-            .allocate_frame()
-            .ok_or(MapToError::FrameAllocationFailed)?;
+        let frame = match frame_allocator.allocate_frame() {
+            Some(frame) => frame,
+            None => return (mapped, Err(MapToError::FrameAllocationFailed)),
+        };
         let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-        unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+        match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+            Ok(flush) => flush.flush(),
+            Err(e) => return (mapped, Err(e)),
+        }
+        mapped += PAGE_SIZE;
     }
+    (mapped, Ok(()))
+}
+
+/// Maps the first `HEAP_SIZE` bytes of the heap. `memory::init` and
+/// `BootInfoFrameAllocator::init` have to have been called.
+pub fn init_heap() -> Result<(), &'static str> {
+    let (_, result) = memory::with_mapper(|mapper| {
+        map_heap(
+            HEAP_START,
+            HEAP_START + HEAP_SIZE,
+            mapper,
+            &mut BootInfoFrameAllocator::handle(),
+        )
+    })
+    .ok_or("the page tables haven't been set up")?;
+    result.map_err(|e| match e {
+        MapToError::FrameAllocationFailed => "out of frames for the heap",
+        _ => "the heap's pages are already mapped",
+    })?;
+    HEAP_MAPPED.store(HEAP_SIZE, Ordering::SeqCst);
 
     unsafe {
         ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
@@ -45,6 +87,71 @@ pub fn init_heap(
     Ok(())
 }
 
+/// Maps more of the heap onto the end of it, enough for `needed` more bytes
+/// (if the limit and the free frames allow). Returns how many bytes it mapped,
+/// which might be fewer than asked for, or none at all.
+///
+/// Called by the allocator with its lock held, when it's run out.
+fn grow(needed: usize) -> usize {
+    let mapped = HEAP_MAPPED.load(Ordering::SeqCst);
+    if mapped == 0 {
+        return 0;
+    }
+    let room = HEAP_LIMIT.load(Ordering::SeqCst).saturating_sub(mapped) & !(PAGE_SIZE - 1);
+    let by = align_up(needed.max(GROW_STEP), PAGE_SIZE).min(room);
+    if by == 0 {
+        return 0;
+    }
+    let start = HEAP_START + mapped;
+    // if the page tables are busy, whoever has them is the one allocating,
+    // and waiting for them would never end
+    let grown = memory::try_with_mapper(|mapper| {
+        let mut frame_allocator = BootInfoFrameAllocator::handle();
+        map_heap(start, start + by, mapper, &mut frame_allocator).0
+    })
+    .unwrap_or(0);
+    HEAP_MAPPED.store(mapped + grown, Ordering::SeqCst);
+    grown
+}
+
+/// Where the mapped part of the heap ends, for the allocator to check it's
+/// the one at the end before growing.
+fn heap_end() -> usize {
+    HEAP_START + HEAP_MAPPED.load(Ordering::SeqCst)
+}
+
+/// How much of the heap is mapped right now, in bytes.
+pub fn heap_size() -> usize {
+    HEAP_MAPPED.load(Ordering::SeqCst)
+}
+
+/// How big the heap is allowed to grow, in bytes.
+pub fn heap_limit() -> usize {
+    HEAP_LIMIT.load(Ordering::SeqCst)
+}
+
+/// Changes how big the heap is allowed to grow. It never shrinks, so the
+/// limit can't be below what's mapped already.
+pub fn set_heap_limit(bytes: usize) -> Result<(), &'static str> {
+    if bytes < heap_size() {
+        return Err("the heap is already bigger than that");
+    }
+    HEAP_LIMIT.store(bytes, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Says what couldn't be allocated, since the panic that follows only gives
+/// the size. It goes to the serial port, since printing to the screen can
+/// need the heap.
+fn report_out_of_memory(layout: Layout) {
+    crate::serial_println!(
+        "(X_X)  [heap]: out of memory allocating {:?}, with {} KiB of the heap mapped out of at most {} KiB",
+        layout,
+        heap_size() / 1024,
+        heap_limit() / 1024
+    );
+}
+
 pub struct Dummy;
 
 unsafe impl GlobalAlloc for Dummy {
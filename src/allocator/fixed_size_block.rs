@@ -60,23 +60,32 @@ impl FixedSizeBlockAllocator {
             .init(heap_start as *mut u8, heap_size);
     }
 
-    /// Allocates using the fallback allocator.
+    /// Allocates using the fallback allocator, growing the heap if it's run
+    /// out (and it's the one at the end of the heap).
     fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
-        match self.fallback_allocator.allocate_first_fit(layout) {
-            Ok(ptr) => ptr.as_ptr(),
-            Err(_) => ptr::null_mut(),
+        loop {
+            if let Ok(ptr) = self.fallback_allocator.allocate_first_fit(layout) {
+                return ptr.as_ptr();
+            }
+            if self.fallback_allocator.top() as usize != super::heap_end() {
+                return ptr::null_mut();
+            }
+            // enough for it even if none of the free space at the end can be used
+            match super::grow(layout.size() + layout.align()) {
+                0 => return ptr::null_mut(),
+                grown => unsafe { self.fallback_allocator.extend(grown) },
+            }
         }
     }
-}
 
-unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let mut allocator = self.lock();
+    /// Takes a block from the list for its size, or from the fallback
+    /// allocator if the list is empty or it's too big for any of them.
+    fn allocate(&mut self, layout: Layout) -> *mut u8 {
         match list_index(&layout) {
             Some(index) => {
-                match allocator.list_heads[index].take() {
+                match self.list_heads[index].take() {
                     Some(node) => {
-                        allocator.list_heads[index] = node.next.take();
+                        self.list_heads[index] = node.next.take();
                         node as *mut ListNode as *mut u8
                     }
                     None => {
@@ -85,12 +94,22 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
                         // only works if all block sizes are a power of 2
                         let block_align = block_size;
                         let layout = Layout::from_size_align(block_size, block_align).unwrap();
-                        allocator.fallback_alloc(layout)
+                        self.fallback_alloc(layout)
                     }
                 }
             }
-            None => allocator.fallback_alloc(layout),
+            None => self.fallback_alloc(layout),
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.lock().allocate(layout);
+        if ptr.is_null() {
+            super::report_out_of_memory(layout);
         }
+        ptr
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
@@ -12,6 +12,7 @@ extern "C" {
     pub fn read_rtc();
 }
 
+#[derive(Debug, Clone, Copy)]
 pub struct Time {
     pub second: u8,
     pub minute: u8,
@@ -1,299 +1,1170 @@
 use crate::{
-    acpi, base64, cmos::*, dbg, disks::ahci::*, pci, print, println, randomness, task::keyboard,
-    vga_buffer::WRITER,
+    acpi, base64,
+    cmos::*,
+    dbg,
+    disks::ahci::*,
+    editor, framebuffer,
+    fs::vfs,
+    gui, interrupts, pci, print, println, randomness,
+    task::{executor::Spawner, keyboard, yield_now},
+    time,
+    vga_buffer::{self, console},
 };
 use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    format,
     string::{String, ToString},
+    sync::Arc,
+    vec,
     vec::Vec,
 };
-use futures_util::stream::StreamExt;
-use pc_keyboard::{DecodedKey, Keyboard, ScancodeSet1};
+use core::time::Duration;
+use futures_util::{
+    future::{select, Either, FutureExt, LocalBoxFuture},
+    stream::StreamExt,
+};
+use jobs::JobTable;
+use pc_keyboard::DecodedKey;
+use script::{AndOr, Command, Connector, ParseError};
+use spin::Mutex;
+
+pub mod debug;
+pub mod files;
+pub mod jobs;
+pub mod script;
 
-static SHSH_VERSION: &str = "b0.4";
+static SHSH_VERSION: &str = "b0.5";
 
-pub async fn run_command_line() {
+/// Runs once every time the shell starts, if it exists.
+const STARTUP_SCRIPT: &str = "/etc/shshrc";
+/// Used when `PS1` isn't set.
+const DEFAULT_PROMPT: &str = "$ ";
+/// Shown while an `if`/`for`/`while` or a quote is still open.
+const CONTINUATION_PROMPT: &str = "> ";
+
+pub async fn run_command_line(spawner: Spawner) {
     println!("Made by SniverDaBest\nSHSH {}", SHSH_VERSION);
-    let mut scancodes = keyboard::ScancodeStream::new();
-    let mut keyboard = Keyboard::new(
-        ScancodeSet1::new(),
-        pc_keyboard::layouts::Us104Key,
-        pc_keyboard::HandleControl::Ignore,
-    );
+    let mut keys = keyboard::dispatch::KeyEventStream::new();
+
+    let mut shell = Shell::new(spawner);
+    if vfs::exists(STARTUP_SCRIPT) {
+        shell.source(STARTUP_SCRIPT, &[]).await;
+    }
 
     let mut input_buffer = String::new();
-    let mut prompt = "$ ".to_string();
+    // lines of a compound command that isn't finished yet
+    let mut pending = String::new();
 
     // Initial prompt display
-    print!("{}", prompt);
+    print!("{}", shell.prompt());
 
     loop {
-        while let Some(scancode) = scancodes.next().await {
-            if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-                if let Some(key) = keyboard.process_keyevent(key_event) {
-                    match key {
-                        DecodedKey::Unicode(character) => {
-                            if character == '\n' {
-                                print!("\n");
-                                // Process command
-                                process_command(&input_buffer);
-                                input_buffer.clear();
-                                prompt = "$ ".to_string();
-                                // Move to the next line and show the new prompt
-
-                                print!("\n{}", prompt);
-                            } else {
-                                input_buffer.push(character);
-                                // Redraw input buffer
-                                print!("{}", character);
+        let interrupted_or_pasted = select(keyboard::interrupted(), keyboard::pasted());
+        let typed = match select(keys.next(), interrupted_or_pasted).await {
+            // Ctrl and Alt combinations aren't typing
+            Either::Left((Some(press), _)) if press.modifiers.ctrl || press.modifiers.alt => {
+                continue
+            }
+            Either::Left((Some(press), _)) => match press.key {
+                DecodedKey::Unicode(character) => String::from(character),
+                DecodedKey::RawKey(_) => continue,
+            },
+            Either::Left((None, _)) => continue,
+            Either::Right((Either::Left(_), _)) => {
+                // Ctrl-C at the prompt throws away whatever was typed
+                input_buffer.clear();
+                pending.clear();
+                print!("^C\n\n{}", shell.prompt());
+                continue;
+            }
+            // pasted text goes in as if it was typed
+            Either::Right((Either::Right(_), _)) => vga_buffer::selection::clipboard(),
+        };
+
+        for character in typed.chars() {
+            if character == '\n' {
+                print!("\n");
+                pending.push_str(&input_buffer);
+                pending.push('\n');
+                input_buffer.clear();
+
+                match script::parse(&pending) {
+                    Ok(script) => {
+                        shell.run_interactive(&script).await;
+                        pending.clear();
+                    }
+                    Err(ParseError::Incomplete) => {
+                        print!("{}", CONTINUATION_PROMPT);
+                        continue;
+                    }
+                    Err(e) => {
+                        println!("Syntax error: {}", e);
+                        shell.last_status = 2;
+                        pending.clear();
+                    }
+                }
+
+                shell.jobs.lock().report_finished();
+                // Move to the next line and show the new prompt
+                print!("\n{}", shell.prompt());
+            } else if character == '\u{8}' {
+                // backspace: rub out the last character typed, if there is one
+                if input_buffer.pop().is_some() {
+                    print!("\x08 \x08");
+                }
+            } else {
+                input_buffer.push(character);
+                // Redraw input buffer
+                print!("{}", character);
+            }
+        }
+    }
+}
+
+/// The state of a single SHSH instance.
+///
+/// Cloning a `Shell` gives you a subshell: it starts with the same variables,
+/// but nothing it changes leaks back into the parent. Scripts that are run by
+/// path (instead of through `source`) get one of these.
+#[derive(Clone)]
+pub struct Shell {
+    vars: BTreeMap<String, String>,
+    exported: BTreeSet<String>,
+    /// `$0`, `$1`, ... -- the name of the running script and its arguments.
+    args: Vec<String>,
+    /// The exit status of the last command, available as `$?`.
+    last_status: i32,
+    /// The working directory relative paths are resolved against.
+    cwd: String,
+    /// Background jobs. Subshells share them with the shell they came from.
+    jobs: Arc<Mutex<JobTable>>,
+}
+
+impl Shell {
+    pub fn new(spawner: Spawner) -> Self {
+        let mut vars = BTreeMap::new();
+        vars.insert("PWD".to_string(), "/".to_string());
+        Shell {
+            vars,
+            exported: BTreeSet::new(),
+            args: vec!["shsh".to_string()],
+            last_status: 0,
+            cwd: "/".to_string(),
+            jobs: Arc::new(Mutex::new(JobTable::new(spawner))),
+        }
+    }
+
+    /// The prompt to show, taken from `PS1`. `\w` is replaced with the working directory.
+    pub fn prompt(&self) -> String {
+        self.vars
+            .get("PS1")
+            .map(String::as_str)
+            .unwrap_or(DEFAULT_PROMPT)
+            .replace("\\w", &self.cwd)
+    }
+
+    /// Looks up a variable, including the special `?`, `#` and positional parameters.
+    pub fn get_var(&self, name: &str) -> Option<String> {
+        match name {
+            "?" => Some(self.last_status.to_string()),
+            "#" => Some((self.args.len() - 1).to_string()),
+            _ => match name.parse::<usize>() {
+                Ok(index) => self.args.get(index).cloned(),
+                Err(_) => self.vars.get(name).cloned(),
+            },
+        }
+    }
+
+    pub fn set_var(&mut self, name: &str, value: &str) {
+        self.vars.insert(name.to_string(), value.to_string());
+    }
+
+    pub fn unset_var(&mut self, name: &str) {
+        self.vars.remove(name);
+        self.exported.remove(name);
+    }
+
+    /// Expands a single word from a script into its arguments.
+    fn expand(&self, word: &str) -> Vec<String> {
+        script::expand_word(word, &|name| self.get_var(name))
+    }
+
+    /// Parses and runs `source`, returning the status of the last command.
+    pub async fn run_script(&mut self, source: &str) -> i32 {
+        match script::parse(source) {
+            Ok(script) => self.exec_list(&script).await,
+            Err(e) => {
+                println!("Syntax error: {}", e);
+                self.last_status = 2;
+                2
+            }
+        }
+    }
+
+    /// Runs the script at `path` in this shell, so it can change our variables.
+    pub async fn source(&mut self, path: &str, args: &[String]) -> i32 {
+        let path = vfs::resolve(&self.cwd, path);
+        let contents = match vfs::read(&path) {
+            Ok(contents) => contents,
+            Err(e) => {
+                println!("{}: {}", path, e);
+                return 1;
+            }
+        };
+        let source = match core::str::from_utf8(&contents) {
+            Ok(source) => source,
+            Err(_) => {
+                println!("{}: not a text file", path);
+                return 1;
+            }
+        };
+
+        let saved_args = if args.is_empty() {
+            None
+        } else {
+            let mut new_args = vec![path.clone()];
+            new_args.extend_from_slice(args);
+            Some(core::mem::replace(&mut self.args, new_args))
+        };
+        let status = self.run_script(source).await;
+        if let Some(saved_args) = saved_args {
+            self.args = saved_args;
+        }
+        status
+    }
+
+    /// Runs a script typed at the prompt. Ctrl-C stops it.
+    pub async fn run_interactive(&mut self, script: &[AndOr]) -> i32 {
+        keyboard::clear_interrupt();
+        let status = match select(self.exec_list(script), keyboard::interrupted()).await {
+            Either::Left((status, _)) => status,
+            Either::Right(_) => {
+                println!("^C");
+                jobs::STATUS_INTERRUPTED
+            }
+        };
+        self.last_status = status;
+        status
+    }
+
+    // boxed, since scripts can nest (and `source` each other)
+    pub fn exec_list<'a>(&'a mut self, list: &'a [AndOr]) -> LocalBoxFuture<'a, i32> {
+        async move {
+            let mut status = 0;
+            for and_or in list {
+                status = if and_or.background {
+                    self.spawn_job(and_or)
+                } else {
+                    self.exec_and_or(and_or).await
+                };
+            }
+            status
+        }
+        .boxed_local()
+    }
+
+    /// Starts `and_or` as a background job in a subshell.
+    fn spawn_job(&mut self, and_or: &AndOr) -> i32 {
+        let mut subshell = self.clone();
+        let and_or = AndOr {
+            background: false,
+            ..and_or.clone()
+        };
+        let command = and_or.to_string();
+        let id = self
+            .jobs
+            .lock()
+            .spawn(command, async move { subshell.exec_and_or(&and_or).await });
+        println!("[{}]", id);
+        0
+    }
+
+    async fn exec_and_or(&mut self, and_or: &AndOr) -> i32 {
+        let mut status = self.exec_command(&and_or.first).await;
+        for (connector, command) in &and_or.rest {
+            let run = match connector {
+                Connector::And => status == 0,
+                Connector::Or => status != 0,
+            };
+            if run {
+                status = self.exec_command(command).await;
+            }
+        }
+        status
+    }
+
+    async fn exec_command(&mut self, command: &Command) -> i32 {
+        let status = match command {
+            Command::Simple(words) => self.exec_simple(words).await,
+            Command::If {
+                branches,
+                otherwise,
+            } => {
+                let mut status = 0;
+                let mut taken = false;
+                for (condition, body) in branches {
+                    if self.exec_list(condition).await == 0 {
+                        status = self.exec_list(body).await;
+                        taken = true;
+                        break;
+                    }
+                }
+                if !taken {
+                    if let Some(otherwise) = otherwise {
+                        status = self.exec_list(otherwise).await;
+                    }
+                }
+                status
+            }
+            Command::For { var, items, body } => {
+                let items: Vec<String> = items.iter().flat_map(|item| self.expand(item)).collect();
+                let mut status = 0;
+                for item in items {
+                    self.set_var(var, &item);
+                    status = self.exec_list(body).await;
+                    yield_now().await;
+                }
+                status
+            }
+            Command::While { condition, body } => {
+                let mut status = 0;
+                while self.exec_list(condition).await == 0 {
+                    status = self.exec_list(body).await;
+                    // so `while true` can still be interrupted
+                    yield_now().await;
+                }
+                status
+            }
+        };
+        self.last_status = status;
+        status
+    }
+
+    async fn exec_simple(&mut self, words: &[String]) -> i32 {
+        // `NAME=value` on its own sets a variable
+        if let [word] = words {
+            if let Some((name, value)) = word.split_once('=') {
+                if script::is_valid_name(name) {
+                    let value = self.expand(value).join(" ");
+                    self.set_var(name, &value);
+                    return 0;
+                }
+            }
+        }
+
+        let args: Vec<String> = words.iter().flat_map(|word| self.expand(word)).collect();
+        if args.is_empty() {
+            return 0;
+        }
+
+        if let Some(status) = self.run_builtin(&args).await {
+            return status;
+        }
+
+        if args[0].contains('/') {
+            // running a script by path gets a subshell, like a real program would
+            let mut subshell = self.clone();
+            return subshell.source(&args[0], &args[1..]).await;
+        }
+
+        if let Some(status) = files::run(&self.cwd, &args) {
+            return status;
+        }
+        if let Some(status) = debug::run(&self.cwd, &args) {
+            return status;
+        }
+        if args[0] == "edit" {
+            return match args.get(1) {
+                Some(path) => match editor::edit(&vfs::resolve(&self.cwd, path)).await {
+                    Ok(()) => 0,
+                    Err(e) => {
+                        println!("edit: {}: {}", path, e);
+                        1
+                    }
+                },
+                None => {
+                    println!("Usage: edit FILE");
+                    2
+                }
+            };
+        }
+        if args[0] == "fbcon" {
+            return fbcon(&self.cwd, &args[1..]);
+        }
+        if args[0] == "gui" {
+            return gui_command(&args[1..]);
+        }
+
+        process_command(&args).await
+    }
+
+    /// Runs commands that need to look at or change the shell itself.
+    ///
+    /// Returns `None` if `args` isn't a builtin.
+    async fn run_builtin(&mut self, args: &[String]) -> Option<i32> {
+        let status = match args[0].as_str() {
+            "set" => {
+                if args.len() == 1 {
+                    for (name, value) in &self.vars {
+                        println!("{}={}", name, value);
+                    }
+                    0
+                } else if let Some((name, value)) = args[1].split_once('=') {
+                    self.assign("set", name, value)
+                } else if args.len() >= 3 {
+                    let value = args[2..].join(" ");
+                    self.assign("set", &args[1], &value)
+                } else {
+                    println!("Usage: set [NAME VALUE | NAME=VALUE]");
+                    2
+                }
+            }
+            "export" => {
+                if args.len() == 1 {
+                    for name in &self.exported {
+                        println!(
+                            "export {}={}",
+                            name,
+                            self.vars.get(name).map(String::as_str).unwrap_or("")
+                        );
+                    }
+                    return Some(0);
+                }
+                let mut status = 0;
+                for arg in &args[1..] {
+                    let name = match arg.split_once('=') {
+                        Some((name, value)) => {
+                            if self.assign("export", name, value) != 0 {
+                                status = 1;
+                                continue;
                             }
+                            name
                         }
-                        DecodedKey::RawKey(_) => {
-                            
+                        None if script::is_valid_name(arg) => arg.as_str(),
+                        None => {
+                            println!("export: invalid variable name '{}'", arg);
+                            status = 1;
+                            continue;
+                        }
+                    };
+                    self.exported.insert(name.to_string());
+                }
+                status
+            }
+            "unset" => {
+                for name in &args[1..] {
+                    self.unset_var(name);
+                }
+                0
+            }
+            "source" | "." => match args.get(1) {
+                Some(path) => {
+                    let path = path.clone();
+                    self.source(&path, &args[2..]).await
+                }
+                None => {
+                    println!("Usage: {} FILE [ARGS...]", args[0]);
+                    2
+                }
+            },
+            "cd" => {
+                let target = match args.get(1) {
+                    Some(target) => target.clone(),
+                    None => self
+                        .vars
+                        .get("HOME")
+                        .cloned()
+                        .unwrap_or_else(|| "/".to_string()),
+                };
+                let path = vfs::resolve(&self.cwd, &target);
+                if vfs::is_dir(&path) {
+                    self.set_var("PWD", &path);
+                    self.cwd = path;
+                    0
+                } else if vfs::exists(&path) {
+                    println!("cd: {}: {}", target, vfs::FsError::NotADirectory);
+                    1
+                } else {
+                    println!("cd: {}: {}", target, vfs::FsError::NotFound);
+                    1
+                }
+            }
+            "pwd" => {
+                println!("{}", self.cwd);
+                0
+            }
+            "jobs" => {
+                self.jobs.lock().list();
+                0
+            }
+            "fg" => self.foreground(args.get(1)).await,
+            "kill" => {
+                let specs: Vec<Option<usize>> = if args.len() > 1 {
+                    args[1..]
+                        .iter()
+                        .map(|spec| jobs::parse_job_spec(spec))
+                        .collect()
+                } else {
+                    vec![self.jobs.lock().current()]
+                };
+                let mut status = 0;
+                for (i, spec) in specs.into_iter().enumerate() {
+                    let table = self.jobs.lock();
+                    match spec.and_then(|id| table.get(id)) {
+                        Some(job) => job.state.cancel(jobs::STATUS_KILLED),
+                        None => {
+                            let spec = args.get(i + 1).map(String::as_str).unwrap_or("%+");
+                            println!("kill: {}: no such job", spec);
+                            status = 1;
                         }
                     }
                 }
+                status
+            }
+            "true" => 0,
+            "false" => 1,
+            "test" => self.test(&args[1..]),
+            "[" => {
+                if args[args.len() - 1] != "]" {
+                    println!("[: missing ']'");
+                    2
+                } else {
+                    self.test(&args[1..args.len() - 1])
+                }
+            }
+            _ => return None,
+        };
+        Some(status)
+    }
+
+    /// The `fg` builtin: waits for a background job, which Ctrl-C then stops.
+    async fn foreground(&mut self, spec: Option<&String>) -> i32 {
+        let id = match spec {
+            Some(spec) => jobs::parse_job_spec(spec),
+            None => self.jobs.lock().current(),
+        };
+        let job = id.and_then(|id| {
+            let table = self.jobs.lock();
+            table
+                .get(id)
+                .map(|job| (id, job.command.clone(), job.state.clone()))
+        });
+        let (id, command, state) = match job {
+            Some(job) => job,
+            None => {
+                println!("fg: no such job");
+                return 1;
+            }
+        };
+
+        println!("{}", command);
+        keyboard::clear_interrupt();
+        let status = match select(state.wait(), keyboard::interrupted()).await {
+            Either::Left((status, _)) => status,
+            Either::Right(_) => {
+                println!("^C");
+                state.cancel(jobs::STATUS_INTERRUPTED);
+                state.wait().await
+            }
+        };
+        self.jobs.lock().remove(id);
+        status
+    }
+
+    fn assign(&mut self, command: &str, name: &str, value: &str) -> i32 {
+        if script::is_valid_name(name) {
+            self.set_var(name, value);
+            0
+        } else {
+            println!("{}: invalid variable name '{}'", command, name);
+            1
+        }
+    }
+
+    /// The `test` builtin. 0 means true, 1 means false and 2 means bad arguments.
+    fn test(&self, args: &[String]) -> i32 {
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        match self.eval_test(&args) {
+            Ok(true) => 0,
+            Ok(false) => 1,
+            Err(e) => {
+                println!("test: {}", e);
+                2
+            }
+        }
+    }
+
+    fn eval_test(&self, args: &[&str]) -> Result<bool, String> {
+        if let Some(&"!") = args.first() {
+            return self.eval_test(&args[1..]).map(|result| !result);
+        }
+
+        match *args {
+            [] => Ok(false),
+            [value] => Ok(!value.is_empty()),
+            [op, value] => {
+                let path = vfs::resolve(&self.cwd, value);
+                match op {
+                    "-z" => Ok(value.is_empty()),
+                    "-n" => Ok(!value.is_empty()),
+                    "-e" => Ok(vfs::exists(&path)),
+                    "-f" => Ok(vfs::exists(&path) && !vfs::is_dir(&path)),
+                    "-d" => Ok(vfs::is_dir(&path)),
+                    _ => Err(format!("unknown operator '{}'", op)),
+                }
             }
+            [left, op, right] => match op {
+                "=" | "==" => Ok(left == right),
+                "!=" => Ok(left != right),
+                "-eq" | "-ne" | "-lt" | "-le" | "-gt" | "-ge" => {
+                    let parse = |value: &str| {
+                        value
+                            .parse::<i64>()
+                            .map_err(|_| format!("'{}' isn't a number", value))
+                    };
+                    let (left, right) = (parse(left)?, parse(right)?);
+                    Ok(match op {
+                        "-eq" => left == right,
+                        "-ne" => left != right,
+                        "-lt" => left < right,
+                        "-le" => left <= right,
+                        "-gt" => left > right,
+                        _ => left >= right,
+                    })
+                }
+                _ => Err(format!("unknown operator '{}'", op)),
+            },
+            _ => Err("too many arguments".to_string()),
         }
     }
 }
 
-fn process_command(command: &str) {
-    // Implement command processing logic here
-    // println!("Command received: {}", command); // debug statement that shouldn't always run
-
-    // Example: simple echo command
-    if command.trim().contains("echo") {
-        println!("{}", command.replace("echo ", ""));
-    } else if command.trim().contains("clear") {
-        WRITER.lock().clear_screen();
-    } else if command.trim().contains("ver") {
-        println!("SHSH Version {}", SHSH_VERSION);
-    } else if command.trim().contains("b64encode") {
-        let input_str = command.split_whitespace().nth(1).unwrap_or("").as_bytes();
-        println!("{}", base64::encode(input_str));
-    } else if command.trim().contains("b64decode") {
-        let input_str = command.split_whitespace().nth(1).unwrap_or("").as_bytes();
-        println!("{}", base64::decode(input_str));
-    } else if command.trim().contains("randint") {
-        match randomness::rand_u64() {
-            Ok(val) => println!("{}", val.unwrap()),
-            Err(e) => println!("0_0  [randomness]: {}", e),
-        }
-    } else if command.trim().contains("pci") {
-        for arg in command.split_whitespace() {
-            if arg == "-h" || arg == "--help" {
-                println!("PCI(e) Utility");
-                println!("-l/--list -- Lists PCI devices.");
-                println!("-h/--help -- Shows this message.");
-                println!("-d/--device -- Only shows devices with provided device ID.");
-                println!("-v/--vendor -- Only shows devices with provided vendor ID.");
-                println!(
-                    "-c/--class -- Only shows devices with provided class code. -- UNIMPLEMENTED!!"
-                );
+/// `fbcon [WIDTHxHEIGHT] [FONT]`: moves the console to a graphics mode.
+fn fbcon(cwd: &str, args: &[String]) -> i32 {
+    let (mut width, mut height) = (1024, 768);
+    let mut font = None;
+    for arg in args {
+        let size = arg
+            .split_once('x')
+            .and_then(|(w, h)| Some((w.parse().ok()?, h.parse().ok()?)));
+        if let Some(size) = size {
+            (width, height) = size;
+            continue;
+        }
+        let path = vfs::resolve(cwd, arg);
+        let parsed = vfs::read(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|data| framebuffer::psf::Font::parse(&data));
+        match parsed {
+            Ok(parsed) => font = Some(parsed),
+            Err(e) => {
+                println!("fbcon: {}: {}", arg, e);
+                return 1;
+            }
+        }
+    }
+
+    match framebuffer::console::enable(width, height, font) {
+        Ok((columns, rows)) => {
+            println!("{}x{}, {} columns by {} rows", width, height, columns, rows);
+            0
+        }
+        Err(e) => {
+            println!("fbcon: {}", e);
+            1
+        }
+    }
+}
+
+/// `gui [WIDTHxHEIGHT] | term | demo | list | raise ID | move ID X Y | close ID`:
+/// starts the desktop, or manages its windows.
+fn gui_command(args: &[String]) -> i32 {
+    use gui::draw::{rgb, rgba, Rect};
+
+    let number = |index: usize| args.get(index).and_then(|arg| arg.parse::<i32>().ok());
+    let subcommand = args.first().map(String::as_str).unwrap_or("");
+    let size = subcommand
+        .split_once('x')
+        .and_then(|(w, h)| Some((w.parse().ok()?, h.parse().ok()?)));
+    if subcommand.is_empty() || size.is_some() {
+        let (width, height) = size.unwrap_or((1024, 768));
+        return match gui::start(width, height) {
+            Ok(()) => 0,
+            Err(e) => {
+                println!("gui: {}", e);
+                1
+            }
+        };
+    }
+    if !gui::is_running() {
+        println!("gui: the desktop isn't running (start it with `gui`)");
+        return 1;
+    }
+
+    let result = match (subcommand, number(1), number(2), number(3)) {
+        ("term", ..) => gui::open_terminal().map(|id| println!("window {}", id)),
+        ("demo", ..) => gui::with_desktop(|desktop| {
+            let id = desktop.open_window("Demo", 240, 160)?;
+            let font = desktop.font().clone();
+            desktop.draw(id, |canvas| {
+                canvas.fill_rect(Rect::new(0, 0, 240, 160), rgb(0xFF, 0xFF, 0xFF));
+                for i in 0..8 {
+                    canvas.line(0, i * 20, 239, 159 - i * 20, rgb(0x00, 0x80, 0x00));
+                }
+                canvas.fill_rect(Rect::new(20, 20, 100, 60), rgb(0xAA, 0x00, 0x00));
+                canvas.blend_rect(Rect::new(70, 50, 100, 60), rgba(0x00, 0x00, 0xFF, 0x80));
+                canvas.rect(Rect::new(10, 10, 220, 140), rgb(0x00, 0x00, 0x00));
+                canvas.text(&font, 20, 124, "Lemonade", rgb(0x00, 0x00, 0x00));
+            });
+            Ok(id)
+        })
+        .unwrap_or_else(|| Err(String::from("the desktop isn't running")))
+        .map(|id| println!("window {}", id)),
+        ("list", ..) => {
+            let windows = gui::with_desktop(|desktop| {
+                desktop
+                    .windows()
+                    .map(|(id, title, frame)| {
+                        format!(
+                            "{:>3}  {}x{} at {},{}  {}",
+                            id, frame.width, frame.height, frame.x, frame.y, title
+                        )
+                    })
+                    .collect::<Vec<_>>()
+            });
+            for line in windows.unwrap_or_default() {
+                println!("{}", line);
+            }
+            Ok(())
+        }
+        ("raise", Some(id), ..) => found(gui::with_desktop(|d| d.raise(id as usize))),
+        ("close", Some(id), ..) => found(gui::with_desktop(|d| d.close_window(id as usize))),
+        ("move", Some(id), Some(x), Some(y)) => {
+            found(gui::with_desktop(|d| d.move_window(id as usize, x, y)))
+        }
+        _ => {
+            println!("Usage: gui [WIDTHxHEIGHT] | term | demo | list | raise ID | move ID X Y | close ID");
+            return 2;
+        }
+    };
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            println!("gui: {}", e);
+            1
+        }
+    }
+}
+
+/// The `clocksource` command: lists the clocks, or picks one.
+fn clocksource(args: &[String]) -> i32 {
+    use time::ClockSource;
+
+    let name = match args.first() {
+        Some(name) => name,
+        None => {
+            let mhz = |hz: u64| format!("{}.{:03} MHz", hz / 1_000_000, hz / 1000 % 1000);
+            for &source in ClockSource::ALL.iter() {
+                let detail = match (source, time::tsc::frequency()) {
+                    (ClockSource::Pit, _) => format!("{} ticks a second", time::TICKS_PER_SECOND),
+                    (ClockSource::Tsc, Some(hz)) if time::tsc::is_invariant() => mhz(hz),
+                    (ClockSource::Tsc, Some(hz)) => format!("{} (the rate isn't steady)", mhz(hz)),
+                    (ClockSource::Tsc, None) => String::from("not calibrated"),
+                    (ClockSource::Hpet, _) => match time::hpet::frequency() {
+                        Some(hz) => format!(
+                            "{}, {} comparators",
+                            mhz(hz),
+                            time::hpet::comparators().len()
+                        ),
+                        None => String::from("not found"),
+                    },
+                };
+                let current = if source == time::clock_source() {
+                    '*'
+                } else {
+                    ' '
+                };
+                println!("{} {:<4} {}", current, source.name(), detail);
+            }
+            if let Some(hz) = interrupts::apic::timer::frequency() {
+                println!("The APIC timer runs at {}.", mhz(hz));
+            }
+            return 0;
+        }
+    };
+    match ClockSource::ALL.iter().find(|source| source.name() == name) {
+        Some(&source) => match time::set_clock_source(source) {
+            Ok(()) => 0,
+            Err(e) => {
+                println!("clocksource: {}", e);
+                1
+            }
+        },
+        None => {
+            println!("Usage: clocksource [pit | tsc | hpet]");
+            2
+        }
+    }
+}
+
+/// Turns whether a window was found into a `gui` result.
+fn found(found: Option<bool>) -> Result<(), String> {
+    match found {
+        Some(true) => Ok(()),
+        _ => Err(String::from("no such window")),
+    }
+}
+
+/// Runs a (non-builtin) command, returning its exit status.
+async fn process_command(args: &[String]) -> i32 {
+    // the older commands still look at the whole line
+    let command = args.join(" ");
+
+    match args[0].as_str() {
+        "echo" => println!("{}", args[1..].join(" ")),
+        "clear" => console::writer().clear_screen(),
+        "ver" => println!("SHSH Version {}", SHSH_VERSION),
+        "kbd" => match (args.get(1).map(String::as_str), args.get(2)) {
+            (None, _) => {
+                let on_off = |on| if on { "on" } else { "off" };
+                let locks = keyboard::locks();
                 println!(
-                    "-s/--sub -- Only shows devices with provided subclass. -- UNIMPLEMENTED!!"
+                    "Layout: {} (Caps Lock {}, Num Lock {}, Scroll Lock {})",
+                    keyboard::layouts::layout(),
+                    on_off(locks.caps),
+                    on_off(locks.num),
+                    on_off(locks.scroll)
                 );
-                println!("-le/--list-pcie -- Lists PCIe devices. -- UNIMPLEMENTED!!");
-                println!("-de/--device-pcie -- Only shows PCIe devices with provided device ID.");
-                println!("-ve/--vendor-pcie -- Only shows PCIe devices with provided vendor ID.");
-                println!("-ce/--class-pcie -- Only shows PCIe devices with provided class code. UNIMPLEMENTED!!");
-                println!("-se/--sub-pcie -- Only shows PCIe devices with provided subclass. UNIMPLEMENTED!!");
-                println!("NOTE: PCIe is broken :(");
-                break;
-            } else if arg == "-l" || arg == "--list" {
-                let bus = pci::scan_pci_bus();
-                for x in bus {
-                    println!("{}", x);
+            }
+            (Some("list"), _) => {
+                for (name, description, _) in keyboard::layouts::LAYOUTS.iter() {
+                    println!("{:<8} {}", name, description);
                 }
-            } else if arg == "-d" || arg == "--device" {
-                let mut t = 0;
-                for a in command.split_whitespace() {
-                    if a == "-d" || a == "--device" {
-                        t += 1;
-                        break;
-                    }
-                    t += 1;
+            }
+            (Some("set"), Some(name)) => {
+                if !keyboard::layouts::set_layout(name) {
+                    println!("kbd: no layout called {} (see `kbd list`)", name);
+                    return 1;
                 }
-                let bus = pci::scan_pci_bus();
-                for x in bus {
-                    if x.device_id
-                        == command
-                            .split_whitespace()
-                            .nth(t)
-                            .unwrap()
-                            .parse::<u32>()
-                            .unwrap()
-                    {
+            }
+            _ => {
+                println!("Usage: kbd [list | set LAYOUT]");
+                return 2;
+            }
+        },
+        "b64encode" => {
+            let input_str = args.get(1).map(String::as_str).unwrap_or("").as_bytes();
+            println!("{}", base64::encode(input_str));
+        }
+        "b64decode" => {
+            let input_str = args.get(1).map(String::as_str).unwrap_or("").as_bytes();
+            println!("{}", base64::decode(input_str));
+        }
+        "randint" => match randomness::rand_u64() {
+            Ok(val) => println!("{}", val.unwrap()),
+            Err(e) => {
+                println!("0_0  [randomness]: {}", e);
+                return 1;
+            }
+        },
+        "pci" => {
+            for arg in command.split_whitespace() {
+                if arg == "-h" || arg == "--help" {
+                    println!("PCI(e) Utility");
+                    println!("-l/--list -- Lists PCI devices.");
+                    println!("-h/--help -- Shows this message.");
+                    println!("-d/--device -- Only shows devices with provided device ID.");
+                    println!("-v/--vendor -- Only shows devices with provided vendor ID.");
+                    println!(
+                        "-c/--class -- Only shows devices with provided class code. -- UNIMPLEMENTED!!"
+                    );
+                    println!(
+                        "-s/--sub -- Only shows devices with provided subclass. -- UNIMPLEMENTED!!"
+                    );
+                    println!("-le/--list-pcie -- Lists PCIe devices. -- UNIMPLEMENTED!!");
+                    println!(
+                        "-de/--device-pcie -- Only shows PCIe devices with provided device ID."
+                    );
+                    println!(
+                        "-ve/--vendor-pcie -- Only shows PCIe devices with provided vendor ID."
+                    );
+                    println!("-ce/--class-pcie -- Only shows PCIe devices with provided class code. UNIMPLEMENTED!!");
+                    println!("-se/--sub-pcie -- Only shows PCIe devices with provided subclass. UNIMPLEMENTED!!");
+                    println!("NOTE: PCIe is broken :(");
+                    break;
+                } else if arg == "-l" || arg == "--list" {
+                    let bus = pci::scan_pci_bus_async().await;
+                    for x in bus {
                         println!("{}", x);
                     }
-                }
-            } else if arg == "-v" || arg == "--vendor" {
-                let mut t = 0;
-                for a in command.split_whitespace() {
-                    if a == "-v" || a == "--vendor" {
+                } else if arg == "-d" || arg == "--device" {
+                    let mut t = 0;
+                    for a in command.split_whitespace() {
+                        if a == "-d" || a == "--device" {
+                            t += 1;
+                            break;
+                        }
                         t += 1;
-                        break;
                     }
-                    t += 1;
-                }
-                let bus = pci::scan_pci_bus();
-                for x in bus {
-                    if x.vendor_id
-                        == command
-                            .split_whitespace()
-                            .nth(t)
-                            .unwrap()
-                            .parse::<u32>()
-                            .unwrap()
-                    {
-                        println!("{}", x);
+                    let bus = pci::scan_pci_bus_async().await;
+                    for x in bus {
+                        if x.device_id
+                            == command
+                                .split_whitespace()
+                                .nth(t)
+                                .unwrap()
+                                .parse::<u32>()
+                                .unwrap()
+                        {
+                            println!("{}", x);
+                        }
                     }
-                }
-            } else if arg == "-c" || arg == "--class" {
-                let mut t = 0;
-                for a in command.split_whitespace() {
-                    if a == "-c" || a == "--class" {
+                } else if arg == "-v" || arg == "--vendor" {
+                    let mut t = 0;
+                    for a in command.split_whitespace() {
+                        if a == "-v" || a == "--vendor" {
+                            t += 1;
+                            break;
+                        }
                         t += 1;
-                        break;
                     }
-                    t += 1;
-                }
-                let bus = pci::scan_pci_bus();
-                for x in bus {
-                    if x.class_code
-                        == command
-                            .split_whitespace()
-                            .nth(t)
-                            .unwrap()
-                            .parse::<u32>()
-                            .unwrap()
-                    {
-                        println!("{}", x);
+                    let bus = pci::scan_pci_bus_async().await;
+                    for x in bus {
+                        if x.vendor_id
+                            == command
+                                .split_whitespace()
+                                .nth(t)
+                                .unwrap()
+                                .parse::<u32>()
+                                .unwrap()
+                        {
+                            println!("{}", x);
+                        }
                     }
-                }
-            } else if arg == "-s" || arg == "--sub" {
-                let mut t = 0;
-                for a in command.split_whitespace() {
-                    if a == "-s" || a == "--sub" {
+                } else if arg == "-c" || arg == "--class" {
+                    let mut t = 0;
+                    for a in command.split_whitespace() {
+                        if a == "-c" || a == "--class" {
+                            t += 1;
+                            break;
+                        }
                         t += 1;
-                        break;
                     }
-                    t += 1;
-                }
-                let bus = pci::scan_pci_bus();
-                for x in bus {
-                    if x.subclass
-                        == command
-                            .split_whitespace()
-                            .nth(t)
-                            .unwrap()
-                            .parse::<u32>()
-                            .unwrap()
-                    {
-                        println!("{}", x);
+                    let bus = pci::scan_pci_bus_async().await;
+                    for x in bus {
+                        if x.class_code
+                            == command
+                                .split_whitespace()
+                                .nth(t)
+                                .unwrap()
+                                .parse::<u32>()
+                                .unwrap()
+                        {
+                            println!("{}", x);
+                        }
                     }
-                }
-            } else if arg == "-de" || arg == "--device-pcie" {
-                let mut t = 0;
-                for a in command.split_whitespace() {
-                    if a == "-de" || a == "--device-pcie" {
+                } else if arg == "-s" || arg == "--sub" {
+                    let mut t = 0;
+                    for a in command.split_whitespace() {
+                        if a == "-s" || a == "--sub" {
+                            t += 1;
+                            break;
+                        }
                         t += 1;
-                        break;
                     }
-                    t += 1;
-                }
-                let bus = pci::scan_pcie_bus();
-                for x in bus {
-                    if x[1]
-                        == command
-                            .split_whitespace()
-                            .nth(t)
-                            .unwrap()
-                            .parse::<u32>()
-                            .unwrap()
-                    {
-                        println!(
-                            "Device ID '{}' | Vendor ID '{}' | Class Code '{}' | Subclass '{}'",
-                            x[1], x[0], x[2], x[3]
-                        );
+                    let bus = pci::scan_pci_bus_async().await;
+                    for x in bus {
+                        if x.subclass
+                            == command
+                                .split_whitespace()
+                                .nth(t)
+                                .unwrap()
+                                .parse::<u32>()
+                                .unwrap()
+                        {
+                            println!("{}", x);
+                        }
                     }
-                }
-            } else if arg == "-ve" || arg == "--vendor-pcie" {
-                let mut t = 0;
-                for a in command.split_whitespace() {
-                    if a == "-ve" || a == "--vendor-pcie" {
+                } else if arg == "-de" || arg == "--device-pcie" {
+                    let mut t = 0;
+                    for a in command.split_whitespace() {
+                        if a == "-de" || a == "--device-pcie" {
+                            t += 1;
+                            break;
+                        }
                         t += 1;
-                        break;
                     }
-                    t += 1;
-                }
-                let bus = pci::scan_pcie_bus();
-                for x in bus {
-                    if x[0]
-                        == command
-                            .split_whitespace()
-                            .nth(t)
-                            .unwrap()
-                            .parse::<u32>()
-                            .unwrap()
-                    {
-                        println!(
-                            "Device ID '{}' | Vendor ID '{}' | Class Code '{}' | Subclass '{}'",
-                            x[1], x[0], x[2], x[3]
-                        );
+                    let bus = pci::scan_pcie_bus();
+                    for x in bus {
+                        if x[1]
+                            == command
+                                .split_whitespace()
+                                .nth(t)
+                                .unwrap()
+                                .parse::<u32>()
+                                .unwrap()
+                        {
+                            println!(
+                                "Device ID '{}' | Vendor ID '{}' | Class Code '{}' | Subclass '{}'",
+                                x[1], x[0], x[2], x[3]
+                            );
+                        }
+                    }
+                } else if arg == "-ve" || arg == "--vendor-pcie" {
+                    let mut t = 0;
+                    for a in command.split_whitespace() {
+                        if a == "-ve" || a == "--vendor-pcie" {
+                            t += 1;
+                            break;
+                        }
+                        t += 1;
+                    }
+                    let bus = pci::scan_pcie_bus();
+                    for x in bus {
+                        if x[0]
+                            == command
+                                .split_whitespace()
+                                .nth(t)
+                                .unwrap()
+                                .parse::<u32>()
+                                .unwrap()
+                        {
+                            println!(
+                                "Device ID '{}' | Vendor ID '{}' | Class Code '{}' | Subclass '{}'",
+                                x[1], x[0], x[2], x[3]
+                            );
+                        }
                     }
                 }
             }
         }
-    } else if command.trim().contains("ahci") {
-        if command.trim().contains("-h") {
-            println!("AHCI Utility");
-            println!("-h -- Shows this help message.");
-            println!("-l -- Lists connected AHCI devices.");
-            println!("-r -- Reads sectors from an AHCI device.");
-            println!("-t -- Tests a little thing.");
-        } else if command.trim().contains("-l") {
-            scan_for_ahci_controllers(true);
-        } else if command.contains("-r") {
-            todo!();
-        } else if command.contains("-t") {
-            let ahci_controllers = scan_for_ahci_controllers(false);
-            if ahci_controllers.is_empty() {
-                println!("No AHCI Controllers found.");
-                return;
-            }
-            let ahci_devices = scan_for_used_ports(&ahci_controllers[0], false);
-            if ahci_devices.is_empty() {
-                println!("No AHCI Devices found.");
-                return;
-            }
-
-            let mut sectors: Vec<u64> = Vec::new();
-            sectors.push(0);
-
-            ahci_write(&ahci_devices[0], sectors, &[1 as u8, 5 as u8, 3 as u8]);
-        }
-    } else if command.trim().contains("time") {
-        let time = Time::from_current();
-        println!("Current time is: {}", time);
-    } else if command.trim().contains("help") {
-        println!("SHSH Version {}.", SHSH_VERSION);
-        println!("help -- Shows this message.");
-        println!("echo [input] -- Echos user input.");
-        println!("clear -- Clears the screen.");
-        println!(
-            "ver -- Shows the version of SHSH. (currently running version {})",
-            SHSH_VERSION
-        );
-        println!("b64encode [input] -- Encodes user input into Base64");
-        println!("b64decode [base64] -- Decodes Base64 user input into normal text.");
-        println!("randint [seed] -- Generates a random number based on a seed.");
-        println!("pci -- The PCI(e) utility.");
-        println!("ahci -- The AHCI utility.");
-        println!("time -- Shows the current time and date.");
-    } else if command.trim() == "" {
-        println!();
-    } else {
-        println!("Unknown command: {}", command);
+        "ahci" => {
+            if command.trim().contains("-h") {
+                println!("AHCI Utility");
+                println!("-h -- Shows this help message.");
+                println!("-l -- Lists connected AHCI devices.");
+                println!("-r -- Reads sectors from an AHCI device.");
+                println!("-t -- Tests a little thing.");
+            } else if command.trim().contains("-l") {
+                scan_for_ahci_controllers(true);
+            } else if command.contains("-r") {
+                println!("ahci -r is not implemented yet.");
+                return 1;
+            } else if command.contains("-t") {
+                let ahci_controllers = scan_for_ahci_controllers(false);
+                if ahci_controllers.is_empty() {
+                    println!("No AHCI Controllers found.");
+                    return 1;
+                }
+                let ahci_devices = scan_for_used_ports(&ahci_controllers[0], false);
+                if ahci_devices.is_empty() {
+                    println!("No AHCI Devices found.");
+                    return 1;
+                }
+
+                let mut sectors: Vec<u64> = Vec::new();
+                sectors.push(0);
+
+                ahci_write(&ahci_devices[0], sectors, &[1 as u8, 5 as u8, 3 as u8]);
+            }
+        }
+        "time" => {
+            let time = Time::from_current();
+            println!("Current time is: {}", time);
+        }
+        "uptime" => println!("Up {}", time::Clock(time::uptime())),
+        "clocksource" => return clocksource(&args[1..]),
+        "sleep" => {
+            // negative, NaN or so long the clock would overflow are all refused
+            let deadline = args
+                .get(1)
+                .and_then(|seconds| seconds.parse::<f64>().ok())
+                .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+                .and_then(|duration| time::Instant::now().checked_add(duration));
+            match deadline {
+                Some(deadline) => time::timer::sleep_until(deadline).await,
+                None => {
+                    println!("Usage: sleep SECONDS");
+                    return 2;
+                }
+            }
+        }
+        "scrollback" => match args.get(1).map(|lines| lines.parse::<usize>()) {
+            None => println!(
+                "{} lines (Shift+PageUp/PageDown to scroll)",
+                console::writer().scrollback_size()
+            ),
+            Some(Ok(lines)) => vga_buffer::enable_scrollback(lines),
+            Some(Err(_)) => {
+                println!("Usage: scrollback [LINES]");
+                return 2;
+            }
+        },
+        "help" => {
+            println!("SHSH Version {}.", SHSH_VERSION);
+            println!("help -- Shows this message.");
+            println!("echo [input] -- Echos user input.");
+            println!("clear -- Clears the screen.");
+            println!(
+                "ver -- Shows the version of SHSH. (currently running version {})",
+                SHSH_VERSION
+            );
+            println!("b64encode [input] -- Encodes user input into Base64");
+            println!("b64decode [base64] -- Decodes Base64 user input into normal text.");
+            println!("randint [seed] -- Generates a random number based on a seed.");
+            println!("pci -- The PCI(e) utility.");
+            println!("ahci -- The AHCI utility.");
+            println!("time -- Shows the current time and date.");
+            println!("uptime -- Shows how long it's been since boot.");
+            println!("sleep [seconds] -- Waits a while. (fractions work too)");
+            println!(
+                "clocksource [pit | tsc | hpet] -- Shows or changes where the time comes from."
+            );
+            println!("scrollback [lines] -- Shows or sets how many lines Shift+PageUp can go back. (0 turns it off)");
+            println!("set [NAME VALUE] -- Sets a variable, or lists them all.");
+            println!("export [NAME[=VALUE]] -- Marks a variable as exported, or lists them.");
+            println!("unset [NAME] -- Removes a variable.");
+            println!("source [file] -- Runs a script in the current shell. (also `.`)");
+            println!("test [expr] -- Checks a condition. (also `[ expr ]`)");
+            println!("true/false -- Does nothing, successfully or not.");
+            println!("cd [dir] -- Changes the working directory. pwd -- Shows it.");
+            println!("ls [-l] [-a] [path...] -- Lists a directory.");
+            println!("cat [file...] -- Shows the contents of files.");
+            println!("cp [-r] [src...] [dest] -- Copies files. (-r for directories)");
+            println!("mv [src...] [dest] -- Moves or renames files.");
+            println!("rm [-r] [-f] [path...] -- Removes files. (-r for directories)");
+            println!("mkdir [-p] [dir...] -- Creates directories.");
+            println!("touch [file...] -- Creates files or updates their time.");
+            println!("stat [path...] -- Shows the size, attributes and times of a path.");
+            println!(
+                "attrib [+r|-r|+h|-h|+s|-s|+a|-a] [path...] -- Shows or changes FAT attributes."
+            );
+            println!("edit [file] -- Opens a file in the text editor.");
+            println!("fbcon [WIDTHxHEIGHT] [font.psf] -- Moves the console to a graphics mode. (1024x768 by default)");
+            println!("kbd [list | set LAYOUT] -- Shows or changes the keyboard layout.");
+            println!("gui [WIDTHxHEIGHT] -- Starts the desktop. (term, demo, list, raise, move, close for windows)");
+            println!("hexdump [file] [offset] [length] -- Dumps a file. (-s [sector] [count] for the disk)");
+            println!("peek [-p] [-b|-w|-d|-q] [addr] [length] -- Dumps memory. (-p for physical)");
+            println!("poke [-p] [-b|-w|-d|-q] [addr] [value] -- Writes to memory.");
+            println!("vtop [addr] -- Shows what a virtual address is mapped to.");
+            println!("inb/inw/inl [port] -- Reads an I/O port. outb/outw/outl [port] [value] -- Writes one.");
+            println!("rdmsr [msr] -- Reads an MSR. wrmsr [msr] [value] -- Writes one.");
+            println!(
+                "irqstat -- Shows how many times each interrupt has come in, and who handles it."
+            );
+            println!("meminfo -- Shows how much physical memory is free, and how big the heap is.");
+            println!("Scripts can use $VAR, $?, &&, ||, ;, if/then/elif/else/fi,");
+            println!("for NAME in ...; do/done and while ...; do/done.");
+            println!("Ending a command with & runs it in the background. Ctrl-C stops a command.");
+            println!("jobs -- Lists background jobs.");
+            println!("fg [%job] -- Waits for a background job. (Ctrl-C stops it)");
+            println!("kill [%job...] -- Stops background jobs.");
+        }
+        _ => {
+            println!("Unknown command: {}", args[0]);
+            return 127;
+        }
     }
+
+    0
 }
@@ -0,0 +1,475 @@
+//! Low-level debugging commands: `hexdump`, `peek`/`poke`, `vtop`, port I/O
+//! (`inb`, `outb`, ...), `rdmsr`/`wrmsr`, `irqstat` and `meminfo`.
+//!
+//! Every memory access is checked against the page tables first, so a typo'd
+//! address prints an error instead of ending up in `page_fault_handler`.
+
+use crate::{
+    disks::ahci::{scan_for_ahci_controllers, scan_for_used_ports},
+    fs::vfs,
+    interrupts, memory, print, println,
+};
+use alloc::{format, string::String, vec::Vec};
+use x86_64::{instructions::port::Port, PhysAddr, VirtAddr};
+
+/// How much `peek` and `hexdump` show if you don't say.
+const DEFAULT_LENGTH: u64 = 64;
+/// Nobody wants to scroll through more than this on a VGA console.
+const MAX_LENGTH: u64 = 4096;
+const SECTOR_SIZE: u32 = 512;
+
+/// Runs a debugging command, returning `None` if `args` isn't one.
+pub fn run(cwd: &str, args: &[String]) -> Option<i32> {
+    let result = match args[0].as_str() {
+        "hexdump" => hexdump_command(cwd, &args[1..]),
+        "peek" => peek(&args[1..]),
+        "poke" => poke(&args[1..]),
+        "vtop" => vtop(&args[1..]),
+        "inb" | "inw" | "inl" => port_in(&args[0], &args[1..]),
+        "outb" | "outw" | "outl" => port_out(&args[0], &args[1..]),
+        "rdmsr" => rdmsr(&args[1..]),
+        "wrmsr" => wrmsr(&args[1..]),
+        "irqstat" => irqstat(&args[1..]),
+        "meminfo" => meminfo(&args[1..]),
+        _ => return None,
+    };
+    Some(match result {
+        Ok(()) => 0,
+        Err(e) => {
+            println!("{}: {}", args[0], e);
+            1
+        }
+    })
+}
+
+/// Parses `0x` prefixed hex, or decimal.
+pub fn parse_number(s: &str) -> Result<u64, String> {
+    let parsed = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(&hex.replace('_', ""), 16),
+        None => s.replace('_', "").parse::<u64>(),
+    };
+    parsed.map_err(|_| format!("'{}' isn't a number", s))
+}
+
+/// Prints `data` as 16 bytes per line, hex on the left and ASCII on the right.
+/// `base` is the address (or offset) shown for the first byte.
+pub fn hexdump(data: &[u8], base: u64) {
+    for (i, line) in data.chunks(16).enumerate() {
+        print!("{:016x}  ", base + i as u64 * 16);
+        for column in 0..16 {
+            match line.get(column) {
+                Some(byte) => print!("{:02x} ", byte),
+                None => print!("   "),
+            }
+            if column == 7 {
+                print!(" ");
+            }
+        }
+        let ascii: String = line
+            .iter()
+            .map(|&byte| {
+                if byte.is_ascii_graphic() || byte == b' ' {
+                    byte as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        println!(" |{}|", ascii);
+    }
+}
+
+/// The width of a single `peek`/`poke`/port access.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Width {
+    Byte,
+    Word,
+    Dword,
+    Qword,
+}
+
+impl Width {
+    fn from_flag(flag: &str) -> Option<Self> {
+        match flag {
+            "-b" => Some(Width::Byte),
+            "-w" => Some(Width::Word),
+            "-d" => Some(Width::Dword),
+            "-q" => Some(Width::Qword),
+            _ => None,
+        }
+    }
+
+    fn bytes(self) -> u64 {
+        match self {
+            Width::Byte => 1,
+            Width::Word => 2,
+            Width::Dword => 4,
+            Width::Qword => 8,
+        }
+    }
+
+    fn max(self) -> u64 {
+        match self {
+            Width::Qword => u64::MAX,
+            width => (1 << (width.bytes() * 8)) - 1,
+        }
+    }
+}
+
+/// The flags `peek` and `poke` share.
+struct AccessOptions<'a> {
+    physical: bool,
+    width: Option<Width>,
+    operands: Vec<&'a str>,
+}
+
+fn parse_access_options(args: &[String]) -> Result<AccessOptions<'_>, String> {
+    let mut options = AccessOptions {
+        physical: false,
+        width: None,
+        operands: Vec::new(),
+    };
+    for arg in args {
+        if arg == "-p" {
+            options.physical = true;
+        } else if let Some(width) = Width::from_flag(arg) {
+            options.width = Some(width);
+        } else if arg.starts_with('-') {
+            return Err(format!("unknown option '{}'", arg));
+        } else {
+            options.operands.push(arg.as_str());
+        }
+    }
+    Ok(options)
+}
+
+/// Turns `addr` into a virtual address that can be accessed for `len` bytes,
+/// going through the physical memory mapping if `physical` is set.
+fn checked_address(addr: u64, len: u64, physical: bool, write: bool) -> Result<VirtAddr, String> {
+    let virt = if physical {
+        let phys =
+            PhysAddr::try_new(addr).map_err(|_| format!("{:#x} isn't a physical address", addr))?;
+        memory::phys_to_virt(phys)
+            .ok_or_else(|| format!("physical address {:#x} isn't mapped", addr))?
+    } else {
+        VirtAddr::try_new(addr).map_err(|_| format!("{:#x} isn't a canonical address", addr))?
+    };
+
+    memory::check_range(virt, len, write).map_err(|bad| {
+        let what = if write { "writable" } else { "mapped" };
+        format!("{:#x} isn't {}", bad.as_u64(), what)
+    })?;
+    Ok(virt)
+}
+
+/// Wider accesses have to be aligned to their width. (The physical memory
+/// mapping is page aligned, so a physical address is aligned the same way.)
+fn check_alignment(addr: u64, width: Width) -> Result<(), String> {
+    if addr % width.bytes() != 0 {
+        return Err(format!(
+            "{:#x} isn't aligned to {} bytes",
+            addr,
+            width.bytes()
+        ));
+    }
+    Ok(())
+}
+
+fn hexdump_command(cwd: &str, args: &[String]) -> Result<(), String> {
+    let usage = "Usage: hexdump FILE [OFFSET [LENGTH]] | hexdump -s SECTOR [COUNT]";
+    match args {
+        [flag, sector, rest @ ..] if flag == "-s" => {
+            let sector = parse_number(sector)?;
+            let count = match rest {
+                [] => 1,
+                [count] => parse_number(count)?,
+                _ => return Err(usage.into()),
+            };
+            let length = count.checked_mul(SECTOR_SIZE as u64);
+            if count == 0 || length.map_or(true, |length| length > MAX_LENGTH) {
+                return Err(format!(
+                    "can show 1 to {} sectors",
+                    MAX_LENGTH / SECTOR_SIZE as u64
+                ));
+            }
+            hexdump_sectors(sector, count as u16)
+        }
+        [path, rest @ ..] if !path.starts_with('-') => {
+            let (offset, length) = match rest {
+                [] => (0, None),
+                [offset] => (parse_number(offset)?, None),
+                [offset, length] => (parse_number(offset)?, Some(parse_number(length)?)),
+                _ => return Err(usage.into()),
+            };
+            let contents =
+                vfs::read(&vfs::resolve(cwd, path)).map_err(|e| format!("{}: {}", path, e))?;
+            let start = (offset as usize).min(contents.len());
+            let end = match length {
+                Some(length) => start.saturating_add(length as usize).min(contents.len()),
+                None => contents.len(),
+            };
+            hexdump(&contents[start..end], start as u64);
+            Ok(())
+        }
+        _ => Err(usage.into()),
+    }
+}
+
+/// Dumps sectors from the first AHCI disk.
+fn hexdump_sectors(sector: u64, count: u16) -> Result<(), String> {
+    let offset = sector
+        .checked_mul(SECTOR_SIZE as u64)
+        .ok_or_else(|| format!("sector {} is past the end of any disk", sector))?;
+    let controllers = scan_for_ahci_controllers(false);
+    let controller = controllers.first().ok_or("no AHCI controllers found")?;
+    // the AHCI driver pokes the HBA registers directly, make sure that won't fault
+    memory::check_range(VirtAddr::new(controller.base_addr as u64), 0x1100, true).map_err(
+        |_| {
+            format!(
+                "the AHCI registers at {:#x} aren't mapped",
+                controller.base_addr
+            )
+        },
+    )?;
+
+    let mut devices = scan_for_used_ports(controller, false);
+    let device = devices.first_mut().ok_or("no AHCI devices found")?;
+    device.set_sector_size(SECTOR_SIZE);
+    let data = controller.read(device, sector, count);
+    hexdump(&data, offset);
+    Ok(())
+}
+
+fn peek(args: &[String]) -> Result<(), String> {
+    let options = parse_access_options(args)?;
+    let (addr, length) = match options.operands[..] {
+        [addr] => (parse_number(addr)?, None),
+        [addr, length] => (parse_number(addr)?, Some(parse_number(length)?)),
+        _ => return Err("Usage: peek [-p] [-b|-w|-d|-q] ADDRESS [LENGTH]".into()),
+    };
+
+    if let Some(width) = options.width {
+        // a single access of exactly that width, which is what MMIO registers want
+        if length.is_some() {
+            return Err("LENGTH can't be used with a width".into());
+        }
+        check_alignment(addr, width)?;
+        let virt = checked_address(addr, width.bytes(), options.physical, false)?;
+        let value = unsafe {
+            match width {
+                Width::Byte => core::ptr::read_volatile(virt.as_ptr::<u8>()) as u64,
+                Width::Word => core::ptr::read_volatile(virt.as_ptr::<u16>()) as u64,
+                Width::Dword => core::ptr::read_volatile(virt.as_ptr::<u32>()) as u64,
+                Width::Qword => core::ptr::read_volatile(virt.as_ptr::<u64>()),
+            }
+        };
+        println!(
+            "{:#x}: {:#0width$x}",
+            addr,
+            value,
+            width = width.bytes() as usize * 2 + 2
+        );
+        return Ok(());
+    }
+
+    let length = length.unwrap_or(DEFAULT_LENGTH);
+    if length == 0 || length > MAX_LENGTH {
+        return Err(format!("LENGTH must be between 1 and {}", MAX_LENGTH));
+    }
+    let virt = checked_address(addr, length, options.physical, false)?;
+    let data: Vec<u8> = (0..length)
+        .map(|i| unsafe { core::ptr::read_volatile((virt + i).as_ptr::<u8>()) })
+        .collect();
+    hexdump(&data, addr);
+    Ok(())
+}
+
+fn poke(args: &[String]) -> Result<(), String> {
+    let options = parse_access_options(args)?;
+    let width = options.width.unwrap_or(Width::Byte);
+    let (addr, value) = match options.operands[..] {
+        [addr, value] => (parse_number(addr)?, parse_number(value)?),
+        _ => return Err("Usage: poke [-p] [-b|-w|-d|-q] ADDRESS VALUE".into()),
+    };
+    if value > width.max() {
+        return Err(format!(
+            "{:#x} doesn't fit in {} byte(s)",
+            value,
+            width.bytes()
+        ));
+    }
+
+    check_alignment(addr, width)?;
+    let virt = checked_address(addr, width.bytes(), options.physical, true)?;
+    unsafe {
+        match width {
+            Width::Byte => core::ptr::write_volatile(virt.as_mut_ptr::<u8>(), value as u8),
+            Width::Word => core::ptr::write_volatile(virt.as_mut_ptr::<u16>(), value as u16),
+            Width::Dword => core::ptr::write_volatile(virt.as_mut_ptr::<u32>(), value as u32),
+            Width::Qword => core::ptr::write_volatile(virt.as_mut_ptr::<u64>(), value),
+        }
+    }
+    Ok(())
+}
+
+/// Shows what a virtual address is mapped to.
+fn vtop(args: &[String]) -> Result<(), String> {
+    let addr = match args {
+        [addr] => parse_number(addr)?,
+        _ => return Err("Usage: vtop ADDRESS".into()),
+    };
+    let virt =
+        VirtAddr::try_new(addr).map_err(|_| format!("{:#x} isn't a canonical address", addr))?;
+    let translation = memory::translate(virt).ok_or(format!("{:#x} isn't mapped", addr))?;
+    println!(
+        "{:#x} -> {:#x} ({} KiB page, {:?})",
+        addr,
+        translation.phys.as_u64(),
+        translation.page_size / 1024,
+        translation.flags
+    );
+    Ok(())
+}
+
+fn parse_port(port: &str) -> Result<u16, String> {
+    let port = parse_number(port)?;
+    if port > u16::MAX as u64 {
+        return Err(format!("{:#x} isn't an I/O port", port));
+    }
+    Ok(port as u16)
+}
+
+fn port_in(command: &str, args: &[String]) -> Result<(), String> {
+    let port = match args {
+        [port] => parse_port(port)?,
+        _ => return Err(format!("Usage: {} PORT", command)),
+    };
+    unsafe {
+        match command {
+            "inb" => println!("{:#06x}: {:#04x}", port, Port::<u8>::new(port).read()),
+            "inw" => println!("{:#06x}: {:#06x}", port, Port::<u16>::new(port).read()),
+            _ => println!("{:#06x}: {:#010x}", port, Port::<u32>::new(port).read()),
+        }
+    }
+    Ok(())
+}
+
+fn port_out(command: &str, args: &[String]) -> Result<(), String> {
+    let (port, value) = match args {
+        [port, value] => (parse_port(port)?, parse_number(value)?),
+        _ => return Err(format!("Usage: {} PORT VALUE", command)),
+    };
+    let width = match command {
+        "outb" => Width::Byte,
+        "outw" => Width::Word,
+        _ => Width::Dword,
+    };
+    if value > width.max() {
+        return Err(format!(
+            "{:#x} doesn't fit in {} byte(s)",
+            value,
+            width.bytes()
+        ));
+    }
+    unsafe {
+        match width {
+            Width::Byte => Port::<u8>::new(port).write(value as u8),
+            Width::Word => Port::<u16>::new(port).write(value as u16),
+            _ => Port::<u32>::new(port).write(value as u32),
+        }
+    }
+    Ok(())
+}
+
+fn parse_msr(msr: &str) -> Result<u32, String> {
+    let msr = parse_number(msr)?;
+    if msr > u32::MAX as u64 {
+        return Err(format!("{:#x} isn't an MSR", msr));
+    }
+    Ok(msr as u32)
+}
+
+fn rdmsr(args: &[String]) -> Result<(), String> {
+    let msr = match args {
+        [msr] => parse_msr(msr)?,
+        _ => return Err("Usage: rdmsr MSR".into()),
+    };
+    let value = interrupts::read_msr(msr).ok_or(format!("MSR {:#x} doesn't exist", msr))?;
+    println!("{:#x}: {:#018x}", msr, value);
+    Ok(())
+}
+
+fn wrmsr(args: &[String]) -> Result<(), String> {
+    let (msr, value) = match args {
+        [msr, value] => (parse_msr(msr)?, parse_number(value)?),
+        _ => return Err("Usage: wrmsr MSR VALUE".into()),
+    };
+    if unsafe { interrupts::write_msr(msr, value) } {
+        Ok(())
+    } else {
+        Err(format!(
+            "the CPU refused to write {:#x} to MSR {:#x}",
+            value, msr
+        ))
+    }
+}
+
+/// Lists the interrupts that have handlers or have come in, and how often.
+fn irqstat(args: &[String]) -> Result<(), String> {
+    if !args.is_empty() {
+        return Err("Usage: irqstat".into());
+    }
+    println!("VECTOR  IRQ          COUNT  HANDLERS");
+    for stat in interrupts::irq::stats() {
+        let irq = match stat.irq {
+            Some(irq) => format!("{}", irq),
+            None => String::from("-"),
+        };
+        let handlers: Vec<&str> = interrupts::irq::handlers(stat.vector).collect();
+        let handlers = if handlers.is_empty() {
+            String::from("(none)")
+        } else {
+            handlers.join(", ")
+        };
+        println!(
+            "{:#6x}  {:>3}  {:>13}  {}",
+            stat.vector, irq, stat.count, handlers
+        );
+    }
+    Ok(())
+}
+
+/// Shows how much physical memory is free, and what's been reserved.
+fn meminfo(args: &[String]) -> Result<(), String> {
+    if !args.is_empty() {
+        return Err("Usage: meminfo".into());
+    }
+    let stats = memory::frames::stats().ok_or("the frame allocator isn't set up")?;
+    let kib = |frames: usize| frames * 4;
+    println!("Usable:   {:>8} KiB", kib(stats.usable));
+    println!("Free:     {:>8} KiB", kib(stats.free));
+    println!("Used:     {:>8} KiB", kib(stats.used));
+    println!("Reserved: {:>8} KiB", kib(stats.reserved));
+    println!(
+        "Heap:     {:>8} KiB (of at most {} KiB)",
+        crate::allocator::heap_size() / 1024,
+        crate::allocator::heap_limit() / 1024
+    );
+    for reservation in memory::frames::reservations() {
+        println!(
+            "  {:#012x}..{:#012x}  {}",
+            reservation.start.as_u64(),
+            reservation.end.as_u64(),
+            reservation.name
+        );
+    }
+    Ok(())
+}
+
+#[test_case]
+fn test_parse_number() {
+    assert_eq!(parse_number("0x1F"), Ok(0x1f));
+    assert_eq!(parse_number("4096"), Ok(4096));
+    assert_eq!(parse_number("0xffff_ffff"), Ok(0xffff_ffff));
+    assert!(parse_number("lemon").is_err());
+}
@@ -0,0 +1,431 @@
+//! The file manipulation commands: `ls`, `cat`, `cp`, `mv`, `rm`, `mkdir`,
+//! `touch`, `stat` and `attrib`.
+//!
+//! `cd` and `pwd` change the shell itself, so they're builtins in `command_line`.
+//! Everything here takes paths relative to the shell's working directory.
+
+use crate::{
+    fs::{
+        vfs::{self, FsError, Metadata},
+        Attributes,
+    },
+    print, println,
+};
+use alloc::{format, string::String, vec::Vec};
+
+/// Runs a file command, returning `None` if `args` isn't one.
+pub fn run(cwd: &str, args: &[String]) -> Option<i32> {
+    let (flags, operands) = split_flags(&args[1..]);
+    let status = match args[0].as_str() {
+        "ls" => ls(cwd, &flags, &operands),
+        "cat" => cat(cwd, &operands),
+        "cp" => cp(cwd, &flags, &operands),
+        "mv" => mv(cwd, &operands),
+        "rm" => rm(cwd, &flags, &operands),
+        "mkdir" => mkdir(cwd, &flags, &operands),
+        "touch" => touch(cwd, &operands),
+        "stat" => stat(cwd, &operands),
+        // `+r`/`-r` look like flags, so attrib does its own parsing
+        "attrib" => attrib(cwd, &args[1..]),
+        _ => return None,
+    };
+    Some(status)
+}
+
+/// Separates `-x` style flags from the other arguments. `-lr` counts as `l` and `r`.
+fn split_flags(args: &[String]) -> (Vec<char>, Vec<&str>) {
+    let mut flags = Vec::new();
+    let mut operands = Vec::new();
+    for arg in args {
+        if arg.len() > 1 && arg.starts_with('-') {
+            flags.extend(arg.chars().skip(1));
+        } else {
+            operands.push(arg.as_str());
+        }
+    }
+    (flags, operands)
+}
+
+/// Formats `size` bytes as `512B`, `1.5K`, `20M` and so on.
+pub fn human_size(size: usize) -> String {
+    const UNITS: [&str; 5] = ["B", "K", "M", "G", "T"];
+    let mut value = size;
+    let mut remainder = 0;
+    let mut unit = 0;
+    while value >= 1024 && unit < UNITS.len() - 1 {
+        remainder = value % 1024;
+        value /= 1024;
+        unit += 1;
+    }
+    if unit == 0 || value >= 10 {
+        format!("{}{}", value, UNITS[unit])
+    } else {
+        format!("{}.{}{}", value, remainder * 10 / 1024, UNITS[unit])
+    }
+}
+
+/// The attribute column of `ls -l`, e.g. `d----` or `-rh-a`.
+fn attribute_string(metadata: &Metadata) -> String {
+    let bits = [
+        (Attributes::AttrDirectory, 'd'),
+        (Attributes::AttrReadOnly, 'r'),
+        (Attributes::AttrHidden, 'h'),
+        (Attributes::AttrSystem, 's'),
+        (Attributes::AttrArchive, 'a'),
+    ];
+    bits.iter()
+        .map(|&(attribute, c)| {
+            if metadata.has_attribute(attribute) {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect()
+}
+
+fn timestamp(time: &crate::cmos::Time) -> String {
+    format!(
+        "{:02}/{:02}/{:04} {:02}:{:02}",
+        time.month, time.day, time.year, time.hour, time.minute
+    )
+}
+
+fn error(command: &str, path: &str, e: FsError) -> i32 {
+    println!("{}: {}: {}", command, path, e);
+    1
+}
+
+fn ls(cwd: &str, flags: &[char], operands: &[&str]) -> i32 {
+    let long = flags.contains(&'l');
+    let all = flags.contains(&'a');
+    let targets = if operands.is_empty() {
+        &["."][..]
+    } else {
+        operands
+    };
+
+    let mut status = 0;
+    for (i, target) in targets.iter().enumerate() {
+        let path = vfs::resolve(cwd, target);
+        let metadata = match vfs::metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                status = error("ls", target, e);
+                continue;
+            }
+        };
+
+        let entries = if metadata.is_dir {
+            match vfs::read_dir(&path) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    status = error("ls", target, e);
+                    continue;
+                }
+            }
+        } else {
+            let name = String::from(*target);
+            Vec::from([vfs::DirEntry { name, metadata }])
+        };
+
+        if targets.len() > 1 && metadata.is_dir {
+            if i > 0 {
+                println!();
+            }
+            println!("{}:", target);
+        }
+
+        for entry in entries {
+            let hidden =
+                entry.name.starts_with('.') || entry.metadata.has_attribute(Attributes::AttrHidden);
+            if hidden && !all {
+                continue;
+            }
+            if long {
+                let size = if entry.metadata.is_dir {
+                    String::from("-")
+                } else {
+                    human_size(entry.metadata.size)
+                };
+                println!(
+                    "{} {:>6} {} {}",
+                    attribute_string(&entry.metadata),
+                    size,
+                    timestamp(&entry.metadata.modified),
+                    entry.name
+                );
+            } else if entry.metadata.is_dir {
+                print!("{}/  ", entry.name);
+            } else {
+                print!("{}  ", entry.name);
+            }
+        }
+        if !long {
+            println!();
+        }
+    }
+    status
+}
+
+fn cat(cwd: &str, operands: &[&str]) -> i32 {
+    if operands.is_empty() {
+        println!("Usage: cat FILE...");
+        return 2;
+    }
+    let mut status = 0;
+    for operand in operands {
+        match vfs::read(&vfs::resolve(cwd, operand)) {
+            Ok(contents) => print!("{}", String::from_utf8_lossy(&contents)),
+            Err(e) => status = error("cat", operand, e),
+        }
+    }
+    status
+}
+
+/// Works out where `source` should end up: inside `dest` if it's a directory,
+/// otherwise at `dest` itself.
+fn destination(cwd: &str, source: &str, dest: &str) -> String {
+    let dest = vfs::resolve(cwd, dest);
+    if vfs::is_dir(&dest) {
+        vfs::resolve(&dest, vfs::file_name(&vfs::resolve(cwd, source)))
+    } else {
+        dest
+    }
+}
+
+fn copy(from: &str, to: &str, recursive: bool) -> Result<(), FsError> {
+    if !vfs::is_dir(from) {
+        return vfs::write(to, &vfs::read(from)?);
+    }
+    if !recursive {
+        return Err(FsError::IsADirectory);
+    }
+    // stops `cp -r a a/b` (or `cp -r / /x`) from copying forever
+    let from_parts = vfs::components(from)?;
+    let to_parts = vfs::components(to)?;
+    if to_parts.len() >= from_parts.len() && to_parts[..from_parts.len()] == from_parts[..] {
+        return Err(FsError::InvalidPath);
+    }
+    match vfs::create_dir(to) {
+        Ok(()) => {}
+        Err(FsError::AlreadyExists) if vfs::is_dir(to) => {}
+        Err(e) => return Err(e),
+    }
+    for entry in vfs::read_dir(from)? {
+        copy(
+            &vfs::resolve(from, &entry.name),
+            &vfs::resolve(to, &entry.name),
+            true,
+        )?;
+    }
+    Ok(())
+}
+
+fn cp(cwd: &str, flags: &[char], operands: &[&str]) -> i32 {
+    let recursive = flags.contains(&'r') || flags.contains(&'R');
+    if operands.len() < 2 {
+        println!("Usage: cp [-r] SOURCE... DEST");
+        return 2;
+    }
+    let (sources, dest) = operands.split_at(operands.len() - 1);
+    if sources.len() > 1 && !vfs::is_dir(&vfs::resolve(cwd, dest[0])) {
+        println!("cp: {}: {}", dest[0], FsError::NotADirectory);
+        return 1;
+    }
+
+    let mut status = 0;
+    for source in sources {
+        let from = vfs::resolve(cwd, source);
+        let to = destination(cwd, source, dest[0]);
+        if let Err(e) = copy(&from, &to, recursive) {
+            status = error("cp", source, e);
+        }
+    }
+    status
+}
+
+fn mv(cwd: &str, operands: &[&str]) -> i32 {
+    if operands.len() < 2 {
+        println!("Usage: mv SOURCE... DEST");
+        return 2;
+    }
+    let (sources, dest) = operands.split_at(operands.len() - 1);
+    if sources.len() > 1 && !vfs::is_dir(&vfs::resolve(cwd, dest[0])) {
+        println!("mv: {}: {}", dest[0], FsError::NotADirectory);
+        return 1;
+    }
+
+    let mut status = 0;
+    for source in sources {
+        let from = vfs::resolve(cwd, source);
+        let to = destination(cwd, source, dest[0]);
+        if let Err(e) = vfs::rename(&from, &to) {
+            status = error("mv", source, e);
+        }
+    }
+    status
+}
+
+fn rm(cwd: &str, flags: &[char], operands: &[&str]) -> i32 {
+    let recursive = flags.contains(&'r') || flags.contains(&'R');
+    let force = flags.contains(&'f');
+    if operands.is_empty() && !force {
+        println!("Usage: rm [-r] [-f] PATH...");
+        return 2;
+    }
+
+    let mut status = 0;
+    for operand in operands {
+        let path = vfs::resolve(cwd, operand);
+        if path == "/" {
+            println!("rm: refusing to remove '/'");
+            status = 1;
+            continue;
+        }
+        let result = match vfs::metadata(&path) {
+            Ok(metadata) if metadata.is_dir && !recursive => Err(FsError::IsADirectory),
+            Ok(_) if recursive => vfs::remove_all(&path),
+            Ok(_) => vfs::remove(&path),
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(()) => {}
+            Err(FsError::NotFound) if force => {}
+            Err(e) => status = error("rm", operand, e),
+        }
+    }
+    status
+}
+
+fn mkdir(cwd: &str, flags: &[char], operands: &[&str]) -> i32 {
+    let parents = flags.contains(&'p');
+    if operands.is_empty() {
+        println!("Usage: mkdir [-p] DIR...");
+        return 2;
+    }
+
+    let mut status = 0;
+    for operand in operands {
+        let path = vfs::resolve(cwd, operand);
+        let result = if parents {
+            vfs::create_dir_all(&path)
+        } else {
+            vfs::create_dir(&path)
+        };
+        if let Err(e) = result {
+            status = error("mkdir", operand, e);
+        }
+    }
+    status
+}
+
+fn touch(cwd: &str, operands: &[&str]) -> i32 {
+    if operands.is_empty() {
+        println!("Usage: touch FILE...");
+        return 2;
+    }
+    let mut status = 0;
+    for operand in operands {
+        if let Err(e) = vfs::touch(&vfs::resolve(cwd, operand)) {
+            status = error("touch", operand, e);
+        }
+    }
+    status
+}
+
+fn stat(cwd: &str, operands: &[&str]) -> i32 {
+    if operands.is_empty() {
+        println!("Usage: stat PATH...");
+        return 2;
+    }
+    let mut status = 0;
+    for operand in operands {
+        let path = vfs::resolve(cwd, operand);
+        match vfs::metadata(&path) {
+            Ok(metadata) => {
+                println!("  File: {}", path);
+                println!(
+                    "  Type: {}",
+                    if metadata.is_dir { "directory" } else { "file" }
+                );
+                println!(
+                    "  Size: {} bytes ({})",
+                    metadata.size,
+                    human_size(metadata.size)
+                );
+                println!(
+                    "  Attributes: {} (0x{:02x})",
+                    attribute_string(&metadata),
+                    metadata.attributes
+                );
+                println!("  Created: {}", timestamp(&metadata.created));
+                println!("  Modified: {}", timestamp(&metadata.modified));
+            }
+            Err(e) => status = error("stat", operand, e),
+        }
+    }
+    status
+}
+
+/// DOS style `attrib`: shows the attributes of a path, or sets/clears them
+/// with `+r`, `-h` and friends.
+fn attrib(cwd: &str, args: &[String]) -> i32 {
+    let mut set = 0u8;
+    let mut clear = 0u8;
+    let mut paths = Vec::new();
+    for arg in args {
+        let (add, letter) = match arg.as_bytes() {
+            [b'+', letter] => (true, *letter),
+            [b'-', letter] => (false, *letter),
+            _ => {
+                paths.push(arg.as_str());
+                continue;
+            }
+        };
+        let attribute = match letter.to_ascii_lowercase() {
+            b'r' => Attributes::AttrReadOnly,
+            b'h' => Attributes::AttrHidden,
+            b's' => Attributes::AttrSystem,
+            b'a' => Attributes::AttrArchive,
+            _ => {
+                println!("attrib: unknown attribute '{}'", arg);
+                return 2;
+            }
+        };
+        if add {
+            set |= attribute as u8;
+        } else {
+            clear |= attribute as u8;
+        }
+    }
+    if paths.is_empty() {
+        println!("Usage: attrib [+r|-r] [+h|-h] [+s|-s] [+a|-a] PATH...");
+        return 2;
+    }
+
+    let mut status = 0;
+    for operand in paths {
+        let path = vfs::resolve(cwd, operand);
+        let result = vfs::metadata(&path).and_then(|metadata| {
+            if set | clear == 0 {
+                println!("{} {}", attribute_string(&metadata), path);
+                Ok(())
+            } else {
+                vfs::set_attributes(&path, (metadata.attributes | set) & !clear)
+            }
+        });
+        if let Err(e) = result {
+            status = error("attrib", operand, e);
+        }
+    }
+    status
+}
+
+#[test_case]
+fn test_human_size() {
+    assert_eq!(human_size(0), "0B");
+    assert_eq!(human_size(1023), "1023B");
+    assert_eq!(human_size(1536), "1.5K");
+    assert_eq!(human_size(20 * 1024 * 1024), "20M");
+}
@@ -0,0 +1,195 @@
+//! Background jobs (`cmd &`) and the `jobs`, `fg` and `kill` builtins.
+//!
+//! A job is just a task on the executor, wrapped so it can be cancelled. There
+//! are no signals: cancelling a job drops its future the next time it's polled,
+//! so a job stops at its next `.await` (commands with long loops call
+//! `task::yield_now` to give it one).
+
+use crate::{
+    println,
+    task::{executor::Spawner, Task},
+};
+use alloc::{boxed::Box, collections::BTreeMap, string::String, sync::Arc};
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicI32, Ordering},
+    task::{Context, Poll},
+};
+use futures_util::{future::LocalBoxFuture, task::AtomicWaker};
+
+/// Exit status of a job stopped with Ctrl-C, like a shell reports SIGINT.
+pub const STATUS_INTERRUPTED: i32 = 130;
+/// Exit status of a job stopped with `kill`, like SIGTERM.
+pub const STATUS_KILLED: i32 = 143;
+
+/// What a running job and the shell watching it share.
+pub struct JobState {
+    cancelled: AtomicBool,
+    /// The status the job reports if it gets cancelled.
+    cancel_status: AtomicI32,
+    finished: AtomicBool,
+    status: AtomicI32,
+    /// Wakes the job's task when it's cancelled, so it notices right away.
+    task_waker: AtomicWaker,
+    /// Wakes whoever is `wait`ing for the job.
+    waiter: AtomicWaker,
+}
+
+impl JobState {
+    fn new() -> Self {
+        JobState {
+            cancelled: AtomicBool::new(false),
+            cancel_status: AtomicI32::new(0),
+            finished: AtomicBool::new(false),
+            status: AtomicI32::new(0),
+            task_waker: AtomicWaker::new(),
+            waiter: AtomicWaker::new(),
+        }
+    }
+
+    /// Asks the job to stop. It finishes with `status` once it's polled again.
+    pub fn cancel(&self, status: i32) {
+        self.cancel_status.store(status, Ordering::SeqCst);
+        self.cancelled.store(true, Ordering::SeqCst);
+        self.task_waker.wake();
+    }
+
+    /// The exit status, or `None` if the job is still running.
+    pub fn status(&self) -> Option<i32> {
+        if self.finished.load(Ordering::SeqCst) {
+            Some(self.status.load(Ordering::SeqCst))
+        } else {
+            None
+        }
+    }
+
+    fn finish(&self, status: i32) {
+        self.status.store(status, Ordering::SeqCst);
+        self.finished.store(true, Ordering::SeqCst);
+        self.waiter.wake();
+    }
+
+    /// Returns a future that completes with the exit status when the job is done.
+    pub fn wait(self: &Arc<Self>) -> Wait {
+        Wait {
+            state: self.clone(),
+        }
+    }
+}
+
+pub struct Wait {
+    state: Arc<JobState>,
+}
+
+impl Future for Wait {
+    type Output = i32;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<i32> {
+        if let Some(status) = self.state.status() {
+            return Poll::Ready(status);
+        }
+        self.state.waiter.register(cx.waker());
+        match self.state.status() {
+            Some(status) => Poll::Ready(status),
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// The future a job's task runs: the command itself, until it's cancelled.
+struct Cancellable {
+    future: LocalBoxFuture<'static, i32>,
+    state: Arc<JobState>,
+}
+
+impl Future for Cancellable {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.state.cancelled.load(Ordering::SeqCst) {
+            let status = self.state.cancel_status.load(Ordering::SeqCst);
+            self.state.finish(status);
+            return Poll::Ready(());
+        }
+        self.state.task_waker.register(cx.waker());
+        match self.future.as_mut().poll(cx) {
+            Poll::Ready(status) => {
+                self.state.finish(status);
+                Poll::Ready(())
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+pub struct Job {
+    pub command: String,
+    pub state: Arc<JobState>,
+}
+
+/// The jobs started by one interactive shell, numbered from 1 like `%1`.
+pub struct JobTable {
+    spawner: Spawner,
+    jobs: BTreeMap<usize, Job>,
+}
+
+impl JobTable {
+    pub fn new(spawner: Spawner) -> Self {
+        JobTable {
+            spawner,
+            jobs: BTreeMap::new(),
+        }
+    }
+
+    /// Spawns `future` as a new job, returning its number.
+    pub fn spawn(&mut self, command: String, future: impl Future<Output = i32> + 'static) -> usize {
+        let id = self.jobs.keys().next_back().map_or(1, |last| last + 1);
+        let state = Arc::new(JobState::new());
+        self.spawner.spawn(Task::new(Cancellable {
+            future: Box::pin(future),
+            state: state.clone(),
+        }));
+        self.jobs.insert(id, Job { command, state });
+        id
+    }
+
+    pub fn get(&self, id: usize) -> Option<&Job> {
+        self.jobs.get(&id)
+    }
+
+    /// The most recently started job, which `fg` and `kill` use by default.
+    pub fn current(&self) -> Option<usize> {
+        self.jobs.keys().next_back().copied()
+    }
+
+    pub fn remove(&mut self, id: usize) -> Option<Job> {
+        self.jobs.remove(&id)
+    }
+
+    /// Prints every job. Finished ones are forgotten once they're shown.
+    pub fn list(&mut self) {
+        for (id, job) in &self.jobs {
+            match job.state.status() {
+                None => println!("[{}]  Running    {}", id, job.command),
+                Some(status) => println!("[{}]  Done ({})   {}", id, status, job.command),
+            }
+        }
+        self.jobs.retain(|_, job| job.state.status().is_none());
+    }
+
+    /// Reports (and forgets) the jobs that finished since the last prompt.
+    pub fn report_finished(&mut self) {
+        for (id, job) in &self.jobs {
+            if let Some(status) = job.state.status() {
+                println!("[{}]  Done ({})   {}", id, status, job.command);
+            }
+        }
+        self.jobs.retain(|_, job| job.state.status().is_none());
+    }
+}
+
+/// Parses a job spec, `%N` or just `N`.
+pub fn parse_job_spec(spec: &str) -> Option<usize> {
+    spec.strip_prefix('%').unwrap_or(spec).parse().ok()
+}
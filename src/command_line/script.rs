@@ -0,0 +1,671 @@
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::fmt;
+
+/// A single token produced by the lexer.
+///
+/// Words keep their quotes and `$` references; they are only expanded right
+/// before a command runs, so `for` loops see the current value of a variable.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Word(String),
+    Separator,
+    Newline,
+    And,
+    Or,
+    Background,
+}
+
+/// Everything that can go wrong while parsing a script.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    /// The input ended in the middle of a quote or a compound command.
+    /// The interactive prompt uses this to ask for another line.
+    Incomplete,
+    Syntax(String),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Incomplete => write!(f, "unexpected end of input"),
+            ParseError::Syntax(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+/// How two commands in an and-or list are joined.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Connector {
+    /// `a && b` -- run `b` only if `a` succeeded.
+    And,
+    /// `a || b` -- run `b` only if `a` failed.
+    Or,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// A plain command with its (still unexpanded) words.
+    Simple(Vec<String>),
+    /// `if ...; then ...; elif ...; then ...; else ...; fi`
+    If {
+        branches: Vec<(Script, Script)>,
+        otherwise: Option<Script>,
+    },
+    /// `for NAME in WORDS...; do ...; done`
+    For {
+        var: String,
+        items: Vec<String>,
+        body: Script,
+    },
+    /// `while ...; do ...; done`
+    While { condition: Script, body: Script },
+}
+
+/// A chain of commands joined with `&&` and `||`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AndOr {
+    pub first: Command,
+    pub rest: Vec<(Connector, Command)>,
+    /// Ended with `&`, so it runs as a background job.
+    pub background: bool,
+}
+
+/// A parsed script is just a list of and-or lists, run one after another.
+pub type Script = Vec<AndOr>;
+
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Command::Simple(words) => write!(f, "{}", words.join(" ")),
+            Command::If {
+                branches,
+                otherwise,
+            } => {
+                for (i, (condition, body)) in branches.iter().enumerate() {
+                    let keyword = if i == 0 { "if" } else { "elif" };
+                    write!(f, "{} ", keyword)?;
+                    fmt_script(f, condition)?;
+                    write!(f, "; then ")?;
+                    fmt_script(f, body)?;
+                    write!(f, "; ")?;
+                }
+                if let Some(otherwise) = otherwise {
+                    write!(f, "else ")?;
+                    fmt_script(f, otherwise)?;
+                    write!(f, "; ")?;
+                }
+                write!(f, "fi")
+            }
+            Command::For { var, items, body } => {
+                write!(f, "for {} in {}; do ", var, items.join(" "))?;
+                fmt_script(f, body)?;
+                write!(f, "; done")
+            }
+            Command::While { condition, body } => {
+                write!(f, "while ")?;
+                fmt_script(f, condition)?;
+                write!(f, "; do ")?;
+                fmt_script(f, body)?;
+                write!(f, "; done")
+            }
+        }
+    }
+}
+
+/// Shows the and-or list roughly the way it was typed (without a trailing `&`),
+/// which is what `jobs` lists.
+impl fmt::Display for AndOr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.first)?;
+        for (connector, command) in &self.rest {
+            let connector = match connector {
+                Connector::And => "&&",
+                Connector::Or => "||",
+            };
+            write!(f, " {} {}", connector, command)?;
+        }
+        Ok(())
+    }
+}
+
+fn fmt_script(f: &mut fmt::Formatter<'_>, script: &Script) -> fmt::Result {
+    for (i, and_or) in script.iter().enumerate() {
+        if i > 0 {
+            write!(f, "; ")?;
+        }
+        write!(f, "{}", and_or)?;
+        if and_or.background {
+            write!(f, " &")?;
+        }
+    }
+    Ok(())
+}
+
+const KEYWORDS: &[&str] = &[
+    "if", "then", "elif", "else", "fi", "for", "in", "do", "done", "while",
+];
+
+/// Parses a whole script (or a line typed at the prompt).
+pub fn parse(source: &str) -> Result<Script, ParseError> {
+    let tokens = tokenize(source)?;
+    let mut parser = Parser { tokens, pos: 0 };
+    let script = parser.parse_list(&[])?;
+    match parser.peek() {
+        None => Ok(script),
+        Some(token) => Err(ParseError::Syntax(format!(
+            "unexpected '{}'",
+            token_text(token)
+        ))),
+    }
+}
+
+/// Returns `true` if `name` can be used as a variable name.
+pub fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c == '_' || c.is_ascii_alphabetic() => {}
+        _ => return false,
+    }
+    chars.all(|c| c == '_' || c.is_ascii_alphanumeric())
+}
+
+fn token_text(token: &Token) -> &str {
+    match token {
+        Token::Word(word) => word,
+        Token::Separator => ";",
+        Token::Newline => "newline",
+        Token::And => "&&",
+        Token::Or => "||",
+        Token::Background => "&",
+    }
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, ParseError> {
+    let mut tokens = Vec::new();
+    let mut word = String::new();
+    let mut chars = source.chars().peekable();
+
+    fn flush(word: &mut String, tokens: &mut Vec<Token>) {
+        if !word.is_empty() {
+            tokens.push(Token::Word(core::mem::take(word)));
+        }
+    }
+
+    while let Some(c) = chars.next() {
+        match c {
+            ' ' | '\t' | '\r' => flush(&mut word, &mut tokens),
+            '\n' => {
+                flush(&mut word, &mut tokens);
+                tokens.push(Token::Newline);
+            }
+            ';' => {
+                flush(&mut word, &mut tokens);
+                tokens.push(Token::Separator);
+            }
+            '#' if word.is_empty() => {
+                // comment, runs until the end of the line
+                while let Some(&next) = chars.peek() {
+                    if next == '\n' {
+                        break;
+                    }
+                    chars.next();
+                }
+            }
+            '&' => {
+                flush(&mut word, &mut tokens);
+                if chars.peek() == Some(&'&') {
+                    chars.next();
+                    tokens.push(Token::And);
+                } else {
+                    tokens.push(Token::Background);
+                }
+            }
+            '|' => {
+                flush(&mut word, &mut tokens);
+                if chars.peek() == Some(&'|') {
+                    chars.next();
+                    tokens.push(Token::Or);
+                } else {
+                    return Err(ParseError::Syntax(
+                        "pipes ('|') aren't supported".to_string(),
+                    ));
+                }
+            }
+            '\'' | '"' => {
+                // quotes are kept in the word, expansion strips them later
+                word.push(c);
+                loop {
+                    match chars.next() {
+                        Some(q) if q == c => {
+                            word.push(q);
+                            break;
+                        }
+                        Some('\\') if c == '"' => {
+                            word.push('\\');
+                            match chars.next() {
+                                Some(escaped) => word.push(escaped),
+                                None => return Err(ParseError::Incomplete),
+                            }
+                        }
+                        Some(other) => word.push(other),
+                        None => return Err(ParseError::Incomplete),
+                    }
+                }
+            }
+            '\\' => match chars.next() {
+                // a backslash before a newline continues the line
+                Some('\n') => {}
+                Some(escaped) => {
+                    word.push('\\');
+                    word.push(escaped);
+                }
+                None => return Err(ParseError::Incomplete),
+            },
+            c => word.push(c),
+        }
+    }
+    flush(&mut word, &mut tokens);
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn peek_keyword(&self) -> Option<&str> {
+        match self.peek() {
+            Some(Token::Word(word)) if KEYWORDS.contains(&word.as_str()) => Some(word),
+            _ => None,
+        }
+    }
+
+    fn skip_newlines(&mut self) {
+        while let Some(Token::Newline) = self.peek() {
+            self.pos += 1;
+        }
+    }
+
+    fn skip_separators(&mut self) {
+        while let Some(Token::Newline) | Some(Token::Separator) = self.peek() {
+            self.pos += 1;
+        }
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<(), ParseError> {
+        match self.next() {
+            Some(Token::Word(ref word)) if word == keyword => Ok(()),
+            Some(token) => Err(ParseError::Syntax(format!(
+                "expected '{}', found '{}'",
+                keyword,
+                token_text(&token)
+            ))),
+            None => Err(ParseError::Incomplete),
+        }
+    }
+
+    /// Parses and-or lists until the input ends or one of `terminators` shows up
+    /// in command position. The terminator itself is left for the caller.
+    fn parse_list(&mut self, terminators: &[&str]) -> Result<Script, ParseError> {
+        let mut list = Vec::new();
+        loop {
+            self.skip_separators();
+            match self.peek_keyword() {
+                Some(keyword) if terminators.contains(&keyword) => return Ok(list),
+                _ => {}
+            }
+            if self.peek().is_none() {
+                return if terminators.is_empty() {
+                    Ok(list)
+                } else {
+                    Err(ParseError::Incomplete)
+                };
+            }
+
+            let mut and_or = self.parse_and_or()?;
+            if let Some(Token::Background) = self.peek() {
+                and_or.background = true;
+                self.pos += 1;
+                list.push(and_or);
+                continue;
+            }
+            list.push(and_or);
+
+            match self.peek() {
+                None | Some(Token::Separator) | Some(Token::Newline) => {}
+                Some(token) => {
+                    return Err(ParseError::Syntax(format!(
+                        "unexpected '{}'",
+                        token_text(token)
+                    )))
+                }
+            }
+        }
+    }
+
+    fn parse_and_or(&mut self) -> Result<AndOr, ParseError> {
+        let first = self.parse_command()?;
+        let mut rest = Vec::new();
+        loop {
+            let connector = match self.peek() {
+                Some(Token::And) => Connector::And,
+                Some(Token::Or) => Connector::Or,
+                _ => break,
+            };
+            self.pos += 1;
+            self.skip_newlines();
+            if self.peek().is_none() {
+                return Err(ParseError::Incomplete);
+            }
+            rest.push((connector, self.parse_command()?));
+        }
+        Ok(AndOr {
+            first,
+            rest,
+            background: false,
+        })
+    }
+
+    fn parse_command(&mut self) -> Result<Command, ParseError> {
+        match self.peek_keyword() {
+            Some("if") => return self.parse_if(),
+            Some("for") => return self.parse_for(),
+            Some("while") => return self.parse_while(),
+            Some(keyword) => {
+                return Err(ParseError::Syntax(format!("unexpected '{}'", keyword)));
+            }
+            None => {}
+        }
+
+        let mut words = Vec::new();
+        while let Some(Token::Word(word)) = self.peek() {
+            words.push(word.clone());
+            self.pos += 1;
+        }
+        if words.is_empty() {
+            return match self.peek() {
+                Some(token) => Err(ParseError::Syntax(format!(
+                    "unexpected '{}'",
+                    token_text(token)
+                ))),
+                None => Err(ParseError::Incomplete),
+            };
+        }
+        Ok(Command::Simple(words))
+    }
+
+    fn parse_if(&mut self) -> Result<Command, ParseError> {
+        self.expect_keyword("if")?;
+        let mut branches = Vec::new();
+        let mut otherwise = None;
+
+        loop {
+            let condition = self.parse_list(&["then"])?;
+            self.expect_keyword("then")?;
+            let body = self.parse_list(&["elif", "else", "fi"])?;
+            branches.push((condition, body));
+
+            match self.peek_keyword() {
+                Some("elif") => {
+                    self.pos += 1;
+                }
+                Some("else") => {
+                    self.pos += 1;
+                    otherwise = Some(self.parse_list(&["fi"])?);
+                    self.expect_keyword("fi")?;
+                    break;
+                }
+                _ => {
+                    self.expect_keyword("fi")?;
+                    break;
+                }
+            }
+        }
+
+        Ok(Command::If {
+            branches,
+            otherwise,
+        })
+    }
+
+    fn parse_for(&mut self) -> Result<Command, ParseError> {
+        self.expect_keyword("for")?;
+        let var = match self.next() {
+            Some(Token::Word(word)) if is_valid_name(&word) => word,
+            Some(token) => {
+                return Err(ParseError::Syntax(format!(
+                    "bad for loop variable '{}'",
+                    token_text(&token)
+                )))
+            }
+            None => return Err(ParseError::Incomplete),
+        };
+        self.skip_newlines();
+        self.expect_keyword("in")?;
+
+        let mut items = Vec::new();
+        while let Some(Token::Word(word)) = self.peek() {
+            items.push(word.clone());
+            self.pos += 1;
+        }
+        self.skip_separators();
+        self.expect_keyword("do")?;
+        let body = self.parse_list(&["done"])?;
+        self.expect_keyword("done")?;
+
+        Ok(Command::For { var, items, body })
+    }
+
+    fn parse_while(&mut self) -> Result<Command, ParseError> {
+        self.expect_keyword("while")?;
+        let condition = self.parse_list(&["do"])?;
+        self.expect_keyword("do")?;
+        let body = self.parse_list(&["done"])?;
+        self.expect_keyword("done")?;
+
+        Ok(Command::While { condition, body })
+    }
+}
+
+/// Expands a raw word into zero or more arguments.
+///
+/// Handles `'single'` and `"double"` quotes, backslash escapes, `$NAME`,
+/// `${NAME}` and the special `$?`, `$#` and `$0`-`$9` parameters (which are
+/// looked up through `lookup` like any other variable). Unquoted expansions are
+/// split on whitespace, so `for x in $LIST` works the way you'd expect.
+pub fn expand_word(raw: &str, lookup: &dyn Fn(&str) -> Option<String>) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut current = String::new();
+    // a quoted empty string (`""`) still counts as an argument
+    let mut has_current = false;
+    let mut chars = raw.chars().peekable();
+    let mut in_double = false;
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' if !in_double => {
+                has_current = true;
+                for q in chars.by_ref() {
+                    if q == '\'' {
+                        break;
+                    }
+                    current.push(q);
+                }
+            }
+            '"' => {
+                has_current = true;
+                in_double = !in_double;
+            }
+            '\\' => {
+                if let Some(escaped) = chars.next() {
+                    current.push(escaped);
+                    has_current = true;
+                }
+            }
+            '$' => {
+                let name = match chars.peek() {
+                    Some('{') => {
+                        chars.next();
+                        let mut name = String::new();
+                        for n in chars.by_ref() {
+                            if n == '}' {
+                                break;
+                            }
+                            name.push(n);
+                        }
+                        name
+                    }
+                    Some(&special)
+                        if special == '?' || special == '#' || special.is_ascii_digit() =>
+                    {
+                        chars.next();
+                        special.to_string()
+                    }
+                    _ => {
+                        let mut name = String::new();
+                        while let Some(&n) = chars.peek() {
+                            if n == '_' || n.is_ascii_alphanumeric() {
+                                name.push(n);
+                                chars.next();
+                            } else {
+                                break;
+                            }
+                        }
+                        name
+                    }
+                };
+
+                if name.is_empty() {
+                    // a lone `$` is just a dollar sign
+                    current.push('$');
+                    has_current = true;
+                    continue;
+                }
+
+                let value = lookup(&name).unwrap_or_default();
+                if in_double {
+                    current.push_str(&value);
+                    has_current = true;
+                } else {
+                    // split unquoted expansions into separate fields
+                    let mut pieces = value.split_whitespace().peekable();
+                    if value.starts_with(char::is_whitespace) && has_current {
+                        fields.push(core::mem::take(&mut current));
+                        has_current = false;
+                    }
+                    while let Some(piece) = pieces.next() {
+                        current.push_str(piece);
+                        has_current = true;
+                        if pieces.peek().is_some() {
+                            fields.push(core::mem::take(&mut current));
+                        }
+                    }
+                    if value.ends_with(char::is_whitespace) && has_current {
+                        fields.push(core::mem::take(&mut current));
+                        has_current = false;
+                    }
+                }
+            }
+            c => {
+                current.push(c);
+                has_current = true;
+            }
+        }
+    }
+    if has_current {
+        fields.push(current);
+    }
+
+    fields
+}
+
+/// Helper for the parser tests, builds a one-command and-or list.
+#[cfg(test)]
+fn simple(words: &[&str]) -> AndOr {
+    AndOr {
+        first: Command::Simple(words.iter().map(|w| w.to_string()).collect()),
+        rest: Vec::new(),
+        background: false,
+    }
+}
+
+#[test_case]
+fn test_parse_and_or() {
+    let script = parse("true && echo yes || echo no; echo done").unwrap();
+    assert_eq!(script.len(), 2);
+    assert_eq!(script[0].rest.len(), 2);
+    assert_eq!(script[0].rest[0].0, Connector::And);
+    assert_eq!(script[0].rest[1].0, Connector::Or);
+    assert_eq!(script[1], simple(&["echo", "done"]));
+}
+
+#[test_case]
+fn test_parse_compound() {
+    let script =
+        parse("for x in a b; do\nif test $x = a; then echo fi; else echo $x; fi\ndone").unwrap();
+    match &script[0].first {
+        Command::For { var, items, body } => {
+            assert_eq!(var, "x");
+            assert_eq!(items.len(), 2);
+            match &body[0].first {
+                Command::If {
+                    branches,
+                    otherwise,
+                } => {
+                    assert_eq!(branches[0].1[0], simple(&["echo", "fi"]));
+                    assert!(otherwise.is_some());
+                }
+                other => panic!("expected if, got {:?}", other),
+            }
+        }
+        other => panic!("expected for, got {:?}", other),
+    }
+    assert_eq!(parse("while true; do echo"), Err(ParseError::Incomplete));
+    assert_eq!(parse("echo 'unterminated"), Err(ParseError::Incomplete));
+}
+
+#[test_case]
+fn test_parse_background() {
+    let script = parse("pci -l & echo started").unwrap();
+    assert_eq!(script.len(), 2);
+    assert!(script[0].background);
+    assert!(!script[1].background);
+    assert_eq!(script[0].to_string(), "pci -l");
+
+    let script = parse("while true; do echo $x; done &").unwrap();
+    assert!(script[0].background);
+    assert_eq!(script[0].to_string(), "while true; do echo $x; done");
+    assert!(parse("& echo").is_err());
+}
+
+#[test_case]
+fn test_expand_word() {
+    let lookup = |name: &str| match name {
+        "A" => Some("one two".to_string()),
+        "?" => Some("0".to_string()),
+        _ => None,
+    };
+    assert_eq!(expand_word("$A", &lookup), ["one", "two"]);
+    assert_eq!(expand_word("\"$A\"", &lookup), ["one two"]);
+    assert_eq!(expand_word("'$A'", &lookup), ["$A"]);
+    assert_eq!(expand_word("x${A}y", &lookup), ["xone", "twoy"]);
+    assert_eq!(expand_word("status=$?", &lookup), ["status=0"]);
+    assert_eq!(expand_word("$MISSING", &lookup), Vec::<String>::new());
+    assert_eq!(expand_word("\"\"", &lookup), [""]);
+}
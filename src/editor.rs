@@ -0,0 +1,594 @@
+//! A small nano-style text editor, running on the VGA text buffer.
+//!
+//! SHSH's `edit FILE` opens it. Files are read and saved through the VFS, so
+//! anything in there (like `/etc/shshrc`) can be changed without rebooting.
+
+use crate::{
+    fs::vfs::{self, FsError},
+    task::keyboard::{self, dispatch::KeyEventStream},
+    vga_buffer::{console, Color, SavedScreen, Writer},
+};
+use alloc::{
+    boxed::Box,
+    format,
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+use futures_util::stream::StreamExt;
+use pc_keyboard::{DecodedKey, KeyCode};
+use x86_64::instructions::interrupts;
+
+/// How many columns a tab takes up on screen.
+const TAB_WIDTH: usize = 4;
+/// The title bar at the top, and the status and shortcut lines at the bottom.
+const RESERVED_ROWS: usize = 3;
+
+const TEXT_COLORS: (Color, Color) = (Color::LightGray, Color::Black);
+const BAR_COLORS: (Color, Color) = (Color::Black, Color::LightGray);
+
+// what Ctrl+letter comes out as from `KeyPress::control_mapped`
+const CTRL_A: char = '\u{01}';
+const CTRL_C: char = '\u{03}';
+const CTRL_E: char = '\u{05}';
+const CTRL_K: char = '\u{0b}';
+const CTRL_O: char = '\u{0f}';
+const CTRL_S: char = '\u{13}';
+const CTRL_U: char = '\u{15}';
+const CTRL_W: char = '\u{17}';
+const CTRL_X: char = '\u{18}';
+const BACKSPACE: char = '\u{08}';
+const ESCAPE: char = '\u{1b}';
+const DELETE: char = '\u{7f}';
+
+const SHORTCUTS: &[(&str, &str)] = &[
+    ("^O", "Save"),
+    ("^X", "Exit"),
+    ("^W", "Search"),
+    ("^K", "Cut"),
+    ("^U", "Paste"),
+    ("^A", "Home"),
+    ("^E", "End"),
+];
+
+/// Opens `path` in the editor and returns once the user exits.
+pub async fn edit(path: &str) -> Result<(), String> {
+    let mut editor = Editor::open(path)?;
+    let _screen = FullScreen::enter();
+    let mut keys = KeyEventStream::new();
+
+    editor.draw();
+    while editor.running {
+        let press = match keys.next().await {
+            Some(press) => press,
+            None => break,
+        };
+        editor.handle_key(press.control_mapped());
+        editor.draw();
+    }
+    Ok(())
+}
+
+/// Takes over the screen and Ctrl-C while the editor runs. Dropping it puts
+/// both back, even if the editor's task gets killed.
+struct FullScreen {
+    saved: Box<SavedScreen>,
+}
+
+impl FullScreen {
+    fn enter() -> Self {
+        keyboard::set_ctrl_c_interrupts(false);
+        let saved = interrupts::without_interrupts(|| Box::new(console::writer().save_screen()));
+        FullScreen { saved }
+    }
+}
+
+impl Drop for FullScreen {
+    fn drop(&mut self) {
+        interrupts::without_interrupts(|| console::writer().restore_screen(&self.saved));
+        keyboard::set_ctrl_c_interrupts(true);
+    }
+}
+
+/// What the status line is asking for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PromptKind {
+    Search,
+    ConfirmExit,
+}
+
+struct Prompt {
+    kind: PromptKind,
+    input: String,
+}
+
+struct Editor {
+    path: String,
+    lines: Vec<Vec<char>>,
+    /// Whether the file ended with a newline, so saving doesn't change that.
+    trailing_newline: bool,
+    /// The cursor, as a line and a character index into it.
+    row: usize,
+    col: usize,
+    /// The first line and the first (display) column on screen.
+    top: usize,
+    left: usize,
+    modified: bool,
+    message: String,
+    prompt: Option<Prompt>,
+    cut_buffer: Vec<Vec<char>>,
+    /// Cutting several lines in a row adds them all to `cut_buffer`, like nano.
+    last_was_cut: bool,
+    last_search: String,
+    running: bool,
+}
+
+impl Editor {
+    fn open(path: &str) -> Result<Self, String> {
+        let (text, message) = match vfs::read(path) {
+            Ok(contents) => match String::from_utf8(contents) {
+                Ok(text) => {
+                    let lines = text.lines().count();
+                    (text, format!("Read {} lines", lines))
+                }
+                Err(_) => return Err("not a text file".to_string()),
+            },
+            Err(FsError::NotFound) => (String::new(), "New file".to_string()),
+            Err(e) => return Err(e.to_string()),
+        };
+        let mut editor = Editor::from_text(path, &text);
+        editor.message = message;
+        Ok(editor)
+    }
+
+    fn from_text(path: &str, text: &str) -> Self {
+        let trailing_newline = text.is_empty() || text.ends_with('\n');
+        let text = text.strip_suffix('\n').unwrap_or(text);
+        let lines = text
+            .split('\n')
+            .map(|line| line.chars().collect())
+            .collect();
+        Editor {
+            path: path.to_string(),
+            lines,
+            trailing_newline,
+            row: 0,
+            col: 0,
+            top: 0,
+            left: 0,
+            modified: false,
+            message: String::new(),
+            prompt: None,
+            cut_buffer: Vec::new(),
+            last_was_cut: false,
+            last_search: String::new(),
+            running: true,
+        }
+    }
+
+    fn text(&self) -> String {
+        let mut text = String::new();
+        for (i, line) in self.lines.iter().enumerate() {
+            if i > 0 {
+                text.push('\n');
+            }
+            text.extend(line.iter());
+        }
+        if self.trailing_newline {
+            text.push('\n');
+        }
+        text
+    }
+
+    fn save(&mut self) -> bool {
+        match vfs::write(&self.path, self.text().as_bytes()) {
+            Ok(()) => {
+                self.modified = false;
+                self.message = format!("Wrote {} lines", self.lines.len());
+                true
+            }
+            Err(e) => {
+                self.message = format!("Error writing {}: {}", self.path, e);
+                false
+            }
+        }
+    }
+
+    fn handle_key(&mut self, key: DecodedKey) {
+        if self.prompt.is_some() {
+            self.handle_prompt_key(key);
+            return;
+        }
+
+        self.message.clear();
+        let was_cut = self.last_was_cut;
+        self.last_was_cut = false;
+        match key {
+            DecodedKey::Unicode(c) => match c {
+                '\n' => self.insert_newline(),
+                BACKSPACE => self.backspace(),
+                DELETE => self.delete(),
+                CTRL_O | CTRL_S => {
+                    self.save();
+                }
+                CTRL_X => {
+                    if self.modified {
+                        self.prompt = Some(Prompt {
+                            kind: PromptKind::ConfirmExit,
+                            input: String::new(),
+                        });
+                    } else {
+                        self.running = false;
+                    }
+                }
+                CTRL_W => {
+                    self.prompt = Some(Prompt {
+                        kind: PromptKind::Search,
+                        input: String::new(),
+                    })
+                }
+                CTRL_K => {
+                    self.cut_line(was_cut);
+                    self.last_was_cut = true;
+                }
+                CTRL_U => self.paste(),
+                CTRL_A => self.col = 0,
+                CTRL_E => self.col = self.lines[self.row].len(),
+                '\t' => self.insert_char('\t'),
+                c if c.is_control() => {}
+                c => self.insert_char(c),
+            },
+            DecodedKey::RawKey(code) => {
+                let page = self.text_rows().saturating_sub(1).max(1);
+                match code {
+                    KeyCode::ArrowLeft => self.move_left(),
+                    KeyCode::ArrowRight => self.move_right(),
+                    KeyCode::ArrowUp => self.move_vertically(-1),
+                    KeyCode::ArrowDown => self.move_vertically(1),
+                    KeyCode::PageUp => self.move_vertically(-(page as isize)),
+                    KeyCode::PageDown => self.move_vertically(page as isize),
+                    KeyCode::Home => self.col = 0,
+                    KeyCode::End => self.col = self.lines[self.row].len(),
+                    KeyCode::Delete => self.delete(),
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    fn handle_prompt_key(&mut self, key: DecodedKey) {
+        let c = match key {
+            DecodedKey::Unicode(c) => c,
+            DecodedKey::RawKey(_) => return,
+        };
+        let prompt = self.prompt.as_mut().unwrap();
+        if c == CTRL_C || c == ESCAPE {
+            self.prompt = None;
+            self.message = "Cancelled".to_string();
+            return;
+        }
+
+        match prompt.kind {
+            PromptKind::ConfirmExit => match c {
+                'y' | 'Y' => {
+                    self.prompt = None;
+                    if self.save() {
+                        self.running = false;
+                    }
+                }
+                'n' | 'N' => self.running = false,
+                _ => {}
+            },
+            PromptKind::Search => match c {
+                '\n' => {
+                    let input = core::mem::take(&mut prompt.input);
+                    self.prompt = None;
+                    if !input.is_empty() {
+                        self.last_search = input;
+                    }
+                    self.search();
+                }
+                BACKSPACE => {
+                    prompt.input.pop();
+                }
+                c if c.is_control() => {}
+                c => prompt.input.push(c),
+            },
+        }
+    }
+
+    fn insert_char(&mut self, c: char) {
+        self.lines[self.row].insert(self.col, c);
+        self.col += 1;
+        self.modified = true;
+    }
+
+    fn insert_newline(&mut self) {
+        let rest = self.lines[self.row].split_off(self.col);
+        self.lines.insert(self.row + 1, rest);
+        self.row += 1;
+        self.col = 0;
+        self.modified = true;
+    }
+
+    fn backspace(&mut self) {
+        if self.col > 0 {
+            self.col -= 1;
+            self.lines[self.row].remove(self.col);
+            self.modified = true;
+        } else if self.row > 0 {
+            let line = self.lines.remove(self.row);
+            self.row -= 1;
+            self.col = self.lines[self.row].len();
+            self.lines[self.row].extend(line);
+            self.modified = true;
+        }
+    }
+
+    fn delete(&mut self) {
+        if self.col < self.lines[self.row].len() {
+            self.lines[self.row].remove(self.col);
+            self.modified = true;
+        } else if self.row + 1 < self.lines.len() {
+            let next = self.lines.remove(self.row + 1);
+            self.lines[self.row].extend(next);
+            self.modified = true;
+        }
+    }
+
+    /// Cuts the current line. Cuts straight after another cut add to the cut buffer.
+    fn cut_line(&mut self, append: bool) {
+        if !append {
+            self.cut_buffer.clear();
+        }
+        if self.lines.len() == 1 {
+            self.cut_buffer.push(core::mem::take(&mut self.lines[0]));
+        } else {
+            self.cut_buffer.push(self.lines.remove(self.row));
+            self.row = self.row.min(self.lines.len() - 1);
+        }
+        self.col = 0;
+        self.modified = true;
+    }
+
+    /// Pastes the cut buffer above the current line.
+    fn paste(&mut self) {
+        if self.cut_buffer.is_empty() {
+            return;
+        }
+        for (i, line) in self.cut_buffer.iter().enumerate() {
+            self.lines.insert(self.row + i, line.clone());
+        }
+        self.row += self.cut_buffer.len();
+        self.col = 0;
+        self.modified = true;
+    }
+
+    fn move_left(&mut self) {
+        if self.col > 0 {
+            self.col -= 1;
+        } else if self.row > 0 {
+            self.row -= 1;
+            self.col = self.lines[self.row].len();
+        }
+    }
+
+    fn move_right(&mut self) {
+        if self.col < self.lines[self.row].len() {
+            self.col += 1;
+        } else if self.row + 1 < self.lines.len() {
+            self.row += 1;
+            self.col = 0;
+        }
+    }
+
+    fn move_vertically(&mut self, lines: isize) {
+        let row = self.row as isize + lines;
+        self.row = row.max(0).min(self.lines.len() as isize - 1) as usize;
+        self.col = self.col.min(self.lines[self.row].len());
+    }
+
+    /// Moves the cursor to the next match of `last_search`, wrapping around.
+    fn search(&mut self) {
+        if self.last_search.is_empty() {
+            self.message = "Nothing to search for".to_string();
+            return;
+        }
+        let query: Vec<char> = self.last_search.chars().collect();
+        match self.find(&query) {
+            Some((row, col)) => {
+                if (row, col) == (self.row, self.col) {
+                    self.message = "This is the only occurrence".to_string();
+                }
+                self.row = row;
+                self.col = col;
+            }
+            None => self.message = format!("\"{}\" not found", self.last_search),
+        }
+    }
+
+    /// Finds the first match of `query` after the cursor, wrapping back to the top.
+    fn find(&self, query: &[char]) -> Option<(usize, usize)> {
+        let count = self.lines.len();
+        for i in 0..=count {
+            let row = (self.row + i) % count;
+            let from = if i == 0 { self.col + 1 } else { 0 };
+            if let Some(col) = find_in_line(&self.lines[row], query, from) {
+                return Some((row, col));
+            }
+        }
+        None
+    }
+
+    fn text_rows(&self) -> usize {
+        Writer::get_buffer_height() - RESERVED_ROWS
+    }
+
+    /// Scrolls so the cursor is on screen.
+    fn scroll(&mut self) {
+        let rows = self.text_rows();
+        let width = Writer::get_buffer_width();
+        if self.row < self.top {
+            self.top = self.row;
+        } else if self.row >= self.top + rows {
+            self.top = self.row + 1 - rows;
+        }
+
+        let col = display_column(&self.lines[self.row], self.col);
+        if col < self.left {
+            self.left = col;
+        } else if col >= self.left + width {
+            self.left = col + 1 - width;
+        }
+    }
+
+    fn draw(&mut self) {
+        self.scroll();
+        interrupts::without_interrupts(|| self.render(&mut console::writer()));
+    }
+
+    fn render(&self, writer: &mut Writer) {
+        let width = Writer::get_buffer_width();
+        let height = Writer::get_buffer_height();
+        let (text_fg, text_bg) = TEXT_COLORS;
+        let (bar_fg, bar_bg) = BAR_COLORS;
+
+        // title bar
+        let title = format!("  SHSH edit    {}", self.path);
+        let title = if self.modified {
+            let flag = "Modified  ";
+            pad(&title, width - flag.len()) + flag
+        } else {
+            pad(&title, width)
+        };
+        writer.write_at(0, 0, &title, bar_fg, bar_bg);
+
+        // the file itself
+        for screen_row in 0..self.text_rows() {
+            let line = match self.lines.get(self.top + screen_row) {
+                Some(line) => visible_part(line, self.left, width),
+                None => String::new(),
+            };
+            writer.write_at(1 + screen_row, 0, &pad(&line, width), text_fg, text_bg);
+        }
+
+        // status line, or the prompt
+        let status_row = height - 2;
+        let mut cursor = (
+            1 + self.row - self.top,
+            display_column(&self.lines[self.row], self.col) - self.left,
+        );
+        match &self.prompt {
+            Some(prompt) => {
+                let text = match prompt.kind {
+                    PromptKind::Search if self.last_search.is_empty() => {
+                        format!("Search: {}", prompt.input)
+                    }
+                    PromptKind::Search => {
+                        format!("Search [{}]: {}", self.last_search, prompt.input)
+                    }
+                    PromptKind::ConfirmExit => {
+                        "Save modified buffer? (Y)es, (N)o, (^C) Cancel ".to_string()
+                    }
+                };
+                cursor = (status_row, text.len().min(width - 1));
+                writer.write_at(status_row, 0, &pad(&text, width), bar_fg, bar_bg);
+            }
+            None if self.message.is_empty() => {
+                writer.write_at(status_row, 0, &pad("", width), text_fg, text_bg);
+            }
+            None => {
+                writer.write_at(status_row, 0, &pad("", width), text_fg, text_bg);
+                let message = format!("[ {} ]", self.message);
+                let col = width.saturating_sub(message.len()) / 2;
+                writer.write_at(status_row, col, &message, bar_fg, bar_bg);
+            }
+        }
+
+        // shortcuts
+        writer.write_at(height - 1, 0, &pad("", width), text_fg, text_bg);
+        let mut col = 0;
+        for (keys, action) in SHORTCUTS {
+            writer.write_at(height - 1, col, keys, bar_fg, bar_bg);
+            writer.write_at(height - 1, col + keys.len() + 1, action, text_fg, text_bg);
+            col += keys.len() + 1 + action.len() + 2;
+        }
+
+        let (row, col) = cursor;
+        writer.set_position(row, col);
+    }
+}
+
+/// Returns the screen column character `index` of `line` starts at, with tabs expanded.
+fn display_column(line: &[char], index: usize) -> usize {
+    line[..index.min(line.len())]
+        .iter()
+        .fold(0, |col, &c| col + char_width(c, col))
+}
+
+fn char_width(c: char, col: usize) -> usize {
+    if c == '\t' {
+        TAB_WIDTH - col % TAB_WIDTH
+    } else {
+        1
+    }
+}
+
+/// Expands tabs in `line` and returns the `width` columns starting at `left`.
+fn visible_part(line: &[char], left: usize, width: usize) -> String {
+    let mut expanded = String::new();
+    let mut col = 0;
+    for &c in line {
+        let char_width = char_width(c, col);
+        for i in 0..char_width {
+            if col + i >= left && col + i < left + width {
+                expanded.push(if c == '\t' { ' ' } else { c });
+            }
+        }
+        col += char_width;
+        if col >= left + width {
+            break;
+        }
+    }
+    expanded
+}
+
+/// Pads (or cuts) `s` to exactly `width` characters.
+fn pad(s: &str, width: usize) -> String {
+    let mut padded: String = s.chars().take(width).collect();
+    let len = padded.chars().count();
+    padded.extend(vec![' '; width - len]);
+    padded
+}
+
+fn find_in_line(line: &[char], query: &[char], from: usize) -> Option<usize> {
+    if query.is_empty() || query.len() > line.len() {
+        return None;
+    }
+    (from..=line.len() - query.len()).find(|&i| line[i..].starts_with(query))
+}
+
+#[test_case]
+fn test_editor_editing() {
+    let mut editor = Editor::from_text("/tmp/test", "one\ntwo\n");
+    assert_eq!(editor.lines.len(), 2);
+
+    editor.handle_key(DecodedKey::RawKey(KeyCode::End));
+    editor.handle_key(DecodedKey::Unicode('!'));
+    editor.handle_key(DecodedKey::Unicode('\n'));
+    editor.handle_key(DecodedKey::Unicode('x'));
+    assert_eq!(editor.text(), "one!\nx\ntwo\n");
+    assert!(editor.modified);
+
+    editor.handle_key(DecodedKey::Unicode(BACKSPACE));
+    editor.handle_key(DecodedKey::Unicode(BACKSPACE));
+    assert_eq!(editor.text(), "one!\ntwo\n");
+
+    editor.handle_key(DecodedKey::RawKey(KeyCode::ArrowDown));
+    editor.handle_key(DecodedKey::Unicode(CTRL_K));
+    editor.handle_key(DecodedKey::Unicode(CTRL_U));
+    assert_eq!(editor.text(), "two\none!\n");
+
+    assert_eq!(editor.find(&['t', 'w']), Some((0, 0)));
+    assert_eq!(display_column(&['\t', 'a'], 2), TAB_WIDTH + 1);
+}
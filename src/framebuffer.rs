@@ -0,0 +1,144 @@
+//! Linear framebuffer graphics.
+//!
+//! The bootloader we use (0.9) leaves the screen in VGA text mode and doesn't
+//! hand us a framebuffer, so `bga` sets a graphics mode itself through the
+//! Bochs/QEMU display adapter (`-vga std`). `console` draws text on top of it.
+
+use crate::memory;
+use x86_64::{PhysAddr, VirtAddr};
+
+pub mod bga;
+pub mod console;
+pub mod psf;
+
+/// The 16 VGA text colors as `0xRRGGBB`, in `vga_buffer::Color` order.
+pub const PALETTE: [u32; 16] = [
+    0x000000, 0x0000aa, 0x00aa00, 0x00aaaa, 0xaa0000, 0xaa00aa, 0xaa5500, 0xaaaaaa, //
+    0x555555, 0x5555ff, 0x55ff55, 0x55ffff, 0xff5555, 0xff55ff, 0xffff55, 0xffffff,
+];
+
+/// A 32 bits per pixel framebuffer. Pixels are `0xRRGGBB`.
+pub struct Framebuffer {
+    pixels: &'static mut [u32],
+    width: usize,
+    height: usize,
+    /// Pixels from the start of one row to the start of the next.
+    stride: usize,
+}
+
+impl Framebuffer {
+    /// Wraps the framebuffer at physical address `phys`, checking it's mapped.
+    ///
+    /// This function is unsafe because the caller must guarantee that there's
+    /// a `stride * height` pixel framebuffer at `phys`, and that nothing else
+    /// is using it.
+    pub unsafe fn from_phys(
+        phys: PhysAddr,
+        width: usize,
+        height: usize,
+        stride: usize,
+    ) -> Result<Framebuffer, &'static str> {
+        let len = stride * height;
+        let virt = memory::phys_to_virt(phys).ok_or("the framebuffer isn't mapped")?;
+        memory::check_range(virt, (len * 4) as u64, true)
+            .map_err(|_| "the framebuffer isn't mapped (all of it)")?;
+        Ok(Framebuffer::from_virt(virt, width, height, stride))
+    }
+
+    /// Like `from_phys`, for a framebuffer that's already mapped at `virt`.
+    pub unsafe fn from_virt(
+        virt: VirtAddr,
+        width: usize,
+        height: usize,
+        stride: usize,
+    ) -> Framebuffer {
+        Framebuffer {
+            pixels: core::slice::from_raw_parts_mut(virt.as_mut_ptr(), stride * height),
+            width,
+            height,
+            stride,
+        }
+    }
+
+    /// Wraps pixels in memory, `width` to a row.
+    pub fn from_slice(pixels: &'static mut [u32], width: usize, height: usize) -> Framebuffer {
+        assert!(pixels.len() >= width * height, "not enough pixels");
+        Framebuffer {
+            pixels,
+            width,
+            height,
+            stride: width,
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn stride(&self) -> usize {
+        self.stride
+    }
+
+    /// Where the first pixel is.
+    pub fn address(&self) -> VirtAddr {
+        VirtAddr::from_ptr(self.pixels.as_ptr())
+    }
+
+    /// The pixels of row `y`.
+    pub fn row(&self, y: usize) -> &[u32] {
+        &self.pixels[y * self.stride..y * self.stride + self.width]
+    }
+
+    pub fn row_mut(&mut self, y: usize) -> &mut [u32] {
+        &mut self.pixels[y * self.stride..y * self.stride + self.width]
+    }
+
+    pub fn put_pixel(&mut self, x: usize, y: usize, color: u32) {
+        if x < self.width && y < self.height {
+            self.pixels[y * self.stride + x] = color;
+        }
+    }
+
+    pub fn get_pixel(&self, x: usize, y: usize) -> Option<u32> {
+        if x < self.width && y < self.height {
+            Some(self.pixels[y * self.stride + x])
+        } else {
+            None
+        }
+    }
+
+    /// Fills a rectangle, cut off at the edges of the screen.
+    pub fn fill_rect(&mut self, x: usize, y: usize, width: usize, height: usize, color: u32) {
+        let x_end = (x + width).min(self.width);
+        for y in y..(y + height).min(self.height) {
+            let row = y * self.stride;
+            for pixel in &mut self.pixels[row + x.min(x_end)..row + x_end] {
+                *pixel = color;
+            }
+        }
+    }
+
+    /// Copies `src` into row `y`, starting at column `x`.
+    pub fn write_row(&mut self, x: usize, y: usize, src: &[u32]) {
+        if y >= self.height || x >= self.width {
+            return;
+        }
+        let len = src.len().min(self.width - x);
+        let start = y * self.stride + x;
+        self.pixels[start..start + len].copy_from_slice(&src[..len]);
+    }
+
+    /// Moves everything up `lines` pixels, filling the bottom with `fill`.
+    pub fn scroll_up(&mut self, lines: usize, fill: u32) {
+        let lines = lines.min(self.height);
+        let moved = (self.height - lines) * self.stride;
+        self.pixels
+            .copy_within(lines * self.stride..lines * self.stride + moved, 0);
+        let width = self.width;
+        self.fill_rect(0, self.height - lines, width, lines, fill);
+    }
+}
@@ -0,0 +1,118 @@
+//! The Bochs Graphics Adapter, which is what QEMU's `-vga std` and Bochs
+//! emulate. Modes are set through two I/O ports, and the framebuffer is
+//! BAR 0 of its PCI device.
+
+use super::Framebuffer;
+use crate::pci;
+use alloc::{format, string::String};
+use x86_64::{instructions::port::Port, PhysAddr};
+
+const INDEX_PORT: u16 = 0x01CE;
+const DATA_PORT: u16 = 0x01CF;
+
+const REGISTER_ID: u16 = 0;
+const REGISTER_XRES: u16 = 1;
+const REGISTER_YRES: u16 = 2;
+const REGISTER_BPP: u16 = 3;
+const REGISTER_ENABLE: u16 = 4;
+const REGISTER_VIRT_WIDTH: u16 = 6;
+/// How much video memory there is, in 64 KiB units. Only QEMU has this one.
+const REGISTER_VIDEO_MEMORY_64K: u16 = 0x0A;
+
+/// `REGISTER_ID` reads back one of these on a BGA.
+const ID_MIN: u16 = 0xB0C0;
+const ID_MAX: u16 = 0xB0C5;
+
+const ENABLED: u16 = 0x01;
+/// Use the linear framebuffer instead of banks at 0xA0000.
+const LFB_ENABLED: u16 = 0x40;
+
+/// The BGA's PCI vendor and device ID.
+const VENDOR_ID: u32 = 0x1234;
+const DEVICE_ID: u32 = 0x1111;
+
+/// How much video memory to assume if the adapter won't say.
+const DEFAULT_VIDEO_MEMORY: usize = 8 * 1024 * 1024;
+
+/// The biggest mode the BGA does.
+pub const MAX_WIDTH: usize = 1600;
+pub const MAX_HEIGHT: usize = 1200;
+
+fn read_register(register: u16) -> u16 {
+    unsafe {
+        Port::new(INDEX_PORT).write(register);
+        Port::new(DATA_PORT).read()
+    }
+}
+
+fn write_register(register: u16, value: u16) {
+    unsafe {
+        Port::new(INDEX_PORT).write(register);
+        Port::new(DATA_PORT).write(value);
+    }
+}
+
+/// Returns `true` if there's a BGA.
+pub fn is_available() -> bool {
+    (ID_MIN..=ID_MAX).contains(&read_register(REGISTER_ID))
+}
+
+/// Finds where the framebuffer is, from the BGA's PCI device.
+fn framebuffer_address() -> Option<PhysAddr> {
+    let device = pci::scan_pci_bus()
+        .into_iter()
+        .find(|device| device.vendor_id == VENDOR_ID && device.device_id == DEVICE_ID)?;
+    // BAR 0, minus the flags in the low bits
+    let bar = pci::read_pci(0x10, &device) & !0xF;
+    if bar == 0 {
+        None
+    } else {
+        Some(PhysAddr::new(u64::from(bar)))
+    }
+}
+
+/// Where the video memory starts and how many bytes of it there are. The
+/// framebuffer is at the start, and whatever the mode doesn't use is free for
+/// drawing off-screen.
+pub fn video_memory() -> Option<(PhysAddr, usize)> {
+    if !is_available() {
+        return None;
+    }
+    let size = match usize::from(read_register(REGISTER_VIDEO_MEMORY_64K)) {
+        0 => DEFAULT_VIDEO_MEMORY,
+        blocks => blocks * 64 * 1024,
+    };
+    Some((framebuffer_address()?, size))
+}
+
+/// Switches to a `width` by `height`, 32 bits per pixel mode.
+///
+/// There's no going back to text mode afterwards: the text mode font lives in
+/// the same video memory, and the framebuffer overwrites it.
+pub fn set_mode(width: usize, height: usize) -> Result<Framebuffer, String> {
+    if !is_available() {
+        return Err(String::from(
+            "no Bochs graphics adapter (try QEMU with -vga std)",
+        ));
+    }
+    if width == 0 || height == 0 || width > MAX_WIDTH || height > MAX_HEIGHT || width % 8 != 0 {
+        return Err(format!(
+            "{}x{} isn't a mode the adapter can do (up to {}x{}, width a multiple of 8)",
+            width, height, MAX_WIDTH, MAX_HEIGHT
+        ));
+    }
+    let address = framebuffer_address().ok_or("can't find the adapter's framebuffer")?;
+
+    write_register(REGISTER_ENABLE, 0);
+    write_register(REGISTER_XRES, width as u16);
+    write_register(REGISTER_YRES, height as u16);
+    write_register(REGISTER_BPP, 32);
+    write_register(REGISTER_ENABLE, ENABLED | LFB_ENABLED);
+
+    // the adapter might have padded the rows
+    let stride = usize::from(read_register(REGISTER_VIRT_WIDTH)).max(width);
+    unsafe { Framebuffer::from_phys(address, width, height, stride) }.map_err(|e| {
+        write_register(REGISTER_ENABLE, 0);
+        format!("{} (at {:#x})", e, address.as_u64())
+    })
+}
@@ -0,0 +1,322 @@
+//! A text console drawn onto the framebuffer, for graphics modes.
+//!
+//! Once it's enabled, `print!` output on the active virtual console goes here
+//! as well as to the console's text buffer, so it keeps working at any
+//! resolution. It understands the same escape sequences as the VGA writer
+//! (mostly: see `FramebufferConsole::csi`). Programs that draw on the text
+//! buffer directly, like the editor, only show up when switching consoles.
+
+use super::{bga, psf::Font, Framebuffer, PALETTE};
+use crate::vga_buffer::{
+    ansi::{Action, Csi, Parser, Style},
+    console, cp437, Writer, DEFAULT_STYLE,
+};
+use alloc::string::String;
+use core::fmt;
+use spin::Mutex;
+
+/// The framebuffer console, once `enable` has been called.
+static CONSOLE: Mutex<Option<FramebufferConsole>> = Mutex::new(None);
+
+pub struct FramebufferConsole {
+    framebuffer: Framebuffer,
+    font: Font,
+    columns: usize,
+    rows: usize,
+    column: usize,
+    row: usize,
+    style: Style,
+    parser: Parser,
+    saved_cursor: (usize, usize),
+    /// Whether the cursor is drawn right now, so it can be undrawn.
+    cursor_drawn: bool,
+}
+
+impl FramebufferConsole {
+    pub fn new(framebuffer: Framebuffer, font: Font) -> Self {
+        let columns = framebuffer.width() / font.width();
+        let rows = framebuffer.height() / font.height();
+        let mut console = FramebufferConsole {
+            framebuffer,
+            font,
+            columns,
+            rows,
+            column: 0,
+            row: 0,
+            style: DEFAULT_STYLE,
+            parser: Parser::new(),
+            saved_cursor: (0, 0),
+            cursor_drawn: false,
+        };
+        console.clear();
+        console
+    }
+
+    /// How many columns and rows of text fit on screen.
+    pub fn size(&self) -> (usize, usize) {
+        (self.columns, self.rows)
+    }
+
+    pub fn framebuffer(&self) -> &Framebuffer {
+        &self.framebuffer
+    }
+
+    /// Gives back the framebuffer, to reuse its memory.
+    pub fn into_framebuffer(self) -> Framebuffer {
+        self.framebuffer
+    }
+
+    fn colors(&self) -> (u32, u32) {
+        let (foreground, background) = self.style.colors();
+        (PALETTE[foreground as usize], PALETTE[background as usize])
+    }
+
+    pub fn write_string(&mut self, s: &str) {
+        self.toggle_cursor(false);
+        for c in s.chars() {
+            match self.parser.advance(c) {
+                Some(Action::Print(c)) => self.put_char(c),
+                Some(Action::Control(c)) => self.control(c),
+                Some(Action::Escape(c)) => self.escape(c),
+                Some(Action::Csi(csi)) => self.csi(&csi),
+                None => {}
+            }
+        }
+        self.toggle_cursor(true);
+    }
+
+    fn put_char(&mut self, c: char) {
+        if self.column >= self.columns {
+            self.new_line();
+        }
+        let (foreground, background) = self.colors();
+        self.draw_char(self.row, self.column, c, foreground, background);
+        self.column += 1;
+    }
+
+    fn draw_char(&mut self, row: usize, col: usize, c: char, foreground: u32, background: u32) {
+        let (width, height) = (self.font.width(), self.font.height());
+        let bytes_per_row = (width + 7) / 8;
+        let (x, y) = (col * width, row * height);
+        let mut line = [0; 32];
+        let line = &mut line[..width.min(32)];
+        for (glyph_row, bits) in self.font.glyph(c).chunks(bytes_per_row).enumerate() {
+            for (i, pixel) in line.iter_mut().enumerate() {
+                let set = bits[i / 8] & (0x80 >> (i % 8)) != 0;
+                *pixel = if set { foreground } else { background };
+            }
+            self.framebuffer.write_row(x, y + glyph_row, line);
+        }
+    }
+
+    fn control(&mut self, c: char) {
+        match c {
+            '\n' => self.new_line(),
+            '\r' => self.column = 0,
+            '\x08' => self.column = self.column.min(self.columns - 1).saturating_sub(1),
+            '\t' if self.column < self.columns => {
+                self.column = ((self.column / 8 + 1) * 8).min(self.columns - 1)
+            }
+            _ => {}
+        }
+    }
+
+    fn escape(&mut self, c: char) {
+        match c {
+            '7' => self.saved_cursor = (self.row, self.column),
+            '8' => {
+                let (row, col) = self.saved_cursor;
+                self.move_to(row, col);
+            }
+            'c' => {
+                self.style = DEFAULT_STYLE;
+                self.clear();
+            }
+            _ => {}
+        }
+    }
+
+    /// Handles the CSI sequences full-screen programs use most: moving the
+    /// cursor (`A`-`D`, `G`, `H`/`f`), erasing (`J`, `K`), colors (`m`) and
+    /// saving the cursor (`s`/`u`).
+    fn csi(&mut self, csi: &Csi) {
+        if csi.private {
+            return;
+        }
+        let n = usize::from(csi.param(0, 1));
+        let (row, col) = (self.row, self.column.min(self.columns - 1));
+        match csi.action {
+            'A' => self.move_to(row.saturating_sub(n), col),
+            'B' => self.move_to(row + n, col),
+            'C' => self.move_to(row, col + n),
+            'D' => self.move_to(row, col.saturating_sub(n)),
+            'G' => self.move_to(row, n - 1),
+            'H' | 'f' => self.move_to(n - 1, usize::from(csi.param(1, 1)) - 1),
+            'J' => match csi.param(0, 0) {
+                0 => {
+                    self.erase(row, col, self.columns);
+                    self.erase_rows(row + 1, self.rows);
+                }
+                1 => {
+                    self.erase_rows(0, row);
+                    self.erase(row, 0, col + 1);
+                }
+                _ => self.erase_rows(0, self.rows),
+            },
+            'K' => match csi.param(0, 0) {
+                0 => self.erase(row, col, self.columns),
+                1 => self.erase(row, 0, col + 1),
+                _ => self.erase(row, 0, self.columns),
+            },
+            'm' => self.style.apply_sgr(csi.params(), DEFAULT_STYLE),
+            's' => self.saved_cursor = (row, col),
+            'u' => {
+                let (row, col) = self.saved_cursor;
+                self.move_to(row, col);
+            }
+            _ => {}
+        }
+    }
+
+    fn move_to(&mut self, row: usize, col: usize) {
+        self.row = row.min(self.rows - 1);
+        self.column = col.min(self.columns - 1);
+    }
+
+    /// Blanks out columns `start..end` of `row`.
+    fn erase(&mut self, row: usize, start: usize, end: usize) {
+        let (_, background) = self.colors();
+        let (width, height) = (self.font.width(), self.font.height());
+        let end = end.min(self.columns);
+        if start < end {
+            self.framebuffer.fill_rect(
+                start * width,
+                row * height,
+                (end - start) * width,
+                height,
+                background,
+            );
+        }
+    }
+
+    fn erase_rows(&mut self, start: usize, end: usize) {
+        for row in start..end {
+            self.erase(row, 0, self.columns);
+        }
+    }
+
+    fn new_line(&mut self) {
+        self.column = 0;
+        if self.row < self.rows - 1 {
+            self.row += 1;
+        } else {
+            let (_, background) = self.colors();
+            self.framebuffer.scroll_up(self.font.height(), background);
+        }
+    }
+
+    /// Clears the screen and moves the cursor to the top left.
+    pub fn clear(&mut self) {
+        let (_, background) = self.colors();
+        let (width, height) = (self.framebuffer.width(), self.framebuffer.height());
+        self.framebuffer.fill_rect(0, 0, width, height, background);
+        self.row = 0;
+        self.column = 0;
+        self.cursor_drawn = false;
+    }
+
+    /// Draws or undraws the cursor, an underline inverting what's under it.
+    fn toggle_cursor(&mut self, draw: bool) {
+        if self.cursor_drawn == draw {
+            return;
+        }
+        self.cursor_drawn = draw;
+        let (width, height) = (self.font.width(), self.font.height());
+        let x = self.column.min(self.columns - 1) * width;
+        let y = self.row * height;
+        for y in y + height - 2..y + height {
+            for x in x..x + width {
+                if let Some(pixel) = self.framebuffer.get_pixel(x, y) {
+                    self.framebuffer.put_pixel(x, y, pixel ^ 0xffffff);
+                }
+            }
+        }
+    }
+
+    /// Draws a virtual console's text screen, for when it's switched to.
+    pub(crate) fn show_text(&mut self, writer: &mut Writer) {
+        self.style = DEFAULT_STYLE;
+        self.clear();
+        let (rows, columns) = (Writer::get_buffer_height(), Writer::get_buffer_width());
+        for row in 0..rows.min(self.rows) {
+            for col in 0..columns.min(self.columns) {
+                let (character, attribute) = writer.cell(row, col);
+                let foreground = PALETTE[usize::from(attribute & 0xF)];
+                let background = PALETTE[usize::from(attribute >> 4)];
+                self.draw_char(row, col, cp437::to_char(character), foreground, background);
+            }
+        }
+        let (row, col) = writer.position();
+        self.move_to(row, col);
+        self.toggle_cursor(true);
+    }
+}
+
+impl fmt::Write for FramebufferConsole {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_string(s);
+        Ok(())
+    }
+}
+
+/// Switches the screen to a `width` by `height` graphics mode and moves the
+/// text console there, drawn with `font` (or the VGA's own font if `None`).
+///
+/// Returns how many columns and rows of text fit.
+pub fn enable(width: usize, height: usize, font: Option<Font>) -> Result<(usize, usize), String> {
+    use x86_64::instructions::interrupts;
+
+    if is_enabled() {
+        return Err(String::from("the framebuffer console is already on"));
+    }
+    // the VGA font has to be read before the mode switch overwrites it
+    let font = match font {
+        Some(font) => font,
+        None => Font::from_vga().ok_or("can't read the VGA font")?,
+    };
+    let framebuffer = bga::set_mode(width, height)?;
+
+    interrupts::without_interrupts(|| {
+        let mut fb_console = FramebufferConsole::new(framebuffer, font);
+        let size = fb_console.size();
+        console::disable_text_display(|writer| fb_console.show_text(writer));
+        *CONSOLE.lock() = Some(fb_console);
+        Ok(size)
+    })
+}
+
+pub fn is_enabled() -> bool {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| CONSOLE.lock().is_some())
+}
+
+/// Prints to the framebuffer console, if it's on. Called by `vga_buffer::_print`
+/// with interrupts off.
+pub(crate) fn _print(args: fmt::Arguments) {
+    use core::fmt::Write;
+
+    if let Some(fb_console) = CONSOLE.lock().as_mut() {
+        fb_console.write_fmt(args).unwrap();
+    }
+}
+
+/// Shows the virtual console that was just switched to. Does nothing if the
+/// framebuffer console is busy, rather than deadlocking.
+pub(crate) fn show_text_console(writer: &mut Writer) {
+    if let Some(mut fb_console) = CONSOLE.try_lock() {
+        if let Some(fb_console) = fb_console.as_mut() {
+            fb_console.show_text(writer);
+        }
+    }
+}
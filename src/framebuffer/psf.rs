@@ -0,0 +1,250 @@
+//! PC Screen Fonts, the bitmap fonts the Linux console uses (`.psf` files,
+//! versions 1 and 2).
+
+use crate::{memory, vga_buffer::cp437};
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
+use x86_64::{instructions::port::Port, PhysAddr};
+
+const PSF1_MAGIC: [u8; 2] = [0x36, 0x04];
+/// The font has 512 glyphs instead of 256.
+const PSF1_MODE_512: u8 = 0x01;
+/// The font has a table saying which glyph goes with which character.
+const PSF1_MODE_HAS_TABLE: u8 = 0x06;
+const PSF1_SEPARATOR: u16 = 0xFFFF;
+const PSF1_START_SEQUENCE: u16 = 0xFFFE;
+
+const PSF2_MAGIC: [u8; 4] = [0x72, 0xb5, 0x4a, 0x86];
+const PSF2_HAS_UNICODE_TABLE: u32 = 0x01;
+const PSF2_SEPARATOR: u8 = 0xFF;
+const PSF2_START_SEQUENCE: u8 = 0xFE;
+
+#[derive(Clone)]
+pub struct Font {
+    width: usize,
+    height: usize,
+    glyph_count: usize,
+    /// Each glyph is `height` rows of `(width + 7) / 8` bytes, most significant bit on the left.
+    glyphs: Vec<u8>,
+    /// Which glyph each character has. Without one, glyphs are in CP437 order.
+    unicode: Option<BTreeMap<char, usize>>,
+}
+
+impl Font {
+    /// Reads a PSF1 or PSF2 font.
+    pub fn parse(data: &[u8]) -> Result<Font, String> {
+        if data.starts_with(&PSF1_MAGIC) {
+            parse_psf1(data)
+        } else if data.starts_with(&PSF2_MAGIC) {
+            parse_psf2(data)
+        } else {
+            Err(String::from("not a PSF font"))
+        }
+    }
+
+    /// Copies the VGA's own 8x16 font out of its memory. Only works while the
+    /// screen is still in text mode.
+    pub fn from_vga() -> Option<Font> {
+        const GLYPHS: usize = 256;
+        const HEIGHT: usize = 16;
+        // each glyph gets 32 rows in plane 2, whatever its real height
+        const SLOT: usize = 32;
+
+        let vga = memory::phys_to_virt(PhysAddr::new(0xA0000))?;
+        memory::check_range(vga, (GLYPHS * SLOT) as u64, false).ok()?;
+        let memory = vga.as_ptr::<u8>();
+
+        let mut glyphs = Vec::with_capacity(GLYPHS * HEIGHT);
+        x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+            let saved = map_font_plane();
+            for glyph in 0..GLYPHS {
+                for row in 0..HEIGHT {
+                    glyphs.push(memory.add(glyph * SLOT + row).read_volatile());
+                }
+            }
+            restore_text_planes(saved);
+        });
+
+        Some(Font {
+            width: 8,
+            height: HEIGHT,
+            glyph_count: GLYPHS,
+            glyphs,
+            unicode: None,
+        })
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// The rows of `c`'s glyph, or of a placeholder if the font doesn't have it.
+    pub fn glyph(&self, c: char) -> &[u8] {
+        let index = match &self.unicode {
+            Some(table) => table
+                .get(&c)
+                .or_else(|| table.get(&'?'))
+                .copied()
+                .unwrap_or(0),
+            None => usize::from(cp437::from_char(c).unwrap_or(cp437::UNKNOWN)),
+        };
+        let size = self.glyph_size();
+        let index = if index < self.glyph_count { index } else { 0 };
+        &self.glyphs[index * size..(index + 1) * size]
+    }
+
+    fn glyph_size(&self) -> usize {
+        (self.width + 7) / 8 * self.height
+    }
+}
+
+fn parse_psf1(data: &[u8]) -> Result<Font, String> {
+    let mode = *data.get(2).ok_or("truncated font")?;
+    let height = usize::from(*data.get(3).ok_or("truncated font")?);
+    let glyph_count = if mode & PSF1_MODE_512 != 0 { 512 } else { 256 };
+    let glyphs = data
+        .get(4..4 + glyph_count * height)
+        .ok_or("truncated font")?
+        .to_vec();
+
+    let unicode = if mode & PSF1_MODE_HAS_TABLE != 0 {
+        let mut table = BTreeMap::new();
+        let mut glyph = 0;
+        let mut in_sequence = false;
+        for entry in data[4 + glyph_count * height..].chunks_exact(2) {
+            match u16::from_le_bytes([entry[0], entry[1]]) {
+                PSF1_SEPARATOR => {
+                    glyph += 1;
+                    in_sequence = false;
+                }
+                // combining sequences, which we can't use
+                PSF1_START_SEQUENCE => in_sequence = true,
+                code if !in_sequence => {
+                    if let Some(c) = char::from_u32(u32::from(code)) {
+                        table.entry(c).or_insert(glyph);
+                    }
+                }
+                _ => {}
+            }
+        }
+        Some(table)
+    } else {
+        None
+    };
+
+    Ok(Font {
+        width: 8,
+        height,
+        glyph_count,
+        glyphs,
+        unicode,
+    })
+}
+
+fn parse_psf2(data: &[u8]) -> Result<Font, String> {
+    let field = |index: usize| -> Result<usize, String> {
+        let bytes = data.get(index * 4..index * 4 + 4).ok_or("truncated font")?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+    };
+    let header_size = field(2)?;
+    let flags = field(3)? as u32;
+    let glyph_count = field(4)?;
+    let glyph_size = field(5)?;
+    let height = field(6)?;
+    let width = field(7)?;
+    if width == 0 || height == 0 || glyph_size != (width + 7) / 8 * height {
+        return Err(String::from("bad font header"));
+    }
+    let end = header_size + glyph_count * glyph_size;
+    let glyphs = data.get(header_size..end).ok_or("truncated font")?.to_vec();
+
+    let unicode = if flags & PSF2_HAS_UNICODE_TABLE != 0 {
+        let mut table = BTreeMap::new();
+        for (glyph, entry) in data[end..].split(|&b| b == PSF2_SEPARATOR).enumerate() {
+            // anything after a start-of-sequence is a combining sequence
+            let singles = entry
+                .split(|&b| b == PSF2_START_SEQUENCE)
+                .next()
+                .unwrap_or(&[]);
+            if let Ok(chars) = core::str::from_utf8(singles) {
+                for c in chars.chars() {
+                    table.entry(c).or_insert(glyph);
+                }
+            }
+        }
+        Some(table)
+    } else {
+        None
+    };
+
+    Ok(Font {
+        width,
+        height,
+        glyph_count,
+        glyphs,
+        unicode,
+    })
+}
+
+/// VGA sequencer and graphics controller registers, which pick the memory
+/// plane 0xA0000 shows.
+const SEQUENCER_INDEX: u16 = 0x3C4;
+const GRAPHICS_INDEX: u16 = 0x3CE;
+const SEQ_MAP_MASK: u8 = 0x02;
+const SEQ_MEMORY_MODE: u8 = 0x04;
+const GC_READ_MAP: u8 = 0x04;
+const GC_MODE: u8 = 0x05;
+const GC_MISC: u8 = 0x06;
+
+unsafe fn read_indexed(index_port: u16, register: u8) -> u8 {
+    Port::new(index_port).write(register);
+    Port::new(index_port + 1).read()
+}
+
+unsafe fn write_indexed(index_port: u16, register: u8, value: u8) {
+    Port::new(index_port).write(register);
+    Port::new(index_port + 1).write(value);
+}
+
+/// Makes 0xA0000 show plane 2, where the font is, returning the registers to
+/// put back afterwards.
+unsafe fn map_font_plane() -> [u8; 5] {
+    let saved = [
+        read_indexed(SEQUENCER_INDEX, SEQ_MAP_MASK),
+        read_indexed(SEQUENCER_INDEX, SEQ_MEMORY_MODE),
+        read_indexed(GRAPHICS_INDEX, GC_READ_MAP),
+        read_indexed(GRAPHICS_INDEX, GC_MODE),
+        read_indexed(GRAPHICS_INDEX, GC_MISC),
+    ];
+    write_indexed(SEQUENCER_INDEX, SEQ_MAP_MASK, 0x04);
+    // sequential addressing instead of odd/even
+    write_indexed(SEQUENCER_INDEX, SEQ_MEMORY_MODE, 0x07);
+    write_indexed(GRAPHICS_INDEX, GC_READ_MAP, 0x02);
+    write_indexed(GRAPHICS_INDEX, GC_MODE, 0x00);
+    // 64K at 0xA0000
+    write_indexed(GRAPHICS_INDEX, GC_MISC, 0x04);
+    saved
+}
+
+unsafe fn restore_text_planes(saved: [u8; 5]) {
+    write_indexed(SEQUENCER_INDEX, SEQ_MAP_MASK, saved[0]);
+    write_indexed(SEQUENCER_INDEX, SEQ_MEMORY_MODE, saved[1]);
+    write_indexed(GRAPHICS_INDEX, GC_READ_MAP, saved[2]);
+    write_indexed(GRAPHICS_INDEX, GC_MODE, saved[3]);
+    write_indexed(GRAPHICS_INDEX, GC_MISC, saved[4]);
+}
+
+#[test_case]
+fn test_parse_psf1() {
+    // a two-row font where glyph 0x41 is a bar
+    let mut data = alloc::vec![0x36, 0x04, 0x00, 0x02];
+    data.resize(4 + 256 * 2, 0);
+    data[4 + 0x41 * 2] = 0xFF;
+    let font = Font::parse(&data).expect("parse failed");
+    assert_eq!((font.width(), font.height()), (8, 2));
+    assert_eq!(font.glyph('A'), &[0xFF, 0x00]);
+    assert!(Font::parse(b"nope").is_err());
+}
@@ -1,5 +1,8 @@
 use alloc::{string::*, vec, vec::*};
 use core::{arch::asm, convert::TryInto};
+
+pub mod vfs;
+
 const FAT32_CLEAN_SHUT_MASK: u32 = 0x08000000;
 const FAT32_HARD_ERROR_MASK: u32 = 0x04000000;
 
@@ -180,7 +183,10 @@ pub struct FSInfo {
     pub fsi_trail_sig: u32,
 }
 
-enum Attributes {
+/// FAT directory entry attribute bits, also used by the VFS.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Attributes {
     AttrReadOnly = 0x01,
     AttrHidden = 0x02,
     AttrSystem = 0x04,
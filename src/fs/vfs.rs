@@ -0,0 +1,442 @@
+//! A small in-memory filesystem that everything above the disk drivers talks to.
+//!
+//! Until AHCI reading works there's nothing to mount, so the whole tree lives on
+//! the heap and is lost on reboot. Paths are absolute and `/`-separated; `.` and
+//! `..` are resolved here, so callers only have to join relative paths onto their
+//! working directory.
+//!
+//! Every node carries FAT style attributes and timestamps, so a FAT32 backend can
+//! be slotted in underneath later without the shell noticing.
+
+use super::Attributes;
+use crate::cmos::Time;
+use alloc::{
+    collections::BTreeMap,
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::fmt;
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+/// The script SHSH runs when it starts.
+const DEFAULT_SHSHRC: &str = "# SHSH startup script, this runs every time the shell starts.
+export PS1='\\w $ '
+";
+
+lazy_static! {
+    static ref ROOT: Mutex<Node> = Mutex::new(Node::new(NodeKind::Directory(BTreeMap::new())));
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsError {
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+    AlreadyExists,
+    DirectoryNotEmpty,
+    ReadOnly,
+    InvalidPath,
+}
+
+impl fmt::Display for FsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            FsError::NotFound => "No such file or directory",
+            FsError::NotADirectory => "Not a directory",
+            FsError::IsADirectory => "Is a directory",
+            FsError::AlreadyExists => "File exists",
+            FsError::DirectoryNotEmpty => "Directory not empty",
+            FsError::ReadOnly => "Read-only file",
+            FsError::InvalidPath => "Invalid path",
+        };
+        write!(f, "{}", msg)
+    }
+}
+
+/// Information about a file or directory.
+#[derive(Debug, Clone, Copy)]
+pub struct Metadata {
+    pub is_dir: bool,
+    /// Size in bytes. Always 0 for directories.
+    pub size: usize,
+    /// FAT attribute bits, see `fs::Attributes`.
+    pub attributes: u8,
+    pub created: Time,
+    pub modified: Time,
+}
+
+impl Metadata {
+    pub fn has_attribute(&self, attribute: Attributes) -> bool {
+        self.attributes & attribute as u8 != 0
+    }
+}
+
+/// An entry returned by `read_dir`.
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    pub name: String,
+    pub metadata: Metadata,
+}
+
+enum NodeKind {
+    File(Vec<u8>),
+    Directory(BTreeMap<String, Node>),
+}
+
+struct Node {
+    kind: NodeKind,
+    attributes: u8,
+    created: Time,
+    modified: Time,
+}
+
+impl Node {
+    fn new(kind: NodeKind) -> Self {
+        let now = Time::from_current();
+        let attributes = match kind {
+            NodeKind::File(_) => Attributes::AttrArchive as u8,
+            NodeKind::Directory(_) => Attributes::AttrDirectory as u8,
+        };
+        Node {
+            kind,
+            attributes,
+            created: now,
+            modified: now,
+        }
+    }
+
+    fn metadata(&self) -> Metadata {
+        let (is_dir, size) = match &self.kind {
+            NodeKind::File(data) => (false, data.len()),
+            NodeKind::Directory(_) => (true, 0),
+        };
+        Metadata {
+            is_dir,
+            size,
+            attributes: self.attributes,
+            created: self.created,
+            modified: self.modified,
+        }
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.attributes & Attributes::AttrReadOnly as u8 != 0
+    }
+
+    /// Marks the node as changed, the same way FAT does.
+    fn touch(&mut self) {
+        self.modified = Time::from_current();
+        if let NodeKind::File(_) = self.kind {
+            self.attributes |= Attributes::AttrArchive as u8;
+        }
+    }
+}
+
+/// Creates the default directory layout (`/etc`, `/tmp`) and files.
+pub fn init() {
+    for dir in ["/etc", "/tmp"] {
+        match create_dir(dir) {
+            Ok(()) | Err(FsError::AlreadyExists) => {}
+            Err(e) => panic!("(X_X)  Unable to create {}: {}", dir, e),
+        }
+    }
+    if !exists("/etc/shshrc") {
+        write("/etc/shshrc", DEFAULT_SHSHRC.as_bytes())
+            .expect("(X_X)  Unable to create /etc/shshrc");
+    }
+}
+
+/// Splits `path` into its components, resolving `.` and `..`.
+pub fn components(path: &str) -> Result<Vec<&str>, FsError> {
+    if !path.starts_with('/') {
+        return Err(FsError::InvalidPath);
+    }
+    let mut parts = Vec::new();
+    for part in path.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            part => parts.push(part),
+        }
+    }
+    Ok(parts)
+}
+
+/// Joins `path` onto the directory `cwd` (unless `path` is already absolute)
+/// and normalizes the result.
+pub fn resolve(cwd: &str, path: &str) -> String {
+    let joined = if path.starts_with('/') {
+        path.to_string()
+    } else {
+        format!("{}/{}", cwd, path)
+    };
+    match components(&joined) {
+        Ok(parts) => format!("/{}", parts.join("/")),
+        Err(_) => "/".to_string(),
+    }
+}
+
+/// Returns the last component of `path`, or `/` for the root.
+pub fn file_name(path: &str) -> &str {
+    match path.trim_end_matches('/').rsplit('/').next() {
+        Some("") | None => "/",
+        Some(name) => name,
+    }
+}
+
+fn lookup<'a>(root: &'a Node, parts: &[&str]) -> Result<&'a Node, FsError> {
+    let mut node = root;
+    for part in parts {
+        node = match &node.kind {
+            NodeKind::Directory(entries) => entries.get(*part).ok_or(FsError::NotFound)?,
+            NodeKind::File(_) => return Err(FsError::NotADirectory),
+        };
+    }
+    Ok(node)
+}
+
+fn lookup_mut<'a>(root: &'a mut Node, parts: &[&str]) -> Result<&'a mut Node, FsError> {
+    let mut node = root;
+    for part in parts {
+        node = match &mut node.kind {
+            NodeKind::Directory(entries) => entries.get_mut(*part).ok_or(FsError::NotFound)?,
+            NodeKind::File(_) => return Err(FsError::NotADirectory),
+        };
+    }
+    Ok(node)
+}
+
+/// Looks up the directory containing the last component of `parts`.
+fn parent_mut<'a>(root: &'a mut Node, parts: &[&str]) -> Result<&'a mut Node, FsError> {
+    let parent = lookup_mut(root, &parts[..parts.len() - 1])?;
+    match parent.kind {
+        NodeKind::Directory(_) => Ok(parent),
+        NodeKind::File(_) => Err(FsError::NotADirectory),
+    }
+}
+
+/// Returns the entries of a directory node. Only call this on directories.
+fn entries_mut(node: &mut Node) -> &mut BTreeMap<String, Node> {
+    match &mut node.kind {
+        NodeKind::Directory(entries) => entries,
+        NodeKind::File(_) => unreachable!("parent_mut only returns directories"),
+    }
+}
+
+/// Returns `true` if a file or directory exists at `path`.
+pub fn exists(path: &str) -> bool {
+    metadata(path).is_ok()
+}
+
+/// Returns `true` if `path` is a directory.
+pub fn is_dir(path: &str) -> bool {
+    matches!(metadata(path), Ok(Metadata { is_dir: true, .. }))
+}
+
+/// Returns the metadata of the file or directory at `path`.
+pub fn metadata(path: &str) -> Result<Metadata, FsError> {
+    let parts = components(path)?;
+    Ok(lookup(&ROOT.lock(), &parts)?.metadata())
+}
+
+/// Lists the directory at `path`, sorted by name.
+pub fn read_dir(path: &str) -> Result<Vec<DirEntry>, FsError> {
+    let parts = components(path)?;
+    match &lookup(&ROOT.lock(), &parts)?.kind {
+        NodeKind::Directory(entries) => Ok(entries
+            .iter()
+            .map(|(name, node)| DirEntry {
+                name: name.clone(),
+                metadata: node.metadata(),
+            })
+            .collect()),
+        NodeKind::File(_) => Err(FsError::NotADirectory),
+    }
+}
+
+/// Reads the whole file at `path`.
+pub fn read(path: &str) -> Result<Vec<u8>, FsError> {
+    let parts = components(path)?;
+    match &lookup(&ROOT.lock(), &parts)?.kind {
+        NodeKind::File(data) => Ok(data.clone()),
+        NodeKind::Directory(_) => Err(FsError::IsADirectory),
+    }
+}
+
+/// Writes `data` to the file at `path`, creating it or replacing its contents.
+pub fn write(path: &str, data: &[u8]) -> Result<(), FsError> {
+    let parts = components(path)?;
+    if parts.is_empty() {
+        return Err(FsError::IsADirectory);
+    }
+    let name = parts[parts.len() - 1];
+    let mut root = ROOT.lock();
+    let parent = parent_mut(&mut root, &parts)?;
+
+    match entries_mut(parent).get_mut(name) {
+        Some(node) => {
+            if node.is_read_only() {
+                return Err(FsError::ReadOnly);
+            }
+            match &mut node.kind {
+                NodeKind::File(contents) => {
+                    contents.clear();
+                    contents.extend_from_slice(data);
+                }
+                NodeKind::Directory(_) => return Err(FsError::IsADirectory),
+            }
+            node.touch();
+        }
+        None => {
+            let node = Node::new(NodeKind::File(data.to_vec()));
+            entries_mut(parent).insert(name.to_string(), node);
+        }
+    }
+    parent.touch();
+    Ok(())
+}
+
+/// Creates an empty file at `path`, or updates its modification time if it exists.
+pub fn touch(path: &str) -> Result<(), FsError> {
+    let parts = components(path)?;
+    let mut root = ROOT.lock();
+    match lookup_mut(&mut root, &parts) {
+        Ok(node) => {
+            node.touch();
+            Ok(())
+        }
+        Err(FsError::NotFound) => {
+            drop(root);
+            write(path, &[])
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Creates an empty directory at `path`. The parent directory must exist.
+pub fn create_dir(path: &str) -> Result<(), FsError> {
+    let parts = components(path)?;
+    if parts.is_empty() {
+        return Err(FsError::AlreadyExists);
+    }
+    let name = parts[parts.len() - 1];
+    let mut root = ROOT.lock();
+    let parent = parent_mut(&mut root, &parts)?;
+    if entries_mut(parent).contains_key(name) {
+        return Err(FsError::AlreadyExists);
+    }
+    parent.touch();
+    let node = Node::new(NodeKind::Directory(BTreeMap::new()));
+    entries_mut(parent).insert(name.to_string(), node);
+    Ok(())
+}
+
+/// Creates the directory at `path` and all of its missing parents.
+pub fn create_dir_all(path: &str) -> Result<(), FsError> {
+    let parts = components(path)?;
+    for i in 1..=parts.len() {
+        let partial = format!("/{}", parts[..i].join("/"));
+        match create_dir(&partial) {
+            Ok(()) => {}
+            Err(FsError::AlreadyExists) if is_dir(&partial) => {}
+            Err(FsError::AlreadyExists) => return Err(FsError::NotADirectory),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Removes a file or an empty directory.
+pub fn remove(path: &str) -> Result<(), FsError> {
+    remove_node(path, false)
+}
+
+/// Removes a file or a directory along with everything inside it.
+pub fn remove_all(path: &str) -> Result<(), FsError> {
+    remove_node(path, true)
+}
+
+fn remove_node(path: &str, recursive: bool) -> Result<(), FsError> {
+    let parts = components(path)?;
+    if parts.is_empty() {
+        return Err(FsError::InvalidPath);
+    }
+    let name = parts[parts.len() - 1];
+    let mut root = ROOT.lock();
+    let parent = parent_mut(&mut root, &parts)?;
+    let entries = entries_mut(parent);
+
+    let node = entries.get(name).ok_or(FsError::NotFound)?;
+    if node.is_read_only() {
+        return Err(FsError::ReadOnly);
+    }
+    if let NodeKind::Directory(children) = &node.kind {
+        if !recursive && !children.is_empty() {
+            return Err(FsError::DirectoryNotEmpty);
+        }
+    }
+    entries.remove(name);
+    parent.touch();
+    Ok(())
+}
+
+/// Moves the file or directory at `from` to `to`. `to` must not exist yet.
+pub fn rename(from: &str, to: &str) -> Result<(), FsError> {
+    let from_parts = components(from)?;
+    let to_parts = components(to)?;
+    if from_parts.is_empty() || to_parts.is_empty() {
+        return Err(FsError::InvalidPath);
+    }
+    if from_parts == to_parts {
+        return Ok(());
+    }
+    // moving a directory into itself would cut it off from the tree
+    if to_parts.len() > from_parts.len() && to_parts[..from_parts.len()] == from_parts[..] {
+        return Err(FsError::InvalidPath);
+    }
+
+    let mut root = ROOT.lock();
+    let target_parent = parent_mut(&mut root, &to_parts)?;
+    if entries_mut(target_parent).contains_key(to_parts[to_parts.len() - 1]) {
+        return Err(FsError::AlreadyExists);
+    }
+
+    let source_parent = parent_mut(&mut root, &from_parts)?;
+    let name = from_parts[from_parts.len() - 1];
+    match entries_mut(source_parent).get(name) {
+        Some(node) if node.is_read_only() => return Err(FsError::ReadOnly),
+        Some(_) => {}
+        None => return Err(FsError::NotFound),
+    }
+    let node = entries_mut(source_parent).remove(name).unwrap();
+    source_parent.touch();
+
+    let target_parent = parent_mut(&mut root, &to_parts)?;
+    target_parent.touch();
+    entries_mut(target_parent).insert(to_parts[to_parts.len() - 1].to_string(), node);
+    Ok(())
+}
+
+/// Replaces the FAT attribute bits of `path`. The directory bit can't be changed.
+pub fn set_attributes(path: &str, attributes: u8) -> Result<(), FsError> {
+    let parts = components(path)?;
+    let mut root = ROOT.lock();
+    let node = lookup_mut(&mut root, &parts)?;
+    let directory = Attributes::AttrDirectory as u8;
+    node.attributes = (attributes & !directory) | (node.attributes & directory);
+    Ok(())
+}
+
+#[test_case]
+fn test_vfs_paths() {
+    assert_eq!(resolve("/etc", "shshrc"), "/etc/shshrc");
+    assert_eq!(resolve("/etc", "../tmp/./x"), "/tmp/x");
+    assert_eq!(resolve("/etc", "/"), "/");
+    assert_eq!(components("relative"), Err(FsError::InvalidPath));
+    assert_eq!(file_name("/etc/shshrc"), "shshrc");
+    assert_eq!(file_name("/"), "/");
+}
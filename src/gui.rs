@@ -0,0 +1,124 @@
+//! A basic windowing system on top of the framebuffer.
+//!
+//! `draw` is the drawing library and `compositor` manages the windows. Once
+//! `start` has switched the screen over, console output goes to a terminal
+//! window instead of the VGA text buffer, and programs can open windows of
+//! their own through `with_desktop`.
+
+use crate::framebuffer::{self, bga, psf::Font};
+use crate::task::mouse::MouseEvent;
+use crate::vga_buffer::{console, Writer};
+use alloc::string::String;
+use compositor::{Compositor, WindowId};
+use core::fmt;
+use spin::Mutex;
+use video_memory::VideoMemory;
+use x86_64::instructions::interrupts;
+
+pub mod compositor;
+pub mod draw;
+pub mod video_memory;
+
+/// The compositor, once `start` has been called.
+static DESKTOP: Mutex<Option<Compositor>> = Mutex::new(None);
+
+/// Switches the screen to a `width` by `height` graphics mode and starts the
+/// desktop, with the active console in a terminal window.
+pub fn start(width: usize, height: usize) -> Result<(), String> {
+    if is_running() {
+        return Err(String::from("the desktop is already running"));
+    }
+    if framebuffer::console::is_enabled() {
+        return Err(String::from("the framebuffer console has the screen"));
+    }
+    let (address, size) =
+        bga::video_memory().ok_or("no Bochs graphics adapter (try QEMU with -vga std)")?;
+    // the screen, the back buffer and a terminal window, at least
+    let screen_size = width * height * 4;
+    if 3 * screen_size > size {
+        return Err(String::from("not enough video memory for that mode"));
+    }
+    // the VGA font has to be read before the mode switch overwrites it
+    let font = Font::from_vga().ok_or("can't read the VGA font")?;
+    let screen = bga::set_mode(width, height)?;
+
+    let used = screen.stride() * screen.height() * 4;
+    let video_memory =
+        VideoMemory::new(address + used as u64, size - used).ok_or("video memory isn't mapped")?;
+    let mut desktop = Compositor::new(screen, video_memory, font)?;
+    desktop.open_terminal(
+        "Terminal",
+        Writer::get_buffer_width(),
+        Writer::get_buffer_height(),
+    )?;
+
+    interrupts::without_interrupts(|| {
+        console::disable_text_display(|writer| desktop.show_text(writer));
+        desktop.compose();
+        *DESKTOP.lock() = Some(desktop);
+    });
+    Ok(())
+}
+
+pub fn is_running() -> bool {
+    interrupts::without_interrupts(|| DESKTOP.lock().is_some())
+}
+
+/// Runs `f` on the compositor, then puts whatever it changed on screen.
+///
+/// Returns `None` if the desktop isn't running. `f` mustn't print, since
+/// printing goes through the compositor too.
+pub fn with_desktop<R>(f: impl FnOnce(&mut Compositor) -> R) -> Option<R> {
+    interrupts::without_interrupts(|| {
+        let mut desktop = DESKTOP.lock();
+        let desktop = desktop.as_mut()?;
+        let result = f(desktop);
+        desktop.compose();
+        Some(result)
+    })
+}
+
+/// Opens another terminal window, which console output goes to from now on.
+pub fn open_terminal() -> Result<WindowId, String> {
+    with_desktop(|desktop| {
+        let id = desktop.open_terminal(
+            "Terminal",
+            Writer::get_buffer_width(),
+            Writer::get_buffer_height(),
+        )?;
+        console::with_active(|writer| desktop.show_text(writer));
+        Ok(id)
+    })
+    .unwrap_or_else(|| Err(String::from("the desktop isn't running")))
+}
+
+/// Hands a mouse event to the desktop. Returns `false` if it isn't running.
+pub fn pointer_event(event: &MouseEvent) -> bool {
+    with_desktop(|desktop| desktop.pointer_event(event)).is_some()
+}
+
+/// Prints to the terminal window, if the desktop is running. Called by
+/// `vga_buffer::_print` with interrupts off.
+///
+/// Does nothing if the compositor is busy (say, something panicked inside
+/// `with_desktop`), rather than deadlocking. The text still gets to the
+/// virtual console.
+pub(crate) fn _print(args: fmt::Arguments) {
+    if let Some(mut desktop) = DESKTOP.try_lock() {
+        if let Some(desktop) = desktop.as_mut() {
+            desktop.write_terminal(args);
+            desktop.compose();
+        }
+    }
+}
+
+/// Shows the virtual console that was just switched to in the terminal
+/// window. Does nothing if the compositor is busy, rather than deadlocking.
+pub(crate) fn show_text_console(writer: &mut Writer) {
+    if let Some(mut desktop) = DESKTOP.try_lock() {
+        if let Some(desktop) = desktop.as_mut() {
+            desktop.show_text(writer);
+            desktop.compose();
+        }
+    }
+}
@@ -0,0 +1,506 @@
+//! Puts overlapping windows together on screen.
+//!
+//! Each window has a framebuffer of its own in video memory. When something
+//! changes, the part of the screen it changed is marked as damaged, and
+//! `compose` redraws just those parts: first into a back buffer, bottom window
+//! to top, then onto the screen in one go so nothing flickers.
+
+use super::{
+    draw::{rgb, rgba, Canvas, Rect},
+    video_memory::VideoMemory,
+};
+use crate::framebuffer::{console::FramebufferConsole, psf::Font, Framebuffer};
+use crate::task::mouse::MouseEvent;
+use crate::vga_buffer::Writer;
+use alloc::{
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+use core::fmt::{self, Write};
+
+pub type WindowId = usize;
+
+const DESKTOP_COLOR: u32 = rgb(0x00, 0x55, 0x80);
+const BORDER_COLOR: u32 = rgb(0x20, 0x20, 0x20);
+const TITLE_COLOR: u32 = rgb(0x80, 0x80, 0x80);
+const FOCUSED_TITLE_COLOR: u32 = rgb(0x00, 0x00, 0xAA);
+const TITLE_TEXT_COLOR: u32 = rgb(0xFF, 0xFF, 0xFF);
+const SHADOW_COLOR: u32 = rgba(0x00, 0x00, 0x00, 0x60);
+
+const BORDER: i32 = 1;
+/// How far the shadow sticks out to the right and below.
+const SHADOW: i32 = 6;
+/// Past this many damaged rectangles, they're merged into one.
+const MAX_DAMAGE: usize = 16;
+
+/// The mouse pointer: `#` is outline, `.` is fill, the rest shows through.
+const POINTER: [&[u8; 11]; 16] = [
+    b"#          ",
+    b"##         ",
+    b"#.#        ",
+    b"#..#       ",
+    b"#...#      ",
+    b"#....#     ",
+    b"#.....#    ",
+    b"#......#   ",
+    b"#.......#  ",
+    b"#........# ",
+    b"#.....#####",
+    b"#..#..#    ",
+    b"#.# #..#   ",
+    b"##  #..#   ",
+    b"#    #..#  ",
+    b"     ####  ",
+];
+const POINTER_OUTLINE: u32 = rgb(0x00, 0x00, 0x00);
+const POINTER_FILL: u32 = rgb(0xFF, 0xFF, 0xFF);
+
+enum Content {
+    Surface(Framebuffer),
+    Terminal(FramebufferConsole),
+}
+
+impl Content {
+    fn framebuffer(&self) -> &Framebuffer {
+        match self {
+            Content::Surface(framebuffer) => framebuffer,
+            Content::Terminal(terminal) => terminal.framebuffer(),
+        }
+    }
+}
+
+struct Window {
+    id: WindowId,
+    title: String,
+    /// The top left corner of the frame.
+    x: i32,
+    y: i32,
+    content: Content,
+}
+
+impl Window {
+    /// The window with its border and title bar.
+    fn frame(&self, font: &Font) -> Rect {
+        let content = self.content.framebuffer();
+        Rect::new(
+            self.x,
+            self.y,
+            content.width() as i32 + 2 * BORDER,
+            content.height() as i32 + 2 * BORDER + title_height(font),
+        )
+    }
+
+    /// Everything the window draws over, shadow included.
+    fn bounds(&self, font: &Font) -> Rect {
+        let frame = self.frame(font);
+        Rect::new(
+            frame.x,
+            frame.y,
+            frame.width + SHADOW,
+            frame.height + SHADOW,
+        )
+    }
+
+    fn content_rect(&self, font: &Font) -> Rect {
+        let content = self.content.framebuffer();
+        Rect::new(
+            self.x + BORDER,
+            self.y + BORDER + title_height(font),
+            content.width() as i32,
+            content.height() as i32,
+        )
+    }
+
+    fn draw(&self, canvas: &mut Canvas, font: &Font, focused: bool) {
+        let frame = self.frame(font);
+        canvas.blend_rect(frame.offset(SHADOW, SHADOW), SHADOW_COLOR);
+        canvas.rect(frame, BORDER_COLOR);
+
+        let title_bar = Rect::new(
+            frame.x + BORDER,
+            frame.y + BORDER,
+            frame.width - 2 * BORDER,
+            title_height(font),
+        );
+        let title_color = if focused {
+            FOCUSED_TITLE_COLOR
+        } else {
+            TITLE_COLOR
+        };
+        canvas.fill_rect(title_bar, title_color);
+        let clip = canvas.clip();
+        canvas.set_clip(clip.intersection(&title_bar).unwrap_or(title_bar));
+        canvas.text(
+            font,
+            title_bar.x + 4,
+            title_bar.y + 2,
+            &self.title,
+            TITLE_TEXT_COLOR,
+        );
+        canvas.set_clip(clip);
+
+        let content = self.content_rect(font);
+        canvas.blit(self.content.framebuffer(), content.x, content.y);
+    }
+}
+
+fn title_height(font: &Font) -> i32 {
+    font.height() as i32 + 4
+}
+
+pub struct Compositor {
+    screen: Framebuffer,
+    /// Where the screen is put together before it's shown.
+    back: Framebuffer,
+    video_memory: VideoMemory,
+    font: Font,
+    /// Bottom to top.
+    windows: Vec<Window>,
+    damage: Vec<Rect>,
+    next_id: WindowId,
+    /// The terminal window console output goes to.
+    terminal: Option<WindowId>,
+    /// Where the tip of the mouse pointer is.
+    pointer: (i32, i32),
+    /// The window being dragged by its title bar, and where in it the
+    /// pointer is.
+    dragging: Option<(WindowId, i32, i32)>,
+    left_held: bool,
+}
+
+impl Compositor {
+    /// Takes over `screen`, drawing windows off-screen in `video_memory`.
+    pub fn new(
+        screen: Framebuffer,
+        mut video_memory: VideoMemory,
+        font: Font,
+    ) -> Result<Compositor, String> {
+        let back = video_memory.allocate(screen.width(), screen.height())?;
+        let whole = Rect::new(0, 0, screen.width() as i32, screen.height() as i32);
+        Ok(Compositor {
+            screen,
+            back,
+            video_memory,
+            font,
+            windows: Vec::new(),
+            damage: vec![whole],
+            next_id: 1,
+            terminal: None,
+            pointer: (whole.width / 2, whole.height / 2),
+            dragging: None,
+            left_held: false,
+        })
+    }
+
+    /// The screen's width and height.
+    pub fn size(&self) -> (usize, usize) {
+        (self.screen.width(), self.screen.height())
+    }
+
+    /// The font window titles and terminals use.
+    pub fn font(&self) -> &Font {
+        &self.font
+    }
+
+    /// Marks part of the screen to be redrawn by the next `compose`.
+    pub fn damage(&mut self, rect: Rect) {
+        if rect.is_empty() {
+            return;
+        }
+        if let Some(damaged) = self
+            .damage
+            .iter_mut()
+            .find(|damaged| damaged.intersection(&rect).is_some())
+        {
+            *damaged = damaged.union(&rect);
+        } else if self.damage.len() < MAX_DAMAGE {
+            self.damage.push(rect);
+        } else {
+            let all = self.damage.iter().fold(rect, |all, rect| all.union(rect));
+            self.damage = vec![all];
+        }
+    }
+
+    /// Opens a blank `width` by `height` window, on top of the others.
+    pub fn open_window(
+        &mut self,
+        title: &str,
+        width: usize,
+        height: usize,
+    ) -> Result<WindowId, String> {
+        let mut framebuffer = self.video_memory.allocate(width, height)?;
+        framebuffer.fill_rect(0, 0, width, height, rgb(0xFF, 0xFF, 0xFF));
+        Ok(self.add(title, Content::Surface(framebuffer)))
+    }
+
+    /// Opens a window with a `columns` by `rows` text console in it, which
+    /// console output goes to from now on.
+    pub fn open_terminal(
+        &mut self,
+        title: &str,
+        columns: usize,
+        rows: usize,
+    ) -> Result<WindowId, String> {
+        let framebuffer = self
+            .video_memory
+            .allocate(columns * self.font.width(), rows * self.font.height())?;
+        let terminal = FramebufferConsole::new(framebuffer, self.font.clone());
+        let id = self.add(title, Content::Terminal(terminal));
+        self.terminal = Some(id);
+        Ok(id)
+    }
+
+    fn add(&mut self, title: &str, content: Content) -> WindowId {
+        let id = self.next_id;
+        self.next_id += 1;
+        // cascade new windows down from the top left
+        let offset = 32 + 24 * (self.windows.len() % 8) as i32;
+        let window = Window {
+            id,
+            title: title.to_string(),
+            x: offset,
+            y: offset,
+            content,
+        };
+        self.damage_focus();
+        self.damage(window.bounds(&self.font));
+        self.windows.push(window);
+        id
+    }
+
+    pub fn close_window(&mut self, id: WindowId) -> bool {
+        let index = match self.index(id) {
+            Some(index) => index,
+            None => return false,
+        };
+        let window = self.windows.remove(index);
+        self.damage(window.bounds(&self.font));
+        self.damage_focus();
+        match window.content {
+            Content::Surface(framebuffer) => self.video_memory.release(framebuffer),
+            Content::Terminal(terminal) => self.video_memory.release(terminal.into_framebuffer()),
+        }
+        if self.terminal == Some(id) {
+            // output goes to the topmost terminal left, if any
+            self.terminal = self
+                .windows
+                .iter()
+                .rev()
+                .find(|window| matches!(window.content, Content::Terminal(_)))
+                .map(|window| window.id);
+        }
+        true
+    }
+
+    /// Moves a window's top left corner to `(x, y)`.
+    pub fn move_window(&mut self, id: WindowId, x: i32, y: i32) -> bool {
+        let index = match self.index(id) {
+            Some(index) => index,
+            None => return false,
+        };
+        self.damage(self.windows[index].bounds(&self.font));
+        self.windows[index].x = x;
+        self.windows[index].y = y;
+        self.damage(self.windows[index].bounds(&self.font));
+        true
+    }
+
+    /// Brings a window to the top.
+    pub fn raise(&mut self, id: WindowId) -> bool {
+        let index = match self.index(id) {
+            Some(index) => index,
+            None => return false,
+        };
+        self.damage_focus();
+        let window = self.windows.remove(index);
+        self.damage(window.bounds(&self.font));
+        self.windows.push(window);
+        true
+    }
+
+    /// The topmost window at `(x, y)`.
+    pub fn window_at(&self, x: i32, y: i32) -> Option<WindowId> {
+        self.windows
+            .iter()
+            .rev()
+            .find(|window| window.frame(&self.font).contains(x, y))
+            .map(|window| window.id)
+    }
+
+    /// Each window's ID, title and frame, bottom to top.
+    pub fn windows(&self) -> impl Iterator<Item = (WindowId, &str, Rect)> + '_ {
+        self.windows
+            .iter()
+            .map(move |window| (window.id, window.title.as_str(), window.frame(&self.font)))
+    }
+
+    /// Draws on the inside of a window, if it's one from `open_window`.
+    pub fn draw<R>(&mut self, id: WindowId, draw: impl FnOnce(&mut Canvas) -> R) -> Option<R> {
+        let index = self.index(id)?;
+        let result = match &mut self.windows[index].content {
+            Content::Surface(framebuffer) => draw(&mut Canvas::new(framebuffer)),
+            Content::Terminal(_) => return None,
+        };
+        self.damage(self.windows[index].content_rect(&self.font));
+        Some(result)
+    }
+
+    /// Prints to the terminal window, if there is one.
+    pub fn write_terminal(&mut self, args: fmt::Arguments) {
+        self.with_terminal(|terminal| terminal.write_fmt(args).unwrap());
+    }
+
+    /// Shows a virtual console's screen in the terminal window.
+    pub fn show_text(&mut self, writer: &mut Writer) {
+        self.with_terminal(|terminal| terminal.show_text(writer));
+    }
+
+    fn with_terminal(&mut self, f: impl FnOnce(&mut FramebufferConsole)) {
+        let index = match self.terminal.and_then(|id| self.index(id)) {
+            Some(index) => index,
+            None => return,
+        };
+        if let Content::Terminal(terminal) = &mut self.windows[index].content {
+            f(terminal);
+        }
+        self.damage(self.windows[index].content_rect(&self.font));
+    }
+
+    /// Moves the pointer, raises windows that are clicked on and drags
+    /// windows around by their title bars.
+    pub fn pointer_event(&mut self, event: &MouseEvent) {
+        let (width, height) = self.size();
+        self.damage(self.pointer_rect());
+        let (x, y) = self.pointer;
+        let x = (x + i32::from(event.dx)).clamp(0, width as i32 - 1);
+        let y = (y + i32::from(event.dy)).clamp(0, height as i32 - 1);
+        self.pointer = (x, y);
+        self.damage(self.pointer_rect());
+
+        if event.buttons.left && !self.left_held {
+            if let Some(id) = self.window_at(x, y) {
+                self.raise(id);
+                let window = &self.windows[self.windows.len() - 1];
+                let frame = window.frame(&self.font);
+                if y < frame.y + BORDER + title_height(&self.font) {
+                    self.dragging = Some((id, x - window.x, y - window.y));
+                }
+            }
+        } else if !event.buttons.left {
+            self.dragging = None;
+        }
+        if let Some((id, offset_x, offset_y)) = self.dragging {
+            self.move_window(id, x - offset_x, y - offset_y);
+        }
+        self.left_held = event.buttons.left;
+    }
+
+    fn pointer_rect(&self) -> Rect {
+        let (x, y) = self.pointer;
+        Rect::new(x, y, POINTER[0].len() as i32, POINTER.len() as i32)
+    }
+
+    /// Redraws the damaged parts of the screen.
+    pub fn compose(&mut self) {
+        let screen = Rect::new(
+            0,
+            0,
+            self.screen.width() as i32,
+            self.screen.height() as i32,
+        );
+        let damage = core::mem::take(&mut self.damage);
+        let top = self.windows.len().wrapping_sub(1);
+        for rect in damage {
+            let rect = match rect.intersection(&screen) {
+                Some(rect) => rect,
+                None => continue,
+            };
+            let mut back = Canvas::new(&mut self.back);
+            back.set_clip(rect);
+            back.fill_rect(rect, DESKTOP_COLOR);
+            for (index, window) in self.windows.iter().enumerate() {
+                if window.bounds(&self.font).intersection(&rect).is_some() {
+                    window.draw(&mut back, &self.font, index == top);
+                }
+            }
+            let (x, y) = self.pointer;
+            for (row, pixels) in POINTER.iter().enumerate() {
+                for (col, pixel) in pixels.iter().enumerate() {
+                    let color = match pixel {
+                        b'#' => POINTER_OUTLINE,
+                        b'.' => POINTER_FILL,
+                        _ => continue,
+                    };
+                    back.pixel(x + col as i32, y + row as i32, color);
+                }
+            }
+            Canvas::new(&mut self.screen).blit_rect(&self.back, rect, rect.x, rect.y);
+        }
+    }
+
+    fn index(&self, id: WindowId) -> Option<usize> {
+        self.windows.iter().position(|window| window.id == id)
+    }
+
+    /// Damages the top window's title bar, for when it's about to stop (or
+    /// start) being the focused one.
+    fn damage_focus(&mut self) {
+        if let Some(window) = self.windows.last() {
+            let frame = window.frame(&self.font);
+            self.damage(Rect::new(
+                frame.x,
+                frame.y,
+                frame.width,
+                title_height(&self.font) + BORDER,
+            ));
+        }
+    }
+}
+
+#[test_case]
+fn test_stacking_and_damage() {
+    use alloc::boxed::Box;
+
+    // a two-row font of blank glyphs
+    let mut font = vec![0x36, 0x04, 0x00, 0x02];
+    font.resize(4 + 256 * 2, 0);
+    let font = Font::parse(&font).unwrap();
+    let screen = Framebuffer::from_slice(Box::leak(vec![0; 80 * 60].into_boxed_slice()), 80, 60);
+    let video_memory = VideoMemory::from_slice(Box::leak(vec![0; 80 * 60 + 64].into_boxed_slice()));
+    let mut desktop = Compositor::new(screen, video_memory, font).unwrap();
+
+    let a = desktop.open_window("a", 4, 4).unwrap();
+    let b = desktop.open_window("b", 4, 4).unwrap();
+    let red = rgb(0xFF, 0, 0);
+    desktop.draw(b, |canvas| canvas.fill_rect(Rect::new(0, 0, 4, 4), red));
+    desktop.move_window(b, 33, 33);
+    assert_eq!(desktop.window_at(34, 34), Some(b));
+    desktop.compose();
+    assert!(desktop.damage.is_empty());
+    // both contents cover this pixel, and b's is on top
+    assert_eq!(desktop.screen.get_pixel(35, 41), Some(red));
+
+    desktop.raise(a);
+    assert_eq!(desktop.window_at(34, 34), Some(a));
+    assert_eq!(
+        desktop.windows().map(|(id, _, _)| id).collect::<Vec<_>>(),
+        vec![b, a]
+    );
+    desktop.compose();
+    assert_eq!(
+        desktop.screen.get_pixel(35, 41),
+        Some(rgb(0xFF, 0xFF, 0xFF))
+    );
+
+    // overlapping damage is merged, the rest kept apart until there's too much
+    desktop.damage(Rect::new(0, 0, 4, 4));
+    desktop.damage(Rect::new(2, 2, 4, 4));
+    assert_eq!(desktop.damage, vec![Rect::new(0, 0, 6, 6)]);
+    for i in 1..MAX_DAMAGE as i32 {
+        desktop.damage(Rect::new(i * 4, 10, 1, 1));
+    }
+    assert_eq!(desktop.damage.len(), MAX_DAMAGE);
+    desktop.damage(Rect::new(0, 20, 1, 1));
+    assert_eq!(desktop.damage, vec![Rect::new(0, 0, 61, 21)]);
+}
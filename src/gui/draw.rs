@@ -0,0 +1,314 @@
+//! Drawing on framebuffers: pixels, lines, rectangles, copying from one
+//! framebuffer to another, translucency and text.
+//!
+//! Colors are `0xAARRGGBB`. Most of the drawing just overwrites pixels and
+//! ignores the alpha byte; the `blend_*` methods mix by it instead, `0xFF`
+//! being opaque and `0x00` invisible.
+
+use crate::framebuffer::{psf::Font, Framebuffer};
+
+/// A rectangle in pixels. It can stick out past the edges of whatever it's
+/// drawn on, or be empty.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32,
+}
+
+impl Rect {
+    pub const fn new(x: i32, y: i32, width: i32, height: i32) -> Rect {
+        Rect {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    /// Just past the right edge.
+    pub fn right(&self) -> i32 {
+        self.x + self.width
+    }
+
+    /// Just past the bottom edge.
+    pub fn bottom(&self) -> i32 {
+        self.y + self.height
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.width <= 0 || self.height <= 0
+    }
+
+    pub fn contains(&self, x: i32, y: i32) -> bool {
+        x >= self.x && x < self.right() && y >= self.y && y < self.bottom()
+    }
+
+    /// The part that's in both, if any.
+    pub fn intersection(&self, other: &Rect) -> Option<Rect> {
+        let x = self.x.max(other.x);
+        let y = self.y.max(other.y);
+        let rect = Rect::new(
+            x,
+            y,
+            self.right().min(other.right()) - x,
+            self.bottom().min(other.bottom()) - y,
+        );
+        if rect.is_empty() {
+            None
+        } else {
+            Some(rect)
+        }
+    }
+
+    /// The smallest rectangle around both.
+    pub fn union(&self, other: &Rect) -> Rect {
+        if self.is_empty() {
+            return *other;
+        } else if other.is_empty() {
+            return *self;
+        }
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        Rect::new(
+            x,
+            y,
+            self.right().max(other.right()) - x,
+            self.bottom().max(other.bottom()) - y,
+        )
+    }
+
+    pub fn offset(&self, dx: i32, dy: i32) -> Rect {
+        Rect::new(self.x + dx, self.y + dy, self.width, self.height)
+    }
+}
+
+/// An opaque color.
+pub const fn rgb(r: u8, g: u8, b: u8) -> u32 {
+    rgba(r, g, b, 0xFF)
+}
+
+pub const fn rgba(r: u8, g: u8, b: u8, a: u8) -> u32 {
+    (a as u32) << 24 | (r as u32) << 16 | (g as u32) << 8 | b as u32
+}
+
+/// Draws `src` over `dst`, by `src`'s alpha.
+pub fn blend(dst: u32, src: u32) -> u32 {
+    let alpha = src >> 24;
+    let channel = |shift: u32| {
+        let (src, dst) = ((src >> shift) & 0xFF, (dst >> shift) & 0xFF);
+        (src * alpha + dst * (255 - alpha)) / 255
+    };
+    0xFF00_0000 | channel(16) << 16 | channel(8) << 8 | channel(0)
+}
+
+/// Draws on a framebuffer, only inside its clip rectangle.
+pub struct Canvas<'a> {
+    target: &'a mut Framebuffer,
+    clip: Rect,
+}
+
+impl<'a> Canvas<'a> {
+    pub fn new(target: &'a mut Framebuffer) -> Canvas<'a> {
+        let clip = Rect::new(0, 0, target.width() as i32, target.height() as i32);
+        Canvas { target, clip }
+    }
+
+    /// The whole of what's being drawn on.
+    pub fn bounds(&self) -> Rect {
+        Rect::new(
+            0,
+            0,
+            self.target.width() as i32,
+            self.target.height() as i32,
+        )
+    }
+
+    pub fn clip(&self) -> Rect {
+        self.clip
+    }
+
+    /// Only draws inside `rect` from now on.
+    pub fn set_clip(&mut self, rect: Rect) {
+        self.clip = rect
+            .intersection(&self.bounds())
+            .unwrap_or(Rect::new(0, 0, 0, 0));
+    }
+
+    pub fn pixel(&mut self, x: i32, y: i32, color: u32) {
+        if self.clip.contains(x, y) {
+            self.target.put_pixel(x as usize, y as usize, color);
+        }
+    }
+
+    pub fn blend_pixel(&mut self, x: i32, y: i32, color: u32) {
+        if self.clip.contains(x, y) {
+            let (x, y) = (x as usize, y as usize);
+            if let Some(pixel) = self.target.get_pixel(x, y) {
+                self.target.put_pixel(x, y, blend(pixel, color));
+            }
+        }
+    }
+
+    /// Draws a line from `(x0, y0)` to `(x1, y1)`, both ends included.
+    pub fn line(&mut self, x0: i32, y0: i32, x1: i32, y1: i32, color: u32) {
+        // Bresenham's, with the error term covering every octant
+        let (dx, dy) = ((x1 - x0).abs(), -(y1 - y0).abs());
+        let (step_x, step_y) = ((x1 - x0).signum(), (y1 - y0).signum());
+        let mut error = dx + dy;
+        let (mut x, mut y) = (x0, y0);
+        loop {
+            self.pixel(x, y, color);
+            if x == x1 && y == y1 {
+                break;
+            }
+            let doubled = 2 * error;
+            if doubled >= dy {
+                error += dy;
+                x += step_x;
+            }
+            if doubled <= dx {
+                error += dx;
+                y += step_y;
+            }
+        }
+    }
+
+    /// Draws the outline of `rect`, one pixel thick, inside it.
+    pub fn rect(&mut self, rect: Rect, color: u32) {
+        let Rect {
+            x,
+            y,
+            width,
+            height,
+        } = rect;
+        self.fill_rect(Rect::new(x, y, width, 1), color);
+        self.fill_rect(Rect::new(x, rect.bottom() - 1, width, 1), color);
+        self.fill_rect(Rect::new(x, y + 1, 1, height - 2), color);
+        self.fill_rect(Rect::new(rect.right() - 1, y + 1, 1, height - 2), color);
+    }
+
+    pub fn fill_rect(&mut self, rect: Rect, color: u32) {
+        if let Some(rect) = rect.intersection(&self.clip) {
+            self.target.fill_rect(
+                rect.x as usize,
+                rect.y as usize,
+                rect.width as usize,
+                rect.height as usize,
+                color,
+            );
+        }
+    }
+
+    /// Fills `rect` with a translucent color.
+    pub fn blend_rect(&mut self, rect: Rect, color: u32) {
+        if let Some(rect) = rect.intersection(&self.clip) {
+            for y in rect.y..rect.bottom() {
+                let row =
+                    &mut self.target.row_mut(y as usize)[rect.x as usize..rect.right() as usize];
+                for pixel in row {
+                    *pixel = blend(*pixel, color);
+                }
+            }
+        }
+    }
+
+    /// Copies all of `src` with its top left corner at `(x, y)`.
+    pub fn blit(&mut self, src: &Framebuffer, x: i32, y: i32) {
+        let from = Rect::new(0, 0, src.width() as i32, src.height() as i32);
+        self.blit_rect(src, from, x, y);
+    }
+
+    /// Copies the `from` part of `src`, with its top left corner at `(x, y)`.
+    pub fn blit_rect(&mut self, src: &Framebuffer, from: Rect, x: i32, y: i32) {
+        self.copy_rows(src, from, x, y, |dst, src| dst.copy_from_slice(src));
+    }
+
+    /// Like `blit`, but mixing `src` in by the alpha of each of its pixels.
+    pub fn blend_blit(&mut self, src: &Framebuffer, x: i32, y: i32) {
+        let from = Rect::new(0, 0, src.width() as i32, src.height() as i32);
+        self.copy_rows(src, from, x, y, |dst, src| {
+            for (dst, &src) in dst.iter_mut().zip(src) {
+                *dst = blend(*dst, src);
+            }
+        });
+    }
+
+    /// Calls `copy` with each row of the `from` part of `src` and the row it
+    /// goes over, both cut down to what's in bounds.
+    fn copy_rows(
+        &mut self,
+        src: &Framebuffer,
+        from: Rect,
+        x: i32,
+        y: i32,
+        mut copy: impl FnMut(&mut [u32], &[u32]),
+    ) {
+        // how far pixels move between `src` and the target
+        let (dx, dy) = (x - from.x, y - from.y);
+        let src_bounds = Rect::new(0, 0, src.width() as i32, src.height() as i32);
+        let region = match from
+            .intersection(&src_bounds)
+            .and_then(|from| from.intersection(&self.clip.offset(-dx, -dy)))
+        {
+            Some(region) => region,
+            None => return,
+        };
+        let (start, end) = (region.x as usize, region.right() as usize);
+        for src_y in region.y..region.bottom() {
+            let dst_row = self.target.row_mut((src_y + dy) as usize);
+            let dst = &mut dst_row[(region.x + dx) as usize..(region.right() + dx) as usize];
+            copy(dst, &src.row(src_y as usize)[start..end]);
+        }
+    }
+
+    /// Draws `c` with its top left corner at `(x, y)`. Only the glyph's set
+    /// pixels are drawn, so whatever is underneath shows through.
+    pub fn char(&mut self, font: &Font, x: i32, y: i32, c: char, color: u32) {
+        let bytes_per_row = (font.width() + 7) / 8;
+        for (row, bits) in font.glyph(c).chunks(bytes_per_row).enumerate() {
+            for col in 0..font.width() {
+                if bits[col / 8] & (0x80 >> (col % 8)) != 0 {
+                    self.pixel(x + col as i32, y + row as i32, color);
+                }
+            }
+        }
+    }
+
+    /// Draws `text` on one line starting at `(x, y)`, returning where it ends.
+    pub fn text(&mut self, font: &Font, x: i32, y: i32, text: &str, color: u32) -> i32 {
+        let mut x = x;
+        for c in text.chars() {
+            self.char(font, x, y, c, color);
+            x += font.width() as i32;
+        }
+        x
+    }
+}
+
+#[test_case]
+fn test_canvas() {
+    use alloc::{boxed::Box, vec};
+
+    let pixels = Box::leak(vec![0; 8 * 4].into_boxed_slice());
+    let mut framebuffer = Framebuffer::from_slice(pixels, 8, 4);
+    let mut canvas = Canvas::new(&mut framebuffer);
+
+    // clipped to the edges
+    canvas.fill_rect(Rect::new(-2, -2, 4, 4), rgb(0xFF, 0, 0));
+    canvas.line(0, 3, 7, 3, rgb(0, 0xFF, 0));
+    canvas.blend_rect(Rect::new(4, 0, 1, 1), rgba(0xFF, 0xFF, 0xFF, 0x80));
+    assert_eq!(framebuffer.get_pixel(1, 1), Some(rgb(0xFF, 0, 0)));
+    assert_eq!(framebuffer.get_pixel(2, 2), Some(0));
+    assert_eq!(framebuffer.get_pixel(7, 3), Some(rgb(0, 0xFF, 0)));
+    assert_eq!(framebuffer.get_pixel(4, 0), Some(rgb(0x80, 0x80, 0x80)));
+
+    let a = Rect::new(0, 0, 4, 4);
+    assert_eq!(
+        a.intersection(&Rect::new(2, 3, 4, 4)),
+        Some(Rect::new(2, 3, 2, 1))
+    );
+    assert_eq!(a.intersection(&Rect::new(4, 0, 1, 1)), None);
+    assert_eq!(a.union(&Rect::new(6, 6, 1, 1)), Rect::new(0, 0, 7, 7));
+}
@@ -0,0 +1,99 @@
+//! The part of video memory the screen doesn't use, handed out as
+//! framebuffers to draw on off-screen. There's a lot more of it than there
+//! is heap.
+
+use crate::{framebuffer::Framebuffer, memory};
+use alloc::{format, string::String, vec, vec::Vec};
+use core::ops::Range;
+use x86_64::{PhysAddr, VirtAddr};
+
+pub struct VideoMemory {
+    /// Where it's mapped.
+    virt: VirtAddr,
+    /// Free pixels, as offsets from `start`, in order and never touching.
+    free: Vec<Range<usize>>,
+}
+
+impl VideoMemory {
+    /// Manages `len` bytes of video memory at `start`.
+    pub fn new(start: PhysAddr, len: usize) -> Option<VideoMemory> {
+        let virt = memory::phys_to_virt(start)?;
+        memory::check_range(virt, len as u64, true).ok()?;
+        Some(VideoMemory {
+            virt,
+            free: vec![0..len / 4],
+        })
+    }
+
+    /// Hands out pixels in memory instead, like `Framebuffer::from_slice`.
+    pub fn from_slice(pixels: &'static mut [u32]) -> VideoMemory {
+        VideoMemory {
+            virt: VirtAddr::from_ptr(pixels.as_mut_ptr()),
+            free: vec![0..pixels.len()],
+        }
+    }
+
+    /// How many pixels are left.
+    pub fn available(&self) -> usize {
+        self.free.iter().map(|range| range.len()).sum()
+    }
+
+    /// Finds room for a `width` by `height` framebuffer.
+    pub fn allocate(&mut self, width: usize, height: usize) -> Result<Framebuffer, String> {
+        let len = width * height;
+        let index = self
+            .free
+            .iter()
+            .position(|range| range.len() >= len)
+            .ok_or_else(|| format!("not enough video memory for {}x{}", width, height))?;
+        let offset = self.free[index].start;
+        let address = self.virt + (offset * 4) as u64;
+        let framebuffer = unsafe { Framebuffer::from_virt(address, width, height, width) };
+        self.free[index].start += len;
+        if self.free[index].is_empty() {
+            self.free.remove(index);
+        }
+        Ok(framebuffer)
+    }
+
+    /// Takes back a framebuffer from `allocate`.
+    pub fn release(&mut self, framebuffer: Framebuffer) {
+        let start = ((framebuffer.address() - self.virt) / 4) as usize;
+        let range = start..start + framebuffer.stride() * framebuffer.height();
+        let index = self
+            .free
+            .iter()
+            .position(|free| free.start > range.start)
+            .unwrap_or(self.free.len());
+        self.free.insert(index, range);
+        // merge with the neighbours where they touch
+        if index + 1 < self.free.len() && self.free[index].end == self.free[index + 1].start {
+            self.free[index].end = self.free.remove(index + 1).end;
+        }
+        if index > 0 && self.free[index - 1].end == self.free[index].start {
+            self.free[index - 1].end = self.free.remove(index).end;
+        }
+    }
+}
+
+#[test_case]
+fn test_release_merges_neighbours() {
+    use alloc::boxed::Box;
+
+    let pixels = Box::leak(vec![0; 64].into_boxed_slice());
+    let mut video_memory = VideoMemory::from_slice(pixels);
+    let a = video_memory.allocate(4, 4).unwrap();
+    let b = video_memory.allocate(4, 4).unwrap();
+    let c = video_memory.allocate(4, 4).unwrap();
+    assert_eq!(video_memory.available(), 16);
+    assert!(video_memory.allocate(8, 4).is_err());
+
+    video_memory.release(a);
+    // merges with the free pixels after it
+    video_memory.release(c);
+    assert_eq!(video_memory.free, vec![0..16, 32..64]);
+    // and with both sides
+    video_memory.release(b);
+    assert_eq!(video_memory.free, vec![0..64]);
+    assert!(video_memory.allocate(8, 8).is_ok());
+}
@@ -1,44 +1,118 @@
-use crate::{gdt, hlt_loop, print, println};
+use crate::println;
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicBool, Ordering},
+};
 use lazy_static::lazy_static;
 use pic8259::ChainedPics;
 use spin;
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::{
+    instructions::port::Port,
+    structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode},
+};
+
+pub mod apic;
+pub mod exceptions;
+pub mod irq;
 
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 
+/// Vectors the kernel's own devices use. Their handlers are registered
+/// through `irq` like any other.
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum InterruptIndex {
     Timer = PIC_1_OFFSET,
     Keyboard,
+    /// IRQ 12, on the second PIC.
+    Mouse = PIC_2_OFFSET + 4,
+    /// The local APIC timer, which isn't an IRQ at all.
+    LocalTimer = 0xF0,
+    /// The HPET comparator `time::hpet` uses for one-shot events, routed
+    /// through the I/O APIC.
+    Hpet,
 }
 
-impl InterruptIndex {
-    fn as_u8(self) -> u8 {
-        self as u8
+pub static PICS: spin::Mutex<ChainedPics> =
+    spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+
+/// The PICs' command ports, and what to write to them to acknowledge an IRQ
+/// or to read which IRQs are in service.
+const PIC_1_COMMAND: u16 = 0x20;
+const PIC_2_COMMAND: u16 = 0xA0;
+const PIC_EOI: u8 = 0x20;
+const PIC_READ_ISR: u8 = 0x0B;
+
+/// Whether `vector` is a spurious IRQ 7 or 15 from the PICs: one that went
+/// away before it could be handled, so it isn't in service and mustn't be
+/// acknowledged. A spurious IRQ 15 came through IRQ 2 on the first PIC for
+/// real though, so that one gets acknowledged here.
+///
+/// Under the APIC, those vectors are ordinary IRQs.
+fn is_spurious_pic_irq(vector: u8) -> bool {
+    if apic::is_enabled() {
+        return false;
+    }
+    let command = match vector {
+        v if v == PIC_1_OFFSET + 7 => PIC_1_COMMAND,
+        v if v == PIC_2_OFFSET + 7 => PIC_2_COMMAND,
+        _ => return false,
+    };
+    let in_service = unsafe {
+        let mut port = Port::<u8>::new(command);
+        port.write(PIC_READ_ISR);
+        port.read()
+    };
+    if in_service & 1 << 7 != 0 {
+        return false;
+    }
+    if command == PIC_2_COMMAND {
+        unsafe { Port::<u8>::new(PIC_1_COMMAND).write(PIC_EOI) };
     }
+    true
+}
 
-    fn as_usize(self) -> usize {
-        usize::from(self.as_u8())
+/// Acknowledges the interrupt on `vector`, at the local APIC or the PICs,
+/// whichever is in use.
+fn end_of_interrupt(vector: u8) {
+    if apic::is_enabled() {
+        apic::end_of_interrupt();
+    } else {
+        unsafe { PICS.lock().notify_end_of_interrupt(vector) };
     }
 }
 
-pub static PICS: spin::Mutex<ChainedPics> =
-    spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+/// Unmasks (or masks) an ISA IRQ, at the I/O APIC or the PICs.
+pub fn set_irq_enabled(irq: u8, enabled: bool) {
+    if apic::is_enabled() {
+        apic::set_isa_irq_masked(irq, !enabled);
+        return;
+    }
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut pics = PICS.lock();
+        let [mut primary, mut secondary] = unsafe { pics.read_masks() };
+        let (mask, bit) = if irq < 8 {
+            (&mut primary, irq)
+        } else {
+            // IRQs on the second PIC come through IRQ 2 on the first
+            primary &= !(1 << 2);
+            (&mut secondary, irq - 8)
+        };
+        if enabled {
+            *mask &= !(1 << bit);
+        } else {
+            *mask |= 1 << bit;
+        }
+        unsafe { pics.write_masks(primary, secondary) };
+    });
+}
 
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
-        idt.page_fault.set_handler_fn(page_fault_handler);
-        unsafe {
-            idt.double_fault
-                .set_handler_fn(double_fault_handler)
-                .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
-        }
-        idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
-        idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
+        exceptions::install(&mut idt);
+        irq::install(&mut idt);
         idt
     };
 }
@@ -60,50 +134,64 @@ extern "x86-interrupt" fn drunk_page_fault_handler(stack_frame: InterruptStackFr
     println!("Stack Frame:\n{:#?}", stack_frame);
 }
 
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
-    println!("Exception: Breakpoint\n{:#?}", stack_frame);
-}
-
-extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: InterruptStackFrame,
-    error_code: PageFaultErrorCode,
-) {
-    use x86_64::registers::control::Cr2;
+/// Set while `read_msr`/`write_msr` run, so a #GP from a bad MSR skips the
+/// instruction instead of taking the kernel down.
+static MSR_PROBE: AtomicBool = AtomicBool::new(false);
+/// Set by the #GP handler when it skipped a faulting MSR instruction.
+static MSR_FAULTED: AtomicBool = AtomicBool::new(false);
 
-    println!("(X_X)\n");
-    println!("Exception: Page Fault");
-    println!("Accessed Address: {:?}", Cr2::read());
-    println!("Error Code: {:?}", error_code);
-    println!("Stack Frame:\n{:#?}", stack_frame);
-    hlt_loop();
+/// Reads an MSR, returning `None` if it doesn't exist (`rdmsr` raised #GP).
+pub fn read_msr(msr: u32) -> Option<u64> {
+    probe_msr(|| {
+        let (high, low): (u32, u32);
+        unsafe {
+            asm!("rdmsr", in("ecx") msr, out("eax") low, out("edx") high, options(nomem, nostack));
+        }
+        (high as u64) << 32 | low as u64
+    })
 }
 
-extern "x86-interrupt" fn double_fault_handler(
-    stack_frame: InterruptStackFrame,
-    _error_code: u64,
-) -> ! {
-    panic!("(X_X)\n\nException: Double Fault\n{:#?}", stack_frame);
+/// Writes an MSR, returning `false` if the CPU refused (`wrmsr` raised #GP).
+///
+/// This is still very unsafe: plenty of MSRs take any value and will happily
+/// break the machine.
+pub unsafe fn write_msr(msr: u32, value: u64) -> bool {
+    let (high, low) = ((value >> 32) as u32, value as u32);
+    probe_msr(|| {
+        asm!("wrmsr", in("ecx") msr, in("eax") low, in("edx") high, options(nostack));
+    })
+    .is_some()
 }
 
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    print!(".");
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
-    }
+/// Runs `f` (a single `rdmsr`/`wrmsr`) with MSR probing on, returning `None` if it faulted.
+fn probe_msr<T>(f: impl FnOnce() -> T) -> Option<T> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        MSR_FAULTED.store(false, Ordering::SeqCst);
+        MSR_PROBE.store(true, Ordering::SeqCst);
+        let result = f();
+        MSR_PROBE.store(false, Ordering::SeqCst);
+        if MSR_FAULTED.load(Ordering::SeqCst) {
+            None
+        } else {
+            Some(result)
+        }
+    })
 }
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    use x86_64::instructions::port::Port;
-
-    let mut port = Port::new(0x60);
-    let scancode: u8 = unsafe { port.read() };
-    crate::task::keyboard::add_scancode(scancode);
-
+/// Called by the #GP handler. If the fault came from an MSR probe, skips
+/// the instruction and returns `true`.
+fn skip_faulting_msr(stack_frame: &mut InterruptStackFrame) -> bool {
+    if !MSR_PROBE.load(Ordering::SeqCst) {
+        return false;
+    }
+    // `rdmsr` (0F 32) and `wrmsr` (0F 30) are both 2 bytes long
+    MSR_FAULTED.store(true, Ordering::SeqCst);
     unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
+        stack_frame
+            .as_mut()
+            .update(|frame| frame.instruction_pointer += 2u64);
     }
+    true
 }
 
 #[test_case]
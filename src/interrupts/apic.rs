@@ -0,0 +1,253 @@
+//! The local APIC and the I/O APICs, which take over from the 8259 PICs when
+//! the MADT says they're there.
+//!
+//! ISA IRQs keep the vectors the PICs gave them, so the IDT doesn't change;
+//! they're just routed through the I/O APIC redirection entries instead, and
+//! acknowledged at the local APIC.
+
+use super::{irq, read_msr, write_msr, InterruptIndex, PICS, PIC_1_OFFSET};
+use crate::{acpi::madt::Madt, memory};
+use alloc::vec::Vec;
+use core::{
+    arch::x86_64::__cpuid,
+    ptr,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use spin::Mutex;
+use x86_64::{instructions::interrupts, PhysAddr, VirtAddr};
+
+pub mod timer;
+
+const IA32_APIC_BASE: u32 = 0x1B;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+/// CPUID leaf 1 EDX bit for an on-chip APIC.
+const CPUID_APIC: u32 = 1 << 9;
+
+/// Local APIC registers.
+const LAPIC_ID: usize = 0x20;
+const LAPIC_TASK_PRIORITY: usize = 0x80;
+const LAPIC_EOI: usize = 0xB0;
+const LAPIC_SPURIOUS: usize = 0xF0;
+/// Set in `LAPIC_SPURIOUS` to turn the local APIC on.
+const LAPIC_SOFTWARE_ENABLE: u32 = 0x100;
+
+/// Where the local APIC sends interrupts nobody should have to acknowledge.
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+/// I/O APIC registers, reached by writing the register number to `IOREGSEL`
+/// and then using `IOWIN`.
+const IOREGSEL: usize = 0x00;
+const IOWIN: usize = 0x10;
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION_TABLE: u32 = 0x10;
+
+/// Redirection entry bits.
+const REDIRECTION_ACTIVE_LOW: u64 = 1 << 13;
+const REDIRECTION_LEVEL_TRIGGERED: u64 = 1 << 15;
+const REDIRECTION_MASKED: u64 = 1 << 16;
+
+/// How many ISA IRQs there are.
+const ISA_IRQS: u8 = 16;
+
+/// Where the local APIC's registers are mapped, or 0 while the PICs are in
+/// use. Kept outside `APIC` so acknowledging an interrupt doesn't need a lock.
+static LOCAL_APIC: AtomicU64 = AtomicU64::new(0);
+
+static APIC: Mutex<Option<Apic>> = Mutex::new(None);
+
+struct IoApic {
+    base: VirtAddr,
+    gsi_base: u32,
+    /// How many redirection entries it has.
+    entries: u32,
+}
+
+impl IoApic {
+    unsafe fn read(&self, register: u32) -> u32 {
+        ptr::write_volatile((self.base + IOREGSEL).as_mut_ptr::<u32>(), register);
+        ptr::read_volatile((self.base + IOWIN).as_ptr::<u32>())
+    }
+
+    unsafe fn write(&self, register: u32, value: u32) {
+        ptr::write_volatile((self.base + IOREGSEL).as_mut_ptr::<u32>(), register);
+        ptr::write_volatile((self.base + IOWIN).as_mut_ptr::<u32>(), value);
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        (self.gsi_base..self.gsi_base + self.entries).contains(&gsi)
+    }
+
+    unsafe fn set_redirection(&self, gsi: u32, entry: u64) {
+        let register = IOAPIC_REDIRECTION_TABLE + 2 * (gsi - self.gsi_base);
+        // masked while it's half written
+        self.write(register, REDIRECTION_MASKED as u32);
+        self.write(register + 1, (entry >> 32) as u32);
+        self.write(register, entry as u32);
+    }
+}
+
+struct Apic {
+    madt: Madt,
+    io_apics: Vec<IoApic>,
+    /// The local APIC ID interrupts are sent to.
+    destination: u8,
+}
+
+impl Apic {
+    /// Routes an ISA IRQ to the vector the PIC would have used.
+    fn route_isa_irq(&self, irq: u8, masked: bool) {
+        let (gsi, active_low, level_triggered) = self.madt.isa_irq(irq);
+        let mut entry = u64::from(PIC_1_OFFSET + irq) | u64::from(self.destination) << 56;
+        if active_low {
+            entry |= REDIRECTION_ACTIVE_LOW;
+        }
+        if level_triggered {
+            entry |= REDIRECTION_LEVEL_TRIGGERED;
+        }
+        if masked {
+            entry |= REDIRECTION_MASKED;
+        }
+        if let Some(io_apic) = self.io_apics.iter().find(|io_apic| io_apic.handles(gsi)) {
+            unsafe { io_apic.set_redirection(gsi, entry) };
+        }
+    }
+}
+
+unsafe fn local_apic_read(base: VirtAddr, register: usize) -> u32 {
+    ptr::read_volatile((base + register).as_ptr::<u32>())
+}
+
+unsafe fn local_apic_write(base: VirtAddr, register: usize, value: u32) {
+    ptr::write_volatile((base + register).as_mut_ptr::<u32>(), value);
+}
+
+fn map(address: PhysAddr) -> Result<VirtAddr, &'static str> {
+    memory::phys_to_virt(address).ok_or("the APIC registers aren't mapped")
+}
+
+/// Whether interrupts go through the APICs rather than the PICs.
+pub fn is_enabled() -> bool {
+    LOCAL_APIC.load(Ordering::SeqCst) != 0
+}
+
+/// The local APIC ID interrupts are sent to, for devices that send their own
+/// (like MSIs).
+pub fn destination() -> Option<u8> {
+    interrupts::without_interrupts(|| APIC.lock().as_ref().map(|apic| apic.destination))
+}
+
+/// Tells the local APIC the interrupt being handled is done.
+pub(super) fn end_of_interrupt() {
+    let base = VirtAddr::new(LOCAL_APIC.load(Ordering::SeqCst));
+    unsafe { local_apic_write(base, LAPIC_EOI, 0) };
+}
+
+/// Masks or unmasks an ISA IRQ at the I/O APIC.
+pub(super) fn set_isa_irq_masked(irq: u8, masked: bool) {
+    interrupts::without_interrupts(|| {
+        if let Some(apic) = APIC.lock().as_ref() {
+            apic.route_isa_irq(irq, masked);
+        }
+    });
+}
+
+/// Sends global system interrupt `gsi` to `vector`, edge triggered and
+/// active high, for devices that aren't on an ISA IRQ (like the HPET).
+pub(crate) fn route_gsi(gsi: u32, vector: u8) -> Result<(), &'static str> {
+    interrupts::without_interrupts(|| {
+        let apic = APIC.lock();
+        let apic = apic.as_ref().ok_or("the APIC isn't in use")?;
+        let io_apic = apic
+            .io_apics
+            .iter()
+            .find(|io_apic| io_apic.handles(gsi))
+            .ok_or("no I/O APIC handles that GSI")?;
+        let entry = u64::from(vector) | u64::from(apic.destination) << 56;
+        unsafe { io_apic.set_redirection(gsi, entry) };
+        Ok(())
+    })
+}
+
+/// Whether an I/O APIC has an input for `gsi`.
+pub(crate) fn handles_gsi(gsi: u32) -> bool {
+    interrupts::without_interrupts(|| {
+        APIC.lock().as_ref().map_or(false, |apic| {
+            apic.io_apics.iter().any(|io_apic| io_apic.handles(gsi))
+        })
+    })
+}
+
+/// Finds the APICs in the MADT, masks the PICs and moves the ISA IRQs over,
+/// keeping unmasked the ones the PICs had unmasked.
+///
+/// On error nothing has changed, and the PICs are still in use.
+pub fn init() -> Result<(), &'static str> {
+    if is_enabled() {
+        return Ok(());
+    }
+    if __cpuid(1).edx & CPUID_APIC == 0 {
+        return Err("the CPU has no local APIC");
+    }
+    let madt = Madt::find().ok_or("there's no MADT in the ACPI tables")?;
+    let local_apic = map(madt.local_apic_address)?;
+    let mut io_apics = Vec::new();
+    for io_apic in &madt.io_apics {
+        let mut io_apic = IoApic {
+            base: map(io_apic.address)?,
+            gsi_base: io_apic.gsi_base,
+            entries: 0,
+        };
+        // the highest entry's index is in bits 16..24
+        io_apic.entries = ((unsafe { io_apic.read(IOAPIC_VERSION) } >> 16) & 0xFF) + 1;
+        io_apics.push(io_apic);
+    }
+    if io_apics.is_empty() {
+        return Err("the MADT doesn't list any I/O APICs");
+    }
+    // it never fires until it's started, so this can come first
+    irq::register_vector(
+        InterruptIndex::LocalTimer as u8,
+        "apic timer",
+        timer::interrupt,
+    )?;
+
+    interrupts::without_interrupts(|| {
+        let enabled_irqs = {
+            let mut pics = PICS.lock();
+            let [primary, secondary] = unsafe { pics.read_masks() };
+            unsafe { pics.write_masks(0xFF, 0xFF) };
+            !(u16::from(secondary) << 8 | u16::from(primary))
+        };
+
+        if let Some(base) = read_msr(IA32_APIC_BASE) {
+            unsafe { write_msr(IA32_APIC_BASE, base | APIC_BASE_ENABLE) };
+        }
+        let destination = unsafe {
+            local_apic_write(
+                local_apic,
+                LAPIC_SPURIOUS,
+                LAPIC_SOFTWARE_ENABLE | u32::from(SPURIOUS_VECTOR),
+            );
+            local_apic_write(local_apic, LAPIC_TASK_PRIORITY, 0);
+            (local_apic_read(local_apic, LAPIC_ID) >> 24) as u8
+        };
+
+        for io_apic in &io_apics {
+            for gsi in io_apic.gsi_base..io_apic.gsi_base + io_apic.entries {
+                unsafe { io_apic.set_redirection(gsi, REDIRECTION_MASKED) };
+            }
+        }
+        let apic = Apic {
+            madt,
+            io_apics,
+            destination,
+        };
+        // IRQ 2 only ever chained the PICs together
+        for irq in (0..ISA_IRQS).filter(|&irq| irq != 2) {
+            apic.route_isa_irq(irq, enabled_irqs & 1 << irq == 0);
+        }
+        *APIC.lock() = Some(apic);
+        LOCAL_APIC.store(local_apic.as_u64(), Ordering::SeqCst);
+    });
+    Ok(())
+}
@@ -0,0 +1,161 @@
+//! The local APIC's timer, which can fire once, every so often, or (on CPUs
+//! that have it) when the TSC reaches a deadline.
+//!
+//! It runs off the bus clock, so its rate has to be measured first, which
+//! `time::calibrate` does.
+
+use super::{local_apic_read, local_apic_write, LOCAL_APIC};
+use crate::{
+    interrupts::{write_msr, InterruptIndex},
+    time::{self, tsc, Instant},
+};
+use core::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+use x86_64::VirtAddr;
+
+/// Timer registers.
+const LVT_TIMER: usize = 0x320;
+const INITIAL_COUNT: usize = 0x380;
+const CURRENT_COUNT: usize = 0x390;
+const DIVIDE_CONFIGURATION: usize = 0x3E0;
+
+/// `DIVIDE_CONFIGURATION` value for counting down once every 16 bus clocks.
+const DIVIDE_BY_16: u32 = 0x3;
+
+/// `LVT_TIMER` bits.
+const LVT_MASKED: u32 = 1 << 16;
+const MODE_PERIODIC: u32 = 1 << 17;
+const MODE_TSC_DEADLINE: u32 = 2 << 17;
+
+const IA32_TSC_DEADLINE: u32 = 0x6E0;
+
+/// Counts per second, dividing by 16. 0 until it's been calibrated.
+static FREQUENCY: AtomicU64 = AtomicU64::new(0);
+/// How many times it has fired.
+static FIRED: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerMode {
+    /// Fires once, this long from now.
+    OneShot(Duration),
+    /// Fires every so often.
+    Periodic(Duration),
+    /// Fires once the TSC reaches `Instant`.
+    TscDeadline(Instant),
+}
+
+fn base() -> Option<VirtAddr> {
+    match LOCAL_APIC.load(Ordering::SeqCst) {
+        0 => None,
+        base => Some(VirtAddr::new(base)),
+    }
+}
+
+/// Starts counting down from as high as it goes, without interrupting.
+pub(crate) fn start_calibration() {
+    if let Some(base) = base() {
+        unsafe {
+            local_apic_write(base, DIVIDE_CONFIGURATION, DIVIDE_BY_16);
+            local_apic_write(base, LVT_TIMER, LVT_MASKED);
+            local_apic_write(base, INITIAL_COUNT, u32::MAX);
+        }
+    }
+}
+
+/// Works out the rate from how far it counted down in `elapsed` nanoseconds
+/// since `start_calibration`, and stops it.
+pub(crate) fn finish_calibration(elapsed: u64) {
+    if let Some(base) = base() {
+        let counted = u32::MAX - unsafe { local_apic_read(base, CURRENT_COUNT) };
+        unsafe { local_apic_write(base, INITIAL_COUNT, 0) };
+        let frequency = u128::from(counted) * 1_000_000_000 / u128::from(elapsed.max(1));
+        FREQUENCY.store(frequency as u64, Ordering::SeqCst);
+    }
+}
+
+/// Counts per second, once it's been calibrated.
+pub fn frequency() -> Option<u64> {
+    match FREQUENCY.load(Ordering::SeqCst) {
+        0 => None,
+        frequency => Some(frequency),
+    }
+}
+
+/// How many times it has fired since boot.
+pub fn fired() -> u64 {
+    FIRED.load(Ordering::SeqCst)
+}
+
+/// Called when the timer fires.
+pub(super) fn interrupt() {
+    FIRED.fetch_add(1, Ordering::SeqCst);
+    time::timer::wake_due();
+}
+
+/// Sets the timer going, instead of whatever it was doing before. Each time
+/// it fires, the timers in `time::timer` that are due get woken.
+pub fn start(mode: TimerMode) -> Result<(), &'static str> {
+    let base = base().ok_or("the local APIC isn't in use")?;
+    let vector = u32::from(InterruptIndex::LocalTimer as u8);
+    match mode {
+        TimerMode::OneShot(duration) | TimerMode::Periodic(duration) => {
+            let frequency = frequency().ok_or("the timer hasn't been calibrated")?;
+            let count = duration.as_nanos() * u128::from(frequency) / 1_000_000_000;
+            let count = count.clamp(1, u128::from(u32::MAX)) as u32;
+            let periodic = match mode {
+                TimerMode::Periodic(_) => MODE_PERIODIC,
+                _ => 0,
+            };
+            unsafe {
+                local_apic_write(base, DIVIDE_CONFIGURATION, DIVIDE_BY_16);
+                local_apic_write(base, LVT_TIMER, vector | periodic);
+                local_apic_write(base, INITIAL_COUNT, count);
+            }
+        }
+        TimerMode::TscDeadline(deadline) => {
+            if !tsc::has_deadline_mode() {
+                return Err("the CPU has no TSC-deadline mode");
+            }
+            let count = tsc::count_at(deadline).ok_or("the TSC hasn't been calibrated")?;
+            unsafe {
+                local_apic_write(base, LVT_TIMER, vector | MODE_TSC_DEADLINE);
+                // the LVT write has to land before the deadline does, or the
+                // deadline can be taken in the old mode and dropped
+                core::arch::asm!("mfence", options(nostack, preserves_flags));
+                if !write_msr(IA32_TSC_DEADLINE, count) {
+                    return Err("the CPU refused the deadline");
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Stops the timer.
+pub fn stop() {
+    if let Some(base) = base() {
+        unsafe {
+            local_apic_write(base, LVT_TIMER, LVT_MASKED);
+            local_apic_write(base, INITIAL_COUNT, 0);
+        }
+        if tsc::has_deadline_mode() {
+            unsafe { write_msr(IA32_TSC_DEADLINE, 0) };
+        }
+    }
+}
+
+#[test_case]
+fn test_one_shot_fires() {
+    super::init().unwrap();
+    time::calibrate().unwrap();
+    let before = fired();
+    start(TimerMode::OneShot(Duration::from_millis(1))).unwrap();
+    let started = Instant::now();
+    while fired() == before {
+        assert!(started.elapsed() < Duration::from_secs(1));
+        x86_64::instructions::hlt();
+    }
+    stop();
+}
@@ -0,0 +1,378 @@
+//! Handlers for every architectural CPU exception.
+//!
+//! The fatal ones print what the CPU pushed (decoding the error code), the
+//! control and segment registers, and the bytes at the faulting instruction,
+//! then stop. Traps like `int3` just print and carry on.
+
+use super::skip_faulting_msr;
+use crate::{gdt, hlt_loop, memory, print, println};
+use core::{arch::asm, fmt};
+use x86_64::{
+    instructions::segmentation::{Segment, DS, ES, FS, GS},
+    registers::control::{Cr0, Cr3, Cr4},
+    structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode},
+    VirtAddr,
+};
+
+/// Mnemonics and names, by vector. Reserved vectors are empty.
+const EXCEPTIONS: [(&str, &str); 32] = [
+    ("#DE", "Divide Error"),
+    ("#DB", "Debug"),
+    ("NMI", "Non-Maskable Interrupt"),
+    ("#BP", "Breakpoint"),
+    ("#OF", "Overflow"),
+    ("#BR", "Bound Range Exceeded"),
+    ("#UD", "Invalid Opcode"),
+    ("#NM", "Device Not Available"),
+    ("#DF", "Double Fault"),
+    ("", "Coprocessor Segment Overrun"),
+    ("#TS", "Invalid TSS"),
+    ("#NP", "Segment Not Present"),
+    ("#SS", "Stack-Segment Fault"),
+    ("#GP", "General Protection Fault"),
+    ("#PF", "Page Fault"),
+    ("", ""),
+    ("#MF", "x87 Floating-Point Exception"),
+    ("#AC", "Alignment Check"),
+    ("#MC", "Machine Check"),
+    ("#XM", "SIMD Floating-Point Exception"),
+    ("#VE", "Virtualization Exception"),
+    ("#CP", "Control Protection Exception"),
+    ("", ""),
+    ("", ""),
+    ("", ""),
+    ("", ""),
+    ("", ""),
+    ("", ""),
+    ("#HV", "Hypervisor Injection Exception"),
+    ("#VC", "VMM Communication Exception"),
+    ("#SX", "Security Exception"),
+    ("", ""),
+];
+
+/// How many bytes of the faulting instruction to show. No instruction is
+/// longer than this.
+const INSTRUCTION_BYTES: usize = 15;
+
+/// Fills in every exception entry of `idt`, except vector 9, which is reserved
+/// (the crate doesn't expose it).
+pub(super) fn install(idt: &mut InterruptDescriptorTable) {
+    idt.divide_error.set_handler_fn(divide_error_handler);
+    idt.debug.set_handler_fn(debug_handler);
+    idt.non_maskable_interrupt
+        .set_handler_fn(non_maskable_interrupt_handler);
+    idt.breakpoint.set_handler_fn(breakpoint_handler);
+    idt.overflow.set_handler_fn(overflow_handler);
+    idt.bound_range_exceeded
+        .set_handler_fn(bound_range_exceeded_handler);
+    idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+    idt.device_not_available
+        .set_handler_fn(device_not_available_handler);
+    unsafe {
+        idt.double_fault
+            .set_handler_fn(double_fault_handler)
+            .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+    }
+    idt.invalid_tss.set_handler_fn(invalid_tss_handler);
+    idt.segment_not_present
+        .set_handler_fn(segment_not_present_handler);
+    idt.stack_segment_fault
+        .set_handler_fn(stack_segment_fault_handler);
+    idt.general_protection_fault
+        .set_handler_fn(general_protection_fault_handler);
+    idt.page_fault.set_handler_fn(page_fault_handler);
+    idt.x87_floating_point
+        .set_handler_fn(x87_floating_point_handler);
+    idt.alignment_check.set_handler_fn(alignment_check_handler);
+    idt.machine_check.set_handler_fn(machine_check_handler);
+    idt.simd_floating_point
+        .set_handler_fn(simd_floating_point_handler);
+    idt.virtualization.set_handler_fn(virtualization_handler);
+    idt.cp_protection_exception
+        .set_handler_fn(control_protection_handler);
+    idt.hv_injection_exception
+        .set_handler_fn(hypervisor_injection_handler);
+    idt.vmm_communication_exception
+        .set_handler_fn(vmm_communication_handler);
+    idt.security_exception
+        .set_handler_fn(security_exception_handler);
+}
+
+/// A #TS, #NP, #SS or #GP error code, which names the selector (or IDT
+/// entry) that caused it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SelectorError(pub u64);
+
+impl fmt::Display for SelectorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0 == 0 {
+            return write!(f, "not caused by a selector");
+        }
+        let index = (self.0 >> 3) & 0x1FFF;
+        match (self.0 >> 1) & 0b11 {
+            0b00 => write!(f, "GDT selector {:#x}", index << 3)?,
+            0b10 => write!(f, "LDT selector {:#x}", index << 3 | 0b100)?,
+            _ => write!(f, "IDT vector {:#x}", index)?,
+        }
+        if self.0 & 1 != 0 {
+            write!(f, ", during an external event")?;
+        }
+        Ok(())
+    }
+}
+
+/// A page fault error code, spelled out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageFaultError(pub PageFaultErrorCode);
+
+impl fmt::Display for PageFaultError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let code = self.0;
+        let access = if code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+            "fetch"
+        } else if code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+            "write"
+        } else {
+            "read"
+        };
+        let mode = if code.contains(PageFaultErrorCode::USER_MODE) {
+            "user"
+        } else {
+            "kernel"
+        };
+        let cause = if code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+            "protection violation"
+        } else {
+            "page not present"
+        };
+        write!(f, "{} {}, {}", mode, access, cause)?;
+        if code.contains(PageFaultErrorCode::MALFORMED_TABLE) {
+            write!(f, ", reserved bit set in a page table")?;
+        }
+        Ok(())
+    }
+}
+
+/// CR2 holds the address of the last page fault. Read directly, since
+/// `Cr2::read` won't take a non-canonical address.
+fn cr2() -> u64 {
+    let value: u64;
+    unsafe { asm!("mov {}, cr2", out(reg) value, options(nomem, nostack, preserves_flags)) };
+    value
+}
+
+/// Prints the exception, its error code and the machine state.
+fn report(vector: u8, stack_frame: &InterruptStackFrame, error_code: Option<u64>) {
+    let (mnemonic, name) = EXCEPTIONS[usize::from(vector)];
+    println!("Exception: {} ({}, vector {})", name, mnemonic, vector);
+    match (vector, error_code) {
+        (14, Some(code)) => {
+            println!("Accessed Address: {:#x}", cr2());
+            println!(
+                "Error Code: {:#x} ({})",
+                code,
+                PageFaultError(PageFaultErrorCode::from_bits_truncate(code))
+            );
+        }
+        (10..=13, Some(code)) => println!("Error Code: {:#x} ({})", code, SelectorError(code)),
+        (_, Some(code)) => println!("Error Code: {:#x}", code),
+        (_, None) => {}
+    }
+
+    println!(
+        "RIP: {:#018x}  CS: {:#x}  RFLAGS: {:#x}",
+        stack_frame.instruction_pointer.as_u64(),
+        stack_frame.code_segment,
+        stack_frame.cpu_flags
+    );
+    println!(
+        "RSP: {:#018x}  SS: {:#x}  DS: {:#x}  ES: {:#x}  FS: {:#x}  GS: {:#x}",
+        stack_frame.stack_pointer.as_u64(),
+        stack_frame.stack_segment,
+        DS::get_reg().0,
+        ES::get_reg().0,
+        FS::get_reg().0,
+        GS::get_reg().0
+    );
+    let (cr3_frame, cr3_flags) = Cr3::read_raw();
+    println!(
+        "CR0: {:#x}  CR2: {:#x}  CR3: {:#x}  CR4: {:#x}",
+        Cr0::read_raw(),
+        cr2(),
+        cr3_frame.start_address().as_u64() | u64::from(cr3_flags),
+        Cr4::read_raw()
+    );
+    print_instruction(stack_frame.instruction_pointer);
+}
+
+/// Prints the bytes at `rip`, if they can be read without faulting again.
+fn print_instruction(rip: VirtAddr) {
+    if memory::check_range(rip, INSTRUCTION_BYTES as u64, false).is_err() {
+        println!("Instruction: (not mapped)");
+        return;
+    }
+    let bytes = unsafe { core::slice::from_raw_parts(rip.as_ptr::<u8>(), INSTRUCTION_BYTES) };
+    print!("Instruction:");
+    for byte in bytes {
+        print!(" {:02x}", byte);
+    }
+    println!();
+}
+
+/// Reports an exception the kernel can't carry on from, and stops.
+fn fatal(vector: u8, stack_frame: &InterruptStackFrame, error_code: Option<u64>) -> ! {
+    println!("(X_X)\n");
+    report(vector, stack_frame, error_code);
+    hlt_loop();
+}
+
+extern "x86-interrupt" fn divide_error_handler(stack_frame: InterruptStackFrame) {
+    fatal(0, &stack_frame, None);
+}
+
+extern "x86-interrupt" fn debug_handler(stack_frame: InterruptStackFrame) {
+    report(1, &stack_frame, None);
+}
+
+extern "x86-interrupt" fn non_maskable_interrupt_handler(stack_frame: InterruptStackFrame) {
+    println!("(0_0)");
+    report(2, &stack_frame, None);
+}
+
+extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
+    report(3, &stack_frame, None);
+}
+
+extern "x86-interrupt" fn overflow_handler(stack_frame: InterruptStackFrame) {
+    report(4, &stack_frame, None);
+}
+
+extern "x86-interrupt" fn bound_range_exceeded_handler(stack_frame: InterruptStackFrame) {
+    fatal(5, &stack_frame, None);
+}
+
+extern "x86-interrupt" fn invalid_opcode_handler(stack_frame: InterruptStackFrame) {
+    fatal(6, &stack_frame, None);
+}
+
+extern "x86-interrupt" fn device_not_available_handler(stack_frame: InterruptStackFrame) {
+    fatal(7, &stack_frame, None);
+}
+
+extern "x86-interrupt" fn double_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) -> ! {
+    report(8, &stack_frame, Some(error_code));
+    panic!("Exception: Double Fault");
+}
+
+extern "x86-interrupt" fn invalid_tss_handler(stack_frame: InterruptStackFrame, error_code: u64) {
+    fatal(10, &stack_frame, Some(error_code));
+}
+
+extern "x86-interrupt" fn segment_not_present_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    fatal(11, &stack_frame, Some(error_code));
+}
+
+extern "x86-interrupt" fn stack_segment_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    fatal(12, &stack_frame, Some(error_code));
+}
+
+extern "x86-interrupt" fn general_protection_fault_handler(
+    mut stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    if skip_faulting_msr(&mut stack_frame) {
+        return;
+    }
+    fatal(13, &stack_frame, Some(error_code));
+}
+
+extern "x86-interrupt" fn page_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    fatal(14, &stack_frame, Some(error_code.bits()));
+}
+
+extern "x86-interrupt" fn x87_floating_point_handler(stack_frame: InterruptStackFrame) {
+    fatal(16, &stack_frame, None);
+}
+
+extern "x86-interrupt" fn alignment_check_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    fatal(17, &stack_frame, Some(error_code));
+}
+
+extern "x86-interrupt" fn machine_check_handler(stack_frame: InterruptStackFrame) -> ! {
+    fatal(18, &stack_frame, None);
+}
+
+extern "x86-interrupt" fn simd_floating_point_handler(stack_frame: InterruptStackFrame) {
+    fatal(19, &stack_frame, None);
+}
+
+extern "x86-interrupt" fn virtualization_handler(stack_frame: InterruptStackFrame) {
+    fatal(20, &stack_frame, None);
+}
+
+extern "x86-interrupt" fn control_protection_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    fatal(21, &stack_frame, Some(error_code));
+}
+
+extern "x86-interrupt" fn hypervisor_injection_handler(stack_frame: InterruptStackFrame) {
+    fatal(28, &stack_frame, None);
+}
+
+extern "x86-interrupt" fn vmm_communication_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    fatal(29, &stack_frame, Some(error_code));
+}
+
+extern "x86-interrupt" fn security_exception_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    fatal(30, &stack_frame, Some(error_code));
+}
+
+#[test_case]
+fn test_decode_error_codes() {
+    use alloc::format;
+
+    assert_eq!(format!("{}", SelectorError(0)), "not caused by a selector");
+    assert_eq!(format!("{}", SelectorError(0x10)), "GDT selector 0x10");
+    assert_eq!(format!("{}", SelectorError(0x1C)), "LDT selector 0x1c");
+    assert_eq!(
+        format!("{}", SelectorError(0x0D << 3 | 0b11)),
+        "IDT vector 0xd, during an external event"
+    );
+    assert_eq!(
+        format!(
+            "{}",
+            PageFaultError(PageFaultErrorCode::from_bits_truncate(0b0011))
+        ),
+        "kernel write, protection violation"
+    );
+    assert_eq!(
+        format!(
+            "{}",
+            PageFaultError(PageFaultErrorCode::from_bits_truncate(0b10100))
+        ),
+        "user fetch, page not present"
+    );
+}
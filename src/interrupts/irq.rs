@@ -0,0 +1,286 @@
+//! Interrupt handlers that drivers register at runtime.
+//!
+//! Every vector from 32 up has a stub in the IDT that counts the interrupt,
+//! calls whatever handlers are registered for it, and acknowledges it. More
+//! than one handler can share a vector (PCI INTx lines are often shared), in
+//! which case they're all called.
+//!
+//! The table is a fixed size so handlers can be registered before the heap
+//! is up, and so the stubs never allocate.
+
+use super::{
+    apic, end_of_interrupt, is_spurious_pic_irq, set_irq_enabled, PIC_1_OFFSET, PIC_2_OFFSET,
+};
+use alloc::vec::Vec;
+use core::{
+    ops::Range,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use spin::Mutex;
+use x86_64::{
+    instructions::interrupts,
+    structures::idt::{InterruptDescriptorTable, InterruptStackFrame},
+};
+
+/// What gets called when the interrupt comes in. The stub acknowledges it
+/// afterwards, so handlers mustn't.
+///
+/// Must not block or allocate.
+pub type Handler = fn();
+
+/// How many handlers can be registered at once, over every vector.
+const MAX_HANDLERS: usize = 64;
+/// How many ISA IRQs there are.
+const ISA_IRQS: u8 = 16;
+/// Vectors `allocate_vector` hands out, above the ISA IRQs and below the
+/// local APIC's own.
+const DYNAMIC_VECTORS: Range<u8> = PIC_2_OFFSET + 8..0xF0;
+
+/// Something `register_irq` or `register_vector` handed out, to give back to
+/// `unregister`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct HandlerId(u64);
+
+#[derive(Clone, Copy)]
+struct Registration {
+    id: HandlerId,
+    vector: u8,
+    name: &'static str,
+    handler: Handler,
+}
+
+/// Only ever locked with interrupts off, so a stub can always take it (and
+/// never has to drop an interrupt because it's busy).
+static HANDLERS: Mutex<[Option<Registration>; MAX_HANDLERS]> = Mutex::new([None; MAX_HANDLERS]);
+
+/// How many times each vector has come in.
+static COUNTS: [AtomicU64; 256] = {
+    const ZERO: AtomicU64 = AtomicU64::new(0);
+    [ZERO; 256]
+};
+
+/// A vector, for `irqstat`.
+#[derive(Debug, Clone, Copy)]
+pub struct VectorStat {
+    pub vector: u8,
+    /// The ISA IRQ it's for, if it is one.
+    pub irq: Option<u8>,
+    pub count: u64,
+}
+
+extern "x86-interrupt" fn stub<const VECTOR: u8>(_stack_frame: InterruptStackFrame) {
+    dispatch(VECTOR);
+}
+
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {}
+
+/// Puts `stub::<VECTOR>` in `$idt` for each vector `16 * high + low`.
+macro_rules! install_stubs {
+    ($idt:ident; $($high:literal)*) => {
+        $(install_stubs!(@row $idt, $high; 0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15);)*
+    };
+    (@row $idt:ident, $high:literal; $($low:literal)*) => {
+        $($idt[$high * 16 + $low].set_handler_fn(stub::<{ $high * 16 + $low }>);)*
+    };
+}
+
+/// Fills in every vector from 32 up.
+pub(super) fn install(idt: &mut InterruptDescriptorTable) {
+    install_stubs!(idt; 2 3 4 5 6 7 8 9 10 11 12 13 14 15);
+    // these mustn't be acknowledged, so they get a handler of their own
+    idt[usize::from(apic::SPURIOUS_VECTOR)].set_handler_fn(spurious_interrupt_handler);
+}
+
+fn dispatch(vector: u8) {
+    COUNTS[usize::from(vector)].fetch_add(1, Ordering::Relaxed);
+    if is_spurious_pic_irq(vector) {
+        return;
+    }
+    // copied out, so handlers run without it locked (and with interrupts off
+    // even when a test calls this directly)
+    let handlers = interrupts::without_interrupts(|| *HANDLERS.lock());
+    for registration in handlers.iter().flatten() {
+        if registration.vector == vector {
+            (registration.handler)();
+        }
+    }
+    end_of_interrupt(vector);
+}
+
+fn is_reserved(vector: u8) -> bool {
+    vector < 32 || vector == apic::SPURIOUS_VECTOR
+}
+
+/// The ISA IRQ a vector is for, if it's one of theirs.
+pub fn irq_of(vector: u8) -> Option<u8> {
+    if (PIC_1_OFFSET..PIC_1_OFFSET + ISA_IRQS).contains(&vector) {
+        Some(vector - PIC_1_OFFSET)
+    } else {
+        None
+    }
+}
+
+/// Calls `handler` whenever `vector` comes in, alongside anything already
+/// registered for it.
+pub fn register_vector(
+    vector: u8,
+    name: &'static str,
+    handler: Handler,
+) -> Result<HandlerId, &'static str> {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+    if is_reserved(vector) {
+        return Err("that vector is reserved");
+    }
+    let id = HandlerId(NEXT_ID.fetch_add(1, Ordering::Relaxed));
+    interrupts::without_interrupts(|| {
+        let mut handlers = HANDLERS.lock();
+        let slot = handlers
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or("too many interrupt handlers")?;
+        *slot = Some(Registration {
+            id,
+            vector,
+            name,
+            handler,
+        });
+        Ok(id)
+    })
+}
+
+/// Calls `handler` whenever ISA IRQ `irq` comes in, and unmasks it.
+pub fn register_irq(
+    irq: u8,
+    name: &'static str,
+    handler: Handler,
+) -> Result<HandlerId, &'static str> {
+    if irq >= ISA_IRQS {
+        return Err("there are only 16 ISA IRQs");
+    }
+    let id = register_vector(PIC_1_OFFSET + irq, name, handler)?;
+    set_irq_enabled(irq, true);
+    Ok(id)
+}
+
+/// Finds a vector nobody's using and registers `handler` for it, for devices
+/// that can be told which vector to use (like MSIs).
+pub fn allocate_vector(
+    name: &'static str,
+    handler: Handler,
+) -> Result<(u8, HandlerId), &'static str> {
+    let (vector, ids) = allocate_vectors(name, &[handler], 1)?;
+    Ok((vector, ids[0]))
+}
+
+/// Like `allocate_vector`, for a run of vectors in a row, one for each of
+/// `handlers`. The first is a multiple of `align`, which multiple-message MSI
+/// needs. Returns the first vector.
+pub fn allocate_vectors(
+    name: &'static str,
+    handlers: &[Handler],
+    align: u8,
+) -> Result<(u8, Vec<HandlerId>), &'static str> {
+    if handlers.is_empty() || align == 0 {
+        return Err("that's no vectors at all");
+    }
+    let count = handlers.len();
+    interrupts::without_interrupts(|| {
+        let first = DYNAMIC_VECTORS
+            .clone()
+            .filter(|&first| first % align == 0)
+            .find(|&first| {
+                (0..count).all(|i| {
+                    let vector = usize::from(first) + i;
+                    vector < usize::from(DYNAMIC_VECTORS.end)
+                        && self::handlers(vector as u8).next().is_none()
+                })
+            })
+            .ok_or("not enough vectors are free")?;
+        let mut ids = Vec::with_capacity(count);
+        for (vector, &handler) in (first..).zip(handlers) {
+            match register_vector(vector, name, handler) {
+                Ok(id) => ids.push(id),
+                Err(e) => {
+                    ids.into_iter().for_each(unregister);
+                    return Err(e);
+                }
+            }
+        }
+        Ok((first, ids))
+    })
+}
+
+/// Stops calling a handler. If it was the last one on an ISA IRQ, the IRQ
+/// gets masked again.
+pub fn unregister(id: HandlerId) {
+    interrupts::without_interrupts(|| {
+        let vector = {
+            let mut handlers = HANDLERS.lock();
+            let slot = handlers
+                .iter_mut()
+                .find(|slot| slot.map_or(false, |registration| registration.id == id));
+            match slot {
+                Some(slot) => slot.take().map(|registration| registration.vector),
+                None => None,
+            }
+        };
+        if let Some(vector) = vector {
+            if let Some(irq) = irq_of(vector) {
+                if handlers(vector).next().is_none() {
+                    set_irq_enabled(irq, false);
+                }
+            }
+        }
+    });
+}
+
+/// The names of the handlers registered for `vector`.
+pub fn handlers(vector: u8) -> impl Iterator<Item = &'static str> {
+    let handlers = interrupts::without_interrupts(|| *HANDLERS.lock());
+    IntoIterator::into_iter(handlers)
+        .flatten()
+        .filter(move |registration| registration.vector == vector)
+        .map(|registration| registration.name)
+}
+
+/// How many times `vector` has come in since boot.
+pub fn count(vector: u8) -> u64 {
+    COUNTS[usize::from(vector)].load(Ordering::Relaxed)
+}
+
+/// Every vector that has a handler or has come in at least once.
+pub fn stats() -> impl Iterator<Item = VectorStat> {
+    (32..=255u8)
+        .filter(|&vector| count(vector) > 0 || handlers(vector).next().is_some())
+        .map(|vector| VectorStat {
+            vector,
+            irq: irq_of(vector),
+            count: count(vector),
+        })
+}
+
+#[test_case]
+fn test_register_and_unregister() {
+    use core::sync::atomic::AtomicBool;
+
+    static CALLED: AtomicBool = AtomicBool::new(false);
+    fn handler() {
+        CALLED.store(true, Ordering::SeqCst);
+    }
+
+    assert!(register_vector(apic::SPURIOUS_VECTOR, "test", handler).is_err());
+    let (vector, id) = allocate_vector("test", handler).unwrap();
+    assert!(DYNAMIC_VECTORS.contains(&vector));
+    let shared = register_vector(vector, "shared", handler).unwrap();
+    assert_eq!(handlers(vector).count(), 2);
+
+    let before = count(vector);
+    dispatch(vector);
+    assert!(CALLED.load(Ordering::SeqCst));
+    assert_eq!(count(vector), before + 1);
+
+    unregister(id);
+    unregister(shared);
+    assert_eq!(handlers(vector).next(), None);
+}
@@ -15,8 +15,11 @@ pub mod base64;
 pub mod cmos;
 pub mod command_line;
 pub mod disks;
+pub mod editor;
+pub mod framebuffer;
 pub mod fs;
 pub mod gdt;
+pub mod gui;
 pub mod hashmaps;
 pub mod interrupts;
 pub mod memory;
@@ -26,12 +29,15 @@ pub mod serial;
 pub mod sorting;
 pub mod spinlock;
 pub mod task;
+pub mod time;
 pub mod vga_buffer; // ... it should be called ACPI'm going to bash my skull into my wall for the 28th time today watching these builds fail...
 
 pub fn init() {
     gdt::init();
     interrupts::init_idt();
     unsafe { interrupts::PICS.lock().initialize() };
+    time::init();
+    task::keyboard::init();
     x86_64::instructions::interrupts::enable();
 }
 pub trait Testable {
@@ -95,8 +101,18 @@ entry_point!(test_kernel_main);
 
 /// Entry point for `cargo xtest`
 #[cfg(test)]
-fn test_kernel_main(_boot_info: &'static BootInfo) -> ! {
+fn test_kernel_main(boot_info: &'static BootInfo) -> ! {
+    use memory::BootInfoFrameAllocator;
+    use x86_64::VirtAddr;
+
     init();
+    // unit tests allocate too
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe {
+        memory::init(phys_mem_offset);
+        BootInfoFrameAllocator::init(&boot_info.memory_map);
+    }
+    allocator::init_heap().expect("heap initialization failed");
     test_main();
     hlt_loop();
 }
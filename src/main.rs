@@ -19,9 +19,13 @@ use lemonade::{
     base64,
     cmos::*,
     command_line::run_command_line,
+    fs,
+    interrupts,
     println,
     sorting::quicksort,
-    task::{executor::Executor, Task},
+    task::{executor::Executor, keyboard, mouse, Task},
+    time,
+    vga_buffer::{self, console},
 };
 
 entry_point!(kernel_main);
@@ -38,22 +42,48 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     println!("Current time is: {}", time);
 
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    unsafe { memory::init(phys_mem_offset) };
     let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
 
-    allocator::init_heap(&mut mapper, &mut frame_allocator)
-        .expect("(X_X)\n\nHeap initialization failed.");
+    allocator::init_heap().expect("(X_X)\n\nHeap initialization failed.");
 
-    unsafe {
-        acpi::map_acpi_region(&mut mapper, &mut frame_allocator);
+    vga_buffer::enable_scrollback(vga_buffer::DEFAULT_SCROLLBACK_LINES);
+    match interrupts::apic::init() {
+        Ok(()) => println!("Interrupts go through the APIC."),
+        Err(e) => println!("(0_0)  [apic]: {}, so the PICs are staying.", e),
     }
+    match time::hpet::init() {
+        Ok(()) => println!("HPET found, with {} comparators.", time::hpet::comparators().len()),
+        Err(e) => println!("(0_0)  [hpet]: {}", e),
+    }
+    match time::calibrate() {
+        Ok(()) => println!("Clock source: {}", time::clock_source().name()),
+        Err(e) => println!("(0_0)  [time]: {}", e),
+    }
+    keyboard::update_leds();
+    match mouse::init() {
+        Ok(true) => println!("Mouse ready, with a scroll wheel."),
+        Ok(false) => println!("Mouse ready."),
+        Err(e) => println!("(0_0)  [mouse]: {}", e),
+    }
+    fs::vfs::init();
+
+    memory::with_mapper(|mapper| unsafe {
+        acpi::map_acpi_region(mapper, &mut frame_allocator);
+    });
 
 
     #[cfg(test)]
     test_main();
 
     let mut executor = Executor::new();
-    executor.spawn(Task::new(run_command_line()));
+    let spawner = executor.spawner();
+    // one shell on each console, Alt+F1..F6 to switch between them
+    for console in 0..console::CONSOLE_COUNT {
+        executor.spawn(Task::on_console(console, run_command_line(spawner.clone())));
+    }
+    executor.spawn(Task::new(keyboard::dispatch::run()));
+    executor.spawn(Task::new(mouse::run_pointer()));
     executor.run();
 }
 
@@ -1,18 +1,54 @@
-use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use bootloader::bootinfo::MemoryMap;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
 use x86_64::{
-    structures::paging::{FrameAllocator, OffsetPageTable, PageTable, PhysFrame, Size4KiB},
+    instructions::interrupts,
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags,
+        PhysFrame, Size4KiB,
+    },
     PhysAddr, VirtAddr,
 };
 
-/// Initialize a new OffsetPageTable.
+pub mod frames;
+
+/// Where the bootloader mapped physical memory. 0 until `init` is called.
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/// Where `map_mmio` puts device registers. Every mapping gets addresses of
+/// its own, which aren't reused after `unmap_mmio`.
+const MMIO_START: u64 = 0x_5555_0000_0000;
+static NEXT_MMIO: AtomicU64 = AtomicU64::new(MMIO_START);
+
+/// The one OffsetPageTable, once `init` has made it. Only locked with
+/// interrupts off, so nothing can change the page tables in the middle of
+/// someone else's `map_to`.
+static MAPPER: Mutex<Option<OffsetPageTable<'static>>> = Mutex::new(None);
+
+/// Initialize the OffsetPageTable `with_mapper` hands out.
 ///
 /// This function is unsafe because the caller must guarantee that the
 /// complete physical memory is mapped to virtual memory at the passed
 /// `physical_memory_offset`. Also, this function must be only called once
 /// to avoid aliasing `&mut` references (which is undefined behavior).
-pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+pub unsafe fn init(physical_memory_offset: VirtAddr) {
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::SeqCst);
     let level_4_table = active_level_4_table(physical_memory_offset);
-    OffsetPageTable::new(level_4_table, physical_memory_offset)
+    let mapper = OffsetPageTable::new(level_4_table, physical_memory_offset);
+    interrupts::without_interrupts(|| *MAPPER.lock() = Some(mapper));
+}
+
+/// Runs `f` on the page tables. Returns `None` before `init`.
+///
+/// `f` mustn't allocate, since the heap needs the page tables to grow.
+pub fn with_mapper<R>(f: impl FnOnce(&mut OffsetPageTable<'static>) -> R) -> Option<R> {
+    interrupts::without_interrupts(|| MAPPER.lock().as_mut().map(f))
+}
+
+/// Like `with_mapper`, but gives up if the page tables are already in use
+/// (which, with interrupts off while they are, means by the caller).
+pub fn try_with_mapper<R>(f: impl FnOnce(&mut OffsetPageTable<'static>) -> R) -> Option<R> {
+    interrupts::without_interrupts(|| MAPPER.try_lock()?.as_mut().map(f))
 }
 
 /// Returns a mutable reference to the active level 4 table.
@@ -33,6 +69,153 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut
     &mut *page_table_ptr // unsafe
 }
 
+/// Returns the offset physical memory is mapped at, or `None` before `init`.
+pub fn physical_memory_offset() -> Option<VirtAddr> {
+    match PHYSICAL_MEMORY_OFFSET.load(Ordering::SeqCst) {
+        0 => None,
+        offset => Some(VirtAddr::new(offset)),
+    }
+}
+
+/// A virtual address that's mapped, and what it's mapped to.
+#[derive(Debug, Clone, Copy)]
+pub struct Translation {
+    pub phys: PhysAddr,
+    /// The flags of every level ANDed together (except `NO_EXECUTE`, which is ORed),
+    /// so `WRITABLE` and `USER_ACCESSIBLE` mean what they say.
+    pub flags: PageTableFlags,
+    /// Size of the page `phys` is in: 4KiB, 2MiB or 1GiB.
+    pub page_size: u64,
+}
+
+/// Walks the active page tables to translate `addr`, without ever faulting.
+///
+/// Returns `None` if `addr` isn't mapped, or if `init` hasn't been called yet.
+pub fn translate(addr: VirtAddr) -> Option<Translation> {
+    use x86_64::registers::control::Cr3;
+
+    let offset = physical_memory_offset()?;
+    let (level_4_table_frame, _) = Cr3::read();
+    let indexes = [
+        addr.p4_index(),
+        addr.p3_index(),
+        addr.p2_index(),
+        addr.p1_index(),
+    ];
+
+    let mut table_addr = level_4_table_frame.start_address();
+    let mut flags = PageTableFlags::all() - PageTableFlags::NO_EXECUTE;
+    for (level, &index) in indexes.iter().enumerate() {
+        // the page tables themselves are always reachable through the offset mapping
+        let table = unsafe { &*(offset + table_addr.as_u64()).as_ptr::<PageTable>() };
+        let entry = &table[index];
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            return None;
+        }
+        flags &= entry.flags() | PageTableFlags::NO_EXECUTE;
+        flags |= entry.flags() & PageTableFlags::NO_EXECUTE;
+
+        // level 1 (P3) can map 1GiB pages, level 2 (P2) can map 2MiB pages
+        let huge = entry.flags().contains(PageTableFlags::HUGE_PAGE);
+        if level == 3 || (huge && (level == 1 || level == 2)) {
+            let page_size = 4096u64 << (9 * (3 - level));
+            let phys = entry.addr() + (addr.as_u64() & (page_size - 1));
+            return Some(Translation {
+                phys,
+                flags,
+                page_size,
+            });
+        }
+        table_addr = entry.addr();
+    }
+    unreachable!()
+}
+
+/// Checks that every byte of `start..start + len` is mapped (and writable, if
+/// `write` is set), so it can be accessed without a page fault.
+///
+/// On failure, returns the first address that can't be accessed.
+pub fn check_range(start: VirtAddr, len: u64, write: bool) -> Result<(), VirtAddr> {
+    if len == 0 {
+        return Ok(());
+    }
+    let end = start
+        .as_u64()
+        .checked_add(len - 1)
+        .ok_or(VirtAddr::new_truncate(u64::MAX))?;
+    let mut addr = start.as_u64();
+    loop {
+        let virt = VirtAddr::try_new(addr).map_err(|_| VirtAddr::new_truncate(addr))?;
+        let translation = translate(virt).ok_or(virt)?;
+        if write && !translation.flags.contains(PageTableFlags::WRITABLE) {
+            return Err(virt);
+        }
+        // jump to the start of the next page
+        let next = (addr & !(translation.page_size - 1)).checked_add(translation.page_size);
+        match next {
+            Some(next) if next <= end => addr = next,
+            _ => return Ok(()),
+        }
+    }
+}
+
+/// Maps `len` bytes of device registers at `phys` uncached, the way MMIO
+/// needs them (the physical memory mapping is cached, and might not reach
+/// that far). Returns where `phys` ends up.
+pub fn map_mmio(phys: PhysAddr, len: u64) -> Result<VirtAddr, &'static str> {
+    let first = PhysFrame::<Size4KiB>::containing_address(phys);
+    let last = PhysFrame::<Size4KiB>::containing_address(phys + (len.max(1) - 1));
+    let pages = (last.start_address() - first.start_address()) / 4096 + 1;
+    let start = VirtAddr::new(NEXT_MMIO.fetch_add(pages * 4096, Ordering::SeqCst));
+
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE;
+    let mapped = with_mapper(|mapper| {
+        let mut frame_allocator = BootInfoFrameAllocator::handle();
+        for (i, frame) in PhysFrame::range_inclusive(first, last).enumerate() {
+            let page = Page::containing_address(start + i as u64 * 4096);
+            match unsafe { mapper.map_to(page, frame, flags, &mut frame_allocator) } {
+                Ok(flush) => flush.flush(),
+                Err(_) => return i as u64,
+            }
+        }
+        pages
+    })
+    .ok_or("the page tables haven't been set up")?;
+    if mapped < pages {
+        unmap_mmio(start, mapped * 4096);
+        return Err("couldn't map the registers");
+    }
+    Ok(start + (phys - first.start_address()))
+}
+
+/// Unmaps registers `map_mmio` mapped. The frames are the device's, so
+/// they aren't given back.
+pub fn unmap_mmio(virt: VirtAddr, len: u64) {
+    if len == 0 {
+        return;
+    }
+    let first = Page::<Size4KiB>::containing_address(virt);
+    let last = Page::<Size4KiB>::containing_address(virt + (len - 1));
+    with_mapper(|mapper| {
+        for page in Page::range_inclusive(first, last) {
+            if let Ok((_, flush)) = mapper.unmap(page) {
+                flush.flush();
+            }
+        }
+    });
+}
+
+/// Returns where physical address `phys` can be accessed, if it's mapped.
+pub fn phys_to_virt(phys: PhysAddr) -> Option<VirtAddr> {
+    let virt = VirtAddr::try_new(
+        physical_memory_offset()?
+            .as_u64()
+            .checked_add(phys.as_u64())?,
+    )
+    .ok()?;
+    translate(virt).map(|_| virt)
+}
+
 /// A FrameAllocator that always returns `None`.
 pub struct EmptyFrameAllocator;
 
@@ -42,10 +225,10 @@ unsafe impl FrameAllocator<Size4KiB> for EmptyFrameAllocator {
     }
 }
 
-/// A FrameAllocator that returns usable frames from the bootloader's memory map.
+/// A FrameAllocator that hands out (and takes back) frames from the bitmap in
+/// `frames`, which it builds from the bootloader's memory map.
 pub struct BootInfoFrameAllocator {
-    memory_map: &'static MemoryMap,
-    next: usize,
+    _private: (),
 }
 
 impl BootInfoFrameAllocator {
@@ -53,32 +236,27 @@ impl BootInfoFrameAllocator {
     ///
     /// This function is unsafe because the caller must guarantee that the passed
     /// memory map is valid. The main requirement is that all frames that are marked
-    /// as `USABLE` in it are really unused.
+    /// as `USABLE` in it are really unused. `init` has to have been called too.
     pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
-        BootInfoFrameAllocator {
-            memory_map,
-            next: 0,
-        }
+        frames::init(memory_map);
+        BootInfoFrameAllocator { _private: () }
     }
 
-    /// Returns an iterator over the usable frames specified in the memory map.
-    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
-        // get usable regions from memory map
-        let regions = self.memory_map.iter();
-        let usable_regions = regions.filter(|r| r.region_type == MemoryRegionType::Usable);
-        // map each region to its address range
-        let addr_ranges = usable_regions.map(|r| r.range.start_addr()..r.range.end_addr());
-        // transform to an iterator of frame start addresses
-        let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
-        // create `PhysFrame` types from the start addresses
-        frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+    /// Another handle to the bitmap `init` built. Allocating through it
+    /// before then just fails.
+    pub fn handle() -> Self {
+        BootInfoFrameAllocator { _private: () }
     }
 }
 
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        let frame = self.usable_frames().nth(self.next);
-        self.next += 1;
-        frame
+        frames::allocate()
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        frames::deallocate(frame);
     }
 }
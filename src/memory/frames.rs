@@ -0,0 +1,367 @@
+//! The physical memory manager: a bitmap with a bit for every frame up to
+//! the end of the last usable region, set while the frame is in use.
+//!
+//! The bitmap lives in the first usable region big enough for it, reached
+//! through the physical memory mapping, so it's there before the heap is.
+
+use super::phys_to_virt;
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::ops::Range;
+use spin::Mutex;
+use x86_64::{
+    instructions::interrupts,
+    structures::paging::{frame::PhysFrameRange, PhysFrame, Size4KiB},
+    PhysAddr,
+};
+
+const FRAME_SIZE: u64 = 4096;
+/// How many regions `reserve` can keep track of.
+const MAX_RESERVATIONS: usize = 16;
+
+/// One bit per frame, set when the frame is used (or isn't RAM at all).
+pub struct FrameBitmap<'a> {
+    words: &'a mut [u64],
+    frames: usize,
+    free: usize,
+    /// Where to start looking for a free frame.
+    next: usize,
+}
+
+impl<'a> FrameBitmap<'a> {
+    /// A bitmap for `frames` frames in `words`, all of them used.
+    pub fn new(words: &'a mut [u64], frames: usize) -> FrameBitmap<'a> {
+        assert!(
+            words.len() * 64 >= frames,
+            "(X_X)  [frames]: bitmap too small"
+        );
+        for word in words.iter_mut() {
+            *word = !0;
+        }
+        FrameBitmap {
+            words,
+            frames,
+            free: 0,
+            next: 0,
+        }
+    }
+
+    pub fn frames(&self) -> usize {
+        self.frames
+    }
+
+    pub fn free(&self) -> usize {
+        self.free
+    }
+
+    pub fn is_free(&self, frame: usize) -> bool {
+        frame < self.frames && self.words[frame / 64] & 1 << (frame % 64) == 0
+    }
+
+    /// Marks frames used, returning how many were free.
+    pub fn mark_used(&mut self, frames: Range<usize>) -> usize {
+        let mut marked = 0;
+        for frame in frames.start..frames.end.min(self.frames) {
+            if self.is_free(frame) {
+                self.words[frame / 64] |= 1 << (frame % 64);
+                marked += 1;
+            }
+        }
+        self.free -= marked;
+        marked
+    }
+
+    /// Marks frames free, returning how many were used.
+    pub fn mark_free(&mut self, frames: Range<usize>) -> usize {
+        let mut marked = 0;
+        for frame in frames.start..frames.end.min(self.frames) {
+            if !self.is_free(frame) {
+                self.words[frame / 64] &= !(1 << (frame % 64));
+                marked += 1;
+            }
+        }
+        self.free += marked;
+        self.next = self.next.min(frames.start);
+        marked
+    }
+
+    /// Takes the lowest free frame from where the last search left off.
+    pub fn allocate(&mut self) -> Option<usize> {
+        let words = self.words.len();
+        for i in 0..words {
+            let index = (self.next / 64 + i) % words;
+            let word = self.words[index];
+            if word == !0 {
+                continue;
+            }
+            let frame = index * 64 + word.trailing_ones() as usize;
+            if frame >= self.frames {
+                continue;
+            }
+            self.mark_used(frame..frame + 1);
+            self.next = frame + 1;
+            return Some(frame);
+        }
+        None
+    }
+
+    /// Takes `count` free frames in a row, starting at a multiple of `align`
+    /// frames (which has to be a power of 2).
+    pub fn allocate_contiguous(&mut self, count: usize, align: usize) -> Option<usize> {
+        if count == 0 || !align.is_power_of_two() {
+            return None;
+        }
+        let mut start = 0;
+        while start + count <= self.frames {
+            // skip past the last used frame in the way, if there is one
+            match (start..start + count)
+                .rev()
+                .find(|&frame| !self.is_free(frame))
+            {
+                Some(used) => start = (used + 1 + align - 1) & !(align - 1),
+                None => {
+                    self.mark_used(start..start + count);
+                    return Some(start);
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Frames and how they're being used, in frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameStats {
+    /// RAM the bootloader said was free for the kernel.
+    pub usable: usize,
+    pub free: usize,
+    /// Taken by `allocate` and friends.
+    pub used: usize,
+    /// Taken by `reserve`.
+    pub reserved: usize,
+}
+
+/// A physical range set aside with `reserve`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reservation {
+    pub start: PhysAddr,
+    pub end: PhysAddr,
+    pub name: &'static str,
+    /// How many of its frames were usable RAM, and so taken out of the pool.
+    pub frames: usize,
+}
+
+struct FrameManager {
+    bitmap: FrameBitmap<'static>,
+    usable: usize,
+    reservations: [Option<Reservation>; MAX_RESERVATIONS],
+}
+
+static FRAMES: Mutex<Option<FrameManager>> = Mutex::new(None);
+
+fn usable_frames(memory_map: &MemoryMap) -> impl Iterator<Item = Range<usize>> + '_ {
+    memory_map
+        .iter()
+        .filter(|region| region.region_type == MemoryRegionType::Usable)
+        .map(|region| {
+            let start = (region.range.start_addr() + FRAME_SIZE - 1) / FRAME_SIZE;
+            let end = region.range.end_addr() / FRAME_SIZE;
+            start as usize..end as usize
+        })
+}
+
+/// Builds the bitmap from the bootloader's memory map. Does nothing if it's
+/// already been built.
+///
+/// This function is unsafe because the caller must guarantee that the passed
+/// memory map is valid, and that `memory::init` has been called.
+pub unsafe fn init(memory_map: &'static MemoryMap) {
+    interrupts::without_interrupts(|| {
+        let mut frames = FRAMES.lock();
+        if frames.is_some() {
+            return;
+        }
+        let frame_count = usable_frames(memory_map).map(|r| r.end).max().unwrap_or(0);
+        let words = (frame_count + 63) / 64;
+        let bitmap_frames = (words * 8 + FRAME_SIZE as usize - 1) / FRAME_SIZE as usize;
+        let home = usable_frames(memory_map)
+            .find(|r| r.len() >= bitmap_frames)
+            .expect("(X_X)  [frames]: no room for the frame bitmap");
+        let virt = phys_to_virt(PhysAddr::new(home.start as u64 * FRAME_SIZE))
+            .expect("(X_X)  [frames]: physical memory isn't mapped");
+        let words = core::slice::from_raw_parts_mut(virt.as_mut_ptr::<u64>(), words);
+
+        let mut bitmap = FrameBitmap::new(words, frame_count);
+        for region in usable_frames(memory_map) {
+            bitmap.mark_free(region);
+        }
+        let usable = bitmap.free();
+        let mut manager = FrameManager {
+            bitmap,
+            usable,
+            reservations: [None; MAX_RESERVATIONS],
+        };
+        let start = home.start as u64 * FRAME_SIZE;
+        manager
+            .reserve(
+                start..start + bitmap_frames as u64 * FRAME_SIZE,
+                "frame bitmap",
+            )
+            .expect("(X_X)  [frames]: couldn't reserve the frame bitmap");
+        *frames = Some(manager);
+    });
+}
+
+impl FrameManager {
+    fn reserve(&mut self, range: Range<u64>, name: &'static str) -> Result<(), &'static str> {
+        let slot = self
+            .reservations
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or("too many reserved regions")?;
+        let frames = (range.start / FRAME_SIZE) as usize
+            ..((range.end + FRAME_SIZE - 1) / FRAME_SIZE) as usize;
+        *slot = Some(Reservation {
+            start: PhysAddr::new(range.start),
+            end: PhysAddr::new(range.end),
+            name,
+            frames: self.bitmap.mark_used(frames),
+        });
+        Ok(())
+    }
+}
+
+fn with_frames<T>(f: impl FnOnce(&mut FrameManager) -> T) -> Option<T> {
+    interrupts::without_interrupts(|| FRAMES.lock().as_mut().map(f))
+}
+
+fn frame_at(frame: usize) -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(frame as u64 * FRAME_SIZE))
+}
+
+fn index_of(frame: PhysFrame) -> usize {
+    (frame.start_address().as_u64() / FRAME_SIZE) as usize
+}
+
+/// Takes a free frame.
+pub fn allocate() -> Option<PhysFrame> {
+    with_frames(|frames| frames.bitmap.allocate())?.map(frame_at)
+}
+
+/// Gives a frame back.
+///
+/// This function is unsafe because the frame mustn't still be mapped or in
+/// use anywhere.
+pub unsafe fn deallocate(frame: PhysFrame) {
+    let freed = with_frames(|frames| {
+        let index = index_of(frame);
+        if index >= frames.bitmap.frames() {
+            return None;
+        }
+        Some(frames.bitmap.mark_free(index..index + 1))
+    });
+    match freed {
+        Some(None) => panic!(
+            "(X_X)  [frames]: {:#x} was freed, but it isn't a frame we hand out",
+            frame.start_address().as_u64()
+        ),
+        Some(Some(0)) => panic!(
+            "(X_X)  [frames]: {:#x} was freed twice",
+            frame.start_address().as_u64()
+        ),
+        _ => {}
+    }
+}
+
+/// Takes `count` frames in a row, the first aligned to `align` bytes (a
+/// power of 2, 4 KiB or more), for things like DMA rings.
+pub fn allocate_contiguous(count: usize, align: u64) -> Option<PhysFrameRange<Size4KiB>> {
+    let align = (align / FRAME_SIZE).max(1) as usize;
+    let start = with_frames(|frames| frames.bitmap.allocate_contiguous(count, align))??;
+    Some(PhysFrame::range(frame_at(start), frame_at(start + count)))
+}
+
+/// Gives back frames from `allocate_contiguous`.
+///
+/// This function is unsafe for the same reasons as `deallocate`.
+pub unsafe fn deallocate_contiguous(range: PhysFrameRange<Size4KiB>) {
+    let freed = with_frames(|frames| {
+        if index_of(range.end) > frames.bitmap.frames() {
+            return None;
+        }
+        Some(
+            frames
+                .bitmap
+                .mark_free(index_of(range.start)..index_of(range.end)),
+        )
+    });
+    match freed {
+        Some(None) => panic!(
+            "(X_X)  [frames]: {:#x}..{:#x} was freed, but it isn't all frames we hand out",
+            range.start.start_address().as_u64(),
+            range.end.start_address().as_u64()
+        ),
+        Some(Some(freed)) => assert_eq!(
+            freed,
+            range.count(),
+            "(X_X)  [frames]: some of {:#x}..{:#x} was already free",
+            range.start.start_address().as_u64(),
+            range.end.start_address().as_u64()
+        ),
+        None => {}
+    }
+}
+
+/// Takes `start..end` out of the pool for good, so nothing else gets handed
+/// it, and remembers it as `name`. Parts of it that aren't usable RAM are
+/// fine; they just aren't counted.
+pub fn reserve(start: PhysAddr, end: PhysAddr, name: &'static str) -> Result<(), &'static str> {
+    if end < start {
+        return Err("the region ends before it starts");
+    }
+    with_frames(|frames| frames.reserve(start.as_u64()..end.as_u64(), name))
+        .ok_or("the frame allocator isn't set up yet")?
+}
+
+/// Everything that's been reserved.
+pub fn reservations() -> impl Iterator<Item = Reservation> {
+    let reservations =
+        with_frames(|frames| frames.reservations).unwrap_or([None; MAX_RESERVATIONS]);
+    IntoIterator::into_iter(reservations).flatten()
+}
+
+pub fn stats() -> Option<FrameStats> {
+    with_frames(|frames| {
+        let free = frames.bitmap.free();
+        let reserved = frames
+            .reservations
+            .iter()
+            .flatten()
+            .map(|reservation| reservation.frames)
+            .sum::<usize>();
+        FrameStats {
+            usable: frames.usable,
+            free,
+            used: frames.usable.saturating_sub(free + reserved),
+            reserved,
+        }
+    })
+}
+
+#[test_case]
+fn test_frame_bitmap() {
+    let mut words = [0u64; 2];
+    let mut bitmap = FrameBitmap::new(&mut words, 100);
+    assert_eq!(bitmap.allocate(), None);
+    assert_eq!(bitmap.mark_free(10..100), 90);
+
+    assert_eq!(bitmap.allocate(), Some(10));
+    assert_eq!(bitmap.allocate(), Some(11));
+    // 16 frames from a multiple of 8, past the two just taken
+    assert_eq!(bitmap.allocate_contiguous(16, 8), Some(16));
+    assert_eq!(bitmap.free(), 90 - 18);
+    assert_eq!(bitmap.allocate_contiguous(200, 1), None);
+
+    assert_eq!(bitmap.mark_free(10..11), 1);
+    assert_eq!(bitmap.mark_free(10..11), 0);
+    assert_eq!(bitmap.allocate(), Some(10));
+}
@@ -1,5 +1,30 @@
 use alloc::vec::*;
 use core::{arch::asm, fmt, ptr, slice::from_raw_parts};
+use x86_64::PhysAddr;
+
+pub mod msi;
+
+/// Config space offsets.
+const COMMAND: u8 = 0x04;
+const BAR_0: u8 = 0x10;
+const CAPABILITIES_POINTER: u8 = 0x34;
+
+/// Status register (the top half of `COMMAND`) bit for a capability list.
+const STATUS_CAPABILITY_LIST: u32 = 1 << 20;
+/// Stops the device using its INTx pin.
+pub const COMMAND_INTERRUPT_DISABLE: u32 = 1 << 10;
+
+/// Capability IDs.
+pub const CAPABILITY_MSI: u8 = 0x05;
+pub const CAPABILITY_MSI_X: u8 = 0x11;
+
+/// One entry in a device's capability list.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capability {
+    pub id: u8,
+    /// Where it is in config space.
+    pub offset: u8,
+}
 
 /// The PCI Device type.
 pub struct PCIDevice {
@@ -82,6 +107,64 @@ pub fn read_pci(offset: u8, pci_device: &PCIDevice) -> u32 {
     }
 }
 
+/// Sets (or clears) bits in the command register.
+pub fn set_command_bits(pci_device: &PCIDevice, bits: u32, set: bool) {
+    // the status half is write-1-to-clear, so it's written back as 0
+    let command = read_pci(COMMAND, pci_device) & 0xFFFF;
+    let command = if set { command | bits } else { command & !bits };
+    write_pci(COMMAND, pci_device, command);
+}
+
+/// Walks the device's capability list.
+pub fn capabilities(pci_device: &PCIDevice) -> Vec<Capability> {
+    let mut capabilities = Vec::new();
+    if read_pci(COMMAND, pci_device) & STATUS_CAPABILITY_LIST == 0 {
+        return capabilities;
+    }
+    let mut offset = read_pci(CAPABILITIES_POINTER, pci_device) as u8 & 0xFC;
+    // there's only room for 48 in config space, so more means it loops
+    while offset >= 0x40 && capabilities.len() < 48 {
+        let header = read_pci(offset, pci_device);
+        capabilities.push(Capability {
+            id: header as u8,
+            offset,
+        });
+        offset = (header >> 8) as u8 & 0xFC;
+    }
+    capabilities
+}
+
+/// Where capability `id` is in config space, if the device has it.
+pub fn find_capability(pci_device: &PCIDevice, id: u8) -> Option<u8> {
+    capabilities(pci_device)
+        .into_iter()
+        .find(|capability| capability.id == id)
+        .map(|capability| capability.offset)
+}
+
+/// Where memory BAR `bar` (0 to 5) points, or `None` if it's an I/O BAR or
+/// isn't set.
+pub fn memory_bar(pci_device: &PCIDevice, bar: u8) -> Option<PhysAddr> {
+    if bar > 5 {
+        return None;
+    }
+    let offset = BAR_0 + bar * 4;
+    let low = read_pci(offset, pci_device);
+    if low & 1 != 0 {
+        return None;
+    }
+    let mut address = u64::from(low & !0xF);
+    // type 2 is a 64-bit BAR, with the top half in the next one
+    if (low >> 1) & 0b11 == 0b10 && bar < 5 {
+        address |= u64::from(read_pci(offset + 4, pci_device)) << 32;
+    }
+    if address == 0 {
+        None
+    } else {
+        PhysAddr::try_new(address).ok()
+    }
+}
+
 /// Reads from the PCI config space via the IO ports.
 pub unsafe fn read_pci_config(bus: u8, slot: u8, func: u8, offset: u8) -> u32 {
     let addr = (1 << 31)
@@ -110,25 +193,42 @@ pub unsafe fn write_pci_config(bus: u8, slot: u8, func: u8, offset: u8, value: u
 pub fn scan_pci_bus() -> Vec<PCIDevice> {
     let mut devices: Vec<PCIDevice> = Vec::new();
     for bus in 0..255 {
-        for slot in 0..32 {
-            for func in 0..8 {
-                let vendor_id = unsafe { read_pci_config(bus, slot, func, 0x00) } & 0xFFFF;
-                if vendor_id != 0xFFFF {
-                    let device_id = unsafe { read_pci_config(bus, slot, func, 0x00) >> 16 };
-                    let class_code = unsafe { read_pci_config(bus, slot, func, 0x08) >> 24 };
-                    let subclass = (unsafe { read_pci_config(bus, slot, func, 0x08) } >> 16) & 0xFF;
+        scan_bus(bus, &mut devices);
+    }
 
-                    devices.push(PCIDevice::new(
-                        vendor_id, device_id, class_code, subclass, bus, slot, func,
-                    ));
-                }
-            }
-        }
+    return devices;
+}
+
+/// Same as `scan_pci_bus`, but lets other tasks run after every bus, so the
+/// shell can interrupt it.
+pub async fn scan_pci_bus_async() -> Vec<PCIDevice> {
+    let mut devices: Vec<PCIDevice> = Vec::new();
+    for bus in 0..255 {
+        scan_bus(bus, &mut devices);
+        crate::task::yield_now().await;
     }
 
     return devices;
 }
 
+/// Scans every slot and function of a single bus.
+fn scan_bus(bus: u8, devices: &mut Vec<PCIDevice>) {
+    for slot in 0..32 {
+        for func in 0..8 {
+            let vendor_id = unsafe { read_pci_config(bus, slot, func, 0x00) } & 0xFFFF;
+            if vendor_id != 0xFFFF {
+                let device_id = unsafe { read_pci_config(bus, slot, func, 0x00) >> 16 };
+                let class_code = unsafe { read_pci_config(bus, slot, func, 0x08) >> 24 };
+                let subclass = (unsafe { read_pci_config(bus, slot, func, 0x08) } >> 16) & 0xFF;
+
+                devices.push(PCIDevice::new(
+                    vendor_id, device_id, class_code, subclass, bus, slot, func,
+                ));
+            }
+        }
+    }
+}
+
 // !! WARNING !!
 //
 // PCIe does not work. It's currently broken, and I will eventually fix it.
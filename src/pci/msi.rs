@@ -0,0 +1,292 @@
+//! Message signalled interrupts, where a device interrupts by writing to the
+//! local APIC instead of pulling an INTx line.
+//!
+//! MSI gives a device up to 32 vectors in a row, set up in its config space.
+//! MSI-X gives it up to 2048, each with its own entry in a table in one of
+//! its BARs. Either way each vector gets its own handler, so a driver can
+//! have one per queue.
+
+use super::{
+    find_capability, memory_bar, read_pci, set_command_bits, write_pci, PCIDevice, CAPABILITY_MSI,
+    CAPABILITY_MSI_X, COMMAND_INTERRUPT_DISABLE,
+};
+use crate::{
+    interrupts::{
+        apic,
+        irq::{self, Handler, HandlerId},
+    },
+    memory,
+};
+use alloc::vec::Vec;
+use core::ptr;
+use x86_64::VirtAddr;
+
+/// Where messages for the local APIC go. The destination APIC ID goes in
+/// bits 12..20.
+const MESSAGE_ADDRESS: u64 = 0xFEE0_0000;
+
+/// MSI message control bits (the top half of the capability's first dword).
+const MSI_ENABLE: u32 = 1 << 0;
+/// How many vectors it can have, as a power of 2, in bits 1..4.
+const MSI_MULTIPLE_CAPABLE_SHIFT: u32 = 1;
+/// How many it's been given, the same way, in bits 4..7.
+const MSI_MULTIPLE_ENABLE_SHIFT: u32 = 4;
+const MSI_64_BIT: u32 = 1 << 7;
+
+/// MSI-X message control bits. The table size minus one is in bits 0..11.
+const MSI_X_FUNCTION_MASK: u32 = 1 << 14;
+const MSI_X_ENABLE: u32 = 1 << 15;
+
+/// MSI-X table entries, and the vector control bit that masks one.
+const MSI_X_ENTRY_SIZE: u64 = 16;
+const MSI_X_ENTRY_MASKED: u32 = 1 << 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MsiKind {
+    Msi,
+    MsiX,
+}
+
+/// The vectors a device was given.
+#[derive(Debug)]
+pub struct MsiVectors {
+    pub kind: MsiKind,
+    /// The vector each handler was given, in the order they were passed.
+    pub vectors: Vec<u8>,
+    handlers: Vec<HandlerId>,
+    /// Where the MSI-X table and pending bits are mapped, for MSI-X.
+    msi_x: Option<MsiXTable>,
+}
+
+/// The MSI-X table and the pending bit array (a bit for each entry, set while
+/// it has an interrupt waiting), mapped uncached.
+#[derive(Debug)]
+struct MsiXTable {
+    entries: usize,
+    table: VirtAddr,
+    pending: VirtAddr,
+}
+
+impl MsiXTable {
+    fn table_len(&self) -> u64 {
+        self.entries as u64 * MSI_X_ENTRY_SIZE
+    }
+
+    fn pending_len(&self) -> u64 {
+        (self.entries as u64 + 63) / 64 * 8
+    }
+
+    fn unmap(&self) {
+        memory::unmap_mmio(self.table, self.table_len());
+        memory::unmap_mmio(self.pending, self.pending_len());
+    }
+}
+
+impl MsiVectors {
+    /// Whether MSI-X entry `entry` has an interrupt waiting, which only
+    /// happens while it's masked. Always `false` for plain MSI.
+    pub fn is_pending(&self, entry: usize) -> bool {
+        match &self.msi_x {
+            Some(msi_x) if entry < msi_x.entries => {
+                let word = msi_x.pending + (entry / 64 * 8) as u64;
+                let bits = unsafe { ptr::read_volatile(word.as_ptr::<u64>()) };
+                bits & 1 << (entry % 64) != 0
+            }
+            _ => false,
+        }
+    }
+}
+
+/// What a device writes, and where, to send `vector` to the local APIC with
+/// ID `apic_id`. Fixed delivery, edge triggered.
+pub fn message(apic_id: u8, vector: u8) -> (u64, u32) {
+    (
+        MESSAGE_ADDRESS | u64::from(apic_id) << 12,
+        u32::from(vector),
+    )
+}
+
+/// Gives the device a vector for each of `handlers`, through MSI-X if it has
+/// it and MSI otherwise, and stops it using INTx.
+pub fn request(
+    device: &PCIDevice,
+    name: &'static str,
+    handlers: &[Handler],
+) -> Result<MsiVectors, &'static str> {
+    if handlers.is_empty() {
+        return Err("that's no vectors at all");
+    }
+    let apic_id = apic::destination().ok_or("MSIs need the APIC")?;
+    let vectors = if let Some(capability) = find_capability(device, CAPABILITY_MSI_X) {
+        request_msi_x(device, capability, apic_id, name, handlers)?
+    } else if let Some(capability) = find_capability(device, CAPABILITY_MSI) {
+        request_msi(device, capability, apic_id, name, handlers)?
+    } else {
+        return Err("the device can't do MSIs");
+    };
+    set_command_bits(device, COMMAND_INTERRUPT_DISABLE, true);
+    Ok(vectors)
+}
+
+/// Turns the device's MSIs off, unregisters their handlers and unmaps the
+/// MSI-X table. It's left with INTx off too, until its driver turns it back
+/// on.
+pub fn release(device: &PCIDevice, vectors: MsiVectors) {
+    let (id, enable) = match vectors.kind {
+        MsiKind::Msi => (CAPABILITY_MSI, MSI_ENABLE),
+        MsiKind::MsiX => (CAPABILITY_MSI_X, MSI_X_ENABLE),
+    };
+    if let Some(capability) = find_capability(device, id) {
+        let header = read_pci(capability, device);
+        write_pci(capability, device, header & !(enable << 16));
+    }
+    vectors.handlers.into_iter().for_each(irq::unregister);
+    if let Some(msi_x) = vectors.msi_x {
+        msi_x.unmap();
+    }
+}
+
+/// MSI vectors have to be a power of 2 in a row, so the handlers are padded
+/// out with the last one, which gets anything sent to the extra vectors.
+fn request_msi(
+    device: &PCIDevice,
+    capability: u8,
+    apic_id: u8,
+    name: &'static str,
+    handlers: &[Handler],
+) -> Result<MsiVectors, &'static str> {
+    let header = read_pci(capability, device);
+    let control = header >> 16;
+    let capable = 1usize << ((control >> MSI_MULTIPLE_CAPABLE_SHIFT) & 0b111).min(5);
+    if handlers.len() > capable {
+        return Err("the device can't have that many MSI vectors");
+    }
+    let count = handlers.len().next_power_of_two();
+    let mut padded = Vec::from(handlers);
+    padded.resize(count, handlers[handlers.len() - 1]);
+    let (first, ids) = irq::allocate_vectors(name, &padded, count as u8)?;
+
+    let (address, data) = message(apic_id, first);
+    write_pci(capability + 4, device, address as u32);
+    let data_offset = if control & MSI_64_BIT != 0 {
+        write_pci(capability + 8, device, (address >> 32) as u32);
+        capability + 12
+    } else {
+        capability + 8
+    };
+    // the message data is only 16 bits, and shares its dword with nothing we
+    // need to keep
+    write_pci(data_offset, device, data);
+
+    let enabled = (count.trailing_zeros() << MSI_MULTIPLE_ENABLE_SHIFT) | MSI_ENABLE;
+    let control = control & !(0b111 << MSI_MULTIPLE_ENABLE_SHIFT) | enabled;
+    write_pci(capability, device, header & 0xFFFF | control << 16);
+
+    Ok(MsiVectors {
+        kind: MsiKind::Msi,
+        vectors: (first..).take(handlers.len()).collect(),
+        handlers: ids,
+        msi_x: None,
+    })
+}
+
+/// Maps `len` bytes of the device's memory at `location`, which is a BAR
+/// number in the low 3 bits and an offset into it in the rest.
+fn map_bar_region(device: &PCIDevice, location: u32, len: u64) -> Result<VirtAddr, &'static str> {
+    let bar = memory_bar(device, (location & 0b111) as u8).ok_or("the MSI-X BAR isn't memory")?;
+    memory::map_mmio(bar + u64::from(location & !0b111), len)
+}
+
+/// Maps the device's MSI-X table, with `entries` entries, and its pending
+/// bits.
+fn map_msi_x_table(
+    device: &PCIDevice,
+    capability: u8,
+    entries: usize,
+) -> Result<MsiXTable, &'static str> {
+    let mut msi_x = MsiXTable {
+        entries,
+        table: VirtAddr::zero(),
+        pending: VirtAddr::zero(),
+    };
+    msi_x.table = map_bar_region(device, read_pci(capability + 4, device), msi_x.table_len())?;
+    match map_bar_region(
+        device,
+        read_pci(capability + 8, device),
+        msi_x.pending_len(),
+    ) {
+        Ok(pending) => msi_x.pending = pending,
+        Err(e) => {
+            memory::unmap_mmio(msi_x.table, msi_x.table_len());
+            return Err(e);
+        }
+    }
+    Ok(msi_x)
+}
+
+fn request_msi_x(
+    device: &PCIDevice,
+    capability: u8,
+    apic_id: u8,
+    name: &'static str,
+    handlers: &[Handler],
+) -> Result<MsiVectors, &'static str> {
+    let header = read_pci(capability, device);
+    let control = header >> 16;
+    let table_size = (control & 0x7FF) as usize + 1;
+    if handlers.len() > table_size {
+        return Err("the device can't have that many MSI-X vectors");
+    }
+    let msi_x = map_msi_x_table(device, capability, table_size)?;
+    let (first, ids) = match irq::allocate_vectors(name, handlers, 1) {
+        Ok(allocated) => allocated,
+        Err(e) => {
+            msi_x.unmap();
+            return Err(e);
+        }
+    };
+
+    // masked as a whole while the table is filled in
+    let masked = header & 0xFFFF | (control | MSI_X_ENABLE | MSI_X_FUNCTION_MASK) << 16;
+    write_pci(capability, device, masked);
+    for entry in 0..table_size {
+        let entry_base = msi_x.table + entry as u64 * MSI_X_ENTRY_SIZE;
+        let register = |offset: u64| (entry_base + offset).as_mut_ptr::<u32>();
+        unsafe {
+            if entry < handlers.len() {
+                let (address, data) = message(apic_id, first + entry as u8);
+                ptr::write_volatile(register(0), address as u32);
+                ptr::write_volatile(register(4), (address >> 32) as u32);
+                ptr::write_volatile(register(8), data);
+                ptr::write_volatile(register(12), 0);
+            } else {
+                ptr::write_volatile(register(12), MSI_X_ENTRY_MASKED);
+            }
+        }
+    }
+    write_pci(capability, device, masked & !(MSI_X_FUNCTION_MASK << 16));
+
+    Ok(MsiVectors {
+        kind: MsiKind::MsiX,
+        vectors: (first..).take(handlers.len()).collect(),
+        handlers: ids,
+        msi_x: Some(msi_x),
+    })
+}
+
+#[test_case]
+fn test_message_and_vector_blocks() {
+    assert_eq!(message(0, 0x50), (0xFEE0_0000, 0x50));
+    assert_eq!(message(3, 0x41), (0xFEE0_3000, 0x41));
+
+    fn handler() {}
+    let (first, ids) = irq::allocate_vectors("test", &[handler as Handler; 4], 4).unwrap();
+    assert_eq!(first % 4, 0);
+    assert_eq!(
+        (first..first + 4)
+            .filter(|&v| irq::handlers(v).count() == 1)
+            .count(),
+        4
+    );
+    ids.into_iter().for_each(irq::unregister);
+}
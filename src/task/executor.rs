@@ -1,4 +1,5 @@
 use super::{Task, TaskId};
+use crate::vga_buffer::console;
 use alloc::{collections::BTreeMap, sync::Arc, task::Wake};
 use core::task::{Context, Poll, Waker};
 use crossbeam_queue::ArrayQueue;
@@ -7,6 +8,22 @@ pub struct Executor {
     tasks: BTreeMap<TaskId, Task>,
     task_queue: Arc<ArrayQueue<TaskId>>,
     waker_cache: BTreeMap<TaskId, Waker>,
+    /// Tasks spawned through a `Spawner`, waiting to be added to `tasks`.
+    new_tasks: Arc<ArrayQueue<Task>>,
+}
+
+/// Spawns tasks onto an `Executor` from inside one of its tasks.
+#[derive(Clone)]
+pub struct Spawner {
+    new_tasks: Arc<ArrayQueue<Task>>,
+}
+
+impl Spawner {
+    pub fn spawn(&self, task: Task) {
+        if self.new_tasks.push(task).is_err() {
+            panic!("0_0\n\nSpawn queue full");
+        }
+    }
 }
 
 impl Executor {
@@ -15,6 +32,14 @@ impl Executor {
             tasks: BTreeMap::new(),
             task_queue: Arc::new(ArrayQueue::new(100)),
             waker_cache: BTreeMap::new(),
+            new_tasks: Arc::new(ArrayQueue::new(100)),
+        }
+    }
+
+    /// Returns a `Spawner` that tasks can use to spawn more tasks.
+    pub fn spawner(&self) -> Spawner {
+        Spawner {
+            new_tasks: self.new_tasks.clone(),
         }
     }
 
@@ -28,17 +53,25 @@ impl Executor {
 
     pub fn run(&mut self) -> ! {
         loop {
+            self.spawn_new_tasks();
             self.run_ready_tasks();
             self.sleep_if_idle();
         }
     }
 
+    fn spawn_new_tasks(&mut self) {
+        while let Some(task) = self.new_tasks.pop() {
+            self.spawn(task);
+        }
+    }
+
     fn run_ready_tasks(&mut self) {
         // destructure `self` to avoid borrow checker errors
         let Self {
             tasks,
             task_queue,
             waker_cache,
+            ..
         } = self;
 
         while let Some(task_id) = task_queue.pop() {
@@ -50,7 +83,10 @@ impl Executor {
                 .entry(task_id)
                 .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
             let mut context = Context::from_waker(waker);
-            match task.poll(&mut context) {
+            let previous_console = console::set_current(task.console);
+            let result = task.poll(&mut context);
+            console::set_current(previous_console);
+            match result {
                 Poll::Ready(()) => {
                     // task done -> remove it and its cached waker
                     tasks.remove(&task_id);
@@ -65,7 +101,7 @@ impl Executor {
         use x86_64::instructions::interrupts::{self, enable_and_hlt};
 
         interrupts::disable();
-        if self.task_queue.is_empty() {
+        if self.task_queue.is_empty() && self.new_tasks.is_empty() {
             enable_and_hlt();
         } else {
             interrupts::enable();
@@ -1,51 +1,212 @@
-use crate::{print, println};
+use crate::{
+    interrupts::irq,
+    print, println,
+    vga_buffer::console::{self, CONSOLE_COUNT},
+};
 use conquer_once::spin::OnceCell;
 use core::{
+    fmt,
+    future::Future,
     pin::Pin,
+    sync::atomic::{AtomicBool, AtomicU8, Ordering},
     task::{Context, Poll},
 };
 use crossbeam_queue::ArrayQueue;
+use dispatch::KeyEventStream;
 use futures_util::{
     stream::{Stream, StreamExt},
     task::AtomicWaker,
 };
-use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
+use pc_keyboard::{
+    layouts::AnyLayout, DecodedKey, HandleControl, KeyCode, KeyState, Keyboard, ScancodeSet1,
+};
+use x86_64::instructions::port::Port;
+
+pub mod dispatch;
+pub mod layouts;
+
+/// Every scancode, with the console that was active when it came in. Only
+/// the dispatcher reads it.
+static SCANCODE_QUEUE: OnceCell<ArrayQueue<(usize, u8)>> = OnceCell::uninit();
+static SCANCODE_WAKER: AtomicWaker = AtomicWaker::new();
+
+/// The keyboard state each virtual console has its own copy of.
+struct ConsoleInput {
+    /// Set when Ctrl-C is pressed, until someone handles it.
+    interrupted: AtomicBool,
+    interrupt_waker: AtomicWaker,
+    /// Cleared by programs that want Ctrl-C as a normal key press.
+    ctrl_c_interrupts: AtomicBool,
+    /// Set when the clipboard should be pasted, until someone does.
+    pasted: AtomicBool,
+    paste_waker: AtomicWaker,
+}
+
+impl ConsoleInput {
+    const fn new() -> Self {
+        ConsoleInput {
+            interrupted: AtomicBool::new(false),
+            interrupt_waker: AtomicWaker::new(),
+            ctrl_c_interrupts: AtomicBool::new(true),
+            pasted: AtomicBool::new(false),
+            paste_waker: AtomicWaker::new(),
+        }
+    }
+}
+
+static INPUTS: [ConsoleInput; CONSOLE_COUNT] = [const { ConsoleInput::new() }; CONSOLE_COUNT];
+
+/// Set when the last scancode was the 0xE0 prefix of an extended key.
+static EXTENDED: AtomicBool = AtomicBool::new(false);
+const EXTENDED_PREFIX: u8 = 0xE0;
+
+/// Make codes of the lock keys.
+const CAPS_LOCK_PRESSED: u8 = 0x3A;
+const NUM_LOCK_PRESSED: u8 = 0x45;
+const SCROLL_LOCK_PRESSED: u8 = 0x46;
+/// Pause sends `E1 1D 45 E1 9D C5`, which has Num Lock's code in it.
+const PAUSE_PREFIX: u8 = 0xE1;
+/// What the keyboard answers commands with, rather than scancodes.
+const ACK: u8 = 0xFA;
+const RESEND: u8 = 0xFE;
+/// Keyboard command to set the LEDs, followed by a byte of `LOCK_*` bits.
+const SET_LEDS: u8 = 0xED;
+
+/// Lock key bits, the same as the LED command takes.
+const LOCK_SCROLL: u8 = 0x01;
+const LOCK_NUM: u8 = 0x02;
+const LOCK_CAPS: u8 = 0x04;
+/// `PENDING_LEDS` when no LED command is waiting to be acknowledged.
+const NO_LEDS: u8 = 0xFF;
+
+/// Which lock keys are on. They're shared between the consoles, like the
+/// LEDs are.
+static LOCKS: AtomicU8 = AtomicU8::new(LOCK_NUM);
+/// The LED byte to send once the keyboard acknowledges `SET_LEDS`.
+static PENDING_LEDS: AtomicU8 = AtomicU8::new(NO_LEDS);
+/// Lock keys held down right now, so holding one doesn't keep toggling it.
+static LOCKS_HELD: AtomicU8 = AtomicU8::new(0);
+/// How many more bytes of a Pause sequence to ignore.
+static PAUSE_BYTES: AtomicU8 = AtomicU8::new(0);
 
-static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
-static WAKER: AtomicWaker = AtomicWaker::new();
+/// Which lock keys are on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Locks {
+    pub caps: bool,
+    pub num: bool,
+    pub scroll: bool,
+}
+
+pub fn locks() -> Locks {
+    let locks = LOCKS.load(Ordering::SeqCst);
+    Locks {
+        caps: locks & LOCK_CAPS != 0,
+        num: locks & LOCK_NUM != 0,
+        scroll: locks & LOCK_SCROLL != 0,
+    }
+}
+
+/// Sets the keyboard's LEDs to match the lock keys.
+pub fn update_leds() {
+    let mut status: Port<u8> = Port::new(0x64);
+    // wait for room in the controller's input buffer, but not forever
+    for _ in 0..10_000 {
+        if unsafe { status.read() } & 0x02 == 0 {
+            PENDING_LEDS.store(LOCKS.load(Ordering::SeqCst), Ordering::SeqCst);
+            unsafe { Port::new(0x60).write(SET_LEDS) };
+            return;
+        }
+    }
+}
 
-/// Called by the keyboard interrupt handler
+/// Toggles the lock keys and their LEDs. Returns `true` if `scancode` was
+/// the keyboard answering an LED command, which isn't a key press.
+fn lock_keys(scancode: u8) -> bool {
+    match scancode {
+        ACK => {
+            let leds = PENDING_LEDS.swap(NO_LEDS, Ordering::SeqCst);
+            if leds != NO_LEDS {
+                unsafe { Port::new(0x60).write(leds) };
+            }
+            return true;
+        }
+        RESEND => return true,
+        _ => {}
+    }
+    let extended = EXTENDED.swap(scancode == EXTENDED_PREFIX, Ordering::SeqCst);
+    if scancode == PAUSE_PREFIX {
+        PAUSE_BYTES.store(2, Ordering::SeqCst);
+        return false;
+    }
+    if PAUSE_BYTES.load(Ordering::SeqCst) > 0 {
+        PAUSE_BYTES.fetch_sub(1, Ordering::SeqCst);
+        return false;
+    }
+    if extended {
+        return false;
+    }
+    // releases are the same codes with the top bit set
+    let lock = match scancode & 0x7F {
+        CAPS_LOCK_PRESSED => LOCK_CAPS,
+        NUM_LOCK_PRESSED => LOCK_NUM,
+        SCROLL_LOCK_PRESSED => LOCK_SCROLL,
+        _ => return false,
+    };
+    if scancode & 0x80 != 0 {
+        LOCKS_HELD.fetch_and(!lock, Ordering::SeqCst);
+    } else if LOCKS_HELD.fetch_or(lock, Ordering::SeqCst) & lock == 0 {
+        LOCKS.fetch_xor(lock, Ordering::SeqCst);
+        update_leds();
+    }
+    false
+}
+
+/// Starts taking scancodes from IRQ 1.
+pub fn init() {
+    irq::register_irq(1, "keyboard", keyboard_interrupt)
+        .expect("(X_X)  [keyboard]: couldn't register the keyboard IRQ");
+}
+
+/// Called on IRQ 1, with a scancode waiting.
+fn keyboard_interrupt() {
+    add_scancode(unsafe { Port::<u8>::new(0x60).read() });
+}
+
+/// Called with each scancode the keyboard sends.
 ///
 /// Must not block or allocate.
 pub(crate) fn add_scancode(scancode: u8) {
+    if lock_keys(scancode) {
+        return;
+    }
+
     if let Ok(queue) = SCANCODE_QUEUE.try_get() {
-        if let Err(_) = queue.push(scancode) {
+        if let Err(_) = queue.push((console::active(), scancode)) {
             println!("WARNING: scancode queue full; dropping keyboard input");
         } else {
-            WAKER.wake();
+            SCANCODE_WAKER.wake();
         }
     } else {
         println!("WARNING: scancode queue uninitialized");
     }
 }
 
-pub struct ScancodeStream {
+/// The scancodes from the interrupt handler, for the dispatcher.
+struct ScancodeStream {
     _private: (),
 }
 
 impl ScancodeStream {
-    pub fn new() -> Self {
-        SCANCODE_QUEUE
-            .try_init_once(|| ArrayQueue::new(100))
-            .expect("ScancodeStream::new should only be called once");
+    fn new() -> Self {
+        SCANCODE_QUEUE.get_or_init(|| ArrayQueue::new(100));
         ScancodeStream { _private: () }
     }
 }
 
 impl Stream for ScancodeStream {
-    type Item = u8;
+    type Item = (usize, u8);
 
-    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>> {
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<(usize, u8)>> {
         let queue = SCANCODE_QUEUE
             .try_get()
             .expect("scancode queue not initialized");
@@ -55,10 +216,10 @@ impl Stream for ScancodeStream {
             return Poll::Ready(Some(scancode));
         }
 
-        WAKER.register(&cx.waker());
+        SCANCODE_WAKER.register(&cx.waker());
         match queue.pop() {
             Some(scancode) => {
-                WAKER.take();
+                SCANCODE_WAKER.take();
                 Poll::Ready(Some(scancode))
             }
             None => Poll::Pending,
@@ -66,22 +227,278 @@ impl Stream for ScancodeStream {
     }
 }
 
-pub async fn print_keypresses() {
-    let mut scancodes = ScancodeStream::new();
-    let mut keyboard = Keyboard::new(
-        ScancodeSet1::new(),
-        layouts::Us104Key,
-        HandleControl::Ignore,
-    );
+/// Turns Ctrl-C on the current console into a normal key press (`false`), or
+/// back into an interrupt.
+pub fn set_ctrl_c_interrupts(enabled: bool) {
+    INPUTS[console::current()]
+        .ctrl_c_interrupts
+        .store(enabled, Ordering::SeqCst);
+}
 
-    while let Some(scancode) = scancodes.next().await {
-        if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-            if let Some(key) = keyboard.process_keyevent(key_event) {
-                match key {
-                    DecodedKey::Unicode(character) => print!("{}", character),
-                    DecodedKey::RawKey(key) => print!("{:?}", key),
-                }
+/// Forgets about a Ctrl-C on the current console nobody has handled yet.
+pub fn clear_interrupt() {
+    INPUTS[console::current()]
+        .interrupted
+        .store(false, Ordering::SeqCst);
+}
+
+/// Called by the dispatcher for Ctrl-C. Returns `false` if the console takes
+/// it as a normal key press instead.
+fn interrupt(console: usize) -> bool {
+    let input = &INPUTS[console];
+    if !input.ctrl_c_interrupts.load(Ordering::SeqCst) {
+        return false;
+    }
+    input.interrupted.store(true, Ordering::SeqCst);
+    input.interrupt_waker.wake();
+    true
+}
+
+/// Returns a future that completes the next time Ctrl-C is pressed on the
+/// current console.
+pub fn interrupted() -> Interrupted {
+    Interrupted {
+        console: console::current(),
+    }
+}
+
+pub struct Interrupted {
+    console: usize,
+}
+
+impl Future for Interrupted {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let input = &INPUTS[self.console];
+        if input.interrupted.swap(false, Ordering::SeqCst) {
+            return Poll::Ready(());
+        }
+
+        input.interrupt_waker.register(&cx.waker());
+        if input.interrupted.swap(false, Ordering::SeqCst) {
+            input.interrupt_waker.take();
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+/// Asks whoever is reading the active console's keyboard to paste the
+/// clipboard (see `vga_buffer::selection`).
+pub fn paste() {
+    let input = &INPUTS[console::active()];
+    input.pasted.store(true, Ordering::SeqCst);
+    input.paste_waker.wake();
+}
+
+/// Returns a future that completes the next time the clipboard is pasted on
+/// the current console.
+pub fn pasted() -> Pasted {
+    Pasted {
+        console: console::current(),
+    }
+}
+
+pub struct Pasted {
+    console: usize,
+}
+
+impl Future for Pasted {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let input = &INPUTS[self.console];
+        if input.pasted.swap(false, Ordering::SeqCst) {
+            return Poll::Ready(());
+        }
+
+        input.paste_waker.register(&cx.waker());
+        if input.pasted.swap(false, Ordering::SeqCst) {
+            input.paste_waker.take();
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+/// Which modifier keys were held for a key press.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Modifiers {
+    pub shift: bool,
+    pub ctrl: bool,
+    /// The left Alt key. The right one is AltGr, which the layouts use for
+    /// typing more characters.
+    pub alt: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyPress {
+    pub key: DecodedKey,
+    pub modifiers: Modifiers,
+}
+
+impl KeyPress {
+    /// The key, with Ctrl plus a letter turned into its ASCII control
+    /// character (Ctrl+A is `'\x01'`), the way terminals send them.
+    pub fn control_mapped(&self) -> DecodedKey {
+        match self.key {
+            DecodedKey::Unicode(c) if self.modifiers.ctrl && c.is_ascii_alphabetic() => {
+                DecodedKey::Unicode(char::from(c.to_ascii_lowercase() as u8 - b'a' + 1))
+            }
+            key => key,
+        }
+    }
+}
+
+impl fmt::Display for KeyPress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.modifiers.ctrl {
+            write!(f, "Ctrl+")?;
+        }
+        if self.modifiers.alt {
+            write!(f, "Alt+")?;
+        }
+        match self.key {
+            DecodedKey::Unicode(c) => write!(f, "{}", c),
+            DecodedKey::RawKey(key) => write!(f, "{:?}", key),
+        }
+    }
+}
+
+/// Turns scancodes into key presses in the layout `kbd set` picked, keeping
+/// track of the modifiers and lock keys.
+pub struct KeyDecoder {
+    keyboard: Keyboard<AnyLayout, ScancodeSet1>,
+    /// Which of `layouts::LAYOUTS` `keyboard` is using.
+    layout: usize,
+    /// Left and right Shift and Ctrl, and the left Alt.
+    shift: [bool; 2],
+    ctrl: [bool; 2],
+    alt: bool,
+}
+
+impl KeyDecoder {
+    pub fn new() -> Self {
+        let layout = layouts::current();
+        KeyDecoder {
+            keyboard: Keyboard::new(
+                ScancodeSet1::new(),
+                layouts::make(layout),
+                HandleControl::Ignore,
+            ),
+            layout,
+            shift: [false; 2],
+            ctrl: [false; 2],
+            alt: false,
+        }
+    }
+
+    fn modifiers(&self) -> Modifiers {
+        Modifiers {
+            shift: self.shift[0] || self.shift[1],
+            ctrl: self.ctrl[0] || self.ctrl[1],
+            alt: self.alt,
+        }
+    }
+
+    /// Feeds in a scancode, returning the key that was pressed if it
+    /// finished one.
+    pub fn add_byte(&mut self, scancode: u8) -> Option<KeyPress> {
+        if self.layout != layouts::current() {
+            *self = KeyDecoder::new();
+        }
+        let event = self.keyboard.add_byte(scancode).ok()??;
+        let down = event.state != KeyState::Up;
+        match event.code {
+            KeyCode::LShift => self.shift[0] = down,
+            KeyCode::RShift => self.shift[1] = down,
+            KeyCode::LControl => self.ctrl[0] = down,
+            KeyCode::RControl => self.ctrl[1] = down,
+            KeyCode::LAlt => self.alt = down,
+            // the lock state is kept by the interrupt handler instead
+            KeyCode::CapsLock | KeyCode::NumpadLock | KeyCode::ScrollLock => return None,
+            _ => {}
+        }
+        let modifiers = self.modifiers();
+        let locks = locks();
+        if !locks.num {
+            if let Some(code) = numpad_navigation(event.code) {
+                return match down {
+                    true => Some(KeyPress {
+                        key: DecodedKey::RawKey(code),
+                        modifiers,
+                    }),
+                    false => None,
+                };
             }
         }
+
+        let key = match self.keyboard.process_keyevent(event)? {
+            // Shift already picked the case, Caps Lock flips it
+            DecodedKey::Unicode(c) if locks.caps && c.is_alphabetic() => {
+                let flipped = if c.is_lowercase() {
+                    c.to_uppercase().next()
+                } else {
+                    c.to_lowercase().next()
+                };
+                DecodedKey::Unicode(flipped.unwrap_or(c))
+            }
+            key => key,
+        };
+        Some(KeyPress { key, modifiers })
     }
 }
+
+impl Default for KeyDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// What the keypad's keys do with Num Lock off.
+fn numpad_navigation(code: KeyCode) -> Option<KeyCode> {
+    Some(match code {
+        KeyCode::Numpad7 => KeyCode::Home,
+        KeyCode::Numpad8 => KeyCode::ArrowUp,
+        KeyCode::Numpad9 => KeyCode::PageUp,
+        KeyCode::Numpad4 => KeyCode::ArrowLeft,
+        KeyCode::Numpad6 => KeyCode::ArrowRight,
+        KeyCode::Numpad1 => KeyCode::End,
+        KeyCode::Numpad2 => KeyCode::ArrowDown,
+        KeyCode::Numpad3 => KeyCode::PageDown,
+        KeyCode::Numpad0 => KeyCode::Insert,
+        KeyCode::NumpadPeriod => KeyCode::Delete,
+        _ => return None,
+    })
+}
+
+pub async fn print_keypresses() {
+    let mut keys = KeyEventStream::new();
+
+    while let Some(press) = keys.next().await {
+        print!("{}", press);
+    }
+}
+
+#[test_case]
+fn test_control_mapped() {
+    let press = |c, ctrl| KeyPress {
+        key: DecodedKey::Unicode(c),
+        modifiers: Modifiers {
+            ctrl,
+            ..Modifiers::default()
+        },
+    };
+    assert_eq!(
+        press('s', true).control_mapped(),
+        DecodedKey::Unicode('\x13')
+    );
+    assert_eq!(
+        press('A', true).control_mapped(),
+        DecodedKey::Unicode('\x01')
+    );
+    assert_eq!(press('s', false).control_mapped(), DecodedKey::Unicode('s'));
+}
@@ -0,0 +1,239 @@
+//! Decodes the keyboard once and hands out the key presses.
+//!
+//! Every console has a stack of `KeyEventStream`s, and what's typed on it
+//! goes to the one on top: the shell, until the editor it runs makes a
+//! stream of its own, and the shell again once the editor drops it. Hotkeys
+//! are looked for first, and work on every console.
+
+use super::{interrupt, KeyDecoder, KeyPress, Modifiers, ScancodeStream};
+use crate::vga_buffer::{
+    self,
+    console::{self, CONSOLE_COUNT},
+};
+use alloc::{sync::Arc, vec::Vec};
+use core::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+use crossbeam_queue::ArrayQueue;
+use futures_util::{
+    stream::{Stream, StreamExt},
+    task::AtomicWaker,
+};
+use pc_keyboard::{DecodedKey, KeyCode};
+use spin::Mutex;
+
+/// How far one Shift+PageUp/PageDown scrolls.
+const SCROLL_LINES: isize = 12;
+
+struct Consumer {
+    presses: ArrayQueue<KeyPress>,
+    waker: AtomicWaker,
+}
+
+/// The streams reading each console, the one with the focus last.
+static FOCUS: [Mutex<Vec<Arc<Consumer>>>; CONSOLE_COUNT] =
+    [const { Mutex::new(Vec::new()) }; CONSOLE_COUNT];
+
+type Action = Arc<dyn Fn() + Send + Sync>;
+
+static HOTKEYS: Mutex<Vec<(KeyPress, Action)>> = Mutex::new(Vec::new());
+
+/// Runs `action` whenever `key` is pressed, on any console, instead of
+/// passing the key on. Returns `false` if something else has that hotkey.
+///
+/// `action` runs in the dispatcher's task, so it shouldn't take long.
+pub fn register_hotkey(key: KeyPress, action: impl Fn() + Send + Sync + 'static) -> bool {
+    let mut hotkeys = HOTKEYS.lock();
+    if hotkeys.iter().any(|(hotkey, _)| *hotkey == key) {
+        return false;
+    }
+    hotkeys.push((key, Arc::new(action)));
+    true
+}
+
+/// Lets `key` through to the consoles again. Returns `false` if it wasn't a
+/// hotkey.
+pub fn unregister_hotkey(key: KeyPress) -> bool {
+    let mut hotkeys = HOTKEYS.lock();
+    let count = hotkeys.len();
+    hotkeys.retain(|(hotkey, _)| *hotkey != key);
+    hotkeys.len() != count
+}
+
+fn raw_key(code: KeyCode, modifiers: Modifiers) -> KeyPress {
+    KeyPress {
+        key: DecodedKey::RawKey(code),
+        modifiers,
+    }
+}
+
+/// Alt+F1..F6 to switch consoles, and Shift+PageUp/PageDown to scroll back.
+fn register_builtin_hotkeys() {
+    let alt = Modifiers {
+        alt: true,
+        ..Modifiers::default()
+    };
+    let shift = Modifiers {
+        shift: true,
+        ..Modifiers::default()
+    };
+    let function_keys = [
+        KeyCode::F1,
+        KeyCode::F2,
+        KeyCode::F3,
+        KeyCode::F4,
+        KeyCode::F5,
+        KeyCode::F6,
+        KeyCode::F7,
+        KeyCode::F8,
+        KeyCode::F9,
+        KeyCode::F10,
+    ];
+    for (console, &code) in function_keys.iter().take(CONSOLE_COUNT).enumerate() {
+        register_hotkey(raw_key(code, alt), move || console::switch_to(console));
+    }
+    register_hotkey(raw_key(KeyCode::PageUp, shift), || {
+        vga_buffer::scroll_from_keyboard(SCROLL_LINES)
+    });
+    register_hotkey(raw_key(KeyCode::PageDown, shift), || {
+        vga_buffer::scroll_from_keyboard(-SCROLL_LINES)
+    });
+}
+
+fn is_ctrl_c(press: &KeyPress) -> bool {
+    press.modifiers.ctrl
+        && !press.modifiers.alt
+        && matches!(
+            press.key,
+            DecodedKey::Unicode('c') | DecodedKey::Unicode('C')
+        )
+}
+
+/// Sends a key press typed on `console` wherever it goes.
+fn dispatch(console: usize, press: KeyPress) {
+    // cloned out, so the action can register hotkeys of its own
+    let action = HOTKEYS
+        .lock()
+        .iter()
+        .find(|(hotkey, _)| *hotkey == press)
+        .map(|(_, action)| action.clone());
+    if let Some(action) = action {
+        action();
+        return;
+    }
+
+    // typing snaps back to the live screen
+    vga_buffer::scroll_from_keyboard(0);
+    if is_ctrl_c(&press) && interrupt(console) {
+        return;
+    }
+    if let Some(consumer) = FOCUS[console].lock().last() {
+        // a full queue means it isn't being read, so drop the key
+        if consumer.presses.push(press).is_ok() {
+            consumer.waker.wake();
+        }
+    }
+}
+
+/// The task that reads the keyboard. Nothing gets any key presses unless
+/// it's running.
+pub async fn run() {
+    register_builtin_hotkeys();
+    let mut scancodes = ScancodeStream::new();
+    let mut decoder = KeyDecoder::new();
+
+    while let Some((console, scancode)) = scancodes.next().await {
+        if let Some(press) = decoder.add_byte(scancode) {
+            dispatch(console, press);
+        }
+    }
+}
+
+pub struct KeyEventStream {
+    console: usize,
+    consumer: Arc<Consumer>,
+}
+
+impl KeyEventStream {
+    /// Starts reading what's typed on the current console, taking the focus
+    /// from whoever had it until this stream is dropped.
+    pub fn new() -> Self {
+        let console = console::current();
+        let consumer = Arc::new(Consumer {
+            presses: ArrayQueue::new(100),
+            waker: AtomicWaker::new(),
+        });
+        FOCUS[console].lock().push(consumer.clone());
+        KeyEventStream { console, consumer }
+    }
+
+    /// Whether key presses come to this stream right now.
+    pub fn has_focus(&self) -> bool {
+        FOCUS[self.console]
+            .lock()
+            .last()
+            .map_or(false, |consumer| Arc::ptr_eq(consumer, &self.consumer))
+    }
+
+    /// Takes the focus back from whatever stream took it later.
+    pub fn focus(&self) {
+        let mut focus = FOCUS[self.console].lock();
+        focus.retain(|consumer| !Arc::ptr_eq(consumer, &self.consumer));
+        focus.push(self.consumer.clone());
+    }
+}
+
+impl Default for KeyEventStream {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for KeyEventStream {
+    fn drop(&mut self) {
+        FOCUS[self.console]
+            .lock()
+            .retain(|consumer| !Arc::ptr_eq(consumer, &self.consumer));
+    }
+}
+
+impl Stream for KeyEventStream {
+    type Item = KeyPress;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<KeyPress>> {
+        let consumer = &self.consumer;
+
+        // fast path
+        if let Some(press) = consumer.presses.pop() {
+            return Poll::Ready(Some(press));
+        }
+
+        consumer.waker.register(&cx.waker());
+        match consumer.presses.pop() {
+            Some(press) => {
+                consumer.waker.take();
+                Poll::Ready(Some(press))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+#[test_case]
+fn test_focus() {
+    let press = KeyPress {
+        key: DecodedKey::Unicode('x'),
+        modifiers: Modifiers::default(),
+    };
+    let shell = KeyEventStream::new();
+    let editor = KeyEventStream::new();
+    assert!(editor.has_focus() && !shell.has_focus());
+    dispatch(console::current(), press);
+    assert_eq!(editor.consumer.presses.pop(), Some(press));
+    assert_eq!(shell.consumer.presses.pop(), None);
+
+    drop(editor);
+    dispatch(console::current(), press);
+    assert_eq!(shell.consumer.presses.pop(), Some(press));
+}
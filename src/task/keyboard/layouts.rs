@@ -0,0 +1,67 @@
+//! The keyboard layouts `kbd set` can switch between.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use pc_keyboard::layouts::{self, AnyLayout};
+
+/// Each layout's name, what it is, and how to make one.
+pub const LAYOUTS: [(&str, &str, fn() -> AnyLayout); 10] = [
+    ("us", "US 104-key", || {
+        AnyLayout::Us104Key(layouts::Us104Key)
+    }),
+    ("uk", "UK 105-key", || {
+        AnyLayout::Uk105Key(layouts::Uk105Key)
+    }),
+    ("de", "German 105-key", || {
+        AnyLayout::De105Key(layouts::De105Key)
+    }),
+    ("azerty", "French AZERTY", || {
+        AnyLayout::Azerty(layouts::Azerty)
+    }),
+    ("dvorak", "US Dvorak", || {
+        AnyLayout::Dvorak104Key(layouts::Dvorak104Key)
+    }),
+    ("dvp", "Programmer Dvorak", || {
+        AnyLayout::DVP104Key(layouts::DVP104Key)
+    }),
+    ("colemak", "Colemak", || {
+        AnyLayout::Colemak(layouts::Colemak)
+    }),
+    ("jis", "Japanese 109-key", || {
+        AnyLayout::Jis109Key(layouts::Jis109Key)
+    }),
+    ("fise", "Finnish/Swedish 105-key", || {
+        AnyLayout::FiSe105Key(layouts::FiSe105Key)
+    }),
+    ("no", "Norwegian 105-key", || {
+        AnyLayout::No105Key(layouts::No105Key)
+    }),
+];
+
+/// Which of `LAYOUTS` is in use.
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+
+/// The index of the layout in use, which changes whenever it's switched.
+pub(super) fn current() -> usize {
+    CURRENT.load(Ordering::SeqCst)
+}
+
+/// The name of the layout in use.
+pub fn layout() -> &'static str {
+    LAYOUTS[current()].0
+}
+
+/// Switches every console to the layout called `name`. Returns `false` if
+/// there's no such layout.
+pub fn set_layout(name: &str) -> bool {
+    match LAYOUTS.iter().position(|&(layout, _, _)| layout == name) {
+        Some(index) => {
+            CURRENT.store(index, Ordering::SeqCst);
+            true
+        }
+        None => false,
+    }
+}
+
+pub(super) fn make(index: usize) -> AnyLayout {
+    (LAYOUTS[index].2)()
+}
@@ -1,3 +1,4 @@
+use crate::vga_buffer::console;
 use alloc::boxed::Box;
 use core::{
     future::Future,
@@ -8,17 +9,26 @@ use core::{
 
 pub mod executor;
 pub mod keyboard;
+pub mod mouse;
 pub mod simple_executor;
 
 pub struct Task {
     id: TaskId,
+    /// The virtual console the task prints to and reads the keyboard from.
+    console: usize,
     future: Pin<Box<dyn Future<Output = ()>>>,
 }
 
 impl Task {
+    /// Creates a task on the current console, so tasks spawned by a shell stay on its console.
     pub fn new(future: impl Future<Output = ()> + 'static) -> Task {
+        Task::on_console(console::current(), future)
+    }
+
+    pub fn on_console(console: usize, future: impl Future<Output = ()> + 'static) -> Task {
         Task {
             id: TaskId::new(),
+            console,
             future: Box::pin(future),
         }
     }
@@ -28,6 +38,32 @@ impl Task {
     }
 }
 
+/// Gives the other tasks a chance to run before continuing.
+///
+/// Long loops should await this every so often, otherwise nothing else (like
+/// noticing Ctrl-C) can happen until they're done.
+pub fn yield_now() -> YieldNow {
+    YieldNow { yielded: false }
+}
+
+pub struct YieldNow {
+    yielded: bool,
+}
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.yielded {
+            Poll::Ready(())
+        } else {
+            self.yielded = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 struct TaskId(u64);
 
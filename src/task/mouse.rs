@@ -0,0 +1,318 @@
+//! The PS/2 mouse, on IRQ 12.
+//!
+//! The mouse sends its movement in packets of 3 bytes, or 4 once it's been
+//! switched into IntelliMouse mode, where the last byte is the scroll wheel.
+//! The interrupt handler puts the packets back together and hands them out
+//! to every `MouseEventStream` there is.
+
+use crate::{gui, interrupts::irq, vga_buffer::selection::Pointer};
+use alloc::{sync::Arc, vec::Vec};
+use core::{
+    pin::Pin,
+    sync::atomic::{AtomicUsize, Ordering},
+    task::{Context, Poll},
+};
+use crossbeam_queue::ArrayQueue;
+use futures_util::{
+    stream::{Stream, StreamExt},
+    task::AtomicWaker,
+};
+use spin::Mutex;
+use x86_64::instructions::{interrupts, port::Port};
+
+/// The PS/2 controller's ports.
+const DATA_PORT: u16 = 0x60;
+const STATUS_PORT: u16 = 0x64;
+const COMMAND_PORT: u16 = 0x64;
+
+/// Status register bits.
+const OUTPUT_FULL: u8 = 0x01;
+const INPUT_FULL: u8 = 0x02;
+
+/// Controller commands.
+const READ_CONFIG: u8 = 0x20;
+const WRITE_CONFIG: u8 = 0x60;
+const ENABLE_AUX: u8 = 0xA8;
+/// Sends the next data byte to the mouse instead of the keyboard.
+const WRITE_AUX: u8 = 0xD4;
+
+/// Controller configuration bits.
+const CONFIG_AUX_INTERRUPT: u8 = 0x02;
+const CONFIG_AUX_CLOCK_DISABLED: u8 = 0x20;
+
+/// Mouse commands, and what it answers them with.
+const SET_DEFAULTS: u8 = 0xF6;
+const SET_SAMPLE_RATE: u8 = 0xF3;
+const GET_ID: u8 = 0xF2;
+const ENABLE_REPORTING: u8 = 0xF4;
+const ACK: u8 = 0xFA;
+
+/// What `GET_ID` says once the wheel (and the 4th and 5th buttons) are on.
+const ID_WHEEL: u8 = 3;
+const ID_FIVE_BUTTONS: u8 = 4;
+
+/// The first byte of every packet has this bit set, which is how we find
+/// our place again if a byte goes missing.
+const PACKET_ALWAYS_SET: u8 = 0x08;
+
+const MOUSE_IRQ: u8 = 12;
+
+/// How long to wait for the controller, in status reads.
+const TIMEOUT: usize = 100_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MouseButtons {
+    pub left: bool,
+    pub right: bool,
+    pub middle: bool,
+}
+
+/// One packet from the mouse.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MouseEvent {
+    /// How far it moved, right and down being positive.
+    pub dx: i16,
+    pub dy: i16,
+    /// Notches the wheel turned, towards you being positive. Always 0 on mice
+    /// without a wheel.
+    pub wheel: i8,
+    /// Which buttons are held down now.
+    pub buttons: MouseButtons,
+}
+
+/// Collects the bytes of a packet as they come in.
+struct PacketReader {
+    bytes: [u8; 4],
+    len: usize,
+}
+
+/// 3, or 4 with a wheel. Set by `init`.
+static PACKET_SIZE: AtomicUsize = AtomicUsize::new(3);
+static PACKET: Mutex<PacketReader> = Mutex::new(PacketReader {
+    bytes: [0; 4],
+    len: 0,
+});
+
+/// The queues of every `MouseEventStream`.
+static SUBSCRIBERS: Mutex<Vec<Arc<Subscriber>>> = Mutex::new(Vec::new());
+
+struct Subscriber {
+    events: ArrayQueue<MouseEvent>,
+    waker: AtomicWaker,
+}
+
+fn wait_for(status: impl Fn(u8) -> bool) -> Result<(), &'static str> {
+    let mut port: Port<u8> = Port::new(STATUS_PORT);
+    for _ in 0..TIMEOUT {
+        if status(unsafe { port.read() }) {
+            return Ok(());
+        }
+    }
+    Err("the PS/2 controller isn't answering")
+}
+
+fn write_command(command: u8) -> Result<(), &'static str> {
+    wait_for(|status| status & INPUT_FULL == 0)?;
+    unsafe { Port::new(COMMAND_PORT).write(command) };
+    Ok(())
+}
+
+fn write_data(data: u8) -> Result<(), &'static str> {
+    wait_for(|status| status & INPUT_FULL == 0)?;
+    unsafe { Port::new(DATA_PORT).write(data) };
+    Ok(())
+}
+
+fn read_data() -> Result<u8, &'static str> {
+    wait_for(|status| status & OUTPUT_FULL != 0)?;
+    Ok(unsafe { Port::new(DATA_PORT).read() })
+}
+
+/// Sends a byte to the mouse and waits for it to acknowledge it.
+fn mouse_command(byte: u8) -> Result<(), &'static str> {
+    write_command(WRITE_AUX)?;
+    write_data(byte)?;
+    match read_data()? {
+        ACK => Ok(()),
+        _ => Err("the mouse didn't acknowledge a command"),
+    }
+}
+
+fn set_sample_rate(rate: u8) -> Result<(), &'static str> {
+    mouse_command(SET_SAMPLE_RATE)?;
+    mouse_command(rate)
+}
+
+/// Turns the mouse on, with its wheel if it has one, and unmasks IRQ 12.
+///
+/// Returns whether there's a wheel.
+pub fn init() -> Result<bool, &'static str> {
+    interrupts::without_interrupts(|| {
+        // throw away anything left over
+        let mut status: Port<u8> = Port::new(STATUS_PORT);
+        while unsafe { status.read() } & OUTPUT_FULL != 0 {
+            unsafe { Port::<u8>::new(DATA_PORT).read() };
+        }
+
+        write_command(ENABLE_AUX)?;
+        write_command(READ_CONFIG)?;
+        let config = read_data()?;
+        write_command(WRITE_CONFIG)?;
+        write_data((config | CONFIG_AUX_INTERRUPT) & !CONFIG_AUX_CLOCK_DISABLED)?;
+
+        mouse_command(SET_DEFAULTS)?;
+        // the magic knock that turns on the IntelliMouse wheel
+        set_sample_rate(200)?;
+        set_sample_rate(100)?;
+        set_sample_rate(80)?;
+        mouse_command(GET_ID)?;
+        let wheel = matches!(read_data()?, ID_WHEEL | ID_FIVE_BUTTONS);
+        PACKET_SIZE.store(if wheel { 4 } else { 3 }, Ordering::SeqCst);
+        set_sample_rate(100)?;
+        mouse_command(ENABLE_REPORTING)?;
+
+        irq::register_irq(MOUSE_IRQ, "mouse", interrupt)?;
+        Ok(wheel)
+    })
+}
+
+/// Called on IRQ 12, with a byte waiting.
+fn interrupt() {
+    add_byte(unsafe { Port::<u8>::new(DATA_PORT).read() });
+}
+
+/// Called with each byte the mouse sends.
+///
+/// Must not block or allocate.
+pub(crate) fn add_byte(byte: u8) {
+    let mut packet = PACKET.lock();
+    if packet.len == 0 && byte & PACKET_ALWAYS_SET == 0 {
+        // out of step, wait for the start of the next packet
+        return;
+    }
+    let len = packet.len;
+    packet.bytes[len] = byte;
+    packet.len += 1;
+    if packet.len < PACKET_SIZE.load(Ordering::SeqCst) {
+        return;
+    }
+    packet.len = 0;
+    let event = decode(&packet.bytes, PACKET_SIZE.load(Ordering::SeqCst));
+
+    // nobody is subscribing while the list is locked, so it's never for long
+    if let Some(subscribers) = SUBSCRIBERS.try_lock() {
+        for subscriber in subscribers.iter() {
+            // a full queue means nobody's reading it, so drop the event
+            if subscriber.events.push(event).is_ok() {
+                subscriber.waker.wake();
+            }
+        }
+    }
+}
+
+fn decode(bytes: &[u8; 4], size: usize) -> MouseEvent {
+    let flags = bytes[0];
+    // 9-bit two's complement, with the sign bit in the flags
+    let movement = |byte: u8, sign: u8, overflow: u8| {
+        if flags & overflow != 0 {
+            0
+        } else if flags & sign != 0 {
+            i16::from(byte) - 0x100
+        } else {
+            i16::from(byte)
+        }
+    };
+    let wheel = if size == 4 {
+        // only the low 4 bits, the rest is the extra buttons on 5-button mice
+        ((bytes[3] << 4) as i8) >> 4
+    } else {
+        0
+    };
+    MouseEvent {
+        dx: movement(bytes[1], 0x10, 0x40),
+        // the mouse counts up as it moves away from you
+        dy: -movement(bytes[2], 0x20, 0x80),
+        wheel,
+        buttons: MouseButtons {
+            left: flags & 0x01 != 0,
+            right: flags & 0x02 != 0,
+            middle: flags & 0x04 != 0,
+        },
+    }
+}
+
+pub struct MouseEventStream {
+    subscriber: Arc<Subscriber>,
+}
+
+impl MouseEventStream {
+    /// Starts getting mouse events. Every stream gets all of them, from the
+    /// time it's made on.
+    pub fn new() -> Self {
+        let subscriber = Arc::new(Subscriber {
+            events: ArrayQueue::new(64),
+            waker: AtomicWaker::new(),
+        });
+        interrupts::without_interrupts(|| SUBSCRIBERS.lock().push(subscriber.clone()));
+        MouseEventStream { subscriber }
+    }
+}
+
+impl Default for MouseEventStream {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for MouseEventStream {
+    fn drop(&mut self) {
+        interrupts::without_interrupts(|| {
+            SUBSCRIBERS
+                .lock()
+                .retain(|subscriber| !Arc::ptr_eq(subscriber, &self.subscriber))
+        });
+    }
+}
+
+impl Stream for MouseEventStream {
+    type Item = MouseEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<MouseEvent>> {
+        let subscriber = &self.subscriber;
+
+        // fast path
+        if let Some(event) = subscriber.events.pop() {
+            return Poll::Ready(Some(event));
+        }
+
+        subscriber.waker.register(&cx.waker());
+        match subscriber.events.pop() {
+            Some(event) => {
+                subscriber.waker.take();
+                Poll::Ready(Some(event))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// Moves the pointer around: on the desktop if it's running, otherwise on
+/// the active console, where it selects text to copy and paste.
+pub async fn run_pointer() {
+    let mut events = MouseEventStream::new();
+    let mut pointer = Pointer::new();
+    while let Some(event) = events.next().await {
+        if !gui::pointer_event(&event) {
+            pointer.handle(&event);
+        }
+    }
+}
+
+#[test_case]
+fn test_decode_packet() {
+    // left button, moved left 2 and away from you 3, wheel one notch away
+    let event = decode(&[0x09 | 0x10, 0xFE, 0x03, 0x0F], 4);
+    assert_eq!((event.dx, event.dy, event.wheel), (-2, -3, -1));
+    assert!(event.buttons.left && !event.buttons.right);
+    assert_eq!(decode(&[0x08, 0x05, 0x00, 0x01], 3).wheel, 0);
+}
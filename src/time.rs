@@ -0,0 +1,259 @@
+//! The kernel's clock, kept by the PIT.
+//!
+//! Channel 0 of the PIT raises IRQ 0 `TICKS_PER_SECOND` times a second, and
+//! every interrupt moves the tick count on. `Instant` reads it as a clock
+//! that starts at boot and never goes backwards. Once `calibrate` has
+//! measured the TSC against it, the TSC can stand in as a finer clock, and
+//! so can the HPET once `hpet::init` has found one.
+
+use crate::interrupts::{apic, irq};
+use core::{
+    convert::TryFrom,
+    fmt,
+    ops::{Add, Sub},
+    sync::atomic::{AtomicU64, AtomicU8, Ordering},
+    time::Duration,
+};
+use x86_64::instructions::{hlt, interrupts, port::Port};
+
+pub mod hpet;
+pub mod timer;
+pub mod tsc;
+
+/// What the PIT's input clock runs at, in Hz.
+const PIT_FREQUENCY: u64 = 1_193_182;
+pub const TICKS_PER_SECOND: u64 = 1000;
+const DIVISOR: u64 = PIT_FREQUENCY / TICKS_PER_SECOND;
+/// How long a tick really is, since the divisor doesn't come out even.
+const NANOS_PER_TICK: u64 = DIVISOR * 1_000_000_000 / PIT_FREQUENCY;
+
+const CHANNEL_0_PORT: u16 = 0x40;
+const COMMAND_PORT: u16 = 0x43;
+/// Channel 0, low byte then high byte, mode 2 (rate generator), binary.
+const CHANNEL_0_RATE_GENERATOR: u8 = 0x34;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// How many ticks `calibrate` measures the other clocks over.
+const CALIBRATION_TICKS: u64 = 50;
+
+/// What `Instant::now` reads the time from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockSource {
+    /// The tick count, good to a millisecond.
+    Pit,
+    /// The TSC, good to a nanosecond or so, once it's calibrated.
+    Tsc,
+    /// The HPET's counter, good to its tick length (10ns on QEMU).
+    Hpet,
+}
+
+impl ClockSource {
+    pub const ALL: [ClockSource; 3] = [ClockSource::Pit, ClockSource::Tsc, ClockSource::Hpet];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ClockSource::Pit => "pit",
+            ClockSource::Tsc => "tsc",
+            ClockSource::Hpet => "hpet",
+        }
+    }
+
+    /// Whether it can be used right now.
+    pub fn is_available(self) -> bool {
+        match self {
+            ClockSource::Pit => true,
+            ClockSource::Tsc => tsc::frequency().is_some(),
+            ClockSource::Hpet => hpet::is_available(),
+        }
+    }
+}
+
+static CLOCK_SOURCE: AtomicU8 = AtomicU8::new(ClockSource::Pit as u8);
+/// The latest time handed out, so switching clocks can't go backwards.
+static LATEST_NANOS: AtomicU64 = AtomicU64::new(0);
+
+/// Sets the PIT going at `TICKS_PER_SECOND`, and starts counting its ticks.
+pub fn init() {
+    interrupts::without_interrupts(|| unsafe {
+        let mut channel_0: Port<u8> = Port::new(CHANNEL_0_PORT);
+        Port::new(COMMAND_PORT).write(CHANNEL_0_RATE_GENERATOR);
+        channel_0.write(DIVISOR as u8);
+        channel_0.write((DIVISOR >> 8) as u8);
+    });
+    irq::register_irq(0, "pit", tick).expect("(X_X)  [time]: couldn't register the timer IRQ");
+}
+
+/// Called on every timer interrupt.
+fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    timer::wake_due();
+}
+
+/// Timer interrupts since boot.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+pub fn clock_source() -> ClockSource {
+    ClockSource::ALL[usize::from(CLOCK_SOURCE.load(Ordering::SeqCst))]
+}
+
+pub fn set_clock_source(source: ClockSource) -> Result<(), &'static str> {
+    if !source.is_available() {
+        return Err(match source {
+            ClockSource::Hpet => "there's no HPET in use",
+            _ => "that clock hasn't been calibrated",
+        });
+    }
+    CLOCK_SOURCE.store(source as u8, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Nanoseconds since boot, from the clock source.
+fn nanos() -> u64 {
+    let nanos = match clock_source() {
+        ClockSource::Tsc => tsc::nanos(),
+        ClockSource::Hpet => hpet::nanos(),
+        ClockSource::Pit => None,
+    }
+    .unwrap_or_else(|| ticks() * NANOS_PER_TICK);
+    LATEST_NANOS.fetch_max(nanos, Ordering::SeqCst).max(nanos)
+}
+
+/// Measures how fast the TSC and the local APIC timer run against the PIT,
+/// and switches the clock over to the TSC if its rate is steady, or else to
+/// the HPET if there is one. Takes
+/// `CALIBRATION_TICKS` ticks, and needs interrupts on to see them.
+pub fn calibrate() -> Result<(), &'static str> {
+    if !interrupts::are_enabled() {
+        return Err("the PIT can't be seen ticking with interrupts off");
+    }
+    // start right on a tick
+    let previous = ticks();
+    while ticks() == previous {
+        hlt();
+    }
+    let (start, start_count) = interrupts::without_interrupts(|| {
+        apic::timer::start_calibration();
+        (ticks(), tsc::read())
+    });
+    while ticks() < start + CALIBRATION_TICKS {
+        hlt();
+    }
+    interrupts::without_interrupts(|| {
+        let (end, end_count) = (ticks(), tsc::read());
+        let elapsed = (end - start) * NANOS_PER_TICK;
+        apic::timer::finish_calibration(elapsed);
+        let frequency = u128::from(end_count - start_count) * 1_000_000_000 / u128::from(elapsed);
+        tsc::set_calibration(frequency as u64, end_count, end * NANOS_PER_TICK);
+    });
+    if tsc::is_invariant() {
+        set_clock_source(ClockSource::Tsc)?;
+    } else if hpet::is_available() {
+        set_clock_source(ClockSource::Hpet)?;
+    }
+    Ok(())
+}
+
+/// How long the kernel has been running.
+pub fn uptime() -> Duration {
+    Instant::now() - Instant::BOOT
+}
+
+/// A point in time, counted from boot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant {
+    nanos: u64,
+}
+
+impl Instant {
+    const BOOT: Instant = Instant { nanos: 0 };
+
+    pub fn now() -> Instant {
+        Instant { nanos: nanos() }
+    }
+
+    /// How long after `earlier` this is, or zero if it's before it.
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.nanos.saturating_sub(earlier.nanos))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        let nanos = u64::try_from(duration.as_nanos()).ok()?;
+        Some(Instant {
+            nanos: self.nanos.checked_add(nanos)?,
+        })
+    }
+
+    pub fn checked_sub(&self, duration: Duration) -> Option<Instant> {
+        let nanos = u64::try_from(duration.as_nanos()).ok()?;
+        Some(Instant {
+            nanos: self.nanos.checked_sub(nanos)?,
+        })
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        self.checked_add(duration)
+            .expect("overflow when adding duration to instant")
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, duration: Duration) -> Instant {
+        self.checked_sub(duration)
+            .expect("overflow when subtracting duration from instant")
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+/// Shows a duration like `uptime` does, as `2d 03:04:05.678`.
+pub struct Clock(pub Duration);
+
+impl fmt::Display for Clock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let seconds = self.0.as_secs();
+        let (days, hours) = (seconds / 86400, seconds / 3600 % 24);
+        if days > 0 {
+            write!(f, "{}d ", days)?;
+        }
+        write!(
+            f,
+            "{:02}:{:02}:{:02}.{:03}",
+            hours,
+            seconds / 60 % 60,
+            seconds % 60,
+            self.0.subsec_millis()
+        )
+    }
+}
+
+#[test_case]
+fn test_clock_runs() {
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_millis(5) {
+        x86_64::instructions::hlt();
+    }
+    assert!(Instant::now() > start);
+    assert_eq!(
+        alloc::format!("{}", Clock(Duration::from_millis(93_784_005))),
+        "1d 02:03:04.005"
+    );
+}
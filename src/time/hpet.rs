@@ -0,0 +1,320 @@
+//! The High Precision Event Timer, found through the ACPI `HPET` table.
+//!
+//! Its main counter runs at a fixed rate the HPET reports itself, so unlike
+//! the TSC it needs no calibrating, and it makes a clock as soon as `init`
+//! has turned it on. One of its comparators, routed through the I/O APIC,
+//! gives one-shot timer events.
+
+use super::{timer, Instant};
+use crate::{
+    acpi::hpet::HpetTable,
+    interrupts::{apic, irq, InterruptIndex},
+    memory,
+};
+use alloc::vec::Vec;
+use core::{
+    ptr,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+use spin::Mutex;
+use x86_64::{instructions::interrupts, VirtAddr};
+
+/// General registers.
+const CAPABILITIES: usize = 0x000;
+const CONFIGURATION: usize = 0x010;
+const MAIN_COUNTER: usize = 0x0F0;
+
+/// `CAPABILITIES` bits. The tick length in femtoseconds is in bits 32..64.
+const COUNT_SIZE_64: u64 = 1 << 13;
+
+/// `CONFIGURATION` bits.
+const ENABLE: u64 = 1 << 0;
+const LEGACY_REPLACEMENT: u64 = 1 << 1;
+
+/// The longest tick the spec allows, in femtoseconds (100ns).
+const MAX_PERIOD: u64 = 100_000_000;
+const FEMTOS_PER_NANO: u128 = 1_000_000;
+
+/// Comparator registers, `COMPARATOR_STRIDE` apart.
+const COMPARATOR_CONFIGURATION: usize = 0x100;
+const COMPARATOR_VALUE: usize = 0x108;
+const COMPARATOR_STRIDE: usize = 0x20;
+
+/// Comparator configuration bits. The I/O APIC inputs it can be routed to are
+/// a bitmap in bits 32..64, and the one it is routed to goes in bits 9..14.
+const COMPARATOR_INTERRUPT_ENABLE: u64 = 1 << 2;
+const COMPARATOR_PERIODIC_CAPABLE: u64 = 1 << 4;
+const COMPARATOR_64_BIT: u64 = 1 << 5;
+const COMPARATOR_FSB_CAPABLE: u64 = 1 << 15;
+const COMPARATOR_ROUTE_SHIFT: u32 = 9;
+
+/// GSIs below this are ISA IRQs, which are spoken for.
+const FIRST_FREE_GSI: u32 = 16;
+
+/// Where the registers are mapped, or 0 until `init` finds them.
+static REGISTERS: AtomicU64 = AtomicU64::new(0);
+/// Femtoseconds per counter tick.
+static PERIOD: AtomicU64 = AtomicU64::new(0);
+/// A reading of the counter, and the time since boot it was taken at, to
+/// count on from.
+static BASE_COUNT: AtomicU64 = AtomicU64::new(0);
+static BASE_NANOS: AtomicU64 = AtomicU64::new(0);
+/// How many one-shot events have fired.
+static FIRED: AtomicU64 = AtomicU64::new(0);
+
+/// The comparator one-shot events use, once `start` has set one up.
+static ONE_SHOT: Mutex<Option<OneShot>> = Mutex::new(None);
+
+#[derive(Debug, Clone, Copy)]
+struct OneShot {
+    comparator: usize,
+    gsi: u32,
+    /// Whether the comparator matches all 64 bits of the counter, rather
+    /// than the low 32.
+    wide: bool,
+}
+
+/// One of the HPET's timers, which interrupts when the counter reaches it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Comparator {
+    pub index: usize,
+    /// Whether it can fire every so often by itself.
+    pub periodic: bool,
+    /// Whether it's 64 bits wide, rather than matching the low 32.
+    pub wide: bool,
+    /// Whether it can send its interrupt as an MSI.
+    pub fsb: bool,
+    /// A bitmap of the I/O APIC inputs it can be routed to.
+    pub routes: u32,
+}
+
+unsafe fn read(base: VirtAddr, register: usize) -> u64 {
+    ptr::read_volatile((base + register).as_ptr::<u64>())
+}
+
+unsafe fn write(base: VirtAddr, register: usize, value: u64) {
+    ptr::write_volatile((base + register).as_mut_ptr::<u64>(), value);
+}
+
+fn registers() -> Option<VirtAddr> {
+    match REGISTERS.load(Ordering::SeqCst) {
+        0 => None,
+        base => Some(VirtAddr::new(base)),
+    }
+}
+
+/// Finds the HPET in the ACPI tables and starts its counter, counting on
+/// from the current time.
+///
+/// HPETs with only a 32-bit counter are left alone, since it wraps every
+/// few minutes at best.
+pub fn init() -> Result<(), &'static str> {
+    if registers().is_some() {
+        return Ok(());
+    }
+    let table = HpetTable::find().ok_or("there's no HPET table in the ACPI tables")?;
+    let base = memory::phys_to_virt(table.address).ok_or("the HPET registers aren't mapped")?;
+    let capabilities = unsafe { read(base, CAPABILITIES) };
+    let period = capabilities >> 32;
+    if period == 0 || period > MAX_PERIOD {
+        return Err("the HPET's tick length makes no sense");
+    }
+    if capabilities & COUNT_SIZE_64 == 0 {
+        return Err("the HPET's counter is only 32 bits wide");
+    }
+
+    interrupts::without_interrupts(|| {
+        unsafe {
+            for index in 0..comparator_count(capabilities) {
+                let register = COMPARATOR_CONFIGURATION + index * COMPARATOR_STRIDE;
+                let config = read(base, register);
+                write(base, register, config & !COMPARATOR_INTERRUPT_ENABLE);
+            }
+            let config = read(base, CONFIGURATION);
+            write(base, CONFIGURATION, (config | ENABLE) & !LEGACY_REPLACEMENT);
+        }
+        BASE_COUNT.store(unsafe { read(base, MAIN_COUNTER) }, Ordering::SeqCst);
+        BASE_NANOS.store(super::nanos(), Ordering::SeqCst);
+        PERIOD.store(period, Ordering::SeqCst);
+        REGISTERS.store(base.as_u64(), Ordering::SeqCst);
+    });
+    Ok(())
+}
+
+fn comparator_count(capabilities: u64) -> usize {
+    ((capabilities >> 8) & 0x1F) as usize + 1
+}
+
+/// Whether `init` found one and turned it on.
+pub fn is_available() -> bool {
+    registers().is_some()
+}
+
+/// Counter ticks per second.
+pub fn frequency() -> Option<u64> {
+    registers()?;
+    Some(1_000_000_000_000_000 / PERIOD.load(Ordering::SeqCst))
+}
+
+/// What the main counter reads.
+pub fn read_counter() -> Option<u64> {
+    Some(unsafe { read(registers()?, MAIN_COUNTER) })
+}
+
+/// Nanoseconds since boot.
+pub fn nanos() -> Option<u64> {
+    let counted = read_counter()?.wrapping_sub(BASE_COUNT.load(Ordering::SeqCst));
+    let nanos = u128::from(counted) * u128::from(PERIOD.load(Ordering::SeqCst)) / FEMTOS_PER_NANO;
+    Some(BASE_NANOS.load(Ordering::SeqCst) + nanos as u64)
+}
+
+/// What the counter will read at `instant`. Instants from before `init` come
+/// out as the time it was turned on at.
+pub fn count_at(instant: Instant) -> Option<u64> {
+    registers()?;
+    let nanos = instant
+        .nanos
+        .saturating_sub(BASE_NANOS.load(Ordering::SeqCst));
+    let counts = u128::from(nanos) * FEMTOS_PER_NANO / u128::from(PERIOD.load(Ordering::SeqCst));
+    Some(
+        BASE_COUNT
+            .load(Ordering::SeqCst)
+            .wrapping_add(counts as u64),
+    )
+}
+
+/// Lists its comparators.
+pub fn comparators() -> Vec<Comparator> {
+    let base = match registers() {
+        Some(base) => base,
+        None => return Vec::new(),
+    };
+    let count = comparator_count(unsafe { read(base, CAPABILITIES) });
+    (0..count)
+        .map(|index| {
+            let config =
+                unsafe { read(base, COMPARATOR_CONFIGURATION + index * COMPARATOR_STRIDE) };
+            Comparator {
+                index,
+                periodic: config & COMPARATOR_PERIODIC_CAPABLE != 0,
+                wide: config & COMPARATOR_64_BIT != 0,
+                fsb: config & COMPARATOR_FSB_CAPABLE != 0,
+                routes: (config >> 32) as u32,
+            }
+        })
+        .collect()
+}
+
+/// How many one-shot events have fired since boot.
+pub fn fired() -> u64 {
+    FIRED.load(Ordering::SeqCst)
+}
+
+/// Called when the one-shot comparator fires.
+fn interrupt() {
+    FIRED.fetch_add(1, Ordering::SeqCst);
+    timer::wake_due();
+}
+
+/// Picks a comparator that can be routed to an I/O APIC input that isn't an
+/// ISA IRQ, 64-bit ones first, and sends that input to `InterruptIndex::Hpet`.
+fn set_up_one_shot() -> Result<OneShot, &'static str> {
+    if !apic::is_enabled() {
+        return Err("HPET events need the APIC");
+    }
+    let mut comparators = comparators();
+    comparators.sort_by_key(|comparator| !comparator.wide);
+    let one_shot = comparators
+        .iter()
+        .find_map(|comparator| {
+            let gsi = (FIRST_FREE_GSI..32)
+                .find(|&gsi| comparator.routes & 1 << gsi != 0 && apic::handles_gsi(gsi))?;
+            Some(OneShot {
+                comparator: comparator.index,
+                gsi,
+                wide: comparator.wide,
+            })
+        })
+        .ok_or("no HPET comparator can reach a free I/O APIC input")?;
+    let id = irq::register_vector(InterruptIndex::Hpet as u8, "hpet", interrupt)?;
+    if let Err(e) = apic::route_gsi(one_shot.gsi, InterruptIndex::Hpet as u8) {
+        irq::unregister(id);
+        return Err(e);
+    }
+    Ok(one_shot)
+}
+
+/// Fires an interrupt once, at `deadline`, which wakes the timers in
+/// `time::timer` that are due. Replaces the last one if it hasn't fired yet.
+pub fn start(deadline: Instant) -> Result<(), &'static str> {
+    let base = registers().ok_or("there's no HPET in use")?;
+    let count = count_at(deadline).ok_or("there's no HPET in use")?;
+    let passed = interrupts::without_interrupts(|| {
+        let mut one_shot = ONE_SHOT.lock();
+        let OneShot {
+            comparator,
+            gsi,
+            wide,
+        } = match *one_shot {
+            Some(one_shot) => one_shot,
+            None => *one_shot.insert(set_up_one_shot()?),
+        };
+        let offset = comparator * COMPARATOR_STRIDE;
+        unsafe {
+            // edge triggered and not periodic, which are both 0
+            let config = u64::from(gsi) << COMPARATOR_ROUTE_SHIFT | COMPARATOR_INTERRUPT_ENABLE;
+            write(base, COMPARATOR_CONFIGURATION + offset, config);
+            // a 32-bit one only looks at the low 32 bits of the counter
+            let count = if wide { count } else { u64::from(count as u32) };
+            write(base, COMPARATOR_VALUE + offset, count);
+            // it only fires when the counter goes past it
+            let now = read(base, MAIN_COUNTER);
+            if wide {
+                Ok(now.wrapping_sub(count) < 1 << 63)
+            } else {
+                Ok((now as u32).wrapping_sub(count as u32) < 1 << 31)
+            }
+        }
+    })?;
+    if passed {
+        interrupt();
+    }
+    Ok(())
+}
+
+/// Fires an interrupt once, `duration` from now.
+pub fn start_in(duration: Duration) -> Result<(), &'static str> {
+    start(Instant::now() + duration)
+}
+
+/// Stops a one-shot event that hasn't fired yet.
+pub fn stop() {
+    let base = match registers() {
+        Some(base) => base,
+        None => return,
+    };
+    interrupts::without_interrupts(|| {
+        if let Some(one_shot) = *ONE_SHOT.lock() {
+            let register = COMPARATOR_CONFIGURATION + one_shot.comparator * COMPARATOR_STRIDE;
+            unsafe {
+                let config = read(base, register);
+                write(base, register, config & !COMPARATOR_INTERRUPT_ENABLE);
+            }
+        }
+    });
+}
+
+#[test_case]
+fn test_hpet_counts() {
+    init().unwrap();
+    assert!(!comparators().is_empty());
+    let start = read_counter().unwrap();
+    let now = nanos().unwrap();
+    x86_64::instructions::hlt();
+    assert!(read_counter().unwrap() > start);
+    assert!(nanos().unwrap() > now);
+    // a moment from now is ahead of the counter
+    let soon = Instant::now() + Duration::from_millis(10);
+    assert!(count_at(soon).unwrap() > read_counter().unwrap());
+}
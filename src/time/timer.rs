@@ -0,0 +1,222 @@
+//! Waiting for time to pass without spinning.
+//!
+//! A `Sleep` that's waiting puts its deadline and waker in `TIMERS`, and the
+//! timer interrupt wakes the ones that are due. The interrupt handler only
+//! wakes them: the futures take themselves out again once they're polled, so
+//! nothing gets freed with interrupts off.
+
+use super::Instant;
+use alloc::collections::BTreeMap;
+use core::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+use futures_util::{
+    future::{select, Either},
+    pin_mut,
+    stream::Stream,
+};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+struct Timer {
+    waker: Waker,
+    /// Set once the interrupt handler has woken it, so it isn't woken again
+    /// on every tick until it's polled.
+    woken: bool,
+}
+
+/// Keyed by the deadline, and an ID to tell apart timers due at once.
+static TIMERS: Mutex<BTreeMap<(Instant, u64), Timer>> = Mutex::new(BTreeMap::new());
+
+/// Called by the timer interrupt handler after every tick.
+///
+/// Must not block or allocate.
+pub(crate) fn wake_due() {
+    let now = Instant::now();
+    // tasks only lock it with interrupts off, so this never fails
+    if let Some(mut timers) = TIMERS.try_lock() {
+        for (_, timer) in timers.range_mut(..=(now, u64::MAX)) {
+            if !timer.woken {
+                timer.woken = true;
+                timer.waker.wake_by_ref();
+            }
+        }
+    }
+}
+
+/// Completes `duration` from now.
+pub fn sleep(duration: Duration) -> Sleep {
+    sleep_until(Instant::now() + duration)
+}
+
+/// Completes at `deadline`, or straight away if that's passed.
+pub fn sleep_until(deadline: Instant) -> Sleep {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+    Sleep {
+        deadline,
+        id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        registered: false,
+    }
+}
+
+pub struct Sleep {
+    deadline: Instant,
+    id: u64,
+    /// Whether it's in `TIMERS`.
+    registered: bool,
+}
+
+impl Sleep {
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    pub fn is_elapsed(&self) -> bool {
+        Instant::now() >= self.deadline
+    }
+
+    /// Makes it wait for `deadline` instead, even if it already finished.
+    pub fn reset(&mut self, deadline: Instant) {
+        self.unregister();
+        self.deadline = deadline;
+    }
+
+    fn unregister(&mut self) {
+        if self.registered {
+            interrupts::without_interrupts(|| TIMERS.lock().remove(&(self.deadline, self.id)));
+            self.registered = false;
+        }
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.is_elapsed() {
+            self.unregister();
+            return Poll::Ready(());
+        }
+
+        let key = (self.deadline, self.id);
+        interrupts::without_interrupts(|| {
+            let mut timers = TIMERS.lock();
+            match timers.get_mut(&key) {
+                Some(timer) => {
+                    if !timer.waker.will_wake(cx.waker()) {
+                        timer.waker = cx.waker().clone();
+                    }
+                    timer.woken = false;
+                }
+                None => {
+                    timers.insert(
+                        key,
+                        Timer {
+                            waker: cx.waker().clone(),
+                            woken: false,
+                        },
+                    );
+                }
+            }
+        });
+        self.registered = true;
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        self.unregister();
+    }
+}
+
+/// Yields every `period`, the first time `period` from now.
+///
+/// The ticks stay in step with when it started rather than drifting by
+/// however late each one is read. Ticks that were missed altogether are
+/// skipped.
+pub fn interval(period: Duration) -> Interval {
+    assert!(
+        period > Duration::ZERO,
+        "(X_X)  [timer]: an interval can't be zero"
+    );
+    Interval {
+        period,
+        sleep: sleep(period),
+    }
+}
+
+pub struct Interval {
+    period: Duration,
+    sleep: Sleep,
+}
+
+impl Interval {
+    pub fn period(&self) -> Duration {
+        self.period
+    }
+}
+
+impl Stream for Interval {
+    type Item = Instant;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Instant>> {
+        if Pin::new(&mut self.sleep).poll(cx).is_pending() {
+            return Poll::Pending;
+        }
+        let tick = self.sleep.deadline();
+        let mut next = tick + self.period;
+        let now = Instant::now();
+        if next <= now {
+            next = now + self.period;
+        }
+        self.sleep.reset(next);
+        Poll::Ready(Some(tick))
+    }
+}
+
+/// What `timeout` gives back when time ran out first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed;
+
+impl fmt::Display for Elapsed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "timed out")
+    }
+}
+
+/// Runs `future`, giving up on it if it takes longer than `duration`.
+pub async fn timeout<F: Future>(future: F, duration: Duration) -> Result<F::Output, Elapsed> {
+    let deadline = sleep(duration);
+    pin_mut!(future);
+    match select(future, deadline).await {
+        Either::Left((output, _)) => Ok(output),
+        Either::Right(_) => Err(Elapsed),
+    }
+}
+
+#[test_case]
+fn test_timers_wake_in_order() {
+    use futures_util::task::noop_waker;
+
+    let waker = noop_waker();
+    let mut cx = Context::from_waker(&waker);
+    let mut late = sleep(Duration::from_millis(4));
+    let mut early = sleep(Duration::from_millis(2));
+    assert!(Pin::new(&mut late).poll(&mut cx).is_pending());
+    assert!(Pin::new(&mut early).poll(&mut cx).is_pending());
+
+    while Pin::new(&mut early).poll(&mut cx).is_pending() {
+        x86_64::instructions::hlt();
+    }
+    assert!(early.deadline() < late.deadline());
+    while Pin::new(&mut late).poll(&mut cx).is_pending() {
+        x86_64::instructions::hlt();
+    }
+    assert!(interrupts::without_interrupts(|| TIMERS.lock().is_empty()));
+}
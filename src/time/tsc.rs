@@ -0,0 +1,87 @@
+//! The CPU's time stamp counter, which makes a nanosecond clock once
+//! `time::calibrate` has measured it against the PIT.
+
+use super::Instant;
+use core::{
+    arch::{asm, x86_64::__cpuid},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// CPUID leaf 0x8000_0007 EDX bit for a TSC that runs at the same rate
+/// whatever the CPU's clock speed and sleep state.
+const CPUID_INVARIANT_TSC: u32 = 1 << 8;
+/// CPUID leaf 1 ECX bit for the local APIC timer's TSC-deadline mode.
+const CPUID_TSC_DEADLINE: u32 = 1 << 24;
+
+/// Counts per second, or 0 until it's been calibrated.
+static FREQUENCY: AtomicU64 = AtomicU64::new(0);
+/// A reading of the counter, and the time since boot it was taken at, to
+/// count on from.
+static BASE_COUNT: AtomicU64 = AtomicU64::new(0);
+static BASE_NANOS: AtomicU64 = AtomicU64::new(0);
+
+pub fn read() -> u64 {
+    let (high, low): (u32, u32);
+    unsafe {
+        asm!("rdtsc", out("eax") low, out("edx") high, options(nomem, nostack));
+    }
+    (high as u64) << 32 | low as u64
+}
+
+/// Whether the counter keeps a steady rate, which it has to for a clock.
+pub fn is_invariant() -> bool {
+    __cpuid(0x8000_0000).eax >= 0x8000_0007 && __cpuid(0x8000_0007).edx & CPUID_INVARIANT_TSC != 0
+}
+
+/// Whether the local APIC timer can fire at a TSC value.
+pub fn has_deadline_mode() -> bool {
+    __cpuid(1).ecx & CPUID_TSC_DEADLINE != 0
+}
+
+/// Counts per second, once it's been calibrated.
+pub fn frequency() -> Option<u64> {
+    match FREQUENCY.load(Ordering::SeqCst) {
+        0 => None,
+        frequency => Some(frequency),
+    }
+}
+
+/// Called by `time::calibrate` with the rate it measured, and a reading
+/// taken `nanos` after boot.
+pub(super) fn set_calibration(frequency: u64, count: u64, nanos: u64) {
+    BASE_COUNT.store(count, Ordering::SeqCst);
+    BASE_NANOS.store(nanos, Ordering::SeqCst);
+    FREQUENCY.store(frequency, Ordering::SeqCst);
+}
+
+/// Nanoseconds since boot, once it's been calibrated.
+pub fn nanos() -> Option<u64> {
+    let frequency = frequency()?;
+    let counted = read().wrapping_sub(BASE_COUNT.load(Ordering::SeqCst));
+    let nanos = u128::from(counted) * 1_000_000_000 / u128::from(frequency);
+    Some(BASE_NANOS.load(Ordering::SeqCst) + nanos as u64)
+}
+
+/// What the counter will read at `instant`, for TSC-deadline mode. Instants
+/// from before calibration come out as the time it was calibrated at.
+pub fn count_at(instant: Instant) -> Option<u64> {
+    let frequency = frequency()?;
+    let (base_count, base_nanos) = (
+        BASE_COUNT.load(Ordering::SeqCst),
+        BASE_NANOS.load(Ordering::SeqCst),
+    );
+    let nanos = instant.nanos.saturating_sub(base_nanos);
+    let counts = u128::from(nanos) * u128::from(frequency) / 1_000_000_000;
+    Some(base_count.wrapping_add(counts as u64))
+}
+
+#[test_case]
+fn test_calibrate() {
+    super::calibrate().unwrap();
+    assert!(frequency().is_some());
+    let now = nanos().unwrap();
+    assert!(nanos().unwrap() >= now);
+    // the deadline for a moment from now is ahead of the counter
+    let soon = super::Instant::now() + core::time::Duration::from_millis(10);
+    assert!(count_at(soon).unwrap() > read());
+}
@@ -1,18 +1,33 @@
-use core::fmt::{self, Debug};
-use lazy_static::lazy_static;
-use spin::Mutex;
+use alloc::{boxed::Box, collections::VecDeque, format};
+use ansi::{Action, Csi, Parser, Style};
+use core::{
+    array,
+    fmt::{self, Debug},
+};
 use volatile::Volatile;
 
-lazy_static! {
-    /// A global `Writer` instance that can be used for printing to the VGA text buffer.
-    ///
-    /// Used by the `print!` and `println!` macros.
-    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
-        column_position: 0,
-        color_code: ColorCode::new(Color::Yellow, Color::Black),
-        buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
-    });
-}
+pub(crate) mod ansi;
+pub mod console;
+pub mod cp437;
+pub mod selection;
+
+/// The VGA CRT controller's index and data ports, which the cursor is set through.
+const CRTC_INDEX: u16 = 0x3D4;
+const CRTC_DATA: u16 = 0x3D5;
+/// CRTC registers: the first and last scanline of the cursor, and its position.
+const CURSOR_START: u8 = 0x0A;
+const CURSOR_END: u8 = 0x0B;
+const CURSOR_LOCATION_HIGH: u8 = 0x0E;
+const CURSOR_LOCATION_LOW: u8 = 0x0F;
+/// Set in `CURSOR_START` to turn the cursor off.
+const CURSOR_DISABLE: u8 = 1 << 5;
+
+/// The colors text starts out with, and what `ESC [ 0 m` goes back to.
+pub(crate) const DEFAULT_STYLE: Style = Style::new(Color::Yellow, Color::Black);
+
+/// How many lines of scrollback `enable_scrollback` is usually called with.
+/// Each line takes 160 bytes of heap.
+pub const DEFAULT_SCROLLBACK_LINES: usize = 100;
 
 /// The standard color palette in VGA text mode.
 #[allow(dead_code)]
@@ -44,7 +59,7 @@ struct ColorCode(u8);
 
 impl ColorCode {
     /// Create a new `ColorCode` with the given foreground and background colors.
-    fn new(foreground: Color, background: Color) -> ColorCode {
+    const fn new(foreground: Color, background: Color) -> ColorCode {
         ColorCode((background as u8) << 4 | (foreground as u8))
     }
 }
@@ -68,86 +83,643 @@ struct Buffer {
     chars: [[Volatile<ScreenChar>; BUFFER_WIDTH]; BUFFER_HEIGHT],
 }
 
+/// What the blinking hardware cursor looks like.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CursorShape {
+    /// The usual thin line at the bottom of the character.
+    Underline,
+    /// The bottom half of the character.
+    HalfBlock,
+    /// The whole character.
+    Block,
+}
+
+impl CursorShape {
+    /// The first and last scanline the cursor covers, out of the font's 16.
+    fn scanlines(self) -> (u8, u8) {
+        match self {
+            CursorShape::Underline => (14, 15),
+            CursorShape::HalfBlock => (8, 15),
+            CursorShape::Block => (0, 15),
+        }
+    }
+}
+
+/// Lines that scrolled off the top of the screen, so they can be paged back to.
+struct Scrollback {
+    lines: VecDeque<[ScreenChar; BUFFER_WIDTH]>,
+    capacity: usize,
+    /// How many lines back the view is. 0 means the live screen is showing.
+    offset: usize,
+    /// While scrolled back, output goes here instead of to the screen.
+    live: Option<Box<Buffer>>,
+}
+
+/// A copy of everything on screen, taken with `Writer::save_screen`.
+///
+/// Lets full-screen programs (like the editor) put the screen back the way
+/// they found it when they exit.
+pub struct SavedScreen {
+    chars: [[ScreenChar; BUFFER_WIDTH]; BUFFER_HEIGHT],
+    column_position: usize,
+    row_position: usize,
+}
+
 /// A writer type that allows writing ASCII bytes and strings to an underlying `Buffer`.
 ///
 /// Wraps lines at `BUFFER_WIDTH`. Supports newline characters and implements the
-/// `core::fmt::Write` trait.
+/// `core::fmt::Write` trait. Strings can use VT100/ANSI escape sequences for
+/// colors and moving the cursor around, see `Writer::csi` for which ones.
 pub struct Writer {
     column_position: usize,
+    row_position: usize,
     color_code: ColorCode,
+    /// What `color_code` was made from, changed with `ESC [ ... m`.
+    style: Style,
+    parser: Parser,
+    /// Where `ESC 7` saved the cursor: row, column and style.
+    saved_cursor: (usize, usize, Style),
+    cursor_shape: CursorShape,
+    /// Changed with `set_cursor_visible` or `ESC [ ? 25 h`/`l`.
+    cursor_visible: bool,
+    /// The VGA text buffer. Only touched while this is the active console.
     buffer: &'static mut Buffer,
+    /// Where the screen is kept while another console is active.
+    backing: &'static mut Buffer,
+    /// Whether this is the active console, drawing straight to `buffer`.
+    visible: bool,
+    /// Needs the heap, so it's `None` until `enable_scrollback` is called.
+    scrollback: Option<Scrollback>,
 }
 
 impl Writer {
+    /// A writer for one console, see `console`.
+    fn new(backing: &'static mut Buffer, visible: bool) -> Self {
+        Writer {
+            column_position: 0,
+            row_position: BUFFER_HEIGHT - 1,
+            color_code: DEFAULT_STYLE.color_code(),
+            style: DEFAULT_STYLE,
+            parser: Parser::new(),
+            saved_cursor: (BUFFER_HEIGHT - 1, 0, DEFAULT_STYLE),
+            cursor_shape: CursorShape::Underline,
+            cursor_visible: true,
+            buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
+            backing,
+            scrollback: None,
+            visible,
+        }
+    }
+
     /// Writes an ASCII byte to the buffer.
     ///
     /// Wraps lines at `BUFFER_WIDTH`. Supports the `\n` newline character.
     pub fn write_byte(&mut self, byte: u8) {
         match byte {
             b'\n' => self.new_line(),
-            byte => {
-                if self.column_position >= BUFFER_WIDTH {
-                    self.new_line();
-                }
+            byte => self.put_byte(byte),
+        }
+    }
 
-                let row = BUFFER_HEIGHT - 1;
-                let col = self.column_position;
+    /// Puts CP437 character `byte` at the cursor, even if it's a control character.
+    fn put_byte(&mut self, byte: u8) {
+        if self.column_position >= BUFFER_WIDTH {
+            self.new_line();
+        }
+
+        let row = self.row_position;
+        let col = self.column_position;
+
+        let color_code = self.color_code;
+        self.screen().chars[row][col].write(ScreenChar {
+            ascii_character: byte,
+            color_code,
+        });
+        self.column_position += 1;
+    }
 
-                let color_code = self.color_code;
-                self.buffer.chars[row][col].write(ScreenChar {
-                    ascii_character: byte,
-                    color_code,
-                });
-                self.column_position += 1;
+    /// Writes the given string to the buffer, following any escape sequences in it.
+    ///
+    /// Wraps lines at `BUFFER_WIDTH`. Supports the `\n` newline character. Non-ASCII
+    /// characters are printed with their CP437 glyph, or a square if the VGA font
+    /// doesn't have one (see `cp437::encode`).
+    fn write_string(&mut self, s: &str) {
+        for c in s.chars() {
+            match self.parser.advance(c) {
+                Some(Action::Print(c)) => self.put_byte(cp437::encode(c)),
+                Some(Action::Control(c)) => self.control(c),
+                Some(Action::Escape(c)) => self.escape(c),
+                Some(Action::Csi(csi)) => self.csi(&csi),
+                None => {}
             }
         }
+        self.update_cursor();
     }
 
-    /// Writes the given ASCII string to the buffer.
+    /// Handles a control character. The ones that don't do anything are ignored.
+    fn control(&mut self, c: char) {
+        match c {
+            '\n' => self.new_line(),
+            '\r' => self.column_position = 0,
+            // backspace only moves the cursor, like on a real terminal
+            '\x08' => {
+                self.column_position = self.column_position.min(BUFFER_WIDTH - 1).saturating_sub(1)
+            }
+            '\t' if self.column_position < BUFFER_WIDTH => {
+                self.column_position = ((self.column_position / 8 + 1) * 8).min(BUFFER_WIDTH - 1)
+            }
+            _ => {}
+        }
+    }
+
+    /// Handles `ESC` followed by `c`.
+    fn escape(&mut self, c: char) {
+        match c {
+            // save and restore the cursor
+            '7' => self.save_cursor(),
+            '8' => self.restore_cursor(),
+            // index: down a line, scrolling at the bottom
+            'D' => {
+                let column = self.column_position;
+                self.new_line();
+                self.column_position = column;
+            }
+            // next line
+            'E' => self.new_line(),
+            // reverse index: up a line, scrolling the other way at the top
+            'M' => {
+                if self.row_position > 0 {
+                    self.row_position -= 1;
+                } else {
+                    self.scroll_down();
+                }
+            }
+            // reset
+            'c' => {
+                self.set_style(DEFAULT_STYLE);
+                for row in 0..BUFFER_HEIGHT {
+                    self.clear_row(row);
+                }
+                self.move_to(0, 0);
+            }
+            _ => {}
+        }
+    }
+
+    /// Handles a CSI sequence. Supports:
     ///
-    /// Wraps lines at `BUFFER_WIDTH`. Supports the `\n` newline character. Does **not**
-    /// support strings with non-ASCII characters, since they can't be printed in the VGA text
-    /// mode.
-    fn write_string(&mut self, s: &str) {
-        for byte in s.bytes() {
-            match byte {
-                // printable ASCII byte or newline
-                0x20..=0x7e | b'\n' => self.write_byte(byte),
-                // not part of printable ASCII range
-                _ => self.write_byte(0xfe),
+    /// - `A`/`B`/`C`/`D`: cursor up/down/forward/back `n` (default 1)
+    /// - `E`/`F`: start of the line `n` down/up
+    /// - `G`: column `n`, `d`: row `n`, `H`/`f`: row and column (1-based)
+    /// - `J`: erase below (0), above (1) or the whole screen (2)
+    /// - `K`: erase to the right (0), to the left (1) or the whole line (2)
+    /// - `@`/`P`: insert/delete `n` characters, `X`: erase `n` characters
+    /// - `m`: colors (SGR), `s`/`u`: save/restore the cursor
+    /// - `? 25 h`/`? 25 l`: show/hide the cursor
+    fn csi(&mut self, csi: &Csi) {
+        if csi.private {
+            match (csi.param(0, 0), csi.action) {
+                (25, 'h') => self.cursor_visible = true,
+                (25, 'l') => self.cursor_visible = false,
+                _ => {}
             }
+            return;
+        }
+        let n = usize::from(csi.param(0, 1));
+        let (row, col) = (
+            self.row_position,
+            self.column_position.min(BUFFER_WIDTH - 1),
+        );
+        match csi.action {
+            'A' => self.move_to(row.saturating_sub(n), col),
+            'B' => self.move_to(row + n, col),
+            'C' => self.move_to(row, col + n),
+            'D' => self.move_to(row, col.saturating_sub(n)),
+            'E' => self.move_to(row + n, 0),
+            'F' => self.move_to(row.saturating_sub(n), 0),
+            'G' => self.move_to(row, n - 1),
+            'd' => self.move_to(n - 1, col),
+            'H' | 'f' => self.move_to(n - 1, usize::from(csi.param(1, 1)) - 1),
+            'J' => match csi.param(0, 0) {
+                0 => {
+                    self.erase(row, col, BUFFER_WIDTH);
+                    for row in row + 1..BUFFER_HEIGHT {
+                        self.clear_row(row);
+                    }
+                }
+                1 => {
+                    for row in 0..row {
+                        self.clear_row(row);
+                    }
+                    self.erase(row, 0, col + 1);
+                }
+                _ => {
+                    for row in 0..BUFFER_HEIGHT {
+                        self.clear_row(row);
+                    }
+                }
+            },
+            'K' => match csi.param(0, 0) {
+                0 => self.erase(row, col, BUFFER_WIDTH),
+                1 => self.erase(row, 0, col + 1),
+                _ => self.clear_row(row),
+            },
+            '@' | 'P' => {
+                let n = n.min(BUFFER_WIDTH - col);
+                let screen = self.screen();
+                if csi.action == '@' {
+                    for c in (col + n..BUFFER_WIDTH).rev() {
+                        let character = screen.chars[row][c - n].read();
+                        screen.chars[row][c].write(character);
+                    }
+                    self.erase(row, col, col + n);
+                } else {
+                    for c in col..BUFFER_WIDTH - n {
+                        let character = screen.chars[row][c + n].read();
+                        screen.chars[row][c].write(character);
+                    }
+                    self.erase(row, BUFFER_WIDTH - n, BUFFER_WIDTH);
+                }
+            }
+            'X' => self.erase(row, col, col + n),
+            'm' => {
+                let mut style = self.style;
+                style.apply_sgr(csi.params(), DEFAULT_STYLE);
+                self.set_style(style);
+            }
+            's' => self.save_cursor(),
+            'u' => self.restore_cursor(),
+            _ => {}
         }
     }
 
-    /// Shifts all lines one line up and clears the last row.
+    /// Moves the cursor, keeping it on screen.
+    fn move_to(&mut self, row: usize, col: usize) {
+        self.row_position = row.min(BUFFER_HEIGHT - 1);
+        self.column_position = col.min(BUFFER_WIDTH - 1);
+    }
+
+    /// Moves the cursor to `row` and `col` (0-based), where the next character
+    /// printed will go. Positions past the edge of the screen are clamped.
+    pub fn set_position(&mut self, row: usize, col: usize) {
+        self.move_to(row, col);
+        self.update_cursor();
+    }
+
+    /// Where the cursor is, as a row and a column.
+    pub fn position(&self) -> (usize, usize) {
+        (
+            self.row_position,
+            self.column_position.min(BUFFER_WIDTH - 1),
+        )
+    }
+
+    pub fn set_cursor_shape(&mut self, shape: CursorShape) {
+        self.cursor_shape = shape;
+        self.update_cursor();
+    }
+
+    pub fn set_cursor_visible(&mut self, visible: bool) {
+        self.cursor_visible = visible;
+        self.update_cursor();
+    }
+
+    /// Moves the hardware cursor to where the next character will go. It's
+    /// hidden while the view is scrolled back.
+    fn update_cursor(&self) {
+        if !self.visible {
+            return;
+        }
+        let (row, col) = self.position();
+        let location = row * BUFFER_WIDTH + col;
+        let (start, end) = self.cursor_shape.scanlines();
+        let start = if self.cursor_visible && !self.is_scrolled_back() {
+            start
+        } else {
+            CURSOR_DISABLE
+        };
+
+        // the top bits of the shape registers mean other things, leave them alone
+        let old_start = read_crtc(CURSOR_START);
+        let old_end = read_crtc(CURSOR_END);
+        write_crtc(CURSOR_START, old_start & 0xC0 | start);
+        write_crtc(CURSOR_END, old_end & 0xE0 | end);
+        write_crtc(CURSOR_LOCATION_HIGH, (location >> 8) as u8);
+        write_crtc(CURSOR_LOCATION_LOW, location as u8);
+    }
+
+    fn set_style(&mut self, style: Style) {
+        self.style = style;
+        self.color_code = style.color_code();
+    }
+
+    fn save_cursor(&mut self) {
+        self.saved_cursor = (self.row_position, self.column_position, self.style);
+    }
+
+    fn restore_cursor(&mut self) {
+        let (row, col, style) = self.saved_cursor;
+        self.move_to(row, col);
+        self.set_style(style);
+    }
+
+    /// Blanks out columns `start..end` of `row`.
+    fn erase(&mut self, row: usize, start: usize, end: usize) {
+        let blank = self.blank();
+        for col in start..end.min(BUFFER_WIDTH) {
+            self.screen().chars[row][col].write(blank);
+        }
+    }
+
+    /// Moves the cursor to the start of the next line, scrolling if it's on the last one.
     fn new_line(&mut self) {
+        self.column_position = 0;
+        if self.row_position < BUFFER_HEIGHT - 1 {
+            self.row_position += 1;
+        } else {
+            self.scroll_up();
+        }
+    }
+
+    /// Shifts all lines one line down and clears the first row.
+    fn scroll_down(&mut self) {
+        let screen = self.screen();
+        for row in (1..BUFFER_HEIGHT).rev() {
+            for col in 0..BUFFER_WIDTH {
+                let character = screen.chars[row - 1][col].read();
+                screen.chars[row][col].write(character);
+            }
+        }
+        self.clear_row(0);
+    }
+
+    /// Shifts all lines one line up and clears the last row.
+    ///
+    /// The top row goes into the scrollback, if there is one.
+    fn scroll_up(&mut self) {
+        if self.scrollback.is_some() {
+            let mut top = [self.blank(); BUFFER_WIDTH];
+            for (col, character) in top.iter_mut().enumerate() {
+                *character = self.screen().chars[0][col].read();
+            }
+            let scrollback = self.scrollback.as_mut().unwrap();
+            if scrollback.lines.len() == scrollback.capacity {
+                scrollback.lines.pop_front();
+            }
+            scrollback.lines.push_back(top);
+            if scrollback.offset > 0 {
+                // keep showing the same lines, the live screen moved on without us
+                scrollback.offset = (scrollback.offset + 1).min(scrollback.lines.len());
+                self.render_scrollback();
+            }
+        }
+
+        let screen = self.screen();
         for row in 1..BUFFER_HEIGHT {
             for col in 0..BUFFER_WIDTH {
-                let character = self.buffer.chars[row][col].read();
-                self.buffer.chars[row - 1][col].write(character);
+                let character = screen.chars[row][col].read();
+                screen.chars[row - 1][col].write(character);
             }
         }
         self.clear_row(BUFFER_HEIGHT - 1);
-        self.column_position = 0;
     }
 
-    /// Clears a row by overwriting it with blank characters.
-    fn clear_row(&mut self, row: usize) {
-        let blank = ScreenChar {
+    /// The buffer output should go to: the screen, or the hidden live screen
+    /// while scrolled back.
+    fn screen(&mut self) -> &mut Buffer {
+        match self.scrollback.as_mut().and_then(|s| s.live.as_mut()) {
+            Some(live) => live,
+            None if self.visible => self.buffer,
+            None => self.backing,
+        }
+    }
+
+    /// Stops drawing to the screen, keeping what's on it in `backing`.
+    fn hide(&mut self) {
+        if !self.visible {
+            return;
+        }
+        self.scroll_to_live();
+        for row in 0..BUFFER_HEIGHT {
+            for col in 0..BUFFER_WIDTH {
+                self.backing.chars[row][col].write(self.buffer.chars[row][col].read());
+            }
+        }
+        self.visible = false;
+    }
+
+    /// Puts this console's screen on the screen, and draws there from now on.
+    fn show(&mut self) {
+        for row in 0..BUFFER_HEIGHT {
+            for col in 0..BUFFER_WIDTH {
+                self.buffer.chars[row][col].write(self.backing.chars[row][col].read());
+            }
+        }
+        self.visible = true;
+        self.update_cursor();
+    }
+
+    fn blank(&self) -> ScreenChar {
+        ScreenChar {
             ascii_character: b' ',
             color_code: self.color_code,
+        }
+    }
+
+    /// Keeps up to `lines` lines that scroll off the top of the screen. 0 turns
+    /// scrollback off. Changing the size forgets what's already been kept.
+    pub fn set_scrollback(&mut self, lines: usize) {
+        self.scroll_to_live();
+        self.scrollback = if lines == 0 {
+            None
+        } else {
+            Some(Scrollback {
+                lines: VecDeque::with_capacity(lines),
+                capacity: lines,
+                offset: 0,
+                live: None,
+            })
+        };
+    }
+
+    /// How many lines of scrollback are kept.
+    pub fn scrollback_size(&self) -> usize {
+        self.scrollback.as_ref().map_or(0, |s| s.capacity)
+    }
+
+    /// Scrolls the view `lines` lines back into the scrollback, or forward if
+    /// `lines` is negative.
+    pub fn scroll(&mut self, lines: isize) {
+        if !self.visible {
+            return;
+        }
+        let scrollback = match self.scrollback.as_mut() {
+            Some(scrollback) => scrollback,
+            None => return,
         };
+        let offset = (scrollback.offset as isize + lines)
+            .max(0)
+            .min(scrollback.lines.len() as isize) as usize;
+        if offset == 0 {
+            self.scroll_to_live();
+            return;
+        }
+
+        if scrollback.live.is_none() {
+            // start sending output to a copy of the screen, so we can draw over it
+            let blank = self.blank();
+            let mut live = Box::new(Buffer {
+                chars: array::from_fn(|_| array::from_fn(|_| Volatile::new(blank))),
+            });
+            for row in 0..BUFFER_HEIGHT {
+                for col in 0..BUFFER_WIDTH {
+                    live.chars[row][col].write(self.buffer.chars[row][col].read());
+                }
+            }
+            let scrollback = self.scrollback.as_mut().unwrap();
+            scrollback.live = Some(live);
+        }
+        self.scrollback.as_mut().unwrap().offset = offset;
+        self.render_scrollback();
+        self.update_cursor();
+    }
+
+    /// Goes back to showing the live screen.
+    pub fn scroll_to_live(&mut self) {
+        let live = match self.scrollback.as_mut() {
+            Some(scrollback) => {
+                scrollback.offset = 0;
+                scrollback.live.take()
+            }
+            None => None,
+        };
+        if let Some(live) = live {
+            for row in 0..BUFFER_HEIGHT {
+                for col in 0..BUFFER_WIDTH {
+                    self.buffer.chars[row][col].write(live.chars[row][col].read());
+                }
+            }
+            self.update_cursor();
+        }
+    }
+
+    /// Returns `true` while the view is scrolled back.
+    pub fn is_scrolled_back(&self) -> bool {
+        self.scrollback.as_ref().map_or(false, |s| s.offset > 0)
+    }
+
+    /// Draws the scrollback (and the top of the live screen below it) onto the screen.
+    fn render_scrollback(&mut self) {
+        let scrollback = match self.scrollback.as_ref() {
+            Some(scrollback) if scrollback.offset > 0 => scrollback,
+            _ => return,
+        };
+        let live = scrollback.live.as_ref().unwrap();
+        let first = scrollback.lines.len() - scrollback.offset;
+        for row in 0..BUFFER_HEIGHT {
+            let line = first + row;
+            for col in 0..BUFFER_WIDTH {
+                let character = match scrollback.lines.get(line) {
+                    Some(line) => line[col],
+                    None => live.chars[line - scrollback.lines.len()][col].read(),
+                };
+                self.buffer.chars[row][col].write(character);
+            }
+        }
+
+        let indicator = format!("[scrollback -{}]", scrollback.offset);
+        let color_code = ColorCode::new(Color::Black, Color::LightGray);
+        let start = BUFFER_WIDTH - indicator.len();
+        for (i, byte) in indicator.bytes().enumerate() {
+            self.buffer.chars[0][start + i].write(ScreenChar {
+                ascii_character: byte,
+                color_code,
+            });
+        }
+    }
+
+    /// Clears a row by overwriting it with blank characters.
+    fn clear_row(&mut self, row: usize) {
+        let blank = self.blank();
         for col in 0..BUFFER_WIDTH {
-            self.buffer.chars[row][col].write(blank);
+            self.screen().chars[row][col].write(blank);
         }
     }
 
-    /// Clears the entire screen and resets the cursor position.
+    /// Clears the entire screen and moves the cursor to the top left.
     pub fn clear_screen(&mut self) {
         for row in 0..BUFFER_HEIGHT {
             self.clear_row(row);
         }
-        self.column_position = 0;
+        self.set_position(0, 0);
+    }
+
+    /// Writes `s` starting at `row` and `col`, without scrolling or moving the
+    /// cursor. Anything past the end of the row is cut off.
+    pub fn write_at(
+        &mut self,
+        row: usize,
+        col: usize,
+        s: &str,
+        foreground: Color,
+        background: Color,
+    ) {
+        if row >= BUFFER_HEIGHT {
+            return;
+        }
+        // full-screen programs want to be seen
+        self.scroll_to_live();
+        let color_code = ColorCode::new(foreground, background);
+        for (col, c) in (col..BUFFER_WIDTH).zip(s.chars()) {
+            // control characters show their CP437 glyph here, there's no cursor to move
+            let ascii_character = cp437::from_char(c).unwrap_or(cp437::UNKNOWN);
+            self.screen().chars[row][col].write(ScreenChar {
+                ascii_character,
+                color_code,
+            });
+        }
+    }
+
+    /// The CP437 character and the attribute byte (background color in the top
+    /// four bits, foreground in the bottom four) at `row`, `col`.
+    pub(crate) fn cell(&mut self, row: usize, col: usize) -> (u8, u8) {
+        let character = self.screen().chars[row][col].read();
+        (character.ascii_character, character.color_code.0)
+    }
+
+    /// Changes the character and attribute at `row`, `col`.
+    pub(crate) fn set_cell(&mut self, row: usize, col: usize, (character, attribute): (u8, u8)) {
+        self.screen().chars[row][col].write(ScreenChar {
+            ascii_character: character,
+            color_code: ColorCode(attribute),
+        });
+    }
+
+    /// Copies the whole screen, see `SavedScreen`.
+    pub fn save_screen(&mut self) -> SavedScreen {
+        let mut saved = SavedScreen {
+            chars: [[self.blank(); BUFFER_WIDTH]; BUFFER_HEIGHT],
+            column_position: self.column_position,
+            row_position: self.row_position,
+        };
+        for row in 0..BUFFER_HEIGHT {
+            for col in 0..BUFFER_WIDTH {
+                saved.chars[row][col] = self.screen().chars[row][col].read();
+            }
+        }
+        saved
+    }
+
+    /// Puts back a screen copied with `save_screen`.
+    pub fn restore_screen(&mut self, saved: &SavedScreen) {
+        for row in 0..BUFFER_HEIGHT {
+            for col in 0..BUFFER_WIDTH {
+                self.screen().chars[row][col].write(saved.chars[row][col]);
+            }
+        }
+        self.column_position = saved.column_position;
+        self.row_position = saved.row_position;
+        self.update_cursor();
     }
 
     /// Get the height of the buffer
@@ -161,6 +733,24 @@ impl Writer {
     }
 }
 
+fn read_crtc(register: u8) -> u8 {
+    use x86_64::instructions::port::Port;
+
+    unsafe {
+        Port::new(CRTC_INDEX).write(register);
+        Port::new(CRTC_DATA).read()
+    }
+}
+
+fn write_crtc(register: u8, value: u8) {
+    use x86_64::instructions::port::Port;
+
+    unsafe {
+        Port::new(CRTC_INDEX).write(register);
+        Port::new(CRTC_DATA).write(value);
+    }
+}
+
 impl fmt::Write for Writer {
     fn write_str(&mut self, s: &str) -> fmt::Result {
         self.write_string(s);
@@ -168,6 +758,29 @@ impl fmt::Write for Writer {
     }
 }
 
+/// Turns on scrollback for the current console, keeping `lines` lines. Needs the heap.
+pub fn enable_scrollback(lines: usize) {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| console::writer().set_scrollback(lines));
+}
+
+/// Called by the keyboard dispatcher for Shift+PageUp/PageDown, and with 0
+/// for any other key, which snaps back to the live screen.
+///
+/// Scrolls the active console. Does nothing if its writer is busy, rather than deadlocking.
+pub(crate) fn scroll_from_keyboard(lines: isize) {
+    if let Some(mut writer) = console::try_lock_active() {
+        if lines == 0 {
+            if writer.is_scrolled_back() {
+                writer.scroll_to_live();
+            }
+        } else {
+            writer.scroll(lines);
+        }
+    }
+}
+
 /// Like the `print!` macro in the standard library, but prints to the VGA text buffer.
 #[macro_export]
 macro_rules! print {
@@ -207,14 +820,18 @@ macro_rules! dbg {
 }
 
 /// Prints the given formatted string to the VGA text buffer
-/// through the global `WRITER` instance.
+/// through the current console's `Writer`.
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
     use x86_64::instructions::interrupts;
 
     interrupts::without_interrupts(|| {
-        WRITER.lock().write_fmt(args).unwrap();
+        console::writer().write_fmt(args).unwrap();
+        if console::current() == console::active() {
+            crate::framebuffer::console::_print(args);
+            crate::gui::_print(args);
+        }
     });
 }
 
@@ -237,7 +854,7 @@ fn test_println_output() {
 
     let s = "Some test string that fits on a single line";
     interrupts::without_interrupts(|| {
-        let mut writer = WRITER.lock();
+        let mut writer = console::writer();
         writeln!(writer, "\n{}", s).expect("writeln failed");
         for (i, c) in s.chars().enumerate() {
             let screen_char = writer.buffer.chars[BUFFER_HEIGHT - 2][i].read();
@@ -245,3 +862,22 @@ fn test_println_output() {
         }
     });
 }
+
+#[test_case]
+fn test_escape_sequences() {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = console::writer();
+        write!(writer, "\x1b[s\x1b[1;3H\x1b[31mX\x1b[0m\x1b[u").expect("write failed");
+        let screen_char = writer.buffer.chars[0][2].read();
+        assert_eq!(screen_char.ascii_character, b'X');
+        assert_eq!(
+            screen_char.color_code,
+            ColorCode::new(Color::Red, Color::Black)
+        );
+        assert_eq!(writer.row_position, BUFFER_HEIGHT - 1);
+        assert_eq!(writer.color_code, DEFAULT_STYLE.color_code());
+    });
+}
@@ -0,0 +1,277 @@
+//! A VT100/ANSI escape sequence parser for the VGA writer.
+//!
+//! Only the state machine lives here; what the sequences actually do is up to
+//! `Writer`. Handles the subset of ECMA-48 that terminals agree on: C0
+//! controls, `ESC x`, and CSI sequences (`ESC [ params final`).
+
+use super::{Color, ColorCode};
+
+/// Parameters past this many are dropped.
+const MAX_PARAMS: usize = 16;
+
+/// What to do with the next character of output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// A character to put on screen.
+    Print(char),
+    /// A C0 control character, like `\n` or `\r`.
+    Control(char),
+    /// `ESC` followed by this character (`ESC 7`, `ESC c`, ...).
+    Escape(char),
+    /// A complete CSI sequence.
+    Csi(Csi),
+}
+
+/// A CSI sequence, like `ESC [ 1 ; 31 m`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Csi {
+    params: [u16; MAX_PARAMS],
+    len: usize,
+    /// Set for DEC private sequences, which start with `?` (like `ESC [ ? 25 l`).
+    pub private: bool,
+    /// The character that ends the sequence and says what it does.
+    pub action: char,
+}
+
+impl Csi {
+    /// The parameters given. Missing ones are 0.
+    pub fn params(&self) -> &[u16] {
+        &self.params[..self.len]
+    }
+
+    /// Parameter `index`, or `default` if it's missing or 0.
+    pub fn param(&self, index: usize, default: u16) -> u16 {
+        match self.params().get(index) {
+            Some(&value) if value != 0 => value,
+            _ => default,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Ground,
+    Escape,
+    /// After `ESC (` and friends, which pick a character set. The next
+    /// character is swallowed, there's only one character set.
+    Charset,
+    Csi,
+}
+
+pub struct Parser {
+    state: State,
+    csi: Csi,
+}
+
+impl Parser {
+    pub const fn new() -> Self {
+        Parser {
+            state: State::Ground,
+            csi: Csi {
+                params: [0; MAX_PARAMS],
+                len: 0,
+                private: false,
+                action: '\0',
+            },
+        }
+    }
+
+    /// Feeds one character to the parser, returning what to do, if anything yet.
+    pub fn advance(&mut self, c: char) -> Option<Action> {
+        match c {
+            '\x1b' => {
+                self.state = State::Escape;
+                return None;
+            }
+            // CAN and SUB cancel a sequence
+            '\x18' | '\x1a' => {
+                self.state = State::Ground;
+                return None;
+            }
+            // other control characters still work in the middle of a sequence
+            '\0'..='\x1f' | '\x7f' => return Some(Action::Control(c)),
+            _ => {}
+        }
+
+        match self.state {
+            State::Ground => Some(Action::Print(c)),
+            State::Escape => match c {
+                '[' => {
+                    self.csi.params = [0; MAX_PARAMS];
+                    self.csi.len = 0;
+                    self.csi.private = false;
+                    self.state = State::Csi;
+                    None
+                }
+                '(' | ')' | '*' | '+' => {
+                    self.state = State::Charset;
+                    None
+                }
+                _ => {
+                    self.state = State::Ground;
+                    Some(Action::Escape(c))
+                }
+            },
+            State::Charset => {
+                self.state = State::Ground;
+                None
+            }
+            State::Csi => match c {
+                '0'..='9' => {
+                    if self.csi.len == 0 {
+                        self.csi.len = 1;
+                    }
+                    if let Some(param) = self.csi.params.get_mut(self.csi.len - 1) {
+                        let digit = c as u16 - '0' as u16;
+                        *param = param.saturating_mul(10).saturating_add(digit);
+                    }
+                    None
+                }
+                ';' => {
+                    // `ESC [ ; 5 H` has an empty first parameter
+                    self.csi.len = (self.csi.len.max(1) + 1).min(MAX_PARAMS);
+                    None
+                }
+                '?' | '>' | '=' | '<' => {
+                    self.csi.private = true;
+                    None
+                }
+                '@'..='~' => {
+                    self.state = State::Ground;
+                    self.csi.action = c;
+                    Some(Action::Csi(self.csi))
+                }
+                // intermediate bytes, which nothing we support uses
+                _ => None,
+            },
+        }
+    }
+}
+
+impl Default for Parser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Colors and attributes set with SGR (`ESC [ ... m`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Style {
+    foreground: Color,
+    background: Color,
+    bold: bool,
+    reverse: bool,
+}
+
+impl Style {
+    pub const fn new(foreground: Color, background: Color) -> Self {
+        Style {
+            foreground,
+            background,
+            bold: false,
+            reverse: false,
+        }
+    }
+
+    /// Applies the parameters of an SGR sequence. `default` is what `ESC [ 0 m`
+    /// goes back to.
+    pub fn apply_sgr(&mut self, params: &[u16], default: Style) {
+        if params.is_empty() {
+            *self = default;
+        }
+        for &param in params {
+            match param {
+                0 => *self = default,
+                1 => self.bold = true,
+                22 => self.bold = false,
+                7 => self.reverse = true,
+                27 => self.reverse = false,
+                30..=37 => self.foreground = ansi_color(param - 30),
+                39 => self.foreground = default.foreground,
+                40..=47 => self.background = ansi_color(param - 40),
+                49 => self.background = default.background,
+                90..=97 => self.foreground = bright(ansi_color(param - 90)),
+                100..=107 => self.background = bright(ansi_color(param - 100)),
+                // underline, blink, 256 colors, ... text mode can't do them
+                _ => {}
+            }
+        }
+    }
+
+    /// The foreground and background colors text should be drawn with, after
+    /// bold and reverse video.
+    pub fn colors(&self) -> (Color, Color) {
+        let foreground = if self.bold {
+            bright(self.foreground)
+        } else {
+            self.foreground
+        };
+        if self.reverse {
+            (self.background, foreground)
+        } else {
+            (foreground, self.background)
+        }
+    }
+
+    pub(super) fn color_code(&self) -> ColorCode {
+        let (foreground, background) = self.colors();
+        ColorCode::new(foreground, background)
+    }
+}
+
+/// The VGA color for ANSI color `index` (0-7). ANSI and VGA order them differently.
+fn ansi_color(index: u16) -> Color {
+    match index {
+        0 => Color::Black,
+        1 => Color::Red,
+        2 => Color::Green,
+        3 => Color::Brown,
+        4 => Color::Blue,
+        5 => Color::Magenta,
+        6 => Color::Cyan,
+        _ => Color::LightGray,
+    }
+}
+
+/// The bright version of one of the 8 dark colors.
+fn bright(color: Color) -> Color {
+    match color {
+        Color::Black => Color::DarkGray,
+        Color::Blue => Color::LightBlue,
+        Color::Green => Color::LightGreen,
+        Color::Cyan => Color::LightCyan,
+        Color::Red => Color::LightRed,
+        Color::Magenta => Color::Pink,
+        Color::Brown => Color::Yellow,
+        Color::LightGray => Color::White,
+        color => color,
+    }
+}
+
+#[test_case]
+fn test_ansi_parser() {
+    let mut parser = Parser::new();
+    let mut actions = [None; 10];
+    for (action, c) in actions.iter_mut().zip("a\x1b[1;31mb\n".chars()) {
+        *action = parser.advance(c);
+    }
+    assert_eq!(actions[0], Some(Action::Print('a')));
+    assert_eq!(actions[1], None);
+    match actions[7] {
+        Some(Action::Csi(csi)) => {
+            assert_eq!(csi.action, 'm');
+            assert_eq!(csi.params(), &[1, 31]);
+            assert_eq!(csi.param(2, 1), 1);
+        }
+        action => panic!("expected a CSI sequence, got {:?}", action),
+    }
+    assert_eq!(actions[8], Some(Action::Print('b')));
+    assert_eq!(actions[9], Some(Action::Control('\n')));
+
+    let mut style = Style::new(Color::Yellow, Color::Black);
+    style.apply_sgr(&[1, 31], Style::new(Color::Yellow, Color::Black));
+    assert_eq!(
+        style.color_code(),
+        ColorCode::new(Color::LightRed, Color::Black)
+    );
+}
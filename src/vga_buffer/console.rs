@@ -0,0 +1,132 @@
+//! Virtual consoles, switched between with Alt+F1 to Alt+F6.
+//!
+//! Each console is a `Writer` of its own, with its own screen, cursor and
+//! colors. Only the active one draws to the VGA buffer; the rest draw into a
+//! copy of it in memory, which is swapped in when you switch to them.
+//!
+//! Every task belongs to a console (see `Task::on_console`), and the executor
+//! makes it the current one while the task runs, so `print!` and the keyboard
+//! streams it creates go to the right place.
+
+use super::{Buffer, Color, ColorCode, ScreenChar, Writer, BUFFER_HEIGHT, BUFFER_WIDTH};
+use core::{
+    ptr::addr_of_mut,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+use lazy_static::lazy_static;
+use spin::{Mutex, MutexGuard};
+
+/// How many consoles there are, one for each of F1 to F6.
+pub const CONSOLE_COUNT: usize = 6;
+
+/// The console on screen.
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+/// The console of the task that's running. Output from outside any task
+/// (while booting, or interrupts while the executor sleeps) goes to console 0.
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+/// Cleared once the screen is in a graphics mode, and the VGA text buffer
+/// isn't shown any more.
+static TEXT_DISPLAY: AtomicBool = AtomicBool::new(true);
+
+const BLANK: ScreenChar = ScreenChar {
+    ascii_character: b' ',
+    color_code: ColorCode::new(Color::Yellow, Color::Black),
+};
+
+/// Where each console's screen is kept while it isn't the active one. Too
+/// big for the heap, and the writers exist before the heap does anyway.
+static mut BACKING: [[[ScreenChar; BUFFER_WIDTH]; BUFFER_HEIGHT]; CONSOLE_COUNT] =
+    [[[BLANK; BUFFER_WIDTH]; BUFFER_HEIGHT]; CONSOLE_COUNT];
+
+lazy_static! {
+    static ref CONSOLES: [Mutex<Writer>; CONSOLE_COUNT] = {
+        let console = |index: usize| {
+            // `Buffer` is laid out just like an array of `ScreenChar`s
+            let backing = unsafe { &mut *(addr_of_mut!(BACKING[index]) as *mut Buffer) };
+            Mutex::new(Writer::new(backing, index == 0))
+        };
+        [
+            console(0),
+            console(1),
+            console(2),
+            console(3),
+            console(4),
+            console(5),
+        ]
+    };
+}
+
+/// Locks the writer of the current console.
+///
+/// Used by the `print!` and `println!` macros.
+pub fn writer() -> MutexGuard<'static, Writer> {
+    CONSOLES[current()].lock()
+}
+
+/// The console output goes to right now.
+pub fn current() -> usize {
+    CURRENT.load(Ordering::SeqCst)
+}
+
+/// Makes `console` the current one, returning the one that was. Used by the
+/// executor around polling a task.
+pub fn set_current(console: usize) -> usize {
+    CURRENT.swap(console.min(CONSOLE_COUNT - 1), Ordering::SeqCst)
+}
+
+/// The console on screen, which gets the keyboard.
+pub fn active() -> usize {
+    ACTIVE.load(Ordering::SeqCst)
+}
+
+/// Puts `console` on screen.
+pub fn switch_to(console: usize) {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let old = active();
+        if console >= CONSOLE_COUNT || console == old {
+            return;
+        }
+        let mut old_writer = CONSOLES[old].lock();
+        let mut new_writer = CONSOLES[console].lock();
+        swap(&mut old_writer, &mut new_writer, console);
+    });
+}
+
+/// Locks the active console's writer, if nobody else has it.
+pub(crate) fn try_lock_active() -> Option<MutexGuard<'static, Writer>> {
+    CONSOLES[active()].try_lock()
+}
+
+/// Stops drawing the active console on the VGA text buffer, for when the
+/// screen is switched to a graphics mode. `show` gets the active console's
+/// writer, to draw what's on it some other way.
+pub fn disable_text_display(show: impl FnOnce(&mut Writer)) {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = CONSOLES[active()].lock();
+        writer.hide();
+        TEXT_DISPLAY.store(false, Ordering::SeqCst);
+        show(&mut writer);
+    });
+}
+
+/// Runs `f` on the active console's writer.
+pub(crate) fn with_active<R>(f: impl FnOnce(&mut Writer) -> R) -> R {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| f(&mut CONSOLES[active()].lock()))
+}
+
+fn swap(old: &mut Writer, new: &mut Writer, console: usize) {
+    old.hide();
+    if TEXT_DISPLAY.load(Ordering::SeqCst) {
+        new.show();
+    } else {
+        crate::framebuffer::console::show_text_console(new);
+        crate::gui::show_text_console(new);
+    }
+    ACTIVE.store(console, Ordering::SeqCst);
+}
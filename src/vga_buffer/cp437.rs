@@ -0,0 +1,115 @@
+//! Code page 437, the character set the VGA's built-in font is laid out in.
+//!
+//! The bottom half is ASCII. The top half has accented letters, Greek, math
+//! symbols, and the box-drawing and block characters TUIs are built from.
+
+/// What's printed for characters the font doesn't have: a small square.
+pub const UNKNOWN: u8 = 0xfe;
+
+/// The glyphs for 0x01 to 0x1f. They're also control characters, so only
+/// `write_at` and the like can put them on screen.
+const LOW: [char; 31] = [
+    '☺', '☻', '♥', '♦', '♣', '♠', '•', '◘', '○', '◙', '♂', '♀', '♪', '♫', '☼', '►', '◄', '↕', '‼',
+    '¶', '§', '▬', '↨', '↑', '↓', '→', '←', '∟', '↔', '▲', '▼',
+];
+
+/// The glyphs for 0x80 to 0xff.
+const HIGH: [char; 128] = [
+    'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å', //
+    'É', 'æ', 'Æ', 'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', '¢', '£', '¥', '₧', 'ƒ', //
+    'á', 'í', 'ó', 'ú', 'ñ', 'Ñ', 'ª', 'º', '¿', '⌐', '¬', '½', '¼', '¡', '«', '»', //
+    '░', '▒', '▓', '│', '┤', '╡', '╢', '╖', '╕', '╣', '║', '╗', '╝', '╜', '╛', '┐', //
+    '└', '┴', '┬', '├', '─', '┼', '╞', '╟', '╚', '╔', '╩', '╦', '╠', '═', '╬', '╧', //
+    '╨', '╤', '╥', '╙', '╘', '╒', '╓', '╫', '╪', '┘', '┌', '█', '▄', '▌', '▐', '▀', //
+    'α', 'ß', 'Γ', 'π', 'Σ', 'σ', 'µ', 'τ', 'Φ', 'Θ', 'Ω', 'δ', '∞', 'φ', 'ε', '∩', //
+    '≡', '±', '≥', '≤', '⌠', '⌡', '÷', '≈', '°', '∙', '·', '√', 'ⁿ', '²', '■', '\u{a0}',
+];
+
+/// Characters that aren't in CP437 but look enough like one that is.
+const LOOKALIKES: [(char, char); 24] = [
+    ('β', 'ß'),
+    ('\u{3bc}', 'µ'),
+    ('\u{2126}', 'Ω'),
+    ('∈', 'ε'),
+    ('∅', 'φ'),
+    ('╭', '┌'),
+    ('╮', '┐'),
+    ('╰', '└'),
+    ('╯', '┘'),
+    ('━', '─'),
+    ('┃', '│'),
+    ('┏', '┌'),
+    ('┓', '┐'),
+    ('┗', '└'),
+    ('┛', '┘'),
+    ('▪', '■'),
+    ('‘', '\''),
+    ('’', '\''),
+    ('“', '"'),
+    ('”', '"'),
+    ('–', '-'),
+    ('—', '-'),
+    ('−', '-'),
+    ('×', 'x'),
+];
+
+/// Returns the CP437 code for `c`, or `None` if the font has nothing like it.
+///
+/// Control characters map to themselves; it's up to the caller to decide
+/// whether they're glyphs or controls.
+pub fn from_char(c: char) -> Option<u8> {
+    if c.is_ascii() {
+        return Some(c as u8);
+    }
+    if c == '⌂' {
+        return Some(0x7f);
+    }
+    let c = LOOKALIKES
+        .iter()
+        .find(|&&(from, _)| from == c)
+        .map_or(c, |&(_, to)| to);
+    if c.is_ascii() {
+        return Some(c as u8);
+    }
+    if let Some(index) = HIGH.iter().position(|&glyph| glyph == c) {
+        return Some(0x80 + index as u8);
+    }
+    LOW.iter()
+        .position(|&glyph| glyph == c)
+        .map(|index| 0x01 + index as u8)
+}
+
+/// Like `from_char`, but printable: characters the font doesn't have and
+/// control characters become `UNKNOWN`.
+pub fn encode(c: char) -> u8 {
+    match from_char(c) {
+        Some(byte) if !c.is_ascii_control() => byte,
+        _ => UNKNOWN,
+    }
+}
+
+/// Returns the character CP437 code `byte` shows.
+pub fn to_char(byte: u8) -> char {
+    match byte {
+        0x01..=0x1f => LOW[usize::from(byte) - 0x01],
+        0x7f => '⌂',
+        0x80..=0xff => HIGH[usize::from(byte) - 0x80],
+        _ => char::from(byte),
+    }
+}
+
+#[test_case]
+fn test_cp437() {
+    assert_eq!(encode('A'), b'A');
+    assert_eq!(encode('─'), 0xc4);
+    assert_eq!(encode('╔'), 0xc9);
+    assert_eq!(encode('é'), 0x82);
+    assert_eq!(encode('█'), 0xdb);
+    assert_eq!(encode('☺'), 0x01);
+    assert_eq!(encode('╭'), 0xda);
+    assert_eq!(encode('\n'), UNKNOWN);
+    assert_eq!(encode('☃'), UNKNOWN);
+    for byte in 0x01..=0xff {
+        assert_eq!(from_char(to_char(byte)), Some(byte));
+    }
+}
@@ -0,0 +1,188 @@
+//! The mouse pointer on the text screen, and copying text with it.
+//!
+//! The pointer is drawn by swapping the colors of the cell under it.
+//! Dragging with the left button selects text, which is copied to the
+//! clipboard when the button is let go; the middle button pastes it into
+//! whatever is reading the keyboard. The wheel scrolls back.
+
+use super::{console, cp437, Writer, BUFFER_HEIGHT, BUFFER_WIDTH};
+use crate::task::{keyboard, mouse::MouseEvent};
+use alloc::string::String;
+use spin::Mutex;
+
+/// How far the mouse moves to go one cell over.
+const COUNTS_PER_COLUMN: i32 = 8;
+const COUNTS_PER_ROW: i32 = 16;
+/// How many lines a notch of the wheel scrolls.
+const WHEEL_LINES: isize = 3;
+
+static CLIPBOARD: Mutex<String> = Mutex::new(String::new());
+
+/// What was last copied.
+pub fn clipboard() -> String {
+    CLIPBOARD.lock().clone()
+}
+
+pub fn set_clipboard(text: &str) {
+    let mut clipboard = CLIPBOARD.lock();
+    clipboard.clear();
+    clipboard.push_str(text);
+}
+
+/// Swaps the foreground and background colors of an attribute byte.
+fn inverted(attribute: u8) -> u8 {
+    attribute.rotate_left(4)
+}
+
+pub struct Pointer {
+    /// Where the pointer is, in mouse counts.
+    x: i32,
+    y: i32,
+    /// The console the pointer is on.
+    console: usize,
+    /// The cell the pointer is drawn on, and what was in it.
+    drawn: Option<(usize, usize, (u8, u8))>,
+    /// The cell the selection started on, while the left button is down.
+    anchor: Option<usize>,
+    /// The cells shown as selected, as `row * BUFFER_WIDTH + col`.
+    highlighted: Option<(usize, usize)>,
+    left_held: bool,
+    middle_held: bool,
+}
+
+impl Pointer {
+    pub fn new() -> Pointer {
+        Pointer {
+            x: (BUFFER_WIDTH as i32 / 2) * COUNTS_PER_COLUMN,
+            y: (BUFFER_HEIGHT as i32 / 2) * COUNTS_PER_ROW,
+            console: console::active(),
+            drawn: None,
+            anchor: None,
+            highlighted: None,
+            left_held: false,
+            middle_held: false,
+        }
+    }
+
+    /// The cell under the pointer, as `row * BUFFER_WIDTH + col`.
+    fn cell(&self) -> usize {
+        let row = (self.y / COUNTS_PER_ROW) as usize;
+        let col = (self.x / COUNTS_PER_COLUMN) as usize;
+        row * BUFFER_WIDTH + col
+    }
+
+    pub fn handle(&mut self, event: &MouseEvent) {
+        console::with_active(|writer| {
+            if console::active() != self.console {
+                // whatever was drawn went with the old console's screen
+                self.console = console::active();
+                self.drawn = None;
+                self.anchor = None;
+                self.highlighted = None;
+            }
+            self.undraw(writer);
+
+            if event.wheel != 0 {
+                writer.scroll(-isize::from(event.wheel) * WHEEL_LINES);
+            }
+            self.x = (self.x + i32::from(event.dx))
+                .clamp(0, BUFFER_WIDTH as i32 * COUNTS_PER_COLUMN - 1);
+            self.y =
+                (self.y + i32::from(event.dy)).clamp(0, BUFFER_HEIGHT as i32 * COUNTS_PER_ROW - 1);
+
+            let buttons = event.buttons;
+            if buttons.left && !self.left_held {
+                // selecting works on the live screen
+                writer.scroll_to_live();
+                self.anchor = Some(self.cell());
+            }
+            if let Some(anchor) = self.anchor {
+                let cell = self.cell();
+                self.highlight(writer, Some((anchor.min(cell), anchor.max(cell))));
+                if !buttons.left {
+                    if let Some((start, end)) = self.highlighted {
+                        set_clipboard(&selected_text(writer, start, end));
+                    }
+                    self.highlight(writer, None);
+                    self.anchor = None;
+                }
+            }
+            if buttons.middle && !self.middle_held {
+                keyboard::paste();
+            }
+            self.left_held = buttons.left;
+            self.middle_held = buttons.middle;
+
+            if !writer.is_scrolled_back() {
+                self.draw(writer);
+            }
+        });
+    }
+
+    /// Shows `range` as selected instead of whatever was before.
+    fn highlight(&mut self, writer: &mut Writer, range: Option<(usize, usize)>) {
+        if self.highlighted == range {
+            return;
+        }
+        // inverting twice puts the colors back
+        for (start, end) in self.highlighted.into_iter().chain(range) {
+            for cell in start..=end {
+                let (row, col) = (cell / BUFFER_WIDTH, cell % BUFFER_WIDTH);
+                let (character, attribute) = writer.cell(row, col);
+                writer.set_cell(row, col, (character, inverted(attribute)));
+            }
+        }
+        self.highlighted = range;
+    }
+
+    fn draw(&mut self, writer: &mut Writer) {
+        let cell = self.cell();
+        let (row, col) = (cell / BUFFER_WIDTH, cell % BUFFER_WIDTH);
+        let (character, attribute) = writer.cell(row, col);
+        writer.set_cell(row, col, (character, inverted(attribute)));
+        self.drawn = Some((row, col, (character, attribute)));
+    }
+
+    fn undraw(&mut self, writer: &mut Writer) {
+        if let Some((row, col, (character, attribute))) = self.drawn.take() {
+            // unless something was written over it since
+            if writer.cell(row, col) == (character, inverted(attribute)) {
+                writer.set_cell(row, col, (character, attribute));
+            }
+        }
+    }
+}
+
+impl Default for Pointer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The text in cells `start..=end`, a line for each row, without the
+/// trailing blanks.
+fn selected_text(writer: &mut Writer, start: usize, end: usize) -> String {
+    let mut text = String::new();
+    for row in start / BUFFER_WIDTH..=end / BUFFER_WIDTH {
+        if row != start / BUFFER_WIDTH {
+            text.push('\n');
+        }
+        let first = if row == start / BUFFER_WIDTH {
+            start % BUFFER_WIDTH
+        } else {
+            0
+        };
+        let last = if row == end / BUFFER_WIDTH {
+            end % BUFFER_WIDTH
+        } else {
+            BUFFER_WIDTH - 1
+        };
+        let line_start = text.len();
+        for col in first..=last {
+            text.push(cp437::to_char(writer.cell(row, col).0));
+        }
+        let trimmed = text[line_start..].trim_end().len();
+        text.truncate(line_start + trimmed);
+    }
+    text
+}
@@ -6,10 +6,10 @@
 
 extern crate alloc;
 
-use alloc::{boxed::Box, vec::Vec};
+use alloc::{boxed::Box, vec, vec::Vec};
 use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
-use lemonade::allocator::HEAP_SIZE;
+use lemonade::allocator::{self, HEAP_SIZE};
 
 entry_point!(main);
 
@@ -20,9 +20,11 @@ fn main(boot_info: &'static BootInfo) -> ! {
 
     lemonade::init();
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
-    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    unsafe {
+        memory::init(phys_mem_offset);
+        BootInfoFrameAllocator::init(&boot_info.memory_map);
+    }
+    allocator::init_heap().expect("heap initialization failed");
 
     test_main();
     loop {}
@@ -54,6 +56,14 @@ fn many_boxes_long_lived() {
     assert_eq!(*long_lived, 1); // new
 }
 
+#[test_case]
+fn large_allocation_grows_heap() {
+    let before = allocator::heap_size();
+    let big = vec![7u8; HEAP_SIZE * 4];
+    assert!(allocator::heap_size() > before);
+    assert_eq!(big[HEAP_SIZE * 4 - 1], 7);
+}
+
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     lemonade::test_panic_handler(info)